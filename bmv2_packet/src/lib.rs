@@ -40,12 +40,41 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use anyhow::Result;
-use nanomsg::Socket;
-use packet::Packet;
+use anyhow::{anyhow, Context, Result};
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream, StreamExt};
+use grpcio::{ChannelBuilder, ClientDuplexReceiver, Environment, StreamingCallSink, WriteFlags};
+use hwaddr::HwAddr;
+use nanomsg::{Protocol, Socket};
+use ovs::rate_limit::RateLimiter;
+use packet::{Builder, Packet};
+use proto::p4runtime::{
+    MasterArbitrationUpdate,
+    PacketMetadata,
+    PacketOut,
+    StreamMessageRequest,
+    StreamMessageResponse,
+    StreamMessageResponse_oneof_update,
+    Uint128,
+};
+use proto::p4runtime_grpc::P4RuntimeClient;
+use protobuf::RepeatedField;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::{Read, Write, ErrorKind};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use thiserror::Error;
+use tokio::runtime::Runtime;
+use vsock::VsockStream;
 
 /// These must match the values in
 /// <https://github.com/p4lang/behavioral-model/blob/main/src/bm_sim/dev_mgr_packet_in.cpp>.
@@ -250,31 +279,845 @@ impl From<Bmv2Message> for Vec<u8> {
     }
 }
 
-/// Sends `request` on `s`, then waits for replies until no more replies have been received for one
-/// second, and returns the replies.
+/// Default source and destination MAC addresses for frames built by [`FrameBuilder`], when the
+/// caller doesn't need any particular address -- most test packets care about the IP/port tuple,
+/// not the Ethernet addresses carrying it.
+fn default_src_mac() -> HwAddr { HwAddr::from([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]) }
+fn default_dst_mac() -> HwAddr { HwAddr::from([0x02, 0x00, 0x00, 0x00, 0x00, 0x02]) }
+
+/// Builds a well-formed Ethernet frame, the write-side complement to [`Frame`]'s `Debug` impl:
+/// that can only describe a frame it already has, and until now there was no way to synthesize
+/// one short of hand-assembling a raw `Vec<u8>` and getting the 4-bit version/IHL nibble,
+/// total-length, IPv4 header checksum, and UDP/TCP pseudo-header checksum right by hand.
+/// [`packet::Builder`] computes all of those, so `FrameBuilder` just has to drive it.
 ///
-/// Ordinarily, `s` should be a `Bmv2Message::PacketOut` to cause a packet to be received on a port.
+/// ```ignore
+/// let frame = FrameBuilder::ipv4_udp(src, dst, sport, dport, payload).build()?;
+/// ```
+pub struct FrameBuilder {
+    src_mac: HwAddr,
+    dst_mac: HwAddr,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    transport: FrameTransport,
+}
+
+/// The transport-layer payload of a frame under construction; see [`FrameBuilder`].
+enum FrameTransport {
+    Udp { sport: u16, dport: u16, payload: Vec<u8> },
+    Tcp { sport: u16, dport: u16, payload: Vec<u8> },
+    Icmp { identifier: u16, sequence: u16, payload: Vec<u8> },
+}
+
+impl FrameBuilder {
+    /// Starts building an Ethernet frame carrying an IPv4 UDP datagram from `src:sport` to
+    /// `dst:dport` with the given `payload`. Use [`Self::src_mac`]/[`Self::dst_mac`] to override
+    /// the Ethernet addresses, which otherwise default to a fixed, arbitrary locally-administered
+    /// pair.
+    pub fn ipv4_udp(src: Ipv4Addr, dst: Ipv4Addr, sport: u16, dport: u16, payload: &[u8]) -> Self {
+        FrameBuilder {
+            src_mac: default_src_mac(),
+            dst_mac: default_dst_mac(),
+            src,
+            dst,
+            transport: FrameTransport::Udp { sport, dport, payload: payload.to_vec() },
+        }
+    }
+
+    /// Like [`Self::ipv4_udp`], but carrying a TCP segment instead.
+    pub fn ipv4_tcp(src: Ipv4Addr, dst: Ipv4Addr, sport: u16, dport: u16, payload: &[u8]) -> Self {
+        FrameBuilder {
+            src_mac: default_src_mac(),
+            dst_mac: default_dst_mac(),
+            src,
+            dst,
+            transport: FrameTransport::Tcp { sport, dport, payload: payload.to_vec() },
+        }
+    }
+
+    /// Like [`Self::ipv4_udp`], but carrying an ICMP echo request instead, with `payload` as the
+    /// echo data. The echo request's identifier and sequence number default to 0; override them
+    /// with [`Self::icmp_identifier`]/[`Self::icmp_sequence`] -- [`ping`] needs both set to
+    /// recognize the matching reply.
+    pub fn ipv4_icmp(src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Self {
+        FrameBuilder {
+            src_mac: default_src_mac(),
+            dst_mac: default_dst_mac(),
+            src,
+            dst,
+            transport: FrameTransport::Icmp { identifier: 0, sequence: 0, payload: payload.to_vec() },
+        }
+    }
+
+    /// Overrides the frame's Ethernet source address (default: an arbitrary locally-administered
+    /// address).
+    pub fn src_mac(mut self, src_mac: HwAddr) -> Self {
+        self.src_mac = src_mac;
+        self
+    }
+
+    /// Overrides the frame's Ethernet destination address (default: an arbitrary
+    /// locally-administered address).
+    pub fn dst_mac(mut self, dst_mac: HwAddr) -> Self {
+        self.dst_mac = dst_mac;
+        self
+    }
+
+    /// Overrides an ICMP echo request's identifier field (default: 0). No-op on a non-ICMP frame.
+    pub fn icmp_identifier(mut self, identifier: u16) -> Self {
+        if let FrameTransport::Icmp { identifier: id, .. } = &mut self.transport {
+            *id = identifier;
+        }
+        self
+    }
+
+    /// Overrides an ICMP echo request's sequence number field (default: 0). No-op on a non-ICMP
+    /// frame.
+    pub fn icmp_sequence(mut self, sequence: u16) -> Self {
+        if let FrameTransport::Icmp { sequence: seq, .. } = &mut self.transport {
+            *seq = sequence;
+        }
+        self
+    }
+
+    /// Serializes the frame, computing the IPv4 header checksum and, for UDP/TCP, the
+    /// pseudo-header checksum, ready to wrap in a [`Bmv2Message::PacketIn`].
+    pub fn build(self) -> Result<Frame> {
+        let ip = packet::ether::Builder::default()
+            .destination(self.dst_mac)?
+            .source(self.src_mac)?
+            .ip()?
+            .v4()?
+            .source(self.src)?
+            .destination(self.dst)?;
+
+        let bytes = match self.transport {
+            FrameTransport::Udp { sport, dport, payload } => ip
+                .udp()?
+                .source(sport)?
+                .destination(dport)?
+                .payload(&payload)?
+                .build()?,
+            FrameTransport::Tcp { sport, dport, payload } => ip
+                .tcp()?
+                .source(sport)?
+                .destination(dport)?
+                .payload(&payload)?
+                .build()?,
+            FrameTransport::Icmp { identifier, sequence, payload } => ip
+                .icmp()?
+                .echo()?
+                .request()?
+                .identifier(identifier)?
+                .sequence(sequence)?
+                .payload(&payload)?
+                .build()?,
+        };
+
+        Ok(Frame(bytes))
+    }
+}
+
+/// A bidirectional, message-oriented channel to bmv2's `--packet-in` interface, abstracting over
+/// the underlying transport so the nerpa controller and the bmv2 dataplane it talks to don't have
+/// to share a filesystem (nanomsg's `ipc://` endpoints) or even run on the same host. See
+/// [`connect`] to open one from an endpoint string, and [`send_and_receive`] to use one.
+pub trait Bmv2Transport {
+    /// Sends `msg`, blocking until it's been written in full.
+    fn send(&mut self, msg: Bmv2Message) -> Result<()>;
+
+    /// Waits up to `timeout_ms` milliseconds for the next message. Returns `Ok(None)` on timeout
+    /// rather than an error -- the same way `send_and_receive`'s loop has always treated a read
+    /// timeout, just no longer hard-coded to nanomsg's `ErrorKind::TimedOut`.
+    fn recv(&mut self, timeout_ms: i32) -> Result<Option<Bmv2Message>>;
+}
+
+/// The original transport: a nanomsg `Protocol::Pair` socket, for an `ipc://` or `tcp://`
+/// endpoint.
+pub struct NanomsgTransport(Socket);
+
+impl NanomsgTransport {
+    /// Connects a nanomsg `Pair` socket to `endpoint`, e.g. `"ipc://bmv2.ipc"`.
+    pub fn connect(endpoint: &str) -> Result<Self> {
+        let mut socket = Socket::new(Protocol::Pair)?;
+        socket.connect(endpoint)?;
+        Ok(NanomsgTransport(socket))
+    }
+}
+
+impl Bmv2Transport for NanomsgTransport {
+    fn send(&mut self, msg: Bmv2Message) -> Result<()> {
+        self.0.write_all(&Vec::<u8>::from(msg))?;
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout_ms: i32) -> Result<Option<Bmv2Message>> {
+        self.0.set_receive_timeout(timeout_ms)?;
+        let mut msg = Vec::new();
+        match self.0.read_to_end(&mut msg) {
+            Ok(_) => Ok(Some(Bmv2Message::try_from(msg)?)),
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The length, in bytes, of the payload following a `Bmv2Message`'s 12-byte fixed header (`type`,
+/// `port`, `more`; see `Bmv2Message`'s `From`/`TryFrom` impls), given the header's `type` and
+/// `more` fields. nanomsg delivers a whole message per read regardless, but AF_VSOCK is a byte
+/// stream, so `VsockTransport` needs this to find each message's boundary for itself.
+fn payload_len(type_: i32, more: i32) -> usize {
+    match type_ {
+        MSG_TYPE_PACKET_IN | MSG_TYPE_PACKET_OUT => more.max(0) as usize,
+        _ => 0,
+    }
+}
+
+/// AF_VSOCK transport, for reaching a bmv2 dataplane running in a different VM than the nerpa
+/// controller, addressed by `(cid, port)` instead of a filesystem path nanomsg IPC would need the
+/// two VMs to share a mount for.
+pub struct VsockTransport(VsockStream);
+
+impl VsockTransport {
+    /// Connects to `cid:port` -- the `3:2345` in a `"vsock://3:2345"` endpoint; see [`connect`].
+    pub fn connect(cid: u32, port: u32) -> Result<Self> {
+        Ok(VsockTransport(VsockStream::connect_with_cid_port(cid, port)?))
+    }
+}
+
+impl Bmv2Transport for VsockTransport {
+    fn send(&mut self, msg: Bmv2Message) -> Result<()> {
+        self.0.write_all(&Vec::<u8>::from(msg))?;
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout_ms: i32) -> Result<Option<Bmv2Message>> {
+        self.0.set_read_timeout(Some(Duration::from_millis(timeout_ms.max(0) as u64)))?;
+
+        let mut header = [0u8; 12];
+        match self.0.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(ref e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let type_ = i32::from_ne_bytes(header[0..4].try_into()?);
+        let more = i32::from_ne_bytes(header[8..12].try_into()?);
+
+        let mut payload = vec![0u8; payload_len(type_, more)];
+        self.0.read_exact(&mut payload)?;
+
+        let mut msg = header.to_vec();
+        msg.extend(payload);
+        Ok(Some(Bmv2Message::try_from(msg)?))
+    }
+}
+
+/// P4Runtime `StreamChannel`-based transport, for driving a real P4Runtime target (or a
+/// P4Runtime-speaking simulator) with the same `Bmv2Message` traffic and [`send_and_receive`] loop
+/// the nanomsg/AF_VSOCK backends use against bmv2 directly -- so the same test (or controller)
+/// logic exercises both kinds of target, selected purely by which endpoint string it's given.
+///
+/// Direction is necessarily reinterpreted relative to the nanomsg backends, since a real target
+/// has no "pretend you just received this" hook the way bmv2's own protocol does: [`Self::send`]ing
+/// a `Bmv2Message::PacketIn { port, .. }` becomes a P4Runtime `PacketOut` whose `egress_port`
+/// controller metadata (see `packet_out_metadata` in `ofp4`, which plays the same role the other
+/// way around) is filled in with `port`, asking the target's P4 program to treat the payload as
+/// freshly arrived there; a `StreamMessageResponse` the target sends back carrying a `PacketIn`
+/// becomes a `Bmv2Message::PacketOut { port, .. }`, reading `port` back out of its `ingress_port`
+/// metadata. Both metadata ids are specific to the P4 program under test (see its P4Info), so the
+/// caller supplies them rather than this transport loading and parsing a P4Info itself.
+///
+/// Internally runs its own Tokio runtime to drive the `grpcio` stream, since
+/// `Bmv2Transport` (unlike the rest of this crate's P4Runtime-facing code) is a blocking interface.
+pub struct P4RuntimeTransport {
+    sink: StreamingCallSink<StreamMessageRequest>,
+    receiver: ClientDuplexReceiver<StreamMessageResponse>,
+    runtime: Runtime,
+    egress_port_metadata_id: u32,
+    ingress_port_metadata_id: u32,
+}
+
+impl P4RuntimeTransport {
+    /// Opens a `StreamChannel` to the P4Runtime server at `target` (e.g. `"127.0.0.1:50051"`) and
+    /// sends it a `MasterArbitrationUpdate` bidding election id 1, the same opening move
+    /// `SwitchClient::new` makes in the full controller. `egress_port_metadata_id` and
+    /// `ingress_port_metadata_id` are the `packet_out.egress_port` and `packet_in.ingress_port`
+    /// controller metadata ids from the target's P4Info (see `ofp4::packet_metadata_ids`).
+    pub fn connect(target: &str, device_id: u64, egress_port_metadata_id: u32,
+                   ingress_port_metadata_id: u32) -> Result<Self> {
+        let runtime = Runtime::new()?;
+        let env = Arc::new(Environment::new(1));
+        let channel = ChannelBuilder::new(env).connect(target);
+        let client = P4RuntimeClient::new(channel);
+
+        let (mut sink, receiver) = client.stream_channel()?;
+        runtime.block_on(async {
+            let mut election_id = Uint128::new();
+            election_id.set_low(1);
+            let mut arbitration = MasterArbitrationUpdate::new();
+            arbitration.set_device_id(device_id);
+            arbitration.set_election_id(election_id);
+            let mut request = StreamMessageRequest::new();
+            request.set_arbitration(arbitration);
+            sink.send((request, WriteFlags::default())).await
+        })?;
+
+        Ok(P4RuntimeTransport { sink, receiver, runtime, egress_port_metadata_id, ingress_port_metadata_id })
+    }
+}
+
+impl Bmv2Transport for P4RuntimeTransport {
+    fn send(&mut self, msg: Bmv2Message) -> Result<()> {
+        let (port, payload) = match msg {
+            Bmv2Message::PacketIn { port, payload } => (port, payload),
+            Bmv2Message::PacketOut { port, payload } => (port, payload),
+            other => return Err(anyhow!("{other:?}: P4Runtime transport only carries PacketIn/PacketOut")),
+        };
+
+        let mut metadatum = PacketMetadata::new();
+        metadatum.set_metadata_id(self.egress_port_metadata_id);
+        metadatum.set_value((port as u32).to_be_bytes().to_vec());
+
+        let mut packet_out = PacketOut::new();
+        packet_out.set_payload(payload.0);
+        packet_out.set_metadata(RepeatedField::from_vec(vec![metadatum]));
+
+        let mut request = StreamMessageRequest::new();
+        request.set_packet(packet_out);
+
+        let sink = &mut self.sink;
+        self.runtime.block_on(async { sink.send((request, WriteFlags::default())).await })?;
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout_ms: i32) -> Result<Option<Bmv2Message>> {
+        let ingress_port_metadata_id = self.ingress_port_metadata_id;
+        let receiver = &mut self.receiver;
+        let response = self.runtime.block_on(async {
+            tokio::time::timeout(Duration::from_millis(timeout_ms.max(0) as u64), receiver.next()).await
+        });
+
+        let response = match response {
+            Ok(Some(response)) => response?,
+            Ok(None) => return Ok(None), // Stream closed.
+            Err(_) => return Ok(None),   // Timed out.
+        };
+
+        let packet = match response.update {
+            Some(StreamMessageResponse_oneof_update::packet(packet)) => packet,
+            _ => return Ok(None),
+        };
+        let port = packet.get_metadata().iter()
+            .find(|m| m.get_metadata_id() == ingress_port_metadata_id)
+            .and_then(|m| m.get_value().try_into().ok())
+            .map(u32::from_be_bytes)
+            .ok_or_else(|| anyhow!("PacketIn is missing ingress_port metadata"))?;
+        Ok(Some(Bmv2Message::PacketOut { port: port as i32, payload: Frame(packet.payload) }))
+    }
+}
+
+/// bmv2's own grpc-based simulated-device interface -- the one this crate's module docs call out
+/// as an alternative to the nanomsg one ("There's another one based on grpc instead") -- carried
+/// over [`proto::packet_io_grpc::PacketIoClient`]'s `StreamPackets` duplex stream. Unlike
+/// [`P4RuntimeTransport`], this talks bmv2's native packet-I/O protocol directly: each
+/// [`proto::packet_io::PacketIoMessage`] carries a `Bmv2Message`'s encoded bytes verbatim (see its
+/// `From`/`TryFrom` impls), so `send`/`recv` here just wrap/unwrap that envelope instead of
+/// reinterpreting ports and payloads the way the P4Runtime target does.
+///
+/// Internally runs its own Tokio runtime to drive the `grpcio` stream, exactly
+/// like [`P4RuntimeTransport`] -- see its doc comment for why.
+pub struct GrpcTransport {
+    sink: StreamingCallSink<proto::packet_io::PacketIoMessage>,
+    receiver: ClientDuplexReceiver<proto::packet_io::PacketIoMessage>,
+    runtime: Runtime,
+}
+
+impl GrpcTransport {
+    /// Opens a `StreamPackets` duplex stream to the bmv2 grpc packet-I/O server at `target`
+    /// (e.g. `"127.0.0.1:9559"`).
+    pub fn connect(target: &str) -> Result<Self> {
+        let runtime = Runtime::new()?;
+        let env = Arc::new(Environment::new(1));
+        let channel = ChannelBuilder::new(env).connect(target);
+        let client = proto::packet_io_grpc::PacketIoClient::new(channel);
+        let (sink, receiver) = client.stream_packets()?;
+        Ok(GrpcTransport { sink, receiver, runtime })
+    }
+}
+
+impl Bmv2Transport for GrpcTransport {
+    fn send(&mut self, msg: Bmv2Message) -> Result<()> {
+        let mut request = proto::packet_io::PacketIoMessage::new();
+        request.set_data(Vec::<u8>::from(msg));
+
+        let sink = &mut self.sink;
+        self.runtime.block_on(async { sink.send((request, WriteFlags::default())).await })?;
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout_ms: i32) -> Result<Option<Bmv2Message>> {
+        let receiver = &mut self.receiver;
+        let response = self.runtime.block_on(async {
+            tokio::time::timeout(Duration::from_millis(timeout_ms.max(0) as u64), receiver.next()).await
+        });
+
+        match response {
+            Ok(Some(response)) => Ok(Some(Bmv2Message::try_from(response?.take_data())?)),
+            Ok(None) => Ok(None), // Stream closed.
+            Err(_) => Ok(None),   // Timed out.
+        }
+    }
+}
+
+/// Opens a [`Bmv2Transport`] for `endpoint`. `"vsock://CID:PORT"` (e.g. `"vsock://3:2345"`)
+/// selects [`VsockTransport`]; `"p4runtime://TARGET?device_id=N&egress_port_metadata_id=N&\
+/// ingress_port_metadata_id=N"` selects [`P4RuntimeTransport`] (see its doc comment for what the
+/// metadata ids mean); `"grpc://TARGET"` selects [`GrpcTransport`], bmv2's own grpc packet-I/O
+/// interface; anything else (`"ipc://bmv2.ipc"`, `"tcp://127.0.0.1:2345"`, ...) is passed straight
+/// through to nanomsg via [`NanomsgTransport`], exactly as every caller connected before this
+/// existed.
+pub fn connect(endpoint: &str) -> Result<Box<dyn Bmv2Transport>> {
+    if let Some(addr) = endpoint.strip_prefix("vsock://") {
+        let (cid, port) = addr.split_once(':')
+            .ok_or_else(|| anyhow!("{endpoint}: expected \"vsock://CID:PORT\""))?;
+        let cid: u32 = cid.parse().with_context(|| format!("{endpoint}: invalid vsock CID"))?;
+        let port: u32 = port.parse().with_context(|| format!("{endpoint}: invalid vsock port"))?;
+        return Ok(Box::new(VsockTransport::connect(cid, port)?));
+    }
+
+    if let Some(target) = endpoint.strip_prefix("grpc://") {
+        return Ok(Box::new(GrpcTransport::connect(target)?));
+    }
+
+    if let Some(rest) = endpoint.strip_prefix("p4runtime://") {
+        let (target, query) = rest.split_once('?')
+            .ok_or_else(|| anyhow!("{endpoint}: expected \"p4runtime://TARGET?device_id=...\""))?;
+        let params: HashMap<&str, &str> = query.split('&')
+            .filter_map(|kv| kv.split_once('='))
+            .collect();
+        let param = |name: &str| -> Result<u64> {
+            params.get(name)
+                .ok_or_else(|| anyhow!("{endpoint}: missing '{name}' parameter"))?
+                .parse().with_context(|| format!("{endpoint}: invalid '{name}' parameter"))
+        };
+        return Ok(Box::new(P4RuntimeTransport::connect(
+            target, param("device_id")?, param("egress_port_metadata_id")? as u32,
+            param("ingress_port_metadata_id")? as u32,
+        )?));
+    }
+
+    Ok(Box::new(NanomsgTransport::connect(endpoint)?))
+}
+
+/// An async, non-panicking client for bmv2's nanomsg `--packet-in` interface, for a caller --
+/// chiefly the controller, which already runs a `tokio` runtime with `features = ["full"]` -- that
+/// wants a long-lived receive loop running concurrently with its other work instead of
+/// [`send_and_receive`]'s one-shot request/reply with a fixed one-second timeout.
+///
+/// A background thread owns the underlying nanomsg [`Socket`] (nanomsg has no async API of its
+/// own), draining [`Self::send`] requests and forwarding every message the socket receives --
+/// `PacketOut`, `PortUp`/`PortDown`, `InfoRep`, or a `recv`/decode error -- onto the `Stream`
+/// `Bmv2Client` itself implements. The stream ends when the socket is closed or the background
+/// thread hits an unrecoverable I/O error; no call in this API panics.
+pub struct Bmv2Client {
+    outgoing: mpsc::UnboundedSender<Bmv2Message>,
+    incoming: mpsc::UnboundedReceiver<Result<Bmv2Message>>,
+}
+
+impl Bmv2Client {
+    /// Connects to bmv2's nanomsg `--packet-in` endpoint, e.g. `"ipc://bmv2.ipc"`, and spawns the
+    /// background thread described in the struct's doc comment.
+    pub fn connect(endpoint: &str) -> Result<Self> {
+        let mut socket = Socket::new(Protocol::Pair)?;
+        socket.connect(endpoint)?;
+
+        let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded::<Bmv2Message>();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded::<Result<Bmv2Message>>();
+
+        thread::spawn(move || {
+            // A short receive timeout, rather than an indefinite one, so a message queued on
+            // `outgoing_rx` while we're blocked in `recv` doesn't have to wait out a slow bmv2.
+            if let Err(e) = socket.set_receive_timeout(100) {
+                let _ = incoming_tx.unbounded_send(Err(e.into()));
+                return;
+            }
+            loop {
+                while let Ok(Some(msg)) = outgoing_rx.try_next() {
+                    if let Err(e) = socket.write_all(&Vec::<u8>::from(msg)) {
+                        let _ = incoming_tx.unbounded_send(Err(e.into()));
+                        return;
+                    }
+                }
+
+                let mut msg = Vec::new();
+                match socket.read_to_end(&mut msg) {
+                    Ok(_) => {
+                        if incoming_tx.unbounded_send(Bmv2Message::try_from(msg)).is_err() {
+                            return; // `Bmv2Client` was dropped.
+                        }
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::TimedOut => continue,
+                    Err(e) => {
+                        let _ = incoming_tx.unbounded_send(Err(e.into()));
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Bmv2Client { outgoing: outgoing_tx, incoming: incoming_rx })
+    }
+
+    /// Queues `msg` to be sent to bmv2 and returns immediately; the write itself happens on the
+    /// background thread. Fails only once the connection's background thread has already exited,
+    /// e.g. after a prior I/O error.
+    pub fn send(&self, msg: Bmv2Message) -> Result<()> {
+        self.outgoing.unbounded_send(msg)
+            .map_err(|e| anyhow!("bmv2 connection is closed: {}", e))
+    }
+}
+
+impl Stream for Bmv2Client {
+    type Item = Result<Bmv2Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.incoming).poll_next(cx)
+    }
+}
+
+/// Sends `request` on `transport`, then waits for replies until no more replies have been received
+/// for one second, and returns the replies.
+///
+/// Ordinarily, `request` should be a `Bmv2Message::PacketIn` to cause a packet to be received on a
+/// port.
 ///
 /// Prints the requests and replies on stdout.
 ///
 /// Panics on I/O error.
-pub fn send_and_receive(s: &mut Socket, request: Bmv2Message) -> Vec<Bmv2Message> {
+pub fn send_and_receive(transport: &mut dyn Bmv2Transport, request: Bmv2Message) -> Vec<Bmv2Message> {
     println!("send {:?}", request);
-    s.write_all(&Vec::<u8>::from(request)).unwrap();
+    transport.send(request).unwrap();
 
-    s.set_receive_timeout(1000).unwrap();
     let mut replies = Vec::new();
-    loop {
-        let mut msg = Vec::new();
-        match s.read_to_end(&mut msg) {
-            Ok(_) => (),
-            Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
-            Err(err) => panic!("read_to_end(): {}", err)
-        };
-        let reply = Bmv2Message::try_from(msg).unwrap();
+    while let Some(reply) = transport.recv(1000).unwrap() {
         println!("receive {:?}", reply);
         replies.push(reply);
-    };
+    }
     println!();
     replies
 }
+
+/// Per-port token-bucket rate limiting for `Bmv2Message`s that carry a port (`PacketIn` and
+/// `PacketOut`), so a flood on one port can't be forwarded downstream -- to DDlog evaluation, an
+/// OVSDB transaction, wherever the caller sends `replies` next -- unbounded. Each port gets its
+/// own [`ovs::rate_limit::RateLimiter`], created the first time that port is seen; `rate_per_sec`
+/// and `capacity` (burst) are shared across every port. A bucket's own `check()` is lock-free, but
+/// the port-to-bucket map sits behind a `Mutex` that every [`Self::check`] and [`Self::throttled`]
+/// call locks, both to find an existing port's bucket and to create a new one.
+pub struct PortRateLimiter {
+    rate_per_sec: i64,
+    capacity: i64,
+    buckets: Mutex<HashMap<i32, RateLimiter>>,
+}
+
+impl PortRateLimiter {
+    /// Creates a limiter that allows `rate_per_sec` messages per second per port on average,
+    /// absorbing bursts of up to `capacity` messages on a single port before it starts throttling
+    /// that port.
+    pub fn new(rate_per_sec: i64, capacity: i64) -> Self {
+        PortRateLimiter { rate_per_sec, capacity, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Consumes a token from `port`'s bucket and returns `true`, or returns `false` -- counting the
+    /// message as throttled, see [`Self::throttled`] -- if `port` has none available.
+    pub fn check(&self, port: i32) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.entry(port).or_insert_with(|| RateLimiter::new(self.rate_per_sec, self.capacity)).check()
+    }
+
+    /// The number of messages `check()` has throttled for `port` so far, or 0 if `port` has never
+    /// been seen.
+    pub fn throttled(&self, port: i32) -> u64 {
+        self.buckets.lock().unwrap().get(&port).map_or(0, RateLimiter::suppressed)
+    }
+}
+
+/// Like [`send_and_receive`], but drops any `PacketIn`/`PacketOut` reply whose port has exceeded
+/// `limiter`'s rate (see [`PortRateLimiter`]) instead of returning it, so a caller that forwards
+/// `replies` on can't be swamped by a flood from one misbehaving port. Every other message kind
+/// passes through unthrottled.
+///
+/// Note that in this harness, `PacketIn` is something *we* send to bmv2, not something bmv2 sends
+/// back (see `Bmv2Message::PacketIn`'s doc comment) -- of the two port-carrying kinds, only
+/// `PacketOut` replies are actually something bmv2 can flood us with here. `PacketIn` is still
+/// covered so this stays correct if it's ever reused on the other side of the connection, where a
+/// real switch's wire protocol sends it to the controller instead.
+pub fn send_and_receive_with_limiter(transport: &mut dyn Bmv2Transport, request: Bmv2Message, limiter: &PortRateLimiter) -> Vec<Bmv2Message> {
+    send_and_receive(transport, request).into_iter()
+        .filter(|reply| match reply {
+            Bmv2Message::PacketIn { port, .. } | Bmv2Message::PacketOut { port, .. } => limiter.check(*port),
+            _ => true,
+        })
+        .collect()
+}
+
+/// Suppresses the "repeating broadcast" failure mode of a learning switch with redundant links:
+/// a broadcast or unknown-unicast frame floods out every port but its ingress one, reaches a
+/// neighbor over a redundant link, and comes straight back, flooding forever. This tracks each
+/// `(ingress_port, src_mac, packet_hash)` triple it's asked about for a short TTL, so
+/// [`send_and_receive_with_flood_suppression`] can recognize the second arrival as a repeat of the
+/// first instead of a fresh broadcast to re-flood.
+pub struct FloodSuppressor {
+    ttl: Duration,
+    seen: Mutex<HashMap<(i32, [u8; 6], u64), Instant>>,
+}
+
+impl FloodSuppressor {
+    /// Creates a suppressor that remembers a `(ingress_port, src_mac, packet_hash)` triple for
+    /// `ttl` before letting it flood again as if it were new.
+    pub fn new(ttl: Duration) -> Self {
+        FloodSuppressor { ttl, seen: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns `true` if `(ingress_port, payload)` repeats a frame seen within `ttl`, recording it
+    /// as seen either way so a third arrival in the window is also caught. A `payload` too short
+    /// to hold a source MAC (under the 6-byte destination and 6-byte source of an Ethernet header)
+    /// is never treated as a repeat, since there's no source address to key on.
+    fn is_repeat(&self, ingress_port: i32, payload: &Frame) -> bool {
+        if payload.0.len() < 12 {
+            return false;
+        }
+        let mut src_mac = [0u8; 6];
+        src_mac.copy_from_slice(&payload.0[6..12]);
+
+        let mut hasher = DefaultHasher::new();
+        payload.0.hash(&mut hasher);
+        let key = (ingress_port, src_mac, hasher.finish());
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, seen_at| now.duration_since(*seen_at) < self.ttl);
+        let repeat = seen.contains_key(&key);
+        seen.insert(key, now);
+        repeat
+    }
+}
+
+/// Like [`send_and_receive`], but applies [`FloodSuppressor`] to a `PacketIn` request: if it
+/// repeats one `suppressor` has seen within its TTL, every `PacketOut` reply is dropped instead of
+/// forwarded, on the theory that it's the same broadcast looping back rather than a fresh one to
+/// flood again. Independent of repeat status, no `PacketOut` reply is ever returned for the same
+/// port the request arrived on (split-horizon: a switch never floods a frame back out the port it
+/// came in on). Requests that aren't a `PacketIn`, and their replies, pass through untouched.
+pub fn send_and_receive_with_flood_suppression(transport: &mut dyn Bmv2Transport, request: Bmv2Message, suppressor: &FloodSuppressor) -> Vec<Bmv2Message> {
+    let ingress = match &request {
+        Bmv2Message::PacketIn { port, payload } => Some((*port, suppressor.is_repeat(*port, payload))),
+        _ => None,
+    };
+
+    let replies = send_and_receive(transport, request);
+    match ingress {
+        Some((_, true)) => replies.into_iter()
+            .filter(|reply| !matches!(reply, Bmv2Message::PacketOut { .. }))
+            .collect(),
+        Some((port, false)) => replies.into_iter()
+            .filter(|reply| !matches!(reply, Bmv2Message::PacketOut { port: out_port, .. } if *out_port == port))
+            .collect(),
+        None => replies,
+    }
+}
+
+/// Magic number for a little-endian pcap global header with microsecond-resolution timestamps.
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+/// `LINKTYPE_ETHERNET` (`DLT_EN10MB`): every `Frame` this crate handles is a full Ethernet frame.
+const PCAP_DLT_EN10MB: u32 = 1;
+
+/// Writes every [`Frame`] passed to [`Self::write`] to a `.pcap` file (`DLT_EN10MB`, i.e.
+/// Ethernet), so a test's traffic can be inspected offline with Wireshark/tcpdump after a
+/// failure, or fed back in with [`replay_pcap`]. See [`send_and_receive_with_pcap`] to tap
+/// [`send_and_receive`]'s request and replies directly instead of calling [`Self::write`] by hand.
+pub struct PcapTap(File);
+
+impl PcapTap {
+    /// Creates (truncating if it already exists) a pcap file at `path` and writes its global
+    /// header; every frame [`Self::write`] appends afterwards is relative to this header.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?; // version_major
+        file.write_all(&4u16.to_le_bytes())?; // version_minor
+        file.write_all(&0i32.to_le_bytes())?; // thiszone: timestamps are UTC
+        file.write_all(&0u32.to_le_bytes())?; // sigfigs: unused, always 0
+        file.write_all(&u32::MAX.to_le_bytes())?; // snaplen: never truncate a captured frame
+        file.write_all(&PCAP_DLT_EN10MB.to_le_bytes())?;
+        Ok(PcapTap(file))
+    }
+
+    /// Appends `frame` as one pcap record, timestamped with the current wall-clock time. Captured
+    /// and original length are always equal, since [`Self::create`] sets `snaplen` to capture
+    /// every byte.
+    pub fn write(&mut self, frame: &Frame) -> Result<()> {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)
+            .context("system clock is set before the Unix epoch")?;
+        let len = frame.0.len() as u32;
+
+        self.0.write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        self.0.write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        self.0.write_all(&len.to_le_bytes())?; // captured length
+        self.0.write_all(&len.to_le_bytes())?; // original length
+        self.0.write_all(&frame.0)?;
+        Ok(())
+    }
+}
+
+/// Like [`send_and_receive`], but also appends every `PacketIn`/`PacketOut` [`Frame`] that passes
+/// through it -- the request's, if it carries one, and each reply's -- to `tap`. Message kinds
+/// without a `Frame` (port up/down, info req/rep) aren't written, same as every other
+/// payload-carrying message kind this crate tracks per-port (see [`PortRateLimiter`],
+/// [`FloodSuppressor`]).
+pub fn send_and_receive_with_pcap(transport: &mut dyn Bmv2Transport, request: Bmv2Message, tap: &mut PcapTap) -> Result<Vec<Bmv2Message>> {
+    if let Bmv2Message::PacketIn { payload, .. } | Bmv2Message::PacketOut { payload, .. } = &request {
+        tap.write(payload)?;
+    }
+
+    let replies = send_and_receive(transport, request);
+    for reply in &replies {
+        if let Bmv2Message::PacketIn { payload, .. } | Bmv2Message::PacketOut { payload, .. } = reply {
+            tap.write(payload)?;
+        }
+    }
+    Ok(replies)
+}
+
+/// Reads a pcap file written by [`PcapTap`] (or any other `DLT_EN10MB` capture), returning a
+/// `Bmv2Message::PacketIn` for each record so a real-world capture can be driven through a
+/// simulated switch and its outputs diffed against the original. `ports` assigns each record's
+/// ingress port in turn, cycling once exhausted; every record arrives on port 0 if `ports` is
+/// empty.
+pub fn replay_pcap(path: impl AsRef<Path>, ports: &[i32]) -> Result<Vec<Bmv2Message>> {
+    let mut file = File::open(path)?;
+
+    let mut global_header = [0u8; 24];
+    file.read_exact(&mut global_header)?;
+    let magic = u32::from_le_bytes(global_header[0..4].try_into()?);
+    if magic != PCAP_MAGIC {
+        return Err(anyhow!("not a little-endian pcap file (magic {:#x})", magic));
+    }
+
+    let mut messages = Vec::new();
+    loop {
+        let mut record_header = [0u8; 16];
+        match file.read_exact(&mut record_header) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let captured_len = u32::from_le_bytes(record_header[8..12].try_into()?);
+
+        let mut payload = vec![0u8; captured_len as usize];
+        file.read_exact(&mut payload)?;
+
+        let port = if ports.is_empty() { 0 } else { ports[messages.len() % ports.len()] };
+        messages.push(Bmv2Message::PacketIn { port, payload: Frame(payload) });
+    }
+    Ok(messages)
+}
+
+/// Outcome of [`ping`].
+#[derive(Debug)]
+pub enum PingResult {
+    /// A matching echo reply came back after the given round-trip time.
+    Reply(Duration),
+
+    /// No matching echo reply arrived before `ping`'s `timeout` elapsed.
+    TimedOut,
+
+    /// A `PacketOut` reply arrived with the IPv4 ethertype whose IPv4 header, or ICMP packet or
+    /// echo payload, couldn't be parsed (e.g. a truncated echo payload), carrying a description of
+    /// what went wrong.
+    Malformed(String),
+}
+
+/// Sends an ICMP echo request identified by `identifier`/`sequence` (see
+/// [`FrameBuilder::ipv4_icmp`]) into `transport` as a `PacketIn` on `port`, then waits up to
+/// `timeout` for a `PacketOut` carrying the matching echo reply, returning a [`PingResult`] and,
+/// on success, the measured round-trip time. A one-call connectivity probe through a P4 program
+/// under test, in place of manually assembling and inspecting frames.
+pub fn ping(
+    transport: &mut dyn Bmv2Transport,
+    port: i32,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    identifier: u16,
+    sequence: u16,
+    payload: &[u8],
+    timeout: Duration,
+) -> Result<PingResult> {
+    let request = FrameBuilder::ipv4_icmp(src, dst, payload)
+        .icmp_identifier(identifier)
+        .icmp_sequence(sequence)
+        .build()?;
+
+    let start = Instant::now();
+    transport.send(Bmv2Message::PacketIn { port, payload: request })?;
+
+    loop {
+        let elapsed = Instant::now().duration_since(start);
+        if elapsed >= timeout {
+            return Ok(PingResult::TimedOut);
+        }
+        let remaining_ms = (timeout - elapsed).as_millis().min(i32::MAX as u128) as i32;
+
+        let reply = match transport.recv(remaining_ms)? {
+            Some(reply) => reply,
+            None => return Ok(PingResult::TimedOut),
+        };
+        let reply_payload = match reply {
+            Bmv2Message::PacketOut { payload, .. } => payload,
+            _ => continue,
+        };
+
+        match is_matching_echo_reply(&reply_payload, identifier, sequence) {
+            Ok(true) => return Ok(PingResult::Reply(Instant::now().duration_since(start))),
+            Ok(false) => continue, // Unrelated traffic, or an echo reply for a different ping.
+            Err(e) => return Ok(PingResult::Malformed(e)),
+        }
+    }
+}
+
+/// Parses `frame` as Ethernet+IPv4+ICMP and reports whether it's an echo reply matching
+/// `identifier`/`sequence`. A frame that isn't Ethernet, carries another ethertype, or carries
+/// another IP protocol is "no match" (`Ok(false)`) rather than malformed -- plenty of that (ARP,
+/// other protocols) is expected mixed into real traffic. A frame with the IPv4 ethertype whose
+/// IPv4 header fails to parse, or an IPv4 ICMP packet that fails to parse further, is an `Err`.
+fn is_matching_echo_reply(frame: &Frame, identifier: u16, sequence: u16) -> std::result::Result<bool, String> {
+    let eth = match packet::ether::Packet::new(&frame.0) {
+        Ok(eth) => eth,
+        Err(_) => return Ok(false),
+    };
+    if eth.protocol() != packet::ether::Protocol::Ipv4 {
+        return Ok(false);
+    }
+
+    let ipv4 = match packet::ip::v4::Packet::new(eth.payload()) {
+        Ok(ipv4) => ipv4,
+        Err(e) => return Err(format!("bad ipv4 header: {}", e)),
+    };
+    if ipv4.protocol() != packet::ip::Protocol::Icmp {
+        return Ok(false);
+    }
+
+    let icmp = match packet::icmp::Packet::new(ipv4.payload()) {
+        Ok(icmp) => icmp,
+        Err(e) => return Err(format!("bad icmp packet: {:?}", e)),
+    };
+    if icmp.kind() != packet::icmp::Kind::EchoReply {
+        return Ok(false);
+    }
+
+    let echo = match packet::icmp::echo::Packet::new(icmp.payload()) {
+        Ok(echo) => echo,
+        Err(e) => return Err(format!("bad icmp echo reply: {:?}", e)),
+    };
+    Ok(echo.identifier() == identifier && echo.sequence() == sequence)
+}
@@ -0,0 +1,69 @@
+use bmv2_packet::{send_and_receive_with_flood_suppression, Bmv2Message, Bmv2Transport, Frame, FloodSuppressor};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// A [`Bmv2Transport`] that never touches bmv2 at all: `send` is a no-op and `recv` just dequeues
+/// whatever replies the test pre-loaded, so these tests can exercise [`FloodSuppressor`] without a
+/// running switch.
+struct MockTransport(VecDeque<Bmv2Message>);
+
+impl MockTransport {
+    fn new(replies: Vec<Bmv2Message>) -> Self {
+        MockTransport(replies.into())
+    }
+}
+
+impl Bmv2Transport for MockTransport {
+    fn send(&mut self, _msg: Bmv2Message) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn recv(&mut self, _timeout_ms: i32) -> anyhow::Result<Option<Bmv2Message>> {
+        Ok(self.0.pop_front())
+    }
+}
+
+/// A broadcast frame from a fixed source MAC, as if arriving on `ingress_port`.
+fn broadcast(ingress_port: i32) -> (Bmv2Message, Frame) {
+    let mut bytes = vec![0xff; 6]; // broadcast destination
+    bytes.extend([0x00, 0x11, 0x22, 0x33, 0x44, 0x55]); // source MAC
+    bytes.extend([0x08, 0x00]); // ethertype
+    let payload = Frame(bytes);
+    (Bmv2Message::PacketIn { port: ingress_port, payload: payload.clone() }, payload)
+}
+
+/// The `PacketOut`s bmv2 would reply with if it flooded `payload` out every port in `ports`.
+fn flooded_out(ports: &[i32], payload: &Frame) -> Vec<Bmv2Message> {
+    ports.iter().map(|&port| Bmv2Message::PacketOut { port, payload: payload.clone() }).collect()
+}
+
+#[test]
+fn same_broadcast_twice_does_not_grow_the_flood() {
+    let suppressor = FloodSuppressor::new(Duration::from_secs(1));
+    let (request, payload) = broadcast(1);
+
+    let mut transport = MockTransport::new(flooded_out(&[2, 3], &payload));
+    let first = send_and_receive_with_flood_suppression(&mut transport, request, &suppressor);
+    assert_eq!(first.len(), 2);
+
+    // The same broadcast arriving again on the same port in quick succession -- e.g. bounced back
+    // by a neighbor over a redundant link -- must not be re-flooded.
+    let (request, _) = broadcast(1);
+    let mut transport = MockTransport::new(flooded_out(&[2, 3], &payload));
+    let second = send_and_receive_with_flood_suppression(&mut transport, request, &suppressor);
+    assert!(second.is_empty(), "repeated broadcast should be fully suppressed, got {:?}", second);
+}
+
+#[test]
+fn never_floods_back_out_the_ingress_port() {
+    let suppressor = FloodSuppressor::new(Duration::from_secs(1));
+    let (request, payload) = broadcast(1);
+
+    // bmv2 itself shouldn't normally echo a port back out its own ingress port, but the
+    // split-horizon rule holds regardless of what bmv2 reports.
+    let mut transport = MockTransport::new(flooded_out(&[1, 2, 3], &payload));
+    let replies = send_and_receive_with_flood_suppression(&mut transport, request, &suppressor);
+
+    assert_eq!(replies.len(), 2);
+    assert!(replies.iter().all(|reply| !matches!(reply, Bmv2Message::PacketOut { port: 1, .. })));
+}
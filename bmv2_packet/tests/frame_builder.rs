@@ -0,0 +1,45 @@
+use bmv2_packet::{Frame, FrameBuilder};
+use std::net::Ipv4Addr;
+
+/// `FrameBuilder` output should round-trip through `Frame`'s `Debug` impl, since that's the only
+/// way this crate can currently inspect a frame's contents without reimplementing a parser here.
+fn debug_of(frame: &Frame) -> String {
+    format!("{:?}", frame)
+}
+
+#[test]
+fn ipv4_udp_round_trips_through_debug() {
+    let src = Ipv4Addr::new(10, 0, 0, 1);
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+    let frame = FrameBuilder::ipv4_udp(src, dst, 1234, 5678, b"hello").build().unwrap();
+
+    let debug = debug_of(&frame);
+    assert!(debug.contains("ipv4(dst=10.0.0.2, src=10.0.0.1)"), "{}", debug);
+    assert!(debug.contains("udp(dst=5678, src=1234)"), "{}", debug);
+}
+
+#[test]
+fn ipv4_tcp_round_trips_through_debug() {
+    let src = Ipv4Addr::new(192, 168, 1, 1);
+    let dst = Ipv4Addr::new(192, 168, 1, 2);
+    let frame = FrameBuilder::ipv4_tcp(src, dst, 80, 443, b"data").build().unwrap();
+
+    let debug = debug_of(&frame);
+    assert!(debug.contains("ipv4(dst=192.168.1.2, src=192.168.1.1)"), "{}", debug);
+    assert!(debug.contains("tcp(dst=443, src=80)"), "{}", debug);
+}
+
+#[test]
+fn overriding_mac_addresses_is_reflected_in_the_built_frame() {
+    let src_mac = "02:00:00:00:00:09".parse().unwrap();
+    let dst_mac = "02:00:00:00:00:0a".parse().unwrap();
+    let frame = FrameBuilder::ipv4_udp(Ipv4Addr::new(1, 2, 3, 4), Ipv4Addr::new(5, 6, 7, 8), 1, 2, b"")
+        .src_mac(src_mac)
+        .dst_mac(dst_mac)
+        .build()
+        .unwrap();
+
+    let debug = debug_of(&frame);
+    assert!(debug.contains("dst=02:00:00:00:00:0a"), "{}", debug);
+    assert!(debug.contains("src=02:00:00:00:00:09"), "{}", debug);
+}
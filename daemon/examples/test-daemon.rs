@@ -15,7 +15,11 @@ struct Args {
 
     /// File to write a greeting message to upon startup.
     #[clap(long)]
-    pub greeting_file: Option<PathBuf>
+    pub greeting_file: Option<PathBuf>,
+
+    /// Abort immediately after starting, to exercise `--monitor`'s restart backoff.
+    #[clap(long)]
+    pub abort_immediately: bool,
 }
 
 fn program_name() -> String {
@@ -24,9 +28,13 @@ fn program_name() -> String {
 
 fn main() -> Result<()> {
     tracing_subscriber::fmt().with_writer(std::io::stderr).init();
-    let Args { daemonize, greeting_file } = Args::parse();
+    let Args { daemonize, greeting_file, abort_immediately } = Args::parse();
     let mut cleanup = unsafe { daemonize.run() };
 
+    if abort_immediately {
+        std::process::abort();
+    }
+
     if let Err(e) = main_loop(&mut cleanup, &greeting_file) {
         event!(Level::ERROR, "{}", e);
         Err(e)?;
@@ -38,7 +38,7 @@ SOFTWARE.
 use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
 use rand::random;
-use signal_hook::{self, consts::signal::*, iterator::Signals};
+use signal_hook::{self, consts::signal::*, iterator::{Handle, Signals}};
 use std::collections::{HashMap, HashSet};
 use std::default::Default;
 use std::fs;
@@ -46,8 +46,13 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
 use tracing::{event, Level};
 
 #[cfg(doc)]
@@ -121,63 +126,242 @@ impl Actions {
     }
 }
 
-/// A singleton object that frees resources in reaction to a fatal signal.
+/// A lock-free, signal-safe snapshot of the registered `Actions`, read-copy-update style like the
+/// one `signal-hook-registry` uses internally, for the same reason: the thread
+/// `SignalHandler::ensure_thread_listener` spawns (and the task `SignalHandler::add_actions_async`
+/// spawns) reacts to a real fatal signal, so it must never be able to deadlock behind a writer that
+/// panicked, or was itself killed by a second signal, while holding a lock on the registry -- that
+/// would permanently block the very cleanup (killing child processes, removing temp directories)
+/// `Cleanup` exists to guarantee.
+///
+/// So the read side (`load`) only ever pins the current epoch, reads the snapshot pointer, and
+/// bumps a refcount: no lock, no CAS loop. Only the write side (`update`) clones the current map,
+/// edits the clone, and compare-and-swaps it into place, retrying if a racing writer got there
+/// first -- writers can still contend with each other, just never with a reader. Reclamation of a
+/// superseded snapshot is deferred to the epoch (`Guard::defer_destroy`, same as
+/// `OfpbufPool`'s free-list) instead of being dropped the instant `update()` swaps it out, so a
+/// reader that already read the old pointer -- but hasn't finished cloning the `Arc` out of it --
+/// can never observe freed memory, unlike a bare `AtomicPtr` where the gap between reading the
+/// pointer and bumping its refcount is a use-after-free window.
+///
+/// This relies on `Actions::run()` being safe to invoke more than once on the same `Actions`
+/// (it drains each set of pids, files and directories as it goes, so a second run on the same
+/// `Actions` finds nothing left to do) and safe to run concurrently with a writer that's in the middle of removing that very `Actions` from the
+/// registry: a reader might run an `Actions` that a concurrent `update()` is also in the process of
+/// dropping from the map, so double-running it (once from the snapshot the reader already loaded,
+/// once if something re-triggers afterward) must be harmless too.
+struct Registry {
+    snapshot: Atomic<Arc<HashMap<u64, Arc<Mutex<Actions>>>>>
+}
+
+impl Registry {
+    fn new() -> Registry {
+        Registry { snapshot: Atomic::new(Arc::new(HashMap::new())) }
+    }
+
+    /// Returns the current snapshot. Never blocks: pinning the epoch never waits on another
+    /// thread, and the only allocation is the `Arc`'s own refcount bump.
+    fn load(&self) -> Arc<HashMap<u64, Arc<Mutex<Actions>>>> {
+        let guard = &epoch::pin();
+        let shared = self.snapshot.load(Ordering::Acquire, guard);
+        // SAFETY: `shared` is never null (`new()` and every successful `update()` install a real
+        // `Arc`) and, being pinned, can't be reclaimed by a concurrent `update()`'s
+        // `defer_destroy` until this guard is dropped -- so dereferencing it and cloning the `Arc`
+        // out from under the pin is safe no matter how `self.snapshot` changes afterward.
+        unsafe { shared.deref() }.clone()
+    }
+
+    /// Applies `f` to a clone of the current snapshot and swaps the result in, retrying if another
+    /// writer raced this one between the load and the swap. Returns whatever `f` returned on the
+    /// attempt that actually won the race.
+    fn update<T>(&self, mut f: impl FnMut(&mut HashMap<u64, Arc<Mutex<Actions>>>) -> T) -> T {
+        let guard = &epoch::pin();
+        loop {
+            let current = self.snapshot.load(Ordering::Acquire, guard);
+            // SAFETY: see `load()` -- `current` can't be reclaimed while `guard` stays pinned.
+            let mut next = (**unsafe { current.deref() }).clone();
+            let result = f(&mut next);
+            let next = Owned::new(Arc::new(next));
+            match self.snapshot.compare_exchange(current, next, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(_) => {
+                    // SAFETY: this thread alone won the swap, so `current` is no longer reachable
+                    // through `self.snapshot`; deferring its destruction lets any reader that read
+                    // it before the swap finish cloning its `Arc` before it's freed.
+                    unsafe { guard.defer_destroy(current); }
+                    return result;
+                },
+                Err(_) => {
+                    // Lost the race: drop the snapshot just built instead of installing it, and
+                    // retry against whatever the winning writer left in place.
+                },
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// The process-wide registry every `Cleanup` -- sync or async -- registers its `Actions` in.
+    /// Kept separate from `SignalHandler`'s own state so a signal can be handled by reading this
+    /// directly, without ever touching `SignalHandler::instance()`'s ordinary `Mutex`.
+    static ref REGISTRY: Registry = Registry::new();
+}
+
+/// A singleton that allocates IDs for the `Actions` added to [`REGISTRY`] and installs the
+/// synchronous signal-reacting thread the first time one is needed.
 struct SignalHandler {
-    actions: Arc<Mutex<HashMap<u64, Arc<Mutex<Actions>>>>>,
     next_id: u64
 }
 
 impl SignalHandler {
-    fn new() -> Result<SignalHandler> {
-        let mut signals = Signals::new(&[SIGTERM, SIGINT, SIGHUP, SIGALRM])?;
-        let actions: Arc<Mutex<HashMap<u64, Arc<Mutex<Actions>>>>>
-            = Arc::new(Mutex::new(HashMap::new()));
-        let actions2 = actions.clone();
-        thread::spawn(move || {
-            for signal in signals.forever() {
-                for (_k, v) in actions2.lock().unwrap().drain() {
-                    v.lock().unwrap().run();
-                }
-                signal_hook::low_level::emulate_default_handler(signal).unwrap();
-                unreachable!();
-            }
-            for (_k, v) in actions2.lock().unwrap().drain() {
-                v.lock().unwrap().run();
-            }
-        });
-        Ok(SignalHandler { actions, next_id: 0 })
+    fn new() -> SignalHandler {
+        SignalHandler { next_id: 0 }
     }
 
-    fn instance() -> MutexGuard<'static, Result<Self>> {
+    fn instance() -> MutexGuard<'static, Self> {
         lazy_static! {
-            static ref INSTANCE: Mutex<Result<SignalHandler>> = Mutex::new(SignalHandler::new());
+            static ref INSTANCE: Mutex<SignalHandler> = Mutex::new(SignalHandler::new());
         }
         INSTANCE.lock().unwrap()
     }
 
-    /// Creates a new `Actions`, adds it to the collection of those that will be invoked when the
-    /// process terminates, and returns it along with an ID that may be used to remove it later.
-    pub fn add_actions() -> Result<(u64, Arc<Mutex<Actions>>)> {
-        match *Self::instance() {
+    /// The default set of signals that trigger cleanup, same as always: `SIGTERM`, `SIGINT`,
+    /// `SIGHUP`, `SIGALRM`. `add_signals()` can add more.
+    const DEFAULT_SIGNALS: &'static [i32] = &[SIGTERM, SIGINT, SIGHUP, SIGALRM];
+
+    /// Spawns the thread that waits on `Signals::forever()` and runs actions when the process
+    /// receives one of `DEFAULT_SIGNALS`, the first time anything registers actions via the
+    /// synchronous `add_actions()` (or calls `add_signals()`). A process that only ever calls
+    /// `add_actions_async()` never spawns this thread at all. Returns a `Handle` that
+    /// `add_signals()` uses to add more signals to the running `Signals` after the fact -- the
+    /// `Signals` iterator that owns the underlying thread can't itself be reached from outside it
+    /// once moved into the closure below, which is exactly what `Handle` is for.
+    fn ensure_thread_listener() -> Result<Handle> {
+        lazy_static! {
+            static ref INSTALLED: Result<Handle> = {
+                let signals = Signals::new(SignalHandler::DEFAULT_SIGNALS)?;
+                let handle = signals.handle();
+                thread::spawn(move || {
+                    for signal in signals.forever() {
+                        for actions in REGISTRY.load().values() {
+                            actions.lock().unwrap().run();
+                        }
+                        // For every signal in `DEFAULT_SIGNALS`, the default disposition
+                        // terminates the process, so this doesn't return. But `add_signals()` lets
+                        // a caller add a signal whose default disposition doesn't (e.g. `SIGWINCH`,
+                        // `SIGCHLD`), so -- unlike the `unreachable!()` this used to end with --
+                        // this loop has to keep servicing whatever signal comes next instead of
+                        // assuming this one killed the process.
+                        let _ = signal_hook::low_level::emulate_default_handler(signal);
+                    }
+                });
+                Ok(handle)
+            };
+        }
+        match *INSTALLED {
+            Ok(ref handle) => Ok(handle.clone()),
             Err(ref e) => Err(anyhow!("{e}")),
-            Ok(ref mut instance) => {
-                let id = instance.next_id;
-                instance.next_id += 1;
-                let actions = Arc::new(Mutex::new(Actions::new()));
-                instance.actions.lock().unwrap().insert(id, actions.clone());
-                Ok((id, actions))
-            }
         }
     }
 
-    /// Removes the `Actions` with the given `id` from the collection (if any).  If `run` is true,
+    /// Adds `signals` to the set that triggers cleanup on the synchronous thread listener,
+    /// installing it first (with `DEFAULT_SIGNALS`) if it isn't already running. Safe to call more
+    /// than once, including with signals already in the set.
+    pub fn add_signals(signals: &[i32]) -> Result<()> {
+        let handle = Self::ensure_thread_listener()?;
+        for &sig in signals {
+            handle.add_signal(sig)?;
+        }
+        Ok(())
+    }
+
+    /// Registers `action` to run whenever `signal` is delivered, independent of any `Cleanup`'s
+    /// registered `Actions` -- the `signal_hook::low_level::register`-style building block
+    /// `signal-hook-registry` itself exposes, for a caller that wants its own callback rather than
+    /// the kill-pids-and-remove-files behavior `Actions::run()` hardcodes. Returns a `SigId` that
+    /// `unregister_signal_action()` can later use to remove it.
+    ///
+    /// # Safety
+    ///
+    /// `action` runs in the same restricted, async-signal-only context `signal_hook`'s own
+    /// low-level registration does; see `signal_hook::low_level::register`'s safety section for
+    /// what that means.
+    pub unsafe fn register_signal_action<F>(signal: i32, action: F) -> Result<signal_hook::SigId>
+        where F: Fn() + Sync + Send + 'static
+    {
+        Ok(signal_hook::low_level::register(signal, action)?)
+    }
+
+    /// Removes a callback previously registered with `register_signal_action()`. Returns `true` if
+    /// `id` was found and removed.
+    pub fn unregister_signal_action(id: signal_hook::SigId) -> bool {
+        signal_hook::low_level::unregister(id)
+    }
+
+    /// Allocates a fresh id under `SignalHandler::instance()`'s ordinary lock (registration is
+    /// never on the signal-reacting path, so there's no deadlock hazard here), then creates a new
+    /// `Actions` and inserts it into [`REGISTRY`] under that id.
+    fn new_actions() -> (u64, Arc<Mutex<Actions>>) {
+        let mut instance = Self::instance();
+        let id = instance.next_id;
+        instance.next_id += 1;
+        drop(instance);
+
+        let actions = Arc::new(Mutex::new(Actions::new()));
+        let inserted = actions.clone();
+        REGISTRY.update(move |map| drop(map.insert(id, inserted.clone())));
+        (id, actions)
+    }
+
+    /// Creates a new `Actions`, adds it to [`REGISTRY`], and returns it along with an ID that may
+    /// be used to remove it later.
+    pub fn add_actions() -> Result<(u64, Arc<Mutex<Actions>>)> {
+        Self::ensure_thread_listener()?;
+        Ok(Self::new_actions())
+    }
+
+    /// Async analogue of `add_actions()`: registers a new `Actions` in the same [`REGISTRY`]
+    /// `add_actions()` uses, but arranges for it to run from a task spawned on the calling tokio
+    /// runtime -- via `tokio::signal::unix::signal` -- instead of `add_actions()`'s dedicated OS
+    /// thread. Returns the spawned task's `JoinHandle` alongside the usual id and `Actions`; most
+    /// callers can drop it (as `Cleanup::new_async()` does by default), but one that wants to know
+    /// once a signal has actually been handled can await it.
+    ///
+    /// Must be called from within a running tokio runtime. A process that forks should call this
+    /// again in the child on its own runtime rather than relying on the parent's task, which does
+    /// not survive the fork.
+    pub fn add_actions_async() -> Result<(u64, Arc<Mutex<Actions>>, JoinHandle<()>)> {
+        let mut term = signal(SignalKind::terminate())?;
+        let mut int = signal(SignalKind::interrupt())?;
+        let mut hup = signal(SignalKind::hangup())?;
+        let mut alrm = signal(SignalKind::alarm())?;
+
+        let (id, actions) = Self::new_actions();
+
+        let handle = tokio::spawn(async move {
+            // Unlike `ensure_thread_listener`'s thread, there's no tokio equivalent of
+            // `signal_hook::low_level::emulate_default_handler` to re-raise the signal with its
+            // default disposition afterward, so this just runs the registered actions once and
+            // returns; the caller's own runtime shutdown is expected to take it from there.
+            tokio::select! {
+                _ = term.recv() => (),
+                _ = int.recv() => (),
+                _ = hup.recv() => (),
+                _ = alrm.recv() => (),
+            }
+            for actions in REGISTRY.load().values() {
+                actions.lock().unwrap().run();
+            }
+        });
+        Ok((id, actions, handle))
+    }
+
+    /// Removes the `Actions` with the given `id` from [`REGISTRY`] (if any).  If `run` is true,
     /// runs the associated actions, otherwise skips them.
     pub fn remove_actions(id: u64, run: bool) {
-        if let Ok(ref mut instance) = *Self::instance() {
-            if let Some(actions) = instance.actions.lock().unwrap().remove(&id) {
-                if run {
-                    actions.lock().unwrap().run();
-                }
+        let removed = REGISTRY.update(move |map| map.remove(&id));
+        if run {
+            if let Some(actions) = removed {
+                actions.lock().unwrap().run();
             }
         }
     }
@@ -190,7 +374,8 @@ impl SignalHandler {
 /// signal.
 pub struct Cleanup {
     actions: Arc<Mutex<Actions>>,
-    actions_id: u64
+    actions_id: u64,
+    signal_task: Option<JoinHandle<()>>
 }
 
 impl Cleanup {
@@ -201,10 +386,42 @@ impl Cleanup {
     /// Cleanup on signal handling happens in a thread that `Cleanup` creates.  This means that
     /// calling `fork` will prevent cleanup due to a signal from happening in the child process
     /// (but not cleanup due to drop).  Therefore, a process that forks should create a `Cleanup`
-    /// only in the child, not in the parent.
+    /// only in the child, not in the parent.  A process already running a tokio runtime -- as the
+    /// p4ext tests and the gRPC/P4Runtime code in this repo do -- should use `new_async()` instead,
+    /// which reacts to signals from a task on that runtime rather than from a dedicated thread (its
+    /// task does not survive a fork either).
     pub fn new() -> Result<Cleanup> {
         let (actions_id, actions) = SignalHandler::add_actions()?;
-        Ok(Cleanup { actions, actions_id })
+        Ok(Cleanup { actions, actions_id, signal_task: None })
+    }
+
+    /// Like `new()`, but first adds `signals` (see `SignalHandler::add_signals`) to the set that
+    /// triggers cleanup, so a caller isn't stuck with just the default `SIGTERM`/`SIGINT`/
+    /// `SIGHUP`/`SIGALRM`. The added signals are process-wide and not specific to this `Cleanup`:
+    /// any signal in the set runs every registered `Cleanup`'s actions, same as today.
+    pub fn with_signals(signals: &[i32]) -> Result<Cleanup> {
+        SignalHandler::add_signals(signals)?;
+        Self::new()
+    }
+
+    /// Async analogue of `new()`: registers the same signal-triggered cleanup, but via a task
+    /// spawned on the calling tokio runtime (see `SignalHandler::add_actions_async`) instead of a
+    /// dedicated OS thread. Must be called from within a running tokio runtime. Because the task
+    /// doesn't survive a fork the way `new()`'s thread's actions (but not its thread) do, a process
+    /// that forks should call this again in the child on its own runtime.
+    pub async fn new_async() -> Result<Cleanup> {
+        let (actions_id, actions, signal_task) = SignalHandler::add_actions_async()?;
+        Ok(Cleanup { actions, actions_id, signal_task: Some(signal_task) })
+    }
+
+    /// Waits for the task `new_async()` installed to finish running this `Cleanup`'s actions in
+    /// response to a signal. Resolves immediately if this `Cleanup` was created with `new()`
+    /// instead, since there's no such task to wait for, or if called more than once.
+    pub async fn wait_for_signal(&mut self) -> Result<()> {
+        match self.signal_task.take() {
+            Some(handle) => Ok(handle.await?),
+            None => Ok(())
+        }
     }
 
     /// Drops `self` **without** executing any of its cleanup actions.
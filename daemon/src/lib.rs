@@ -71,6 +71,7 @@ SOFTWARE.
 use anyhow::{anyhow, Context};
 use clap::Parser;
 use libc::{self, c_int};
+use signal_hook::{consts::signal::*, iterator::Signals};
 use std::env::set_current_dir;
 use std::ffi::{CString, OsString};
 use std::fs::{File, read_dir};
@@ -83,6 +84,8 @@ use std::time::{Duration, Instant};
 use tracing::{event, Level};
 
 mod cleanup;
+pub mod lock;
+pub mod pidfd;
 pub mod proctitle;
 
 pub use cleanup::Cleanup;
@@ -185,6 +188,30 @@ pub struct Daemonize {
     /// Create pidfile
     #[clap(long)]
     pub pidfile: Option<PathBuf>,
+
+    /// Start even if a pidfile is already locked by a running instance, replacing its pidfile
+    #[clap(long)]
+    pub overwrite_pidfile: bool,
+
+    /// User to run as (name or numeric uid), dropping privileges after setup
+    #[clap(long)]
+    pub user: Option<String>,
+
+    /// Group to run as (name or numeric gid), dropping privileges after setup
+    #[clap(long)]
+    pub group: Option<String>,
+
+    /// Redirect stdout to this file instead of /dev/null (may be the same path as `stderr`)
+    #[clap(long)]
+    pub stdout: Option<PathBuf>,
+
+    /// Redirect stderr to this file instead of /dev/null (may be the same path as `stdout`)
+    #[clap(long)]
+    pub stderr: Option<PathBuf>,
+
+    /// How `--monitor` restarts a crashed (or, with `--restart-on-success`, any exited) child
+    #[clap(flatten)]
+    pub restart_policy: RestartPolicy,
 }
 
 impl Daemonize {
@@ -210,6 +237,59 @@ impl Daemonize {
         daemonizing.finish();
         cleanup
     }
+
+    /// Returns the pid of the already-running instance recorded in `pidfile`, the same way
+    /// `--pidfile` itself would recognize it: the pidfile must exist, be locked (via `fcntl`),
+    /// and contain the pid of the process holding that lock.  Unlike `--pidfile` startup, this
+    /// never deletes a stale pidfile; it just reports that none is running.
+    pub fn read_pidfile(pidfile: &Path) -> anyhow::Result<c_int> {
+        match Daemonizing::read_pidfile(pidfile, false)? {
+            Some(pid) => Ok(pid),
+            None => Err(anyhow!("{}: no running instance", pidfile.display())),
+        }
+    }
+
+    /// Sends `signal` to the already-running instance recorded in `pidfile`, as found by
+    /// [`Daemonize::read_pidfile`], and returns its pid.  This is the building block for
+    /// `--kill`/`--reload`-style subcommands built on top of this crate.
+    pub fn signal_pidfile(pidfile: &Path, signal: c_int) -> anyhow::Result<c_int> {
+        let pid = Self::read_pidfile(pidfile)?;
+        if unsafe { libc::kill(pid, signal) } != 0 {
+            Err(anyhow!("{}: failed to signal pid {pid} ({})",
+                        pidfile.display(), Error::last_os_error()))?;
+        }
+        Ok(pid)
+    }
+}
+
+/// Controls how `--monitor` restarts the child process it supervises.
+///
+/// The delay before a restart starts at `restart_delay` and, after each crash that happens
+/// before the child has been up for `HEALTHY_UPTIME`, is multiplied by `backoff_multiplier` (if
+/// given) up to `MAX_RESTART_DELAY`.  Once the child stays up for `HEALTHY_UPTIME`, the delay and
+/// crash count both reset, so a daemon that crashes occasionally but mostly runs fine doesn't
+/// creep toward the ceiling forever.
+#[derive(Clone, Debug, Default, Parser, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// Initial delay, in seconds, before restarting a crashed child
+    #[clap(long, default_value = "10", value_parser = parse_secs)]
+    pub restart_delay: Duration,
+
+    /// Give up and exit nonzero after this many consecutive restarts
+    #[clap(long)]
+    pub max_restarts: Option<u32>,
+
+    /// Also restart the child when it exits on its own (exit code 0, or a non-error signal)
+    #[clap(long)]
+    pub restart_on_success: bool,
+
+    /// Multiply the restart delay by this much after each crash, up to a ceiling
+    #[clap(long)]
+    pub backoff_multiplier: Option<u32>,
+}
+
+fn parse_secs(s: &str) -> Result<Duration, std::num::ParseIntError> {
+    Ok(Duration::from_secs(s.parse()?))
 }
 
 /// The current process, in the process of daemonizing.
@@ -225,24 +305,36 @@ pub struct Daemonizing {
 impl Daemonizing {
     /// Completes the daemonization process:
     ///
+    ///   - If we detached, redirects the `stdin`, `stdout`, and `stderr` fds.  `stdin` is always
+    ///     replaced by `/dev/null`.  `stdout` and `stderr` are replaced by the `stdout`/`stderr`
+    ///     options, if given, or `/dev/null` otherwise.  This happens before the chdir below, so
+    ///     that relative `stdout`/`stderr` paths resolve against the original working directory.
+    ///
     ///   - If we detached, changes the current directory to the root, unless that behavior is
     ///     disabled.
     ///
-    ///   - If we detached, closes the `stdin`, `stdout`, and `stderr` fds.  For safety, instead
-    ///     of leaving fds 0, 1, and 2 unpopulated, we replace them by `/dev/null`.
-    ///
     ///   - Notifies the parent process that daemonization is complete.  This allows the parent
     ///     process to exit successfully, indicating to the process that in turn
+    ///
+    ///   - If `user`/`group` are set, drops privileges to them, after everything above that
+    ///     needs root (chdir, opening `/dev/null` on low-numbered fds, creating the pidfile) has
+    ///     already happened.
     pub fn finish(mut self) {
         if self.options.detach {
+            redirect_standard_fds(self.options.stdout.as_deref(), self.options.stderr.as_deref());
             if !self.options.no_chdir {
                 drop(set_current_dir("/"));
             }
-            close_standard_fds();
         }
         if let Some(ref mut pipe) = self.notify_pipe {
             fork_notify_startup(pipe);
         }
+        if self.options.user.is_some() || self.options.group.is_some() {
+            if let Err(error) = drop_privileges(self.options.user.as_deref(), self.options.group.as_deref()) {
+                event!(Level::ERROR, "could not drop privileges ({error})");
+                exit(1);
+            }
+        }
     }
 
     unsafe fn new(options: Daemonize) -> (Self, Cleanup) {
@@ -275,9 +367,9 @@ impl Daemonizing {
                         fork_notify_startup(notify_pipe);
                     }
                     if options.detach {
-                        close_standard_fds();
+                        redirect_standard_fds(options.stdout.as_deref(), options.stderr.as_deref());
                     }
-                    Self::monitor_daemon(child_pid)
+                    Self::monitor_daemon(child_pid, &options.restart_policy)
                 },
                 ForkAndWaitResult::InChild { notify_pipe } => notify_pipe
             })
@@ -289,13 +381,22 @@ impl Daemonizing {
         let mut cleanup = match cleanup::Cleanup::new() {
             Ok(cleanup) => cleanup,
             Err(error) => {
-                event!(Level::ERROR, "could not arrange for cleanup on process exit ({error})");
+                let message = format!("could not arrange for cleanup on process exit ({error})");
+                event!(Level::ERROR, "{message}");
+                if let Some(ref mut pipe) = notify_pipe {
+                    fork_notify_failure(pipe, 1, &message);
+                }
                 exit(1);
             }
         };
         if let Some(ref pidfile) = options.pidfile {
-            if let Err(error) = Self::make_pidfile(pidfile, &mut cleanup) {
-                event!(Level::ERROR, "failed to create pidfile ({error})");
+            if let Err(error) = Self::make_pidfile(pidfile, &mut cleanup, options.user.as_deref(),
+                                                    options.group.as_deref(), options.overwrite_pidfile) {
+                let message = format!("failed to create pidfile ({error})");
+                event!(Level::ERROR, "{message}");
+                if let Some(ref mut pipe) = notify_pipe {
+                    fork_notify_failure(pipe, 1, &message);
+                }
                 exit(1);
             }
         }
@@ -303,18 +404,70 @@ impl Daemonizing {
         (Daemonizing { options, notify_pipe }, cleanup)
     }
 
-    fn monitor_daemon(mut child_pid: c_int) -> File {
+    /// Monitors `child_pid`, restarting it on a crash, until it exits normally or we're asked to
+    /// stop.  Rather than just `waitpid`ing in a loop, this also watches for signals sent to the
+    /// monitor itself, so that a monitored daemon can be stopped or reloaded the same way as an
+    /// unmonitored one:
+    ///
+    ///   - `SIGTERM`/`SIGINT` are forwarded to the child, which is then reaped and used to
+    ///     determine the monitor's own exit status.  This stops supervision entirely.
+    ///
+    ///   - `SIGHUP`/`SIGUSR1`/`SIGUSR2` are forwarded to the child without otherwise disturbing
+    ///     the monitor; in particular, the child reacting to them (e.g. by restarting workers
+    ///     internally) isn't mistaken for a crash.
+    ///
+    /// Restarts themselves are governed by `policy`; see [`RestartPolicy`].
+    fn monitor_daemon(mut child_pid: c_int, policy: &RestartPolicy) -> File {
+        /// Ceiling for the backed-off restart delay, regardless of `backoff_multiplier`.
+        const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+        /// A child that stays up this long is considered healthy again: the crash count and
+        /// restart delay both reset.
+        const HEALTHY_UPTIME: Duration = Duration::from_secs(60);
+
+        let mut signals = match Signals::new(&[SIGTERM, SIGINT, SIGHUP, SIGUSR1, SIGUSR2, SIGCHLD]) {
+            Ok(signals) => signals,
+            Err(error) => {
+                event!(Level::ERROR, "could not set up monitor signal handling ({error})");
+                exit(1);
+            }
+        };
+        let mut signals = signals.forever();
+
         let mut next_restart = None;
         let mut crashes = 0;
+        let mut restart_delay = policy.restart_delay;
         let mut child_status = None;
+        let mut child_started = Instant::now();
         let mut status_msg = String::from("healthy");
         loop {
             proctitle::set(&format!("monitoring pid {child_pid} ({status_msg})"));
-            let status = match child_status {
+            let status = match child_status.take() {
                 Some(status) => status,
-                None => sys::xwaitpid(child_pid, 0).1,
+                None => loop {
+                    match signals.next().expect("signal iterator never ends") {
+                        signal @ (SIGTERM | SIGINT) => {
+                            event!(Level::INFO,
+                                   "received signal {signal}, forwarding to pid {child_pid} and exiting");
+                            unsafe { libc::kill(child_pid, signal); }
+                            let (_, status) = sys::xwaitpid(child_pid, 0);
+                            exit(status.code().unwrap_or(128 + status.signal().unwrap_or(0)));
+                        },
+                        signal @ (SIGHUP | SIGUSR1 | SIGUSR2) => {
+                            event!(Level::INFO, "received signal {signal}, forwarding to pid {child_pid}");
+                            unsafe { libc::kill(child_pid, signal); }
+                        },
+                        _ /* SIGCHLD */ => match sys::waitpid(child_pid, libc::WNOHANG) {
+                            Ok((0, _)) => (),
+                            Ok((_, status)) => break status,
+                            Err(error) => {
+                                event!(Level::ERROR, "waitpid failed ({error})");
+                                exit(1);
+                            }
+                        },
+                    }
+                },
             };
-            if !Self::should_restart(status) {
+            if !Self::should_restart(status, policy) {
                 event!(Level::INFO, "pid {child_pid} died ({status}), exiting");
                 exit(0);
             }
@@ -327,22 +480,45 @@ impl Daemonizing {
                 }
             }
 
-            crashes += 1;
+            if child_started.elapsed() >= HEALTHY_UPTIME {
+                // It ran long enough to be considered healthy; forgive its past crashes.
+                crashes = 0;
+                restart_delay = policy.restart_delay;
+            } else {
+                crashes += 1;
+                if let Some(max_restarts) = policy.max_restarts {
+                    if crashes > max_restarts {
+                        event!(Level::ERROR, "pid {child_pid} died ({status}), {crashes} crashes \
+                                               exceeds limit of {max_restarts}, giving up");
+                        exit(1);
+                    }
+                }
+                if let Some(multiplier) = policy.backoff_multiplier {
+                    if crashes > 1 {
+                        restart_delay = (restart_delay * multiplier).min(MAX_RESTART_DELAY);
+                    }
+                }
+            }
             status_msg = format!("{crashes} crashes: pid {child_pid} died ({status})");
 
-            // Throttle restarts to no more than once every 10 seconds.
+            // Throttle restarts to no more than once per `restart_delay`.  The first restart is
+            // measured from when the crashed child was started.
+            if next_restart.is_none() {
+                next_restart = Some(child_started + restart_delay);
+            }
             let now = Instant::now();
             match next_restart {
                 Some(time) if now < time => {
-                    event!(Level::ERROR, "{}, waiting until 10 seconds since last restart", status_msg);
+                    event!(Level::ERROR, "{}, waiting {:?} since last restart", status_msg, time - now);
                     sleep(time - now);
                 },
                 _ => (),
             }
-            next_restart = Some(Instant::now() + Duration::from_secs(10));
+            next_restart = Some(Instant::now() + restart_delay);
 
             // Restart.
             event!(Level::INFO, "{}, restarting", status_msg);
+            child_started = Instant::now();
             (child_pid, child_status) = match fork_and_wait_for_startup() {
                 ForkAndWaitResult::ForkFailed { child_pid, status } => (child_pid, Some(status)),
                 ForkAndWaitResult::InParent { child_pid } => (child_pid, None),
@@ -351,7 +527,10 @@ impl Daemonizing {
         }
     }
 
-    fn should_restart(status: ExitStatus) -> bool {
+    fn should_restart(status: ExitStatus, policy: &RestartPolicy) -> bool {
+        if policy.restart_on_success {
+            return true;
+        }
         match status.signal() {
             Some(signal) => {
                 const ERROR_SIGNALS: &[c_int] = &[
@@ -363,7 +542,13 @@ impl Daemonizing {
         }
     }
 
-    fn make_pidfile(pidfile: &Path, cleanup: &mut Cleanup) -> anyhow::Result<()> {
+    fn make_pidfile(
+        pidfile: &Path,
+        cleanup: &mut Cleanup,
+        user: Option<&str>,
+        group: Option<&str>,
+        overwrite_pidfile: bool,
+    ) -> anyhow::Result<()> {
         // Everyone shares the same file which will be treated as a lock.  To avoid some
         // uncomfortable race conditions, we can't set up the fatal signal unlink until we've
         // acquired it.
@@ -371,7 +556,7 @@ impl Daemonizing {
         tmpfile.push(".tmp");
         let tmpfile: PathBuf = tmpfile.into();
 
-        let mut file = File::options().append(true).create(true).open(&tmpfile)
+        let file = File::options().append(true).create(true).open(&tmpfile)
             .with_context(|| format!("{}: create failed", tmpfile.display()))?;
 
         sys::fcntl_set_lock(&file)
@@ -379,14 +564,23 @@ impl Daemonizing {
 
         // We acquired the lock.  Make sure to clean up on exit, and verify
         // that we're allowed to create the actual pidfile.
-        Self::check_already_running(pidfile)?;
+        Self::check_already_running(pidfile, overwrite_pidfile)?;
         cleanup.register_remove_file(pidfile)?;
 
         file.set_len(0).with_context(|| format!("{}: truncate failed", tmpfile.display()))?;
 
-        file.write_all(format!("{}\n", std::process::id()).as_bytes())
+        (&file).write_all(format!("{}\n", std::process::id()).as_bytes())
             .with_context(|| format!("{}: write failed", tmpfile.display()))?;
 
+        // If we're about to drop privileges, chown the pidfile (and the parent "lock" fd we're
+        // about to leak) to the target uid/gid now, while we're still root, so the unprivileged
+        // daemon can still unlink it at exit.
+        if user.is_some() || group.is_some() {
+            let (uid, gid) = sys::resolve_chown_ids(user, group)?;
+            sys::fchown(&file, uid, gid)
+                .with_context(|| format!("{}: chown failed", tmpfile.display()))?;
+        }
+
         std::fs::rename(&tmpfile, &pidfile)
             .with_context(|| format!("failed to rename {} to {}",
                                      tmpfile.display(), pidfile.display()))?;
@@ -397,8 +591,13 @@ impl Daemonizing {
         Ok(())
     }
 
-    fn check_already_running(pidfile: &Path) -> anyhow::Result<()> {
+    fn check_already_running(pidfile: &Path, overwrite: bool) -> anyhow::Result<()> {
         match Self::read_pidfile(pidfile, true) {
+            Ok(Some(pid)) if overwrite => {
+                event!(Level::WARN, "{}: overwriting pidfile of running pid {pid} \
+                                     (--overwrite-pidfile)", pidfile.display());
+                Ok(())
+            },
             Ok(Some(pid)) => Err(anyhow!("{}: already running as pid {pid}, aborting",
                                          pidfile.display()))?,
             Ok(None) => Ok(()),
@@ -478,6 +677,86 @@ fn assert_single_threaded() {
     // Don't know how to count our threads.
 }
 
+/// Resolves `user` (a name or numeric uid) to a `(uid, primary gid, username)` triple, for
+/// `drop_privileges`.
+fn resolve_user(user: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t, CString)> {
+    if let Ok(uid) = user.parse::<libc::uid_t>() {
+        let pwd = unsafe { libc::getpwuid(uid) };
+        if pwd.is_null() {
+            return Err(anyhow!("{user}: no such uid"));
+        }
+        return Ok((uid, unsafe { (*pwd).pw_gid }, CString::new(user)?));
+    }
+
+    let user_cs = CString::new(user)?;
+    let pwd = unsafe { libc::getpwnam(user_cs.as_ptr()) };
+    if pwd.is_null() {
+        return Err(anyhow!("{user}: no such user"));
+    }
+    unsafe { Ok(((*pwd).pw_uid, (*pwd).pw_gid, user_cs)) }
+}
+
+/// Resolves `group` (a name or numeric gid) to a gid, for `drop_privileges`.
+fn resolve_group(group: &str) -> anyhow::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        if unsafe { libc::getgrgid(gid) }.is_null() {
+            return Err(anyhow!("{group}: no such gid"));
+        }
+        return Ok(gid);
+    }
+
+    let group_cs = CString::new(group)?;
+    let grp = unsafe { libc::getgrnam(group_cs.as_ptr()) };
+    if grp.is_null() {
+        return Err(anyhow!("{group}: no such group"));
+    }
+    unsafe { Ok((*grp).gr_gid) }
+}
+
+/// Drops the process's privileges to `user`/`group`, both of which may be a name or numeric id.
+/// If only one is given, the other defaults to the other's corresponding value (`user`'s primary
+/// group, or the target `group` with the current uid).
+///
+/// Order matters here: we must set the gid (and supplementary groups) while still privileged,
+/// because `setuid` gives up the ability to change them.
+fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    let (uid, default_gid, username) = match user {
+        Some(user) => resolve_user(user)?,
+        None => (unsafe { libc::getuid() }, unsafe { libc::getgid() }, CString::new("")?),
+    };
+    let gid = match group {
+        Some(group) => resolve_group(group)?,
+        None => default_gid,
+    };
+
+    if user.is_some() {
+        // Populate the supplementary groups for the target user, not just its primary group.
+        if unsafe { libc::initgroups(username.as_ptr(), gid) } != 0 {
+            return Err(anyhow!("initgroups failed ({})", Error::last_os_error()));
+        }
+    }
+
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(anyhow!("setgid({gid}) failed ({})", Error::last_os_error()));
+    }
+    if unsafe { libc::setuid(uid) } != 0 {
+        return Err(anyhow!("setuid({uid}) failed ({})", Error::last_os_error()));
+    }
+
+    // A classic privilege-escalation footgun is to ignore setuid/setgid's return value (e.g.
+    // because the process was never privileged to begin with, so the call fails silently under
+    // certain sandboxes). Double-check that the change actually took effect.
+    if unsafe { libc::getegid() } != gid {
+        return Err(anyhow!("setgid({gid}) did not take effect"));
+    }
+    if unsafe { libc::geteuid() } != uid {
+        return Err(anyhow!("setuid({uid}) did not take effect"));
+    }
+
+    event!(Level::INFO, "dropped privileges to uid {uid}, gid {gid}");
+    Ok(())
+}
+
 enum ForkAndWaitResult {
     ForkFailed { child_pid: c_int, status: ExitStatus },
     InParent { child_pid: c_int },
@@ -491,12 +770,23 @@ fn fork_and_wait_for_startup() -> ForkAndWaitResult {
             // Running in parent process.
             drop(wfd);
 
-            let mut buf: [u8; 1] = [0; 1];
-            match File::from(rfd).read_exact(&mut buf) {
-                Ok(_) => {
+            let mut pipe = File::from(rfd);
+            let mut marker: [u8; 1] = [0; 1];
+            match pipe.read_exact(&mut marker) {
+                Ok(_) if marker[0] == 0 => {
                     // The child successfully started up.
                     ForkAndWaitResult::InParent { child_pid }
                 },
+                Ok(_) => {
+                    // The child reported a startup failure frame: an error code, then the length
+                    // and bytes of a message describing what went wrong.  Surface the message
+                    // immediately, rather than waiting to infer it from the child's exit status.
+                    let (code, message) = read_failure_frame(&mut pipe)
+                        .unwrap_or_else(|error| (1, format!("malformed startup failure frame ({error})")));
+                    event!(Level::ERROR, "{message}");
+                    sys::xwaitpid(child_pid, 0);
+                    exit(code);
+                },
                 Err(_) => {
                     // The child exited (or closed the pipe) without writing anything to it,
                     // which signifies an error.  Wait for it to die and get the exit status.
@@ -520,6 +810,24 @@ fn fork_and_wait_for_startup() -> ForkAndWaitResult {
     }
 }
 
+/// Reads the `(code, message)` tail of a startup failure frame (the marker byte itself having
+/// already been consumed), as written by [`fork_notify_failure`]: a little-endian `i32` exit
+/// code, then a little-endian `u32` message length, then that many bytes of UTF-8 message.
+fn read_failure_frame(pipe: &mut File) -> Result<(i32, String), Error> {
+    let mut code_bytes = [0; 4];
+    pipe.read_exact(&mut code_bytes)?;
+    let code = i32::from_le_bytes(code_bytes);
+
+    let mut len_bytes = [0; 4];
+    pipe.read_exact(&mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+
+    let mut message_bytes = vec![0; len];
+    pipe.read_exact(&mut message_bytes)?;
+
+    Ok((code, String::from_utf8_lossy(&message_bytes).into_owned()))
+}
+
 fn fork_notify_startup(notify_pipe: &mut File) {
     if let Err(error) = notify_pipe.write_all(&[0; 1]) {
         event!(Level::ERROR, "pipe write failed ({error})");
@@ -527,7 +835,25 @@ fn fork_notify_startup(notify_pipe: &mut File) {
     }
 }
 
-fn close_standard_fds() {
+/// Reports a startup failure to the parent waiting in [`fork_and_wait_for_startup`], as a frame
+/// of a nonzero marker byte, `code` (the exit code the parent should propagate), and `message`
+/// (surfaced via the parent's own logging, since the child's stderr isn't necessarily visible to
+/// whatever ultimately invoked us, e.g. once `--monitor` is in the mix).
+fn fork_notify_failure(notify_pipe: &mut File, code: i32, message: &str) {
+    let mut frame = vec![1];
+    frame.extend_from_slice(&code.to_le_bytes());
+    let message = message.as_bytes();
+    frame.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    frame.extend_from_slice(message);
+    if let Err(error) = notify_pipe.write_all(&frame) {
+        event!(Level::ERROR, "pipe write failed ({error})");
+        exit(1);
+    }
+}
+
+/// Redirects `stdin` to `/dev/null`, and `stdout`/`stderr` to `stdout_path`/`stderr_path`
+/// respectively, falling back to `/dev/null` for either one that's unset.
+fn redirect_standard_fds(stdout_path: Option<&Path>, stderr_path: Option<&Path>) {
     let filename = "/dev/null";
     let dev_null = CString::new(filename).unwrap();
     let null_fd = unsafe { libc::open(dev_null.as_ptr(), libc::O_RDWR) };
@@ -536,11 +862,41 @@ fn close_standard_fds() {
         exit(1);
     }
 
-    for fd in 0..=2 {
-        unsafe { libc::dup2(null_fd, fd) };
-    }
+    unsafe { libc::dup2(null_fd, 0) };
+    redirect_fd(1, stdout_path, null_fd);
+    redirect_fd(2, stderr_path, null_fd);
     unsafe { libc::close(null_fd) };
 }
+
+/// Redirects `fd` to `path` if given, opening it for appending (creating it if necessary), or to
+/// `null_fd` otherwise.
+fn redirect_fd(fd: c_int, path: Option<&Path>, null_fd: c_int) {
+    let log_fd = match path {
+        Some(path) => {
+            let path_cs = match CString::new(path.as_os_str().as_bytes()) {
+                Ok(path_cs) => path_cs,
+                Err(error) => {
+                    event!(Level::ERROR, "{}: invalid path ({error})", path.display());
+                    exit(1);
+                }
+            };
+            let log_fd = unsafe {
+                libc::open(path_cs.as_ptr(), libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND, 0o644)
+            };
+            if log_fd < 0 {
+                event!(Level::ERROR, "{}: open failed ({})", path.display(), Error::last_os_error());
+                exit(1);
+            }
+            Some(log_fd)
+        },
+        None => None,
+    };
+
+    unsafe { libc::dup2(log_fd.unwrap_or(null_fd), fd) };
+    if let Some(log_fd) = log_fd {
+        unsafe { libc::close(log_fd) };
+    }
+}
 
 mod sys {
     //! System call wrappers.
@@ -621,9 +977,9 @@ mod sys {
         }
     }
 
-    fn fcntl_lock_op(file: &File, command: c_int) -> Result<libc::flock, Error> {
+    fn fcntl_lock_op(file: &File, command: c_int, l_type: c_int) -> Result<libc::flock, Error> {
         let mut lck = libc::flock {
-            l_type: libc::F_WRLCK as i16,
+            l_type: l_type as i16,
             l_whence: libc::SEEK_SET as i16,
             l_start: 0,
             l_len: 0,
@@ -642,15 +998,52 @@ mod sys {
         }
     }
 
+    /// Whether a lock allows other locks of the same kind to coexist (`Shared`, `F_RDLCK`) or
+    /// excludes them entirely (`Exclusive`, `F_WRLCK`).
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum LockMode {
+        Shared,
+        Exclusive,
+    }
+
+    impl LockMode {
+        fn l_type(self) -> c_int {
+            match self {
+                LockMode::Shared => libc::F_RDLCK,
+                LockMode::Exclusive => libc::F_WRLCK,
+            }
+        }
+    }
+
     pub fn fcntl_set_lock(file: &File) -> Result<(), Error> {
-        let _ = fcntl_lock_op(file, libc::F_SETLK)?;
+        fcntl_set_lock_mode(file, LockMode::Exclusive)
+    }
+
+    /// Like `fcntl_set_lock`, but takes a `Shared` or `Exclusive` lock as requested, rather than
+    /// always taking an exclusive one.  Fails immediately (`F_SETLK`) if the lock isn't available.
+    pub fn fcntl_set_lock_mode(file: &File, mode: LockMode) -> Result<(), Error> {
+        let _ = fcntl_lock_op(file, libc::F_SETLK, mode.l_type())?;
+        Ok(())
+    }
+
+    /// Like `fcntl_set_lock_mode`, but blocks (`F_SETLKW`) until the lock is granted instead of
+    /// failing immediately if it's held.
+    pub fn fcntl_set_lock_wait(file: &File, mode: LockMode) -> Result<(), Error> {
+        let _ = fcntl_lock_op(file, libc::F_SETLKW, mode.l_type())?;
+        Ok(())
+    }
+
+    /// Releases a lock previously taken by `fcntl_set_lock`/`fcntl_set_lock_mode`/
+    /// `fcntl_set_lock_wait`.
+    pub fn fcntl_unlock(file: &File) -> Result<(), Error> {
+        let _ = fcntl_lock_op(file, libc::F_SETLK, libc::F_UNLCK)?;
         Ok(())
     }
 
     /// Check whether `file` is locked.  Returns `Ok(Some(pid))` if it's locked by process `pid` or
     /// Ok(None) if it's not locked.
     pub fn fcntl_get_lock(file: &File) -> Result<Option<c_int>, Error> {
-        let lck = fcntl_lock_op(file, libc::F_GETLK)?;
+        let lck = fcntl_lock_op(file, libc::F_GETLK, libc::F_WRLCK)?;
         if lck.l_type == libc::F_UNLCK as i16 {
             Ok(None)
         } else {
@@ -658,4 +1051,28 @@ mod sys {
         }
     }
 
+    pub fn fchown(file: &File, uid: libc::uid_t, gid: libc::gid_t) -> Result<(), Error> {
+        match unsafe { libc::fchown(file.as_raw_fd(), uid, gid) } {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(())
+        }
+    }
+
+    /// Resolves `user`/`group` to the `(uid, gid)` pair to pass to `fchown`, where an absent
+    /// `user` or `group` maps to `(uid_t)-1`/`(gid_t)-1` (chown's "leave unchanged" sentinel)
+    /// rather than the current process's ids.
+    pub fn resolve_chown_ids(user: Option<&str>, group: Option<&str>)
+        -> anyhow::Result<(libc::uid_t, libc::gid_t)>
+    {
+        let uid = match user {
+            Some(user) => resolve_user(user)?.0,
+            None => libc::uid_t::MAX,
+        };
+        let gid = match group {
+            Some(group) => resolve_group(group)?,
+            None => libc::gid_t::MAX,
+        };
+        Ok((uid, gid))
+    }
+
 }
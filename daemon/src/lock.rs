@@ -0,0 +1,253 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! An RAII guard around the `fcntl`-based advisory locks used elsewhere in this crate (e.g. for
+//! pidfiles).
+//!
+//! [`crate::sys::fcntl_set_lock`] and [`crate::sys::fcntl_get_lock`] are bare wrappers around
+//! `fcntl(F_SETLK)`/`fcntl(F_GETLK)` that leave release entirely up to the caller.  [`LockGuard`]
+//! takes the lock on construction and releases it on `Drop`, so a guarded critical section can't
+//! leak a held lock if the caller early-returns or panics partway through.
+
+use anyhow::{anyhow, Context};
+use libc::c_int;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{event, Level};
+
+/// An error from attempting to take a lock with [`LockGuard::try_new`] or
+/// [`try_with_lock_no_wait`].
+#[derive(Debug)]
+pub enum LockError {
+    /// Some other process already holds the lock.
+    AlreadyHeld,
+    /// Some other I/O error occurred while attempting to take the lock.
+    Io(io::Error),
+}
+
+impl std::fmt::Display for LockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::AlreadyHeld => write!(f, "lock is already held by another process"),
+            LockError::Io(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for LockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockError::AlreadyHeld => None,
+            LockError::Io(error) => Some(error),
+        }
+    }
+}
+
+impl From<io::Error> for LockError {
+    fn from(error: io::Error) -> LockError {
+        // `F_SETLK` fails this way, rather than blocking, when another process holds the lock.
+        match error.raw_os_error() {
+            Some(libc::EACCES) | Some(libc::EAGAIN) => LockError::AlreadyHeld,
+            _ => LockError::Io(error),
+        }
+    }
+}
+
+/// Holds a write lock on a `File`, taken with `F_SETLK` (so construction fails immediately,
+/// rather than blocking, if another process already holds it).  The lock is released when the
+/// guard is dropped.
+pub struct LockGuard<'a> {
+    file: &'a File,
+}
+
+impl<'a> LockGuard<'a> {
+    /// Takes a write lock on `file`, failing with [`LockError::AlreadyHeld`] rather than blocking
+    /// if some other process already holds it.
+    pub fn try_new(file: &'a File) -> Result<LockGuard<'a>, LockError> {
+        crate::sys::fcntl_set_lock(file)?;
+        Ok(LockGuard { file })
+    }
+}
+
+impl Drop for LockGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(error) = crate::sys::fcntl_unlock(self.file) {
+            event!(Level::WARN, "failed to release lock ({error})");
+        }
+    }
+}
+
+/// Runs `f` while holding a write lock on `file`, releasing the lock before returning (even if
+/// `f` panics).  Fails with [`LockError::AlreadyHeld`], without running `f`, if some other
+/// process already holds the lock.
+pub fn try_with_lock_no_wait<R>(file: &File, f: impl FnOnce() -> R) -> Result<R, LockError> {
+    let _guard = LockGuard::try_new(file)?;
+    Ok(f())
+}
+
+/// The state of the lock on `path`, as determined by [`check_lock`].
+///
+/// `fcntl`'s lock holder pid is meaningless across machines (e.g. `path` on a network
+/// filesystem), so this cross-checks it against the `hostname:pid` sidecar file written by
+/// [`write_lock_identity`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LockState {
+    /// Nobody holds the lock.
+    Free,
+    /// We hold the lock ourselves (`fcntl`'s lock holder pid is our own pid).
+    HeldByUs,
+    /// Some other, apparently still-running, process holds the lock.
+    HeldByOther { host: String, pid: c_int },
+    /// The sidecar file says `host` is us, but no process `pid` is running here any more.  Most
+    /// likely, `path` lives on a network filesystem whose lock server didn't notice our crashed
+    /// former self going away.
+    Stale,
+}
+
+/// Returns `path` with the lock identity sidecar file's suffix appended.
+fn lock_data_path(path: &Path) -> PathBuf {
+    let mut data_path = OsString::from(path);
+    data_path.push(".lockdata");
+    data_path.into()
+}
+
+/// Records that we hold the lock on `path`, by atomically writing `host:pid` (our own hostname
+/// and pid) to `path`'s lock identity sidecar file.  Call this just after taking the lock.
+pub fn write_lock_identity(path: &Path) -> anyhow::Result<()> {
+    write_lock_identity_pid(path, std::process::id() as c_int)
+}
+
+/// Like [`write_lock_identity`], but records `pid` instead of our own pid.
+fn write_lock_identity_pid(path: &Path, pid: c_int) -> anyhow::Result<()> {
+    let data_path = lock_data_path(path);
+    let mut tmpfile = OsString::from(&data_path);
+    tmpfile.push(".tmp");
+    let tmpfile: PathBuf = tmpfile.into();
+
+    let contents = format!("{}:{}\n", hostname()?, pid);
+    std::fs::write(&tmpfile, contents)
+        .with_context(|| format!("{}: write failed", tmpfile.display()))?;
+    std::fs::rename(&tmpfile, &data_path)
+        .with_context(|| format!("failed to rename {} to {}", tmpfile.display(), data_path.display()))?;
+    Ok(())
+}
+
+/// Checks whether `path`, backed by `file`, is free, held by us, held by some other apparently
+/// live process, or `Stale` (see [`LockState`]).
+pub fn check_lock(file: &File, path: &Path) -> anyhow::Result<LockState> {
+    let holder_pid = match crate::sys::fcntl_get_lock(file)? {
+        None => return Ok(LockState::Free),
+        Some(pid) => pid,
+    };
+    if holder_pid == std::process::id() as c_int {
+        return Ok(LockState::HeldByUs);
+    }
+
+    let data_path = lock_data_path(path);
+    let contents = std::fs::read_to_string(&data_path)
+        .with_context(|| format!("{}: read failed", data_path.display()))?;
+    let (host, pid) = contents.trim().split_once(':')
+        .ok_or_else(|| anyhow!("{}: malformed lock identity", data_path.display()))?;
+    let pid: c_int = pid.parse()
+        .with_context(|| format!("{}: invalid pid in lock identity", data_path.display()))?;
+
+    if host != hostname()? {
+        return Ok(LockState::HeldByOther { host: host.to_string(), pid });
+    }
+
+    // Same host: is `pid` actually still alive?  `kill(pid, 0)` sends no signal, but still fails
+    // with ESRCH if the process doesn't exist.
+    match unsafe { libc::kill(pid, 0) } {
+        0 => Ok(LockState::HeldByOther { host: host.to_string(), pid }),
+        _ if io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH) => Ok(LockState::Stale),
+        // It exists but we can't signal it (e.g. EPERM); treat it as live.
+        _ => Ok(LockState::HeldByOther { host: host.to_string(), pid }),
+    }
+}
+
+/// If `path`'s lock is [`LockState::Stale`], takes it over: acquires it ourselves and rewrites
+/// the lock identity sidecar file to name us as the new holder.  Fails, without taking the lock,
+/// if it's in any other state, so a live holder (on this host or another) is never clobbered.
+pub fn break_stale_lock<'a>(file: &'a File, path: &Path) -> anyhow::Result<LockGuard<'a>> {
+    match check_lock(file, path)? {
+        LockState::Stale => {
+            // The crashed holder's pid is confirmed dead, so its `fcntl` lock is already gone;
+            // this should succeed.
+            let guard = LockGuard::try_new(file)
+                .map_err(|error| anyhow!("{}: failed to break stale lock ({error})", path.display()))?;
+            write_lock_identity(path)?;
+            Ok(guard)
+        },
+        state => Err(anyhow!("{}: lock is not stale ({state:?})", path.display())),
+    }
+}
+
+/// Forks a child process while holding the exclusive lock on `file`, so that only one process at
+/// a time can have a supervised child running for a given `path`.  On success, returns the
+/// child's pid along with the [`LockGuard`] that must stay alive until the child has been reaped
+/// (see [`reap_locked`]); the lock identity sidecar file is stamped with the child's pid before
+/// returning, so [`check_lock`] on another process correctly reports who's running it.
+///
+/// In the child, `child_fn` runs instead of returning from `spawn_locked` at all (it takes over
+/// the process, typically execing or looping until exit).
+///
+/// Fails, without forking, if `file` is already locked by some other process.
+pub fn spawn_locked<'a>(
+    file: &'a File,
+    path: &Path,
+    child_fn: impl FnOnce() -> !,
+) -> anyhow::Result<(c_int, LockGuard<'a>)> {
+    let guard = LockGuard::try_new(file)
+        .map_err(|error| anyhow!("{}: failed to acquire lock ({error})", path.display()))?;
+    match unsafe { crate::sys::xfork() } {
+        Some(child_pid) => {
+            write_lock_identity_pid(path, child_pid)?;
+            Ok((child_pid, guard))
+        },
+        None => child_fn(),
+    }
+}
+
+/// Waits for the child spawned by [`spawn_locked`] to exit, returning its [`std::process::ExitStatus`].
+/// `guard` is released only once the child has actually been reaped (dropping it at the end of
+/// this function), so another process can't see the lock as free while `child_pid` might still be
+/// running.
+pub fn reap_locked(child_pid: c_int, guard: LockGuard) -> std::process::ExitStatus {
+    let (_, status) = crate::sys::xwaitpid(child_pid, 0);
+    drop(guard);
+    status
+}
+
+/// Resolves the local hostname, for the lock identity sidecar file.
+///
+/// `libc::gethostname` wants a `*mut c_char`, whose signedness (`i8` vs. `u8`) differs by
+/// architecture, so the buffer is built as `c_char` directly rather than assumed to be `u8`.
+fn hostname() -> anyhow::Result<String> {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return Err(anyhow!("gethostname failed ({})", io::Error::last_os_error()));
+    }
+    // POSIX doesn't guarantee NUL-termination if the name fills the whole buffer.
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    let bytes: Vec<u8> = buf[..len].iter().map(|&c| c as u8).collect();
+    String::from_utf8(bytes).context("hostname is not valid UTF-8")
+}
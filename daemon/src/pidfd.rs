@@ -0,0 +1,143 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Race-free process liveness tracking via Linux `pidfd_open(2)`.
+//!
+//! Reading a pid out of a pidfile and then polling it with `kill(pid, 0)` in a sleep-backoff loop
+//! has a real race: by the time a later check runs, the pid may have been recycled by an
+//! unrelated process, so "it responds to signal 0" no longer means "the daemon we started is
+//! still alive". A pidfd pins the process itself rather than its number, so a liveness check
+//! against the same fd can't be fooled by recycling, and `poll(2)` on the fd blocks with no
+//! polling loop at all, becoming readable exactly when the process exits.
+//!
+//! Falls back to the `kill(pid, 0)` polling this replaces when `pidfd_open` isn't available --
+//! non-Linux targets, or a Linux kernel older than 5.3 (`pidfd_open` returns `ENOSYS`).
+
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{FromRawFd, OwnedFd};
+
+/// A process being watched for liveness, backed by a pidfd where the platform and kernel support
+/// it, falling back to polling its pid otherwise.
+pub struct Process {
+    pid: libc::pid_t,
+    #[cfg(target_os = "linux")]
+    fd: Option<OwnedFd>,
+}
+
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: libc::pid_t) -> Result<OwnedFd, Error> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+    }
+}
+
+impl Process {
+    /// Starts watching `pid` for liveness, via a pidfd if the platform and kernel support
+    /// `pidfd_open`, falling back to polling `kill(pid, 0)` otherwise.
+    pub fn open(pid: libc::pid_t) -> Process {
+        #[cfg(target_os = "linux")]
+        {
+            let fd = pidfd_open(pid).ok();
+            return Process { pid, fd };
+        }
+        #[cfg(not(target_os = "linux"))]
+        Process { pid }
+    }
+
+    /// Blocks until the process exits. If it's our own child, also reaps it, so a blocking
+    /// `wait()` on a child of ours behaves like `waitpid`.
+    pub fn wait(&self) -> Result<(), Error> {
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = &self.fd {
+            let mut pollfd = libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            loop {
+                let retval = unsafe { libc::poll(&mut pollfd as *mut libc::pollfd, 1, -1) };
+                if retval >= 0 {
+                    break;
+                }
+                let err = Error::last_os_error();
+                if err.kind() != ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            reap_if_child(fd.as_raw_fd());
+            return Ok(());
+        }
+
+        while self.is_alive() {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if the process has already exited, without blocking.
+    pub fn try_wait(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = &self.fd {
+            let mut pollfd = libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            let retval = unsafe { libc::poll(&mut pollfd as *mut libc::pollfd, 1, 0) };
+            if retval > 0 {
+                reap_if_child(fd.as_raw_fd());
+                return true;
+            }
+            return false;
+        }
+
+        !self.is_alive()
+    }
+
+    /// Sends `signal` to the process.
+    pub fn kill(&self, signal: libc::c_int) -> Result<(), Error> {
+        if unsafe { libc::kill(self.pid, signal) } < 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fallback liveness check for platforms without `pidfd_open`: `kill(pid, 0)` sends no
+    /// signal, but still fails with `ESRCH` if the pid doesn't exist (or has been recycled since
+    /// we started watching it -- a race this path can't close, unlike the pidfd one).
+    fn is_alive(&self) -> bool {
+        // An exited child of ours still answers signal 0 until it's reaped, so reap it first.
+        if unsafe { libc::waitpid(self.pid, std::ptr::null_mut(), libc::WNOHANG) } == self.pid {
+            return false;
+        }
+        unsafe { libc::kill(self.pid, 0) == 0 }
+    }
+}
+
+/// If `pidfd` names one of our own children, reaps it via `waitid(P_PIDFD, ...)` so it doesn't
+/// linger as a zombie; otherwise (e.g. `ECHILD`, because it's someone else's process) this is a
+/// harmless no-op, since `wait`/`try_wait` already learned the process exited from `poll`.
+#[cfg(target_os = "linux")]
+fn reap_if_child(pidfd: RawFd) {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::waitid(libc::P_PIDFD, pidfd as libc::id_t, &mut info as *mut libc::siginfo_t, libc::WEXITED);
+    }
+}
@@ -140,6 +140,13 @@ fn process_exists(pid: libc::pid_t) -> Result<(), std::io::Error> {
     send_signal(pid, 0)
 }
 
+/// Blocks until `pid` exits, via [`daemon::pidfd::Process`] so that the wait can't be fooled by
+/// the pid being recycled to an unrelated process while we wait.
+fn wait_for_pid(pid: libc::pid_t) -> Result<()> {
+    daemon::pidfd::Process::open(pid).wait()?;
+    Ok(())
+}
+
 fn read_pidfile<P>(path: P) -> Result<libc::pid_t>
     where P: AsRef<Path>
 {
@@ -149,10 +156,7 @@ fn read_pidfile<P>(path: P) -> Result<libc::pid_t>
 
 /// This won't work if `pid` is our direct child.  Use `wait_for_child_to_die` in that case.
 fn wait_for_process_to_die(pid: libc::pid_t) -> Result<()> {
-    wait_until(|| match process_exists(pid) {
-        Ok(()) => Incomplete,
-        Err(_) => Complete(())
-    })
+    wait_for_pid(pid)
 }
 
 /// Wait until 'file' exists.
@@ -190,6 +194,36 @@ fn test_pidfile() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_pidfile_already_running() -> Result<()> {
+    init_tracing();
+
+    // Start a daemon and wait for its pidfile to be created.
+    let pidfile_name = pidfile_name()?;
+    let mut first = test_daemon_command()?.arg("--pidfile").arg(&pidfile_name).spawn()?;
+    wait_until_file_exists(&pidfile_name)?;
+    let first_pid = read_pidfile(&pidfile_name)?;
+    assert_eq!(first_pid, first.id() as libc::pid_t);
+
+    // Start a second daemon against the same pidfile.  It should refuse to start, since the
+    // first daemon holds an exclusive lock on it, and exit non-zero without disturbing the first
+    // daemon's pidfile.
+    let mut second = test_daemon_command()?.arg("--pidfile").arg(&pidfile_name).spawn()?;
+    let status = wait_for_child_to_die(&mut second)??;
+    assert!(!status.success());
+    assert_eq!(read_pidfile(&pidfile_name)?, first_pid);
+
+    // The first daemon should be unaffected.
+    process_exists(first_pid)?;
+
+    // Clean up.
+    send_signal(first_pid, libc::SIGTERM)?;
+    first.wait()?;
+    check_file_does_not_exist(&pidfile_name)?;
+
+    Ok(())
+}
+
 /// Waits for `child` to die, and returns:
 ///    - `Ok(Ok(status))`: Child exited with `status`.
 ///    - `Ok(Err(e))`: System reported error waiting for `child` (e.g. we already waited for it).
@@ -281,6 +315,36 @@ fn test_monitor() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_monitor_backoff_gives_up() -> Result<()> {
+    // A daemon that aborts immediately, on every restart, should make the monitor back off
+    // between restarts rather than respawning in a tight loop, and eventually give up and exit
+    // nonzero rather than restarting forever.
+    let pidfile_name = pidfile_name()?;
+    let start = std::time::Instant::now();
+    let mut child = test_daemon_command()?
+        .arg("--pidfile").arg(&pidfile_name)
+        .arg("--abort-immediately")
+        .arg("--monitor")
+        .arg("--restart-delay").arg("1")
+        .arg("--max-restarts").arg("2")
+        .spawn()?;
+
+    let status = wait_for_child_to_die(&mut child)??;
+    assert!(!status.success());
+
+    // Two restarts at a 1-second delay each means the monitor can't have given up in under two
+    // seconds; this is the difference between "backed off" and "spun in a tight loop".
+    assert!(start.elapsed() >= std::time::Duration::from_secs(2));
+
+    // The last daemon instance died by `abort()`, which isn't one of the signals that triggers
+    // pidfile cleanup, and nothing restarts it again to clean up the stale pidfile from its
+    // predecessor -- so, unlike the other tests here, a leftover pidfile is expected.
+    remove_if_exists(&pidfile_name)?;
+
+    Ok(())
+}
+
 #[test]
 fn test_detach() -> Result<()> {
     // Start the daemon and make sure that the pidfile exists immediately.
@@ -33,6 +33,10 @@ extern crate grpcio;
 extern crate proto;
 extern crate protobuf;
 
+mod mastership;
+mod switch_state;
+mod wal;
+
 use num_traits::cast::ToPrimitive;
 
 use differential_datalog::api::HDDlog;
@@ -70,22 +74,30 @@ use proto::p4runtime::{
     MasterArbitrationUpdate,
     StreamMessageRequest,
     StreamMessageResponse,
+    StreamMessageResponse_oneof_update,
     TableAction,
+    Uint128,
 };
 use proto::p4runtime_grpc::P4RuntimeClient;
 use protobuf::Message;
 
+use mastership::{Mastership, Role};
+use switch_state::SwitchState;
+
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
     fmt,
     fs::File,
+    path::Path,
     sync::Arc,
 };
-use tokio::sync::{oneshot, mpsc};
+use tokio::sync::{oneshot, mpsc, watch, Mutex, RwLock};
 use tokio::time::{Duration, sleep};
-use tracing::{debug, error, instrument};
+use tracing::{debug, error, instrument, warn};
+
+use wal::WriteAheadLog;
 
 /// Public handle for the Tokio tasks.
 /// The Tokio task either processes DDlog inputs or pushes outputs to the switch.
@@ -98,38 +110,115 @@ pub struct Controller {
 impl Controller {
     /// Create a new handle for Tokio tasks.
     ///
-    /// Passes `switch_client` and `hddlog` to a `ControllerActor`, which allows interaction with the P4 switch and DDlog program, respectively. Runs the actor asynchronously.
+    /// Passes `switch_clients` and `hddlog` to a `ControllerActor`, which allows interaction with
+    /// the P4 switches and DDlog program, respectively. Runs the actor asynchronously.
     ///
     /// # Arguments
-    /// * `switch_client` - P4 Runtime client with extra information.
+    /// * `switch_clients` - P4 Runtime client for each device this controller drives, keyed by
+    ///   `SwitchClient::device_id`.
     /// * `hddlog` - DDlog program.
     pub fn new(
-        switch_client: SwitchClient,
+        switch_clients: Vec<SwitchClient>,
         hddlog: Arc<HDDlog>,
     ) -> Result<Controller, String> {
         let (sender, receiver) = mpsc::channel(1000);
         let program = ControllerProgram::new(hddlog);
 
-        let mut actor = ControllerActor::new(receiver, switch_client, program);
+        let switch_clients = Self::index_by_device_id(switch_clients);
+        let mut actor = ControllerActor::new(receiver, switch_clients, program, None, None);
         tokio::spawn(async move { actor.run().await });
 
         Ok(Self{sender})
     }
 
+    /// Create a controller handle backed by a write-ahead log at `path`, so a crash doesn't lose
+    /// any batch the controller already committed in DDlog but hadn't confirmed on the switch(es)
+    /// it was meant for.
+    ///
+    /// Every batch `push_outputs` would send is durably appended to the log, tagged with the
+    /// `device_id` it was built for, before it's written to that device. On restore, any record
+    /// left over from a previous run that wasn't yet confirmed is resent to the matching
+    /// `SwitchClient` before the controller starts processing new inputs, and the log is
+    /// compacted once that resend succeeds. Also spawns a background task that compacts the log
+    /// as later live batches are confirmed, so it doesn't grow without bound.
+    ///
+    /// # Arguments
+    /// * `path` - file to durably log committed batches to.
+    /// * `switch_clients` - P4 Runtime client for each device this controller drives, keyed by
+    ///   `SwitchClient::device_id`.
+    /// * `hddlog` - DDlog program.
+    pub async fn restore_from_log<P: AsRef<Path>>(
+        path: P,
+        switch_clients: Vec<SwitchClient>,
+        hddlog: Arc<HDDlog>,
+    ) -> Result<Controller, String> {
+        let mut switch_clients = Self::index_by_device_id(switch_clients);
+
+        let unacked = WriteAheadLog::replay(&path)
+            .map_err(|e| format!("{}: failed to replay write-ahead log ({:#})", path.as_ref().display(), e))?;
+        let mut wal = WriteAheadLog::open(&path)
+            .map_err(|e| format!("{}: failed to open write-ahead log ({:#})", path.as_ref().display(), e))?;
+
+        let mut last_confirmed = None;
+        for record in unacked {
+            let sequence = record.sequence;
+            let switch_client = match switch_clients.get_mut(&record.device_id) {
+                Some(switch_client) => switch_client,
+                None => {
+                    error!("write-ahead log record {} is for unknown device {}; skipping replay", sequence, record.device_id);
+                    continue;
+                },
+            };
+            match switch_client.replay_updates(record.updates).await {
+                Ok(()) => last_confirmed = Some(sequence),
+                Err(e) => error!("failed to replay write-ahead log record {}: {:#?}", sequence, e),
+            }
+        }
+        if let Some(sequence) = last_confirmed {
+            wal.compact(sequence)
+                .map_err(|e| format!("{}: failed to compact write-ahead log ({:#})", path.as_ref().display(), e))?;
+        }
+
+        let wal = Arc::new(Mutex::new(wal));
+        let (compaction_tx, mut compaction_rx) = mpsc::channel::<u64>(1000);
+        let compaction_wal = wal.clone();
+        tokio::spawn(async move {
+            while let Some(sequence) = compaction_rx.recv().await {
+                if let Err(e) = compaction_wal.lock().await.compact(sequence) {
+                    error!("failed to compact write-ahead log: {:#?}", e);
+                }
+            }
+        });
+
+        let (sender, receiver) = mpsc::channel(1000);
+        let program = ControllerProgram::new(hddlog);
+
+        let mut actor = ControllerActor::new(receiver, switch_clients, program, Some(wal), Some(compaction_tx));
+        tokio::spawn(async move { actor.run().await });
+
+        Ok(Self{sender})
+    }
+
+    /// Keys `switch_clients` by `SwitchClient::device_id`, so `ControllerActor` can look up the
+    /// right client for a `device_id`-tagged output relation or write-ahead log record.
+    fn index_by_device_id(switch_clients: Vec<SwitchClient>) -> HashMap<u64, SwitchClient> {
+        switch_clients.into_iter().map(|c| (c.device_id, c)).collect()
+    }
+
     /// Stream inputs from OVSDB and from the data plane.
     ///
     /// Send a message to the `ControllerActor`. On receipt, the actor starts streaming inputs.
     ///
     /// # Arguments
     /// * `hddlog` - DDlog program.
-    /// * `server` - Filepath for OVSDB server.
-    /// * `database` - Name of OVSDB.
+    /// * `ovsdb_endpoints` - (server, database) pair for every OVSDB to read inputs from. A
+    ///   single-switch controller passes a one-entry vector; a multi-switch fabric typically
+    ///   passes one entry per switch.
     #[instrument]
     pub async fn stream_inputs(
         &self,
         hddlog: Arc<HDDlog>,
-        server: String,
-        database: String,
+        ovsdb_endpoints: Vec<(String, String)>,
     ) {
         // The oneshot channel keeps the Actor running that processes inputs.
         // It closes when the Actor task is killed.
@@ -137,8 +226,7 @@ impl Controller {
         let msg = ControllerActorMessage::InputMessage {
             _respond_to: send,
             hddlog,
-            server,
-            database,
+            ovsdb_endpoints,
         };
 
         let message_res = self.sender.send(msg).await;
@@ -148,12 +236,52 @@ impl Controller {
 
         recv.await.expect("Actor task has been killed");
     }
+
+    /// Requests promotion to primary for the switch identified by `device_id`.
+    ///
+    /// See [`SwitchClient::promote`] for what "requesting" promotion means -- the switch, not the
+    /// controller, decides who holds primary. Call [`Self::watch_mastership`] to observe the
+    /// outcome.
+    ///
+    /// # Errors
+    /// Returns an error if `device_id` doesn't name a switch this controller drives.
+    pub async fn promote(&self, device_id: u64) -> Result<(), String> {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerActorMessage::Promote { device_id, respond_to: send };
+
+        let message_res = self.sender.send(msg).await;
+        if message_res.is_err() {
+            error!("could not send message to controller actor: {:#?}", message_res);
+        }
+
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Returns a channel reporting the current primary/backup role this controller holds for the
+    /// switch identified by `device_id`, and every later transition between them.
+    ///
+    /// # Errors
+    /// Returns an error if `device_id` doesn't name a switch this controller drives.
+    pub async fn watch_mastership(&self, device_id: u64) -> Result<watch::Receiver<Role>, String> {
+        let (send, recv) = oneshot::channel();
+        let msg = ControllerActorMessage::WatchMastership { device_id, respond_to: send };
+
+        let message_res = self.sender.send(msg).await;
+        if message_res.is_err() {
+            error!("could not send message to controller actor: {:#?}", message_res);
+        }
+
+        recv.await.expect("Actor task has been killed")
+    }
 }
 
 /// Handle to the running DDlog program.
 #[derive(Debug)]
 pub struct ControllerProgram {
     hddlog: Arc<HDDlog>,
+    /// Id to assign the next committed transaction. Tags each [`wal::LogRecord`] with the
+    /// transaction that produced it, so replay can be ordered against the DDlog history.
+    next_transaction_id: u64,
 }
 
 impl ControllerProgram {
@@ -162,7 +290,7 @@ impl ControllerProgram {
     /// # Arguments
     /// * `hddlog` - DDlog program.
     pub fn new(hddlog: Arc<HDDlog>) -> Self {
-        Self{hddlog}
+        Self{hddlog, next_transaction_id: 0}
     }
 
     /// Apply `updates` to the DDlog program.
@@ -170,13 +298,16 @@ impl ControllerProgram {
     /// This starts a new transaction and attempts to apply updates. If successful, it commits the transaction.
     /// Else, it rolls the transaction back and returns an error.
     ///
+    /// Returns the id assigned to the committed transaction along with its output delta, so a
+    /// caller logging the batch to a write-ahead log can tag the record with it.
+    ///
     /// # Arguments
     /// * `updates` - vector of Updates to apply to the DDlog program.
     #[tracing::instrument]
     pub fn apply_updates(
         &mut self,
         updates:Vec<Update<DDValue>>
-    ) -> Result<DeltaMap<DDValue>, String> {
+    ) -> Result<(u64, DeltaMap<DDValue>), String> {
         self.hddlog.transaction_start()?;
 
         match self.hddlog.apply_updates(&mut updates.into_iter()) {
@@ -188,7 +319,10 @@ impl ControllerProgram {
             }
         };
 
-        self.hddlog.transaction_commit_dump_changes()
+        let delta = self.hddlog.transaction_commit_dump_changes()?;
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id += 1;
+        Ok((transaction_id, delta))
     }
 }
 
@@ -216,6 +350,177 @@ impl fmt::Debug for PacketSink {
     }
 }
 
+/// Compiled tables/actions for the switch's currently installed pipeline, plus the pipeline
+/// cookie they were built from, guarded for concurrent reads.
+//
+// This is a "newtype" style struct, so we can define `Debug` on it (`p4ext::Switch` doesn't
+// implement it).
+struct CachedPipeline(RwLock<Option<(u64, Arc<p4ext::Switch>)>>);
+
+impl fmt::Debug for CachedPipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedPipeline")
+         .finish()
+    }
+}
+
+/// Minimum P4Runtime API version `SwitchClient::new` requires of a target, as
+/// `(major, minor, patch)`, compared component-wise against the version the target reports from
+/// its `Capabilities` RPC. `new` refuses to drive a target reporting an older version.
+const MIN_P4RUNTIME_VERSION: (u32, u32, u32) = (1, 0, 0);
+
+/// Switch features negotiated from a target's P4Runtime capabilities and P4Info, so
+/// `SwitchClient` can skip a write the target won't accept instead of issuing it and failing.
+#[derive(Clone, Debug, Default)]
+struct SwitchCapabilities {
+    /// Whether the target's P4 program defines any digest, so there is anything for
+    /// `configure_digests` to configure.
+    digests: bool,
+    /// Whether the target's P4 program declares `packet_out` controller metadata, so
+    /// `push_outputs` can emit `PacketOut`s the target will accept.
+    packet_out: bool,
+    /// Whether the target supports multicast group programming. P4Runtime targets are expected
+    /// to support this, and there's no capability bit to probe for it directly, so this is always
+    /// `true` today; it's here so a future negotiation step has somewhere to record a `false`.
+    multicast: bool,
+}
+
+/// Converts a P4Runtime `Uint128` election id into a plain `u128` for comparison and arithmetic.
+fn uint128_to_u128(id: &Uint128) -> u128 {
+    ((id.get_high() as u128) << 64) | (id.get_low() as u128)
+}
+
+/// Converts a plain `u128` election id back into the `Uint128` `MasterArbitrationUpdate` expects.
+fn u128_to_uint128(id: u128) -> Uint128 {
+    let mut uint128 = Uint128::new();
+    uint128.set_high((id >> 64) as u64);
+    uint128.set_low(id as u64);
+    uint128
+}
+
+/// Sends `req` -- a `StreamMessageRequest` carrying a `MasterArbitrationUpdate` -- on `sink`,
+/// retrying with exponential backoff if the send fails, e.g. because the stream is still being
+/// established.
+async fn send_arbitration_with_retry(sink: &mut StreamingCallSink<StreamMessageRequest>, req: StreamMessageRequest) {
+    let mut retries = 5;
+    let mut wait = 1000; // milliseconds
+    loop {
+        match sink.send((req.clone(), grpcio::WriteFlags::default())).await {
+            Err(e) => {
+                if retries > 0 {
+                    error!("failed to send master arbitration update: {:#?}", e);
+
+                    retries -= 1;
+                    sleep(Duration::from_millis(wait)).await;
+                    wait *= 2;
+                } else {
+                    break;
+                }
+            },
+            Ok(_) => break,
+        }
+    }
+}
+
+/// Reads `MasterArbitrationUpdate` responses from the switch's stream channel, keeping
+/// `mastership` in sync with the election id the switch currently grants primary.
+///
+/// If the stream ends or errors -- the switch restarted, dropped the connection, etc. -- tears
+/// down cleanly and re-establishes it, resending this controller's current election id with the
+/// same exponential backoff `SwitchClient::new` uses initially. The freshly reconnected sink
+/// replaces the one in `packet_sink`, so `SwitchClient::send_outputs` and `SwitchClient::promote`
+/// keep working against a live stream.
+async fn watch_arbitration(
+    client: P4RuntimeClient,
+    device_id: u64,
+    target: String,
+    mastership: Arc<Mastership>,
+    packet_sink: Arc<Mutex<PacketSink>>,
+    mut receiver: ClientDuplexReceiver<StreamMessageResponse>,
+) {
+    loop {
+        while let Some(result) = receiver.next().await {
+            match result {
+                Ok(response) => {
+                    if let Some(StreamMessageResponse_oneof_update::arbitration(update)) = response.update {
+                        mastership.observe(uint128_to_u128(update.get_election_id())).await;
+                    }
+                },
+                Err(e) => {
+                    error!("{}: error on arbitration stream: {:#?}", target, e);
+                    break;
+                },
+            }
+        }
+
+        warn!("{}: lost arbitration stream to switch; reconnecting", target);
+
+        let (mut sink, new_receiver) = loop {
+            match client.stream_channel() {
+                Ok(result) => break result,
+                Err(e) => {
+                    error!("{}: failed to reopen stream channel: {:#?}", target, e);
+                    sleep(Duration::from_secs(1)).await;
+                },
+            }
+        };
+        receiver = new_receiver;
+
+        let mut upd = MasterArbitrationUpdate::new();
+        upd.set_device_id(device_id);
+        upd.set_election_id(u128_to_uint128(mastership.election_id().await));
+        let mut req = StreamMessageRequest::new();
+        req.set_arbitration(upd);
+        send_arbitration_with_retry(&mut sink, req).await;
+
+        *packet_sink.lock().await = PacketSink(sink);
+    }
+}
+
+/// Reacts to this controller being promoted to primary: reinstalls the pipeline config, since a
+/// newly-promoted controller can't assume the switch still has the config a prior primary left in
+/// place, reconciles `multicast_state` against the device in case a prior primary changed it
+/// while this controller couldn't observe it, then flushes every write
+/// `SwitchClient::guarded_write` parked while this controller was backup.
+async fn watch_promotion(
+    mastership: Arc<Mastership>,
+    parked_writes: Arc<Mutex<Vec<Vec<proto::p4runtime::Update>>>>,
+    multicast_state: Arc<Mutex<SwitchState>>,
+    client: P4RuntimeClient,
+    device_id: u64,
+    role_id: u64,
+    target: String,
+    p4info: String,
+    json: String,
+    cookie: String,
+    action: String,
+) {
+    let mut role_rx = mastership.watch();
+    loop {
+        if role_rx.changed().await.is_err() {
+            // `Mastership` was dropped along with the `SwitchClient` that owns it.
+            return;
+        }
+        if *role_rx.borrow() != Role::Primary {
+            continue;
+        }
+
+        debug!("{}: promoted to primary; reinstalling pipeline and reconciling state", target);
+        p4ext::set_pipeline_config(&p4info, &json, &cookie, &action, device_id, role_id, &target, &client);
+
+        if let Err(e) = multicast_state.lock().await.reconcile(device_id, &client).await {
+            error!("{}: failed to reconcile multicast state after promotion: {:#?}", target, e);
+        }
+
+        let to_flush: Vec<_> = std::mem::take(&mut *parked_writes.lock().await);
+        for updates in to_flush {
+            if let Err(e) = p4ext::write(updates, device_id, role_id, &target, &client) {
+                error!("{}: failed to flush a write parked while backup: {:#?}", target, e);
+            }
+        }
+    }
+}
+
 /// Sends messages to the P4 Runtime switch.
 #[derive(Debug)]
 pub struct SwitchClient {
@@ -224,13 +529,50 @@ pub struct SwitchClient {
     /// The P4 Runtime Client as a newtype for debugging.
     pub client: P4RC,
     p4info: String,
+    // `json`, `cookie`, and `action` are kept (alongside `p4info` above) so a controller promoted
+    // to primary by `watch_promotion` can reissue `p4ext::set_pipeline_config` with the same
+    // arguments `new` used, in case a prior primary left the switch without a pipeline installed.
+    json: String,
+    cookie: String,
+    action: String,
     device_id: u64,
     role_id: u64,
     target: String,
     // Using P4 Info, map each PacketMetadata field to its id.
     // This is used as a cache for metadata for P4 Runtime PacketOuts.
     packet_meta_field_to_id: HashMap<String, u32>,
-    packet_sink: PacketSink,
+    // Shared with `watch_arbitration`, which swaps in a new sink after the stream channel is
+    // re-established following a disconnect.
+    packet_sink: Arc<Mutex<PacketSink>>,
+    // Table entries we've pushed to the switch so far, keyed by table ID and a string rendering
+    // of their match fields. Lets `push_outputs` tell a fresh key (INSERT) from one we've already
+    // written (MODIFY) without a round-trip read of the switch's table state.
+    known_entries: HashSet<(u32, String)>,
+    // Cached, already-parsed `Switch` for the pipeline's current cookie, so `push_outputs`
+    // doesn't re-parse P4Info and rebuild the table/action maps on every call.
+    cached_pipeline: CachedPipeline,
+    // Features negotiated with the target in `new`, so callers can skip writes it doesn't
+    // support rather than issuing ones it will reject.
+    capabilities: SwitchCapabilities,
+    // Tracks whether the switch currently grants this controller primary, from the
+    // `MasterArbitrationUpdate` responses `watch_arbitration` observes.
+    mastership: Arc<Mastership>,
+    // Table-entry updates withheld by `guarded_write` while this controller is backup, to flush
+    // once `watch_promotion` sees it win mastership.
+    parked_writes: Arc<Mutex<Vec<Vec<proto::p4runtime::Update>>>>,
+    // In-memory mirror of the switch's installed multicast groups, so `update_multicast` can diff
+    // against it instead of re-reading every group from the device on each update.
+    multicast_state: Arc<Mutex<SwitchState>>,
+    // Pre-images of the `multicast_state` entries the `update_multicast` calls in the
+    // `build_outputs` currently in flight have already committed eagerly, keyed by multicast
+    // group ID. `build_outputs` clears this and `update_multicast` fills it in; the `send_outputs`
+    // that follows drains it, putting the pre-images back if the write never lands on the switch
+    // so `multicast_state` doesn't diverge from reality.
+    multicast_rollback: Vec<(u32, Vec<proto::p4runtime::Replica>)>,
+    // Pre-images of the `known_entries` membership this batch's `build_outputs` has already
+    // committed eagerly, paired with whether the key was present before the touch. Same rollback
+    // pattern as `multicast_rollback`, for the same reason -- see `send_outputs`.
+    known_entries_rollback: Vec<((u32, String), bool)>,
 }
 
 impl SwitchClient {
@@ -245,6 +587,10 @@ impl SwitchClient {
     /// * `device_id` - ID of the P4-enabled device.
     /// * `role_id` - the desired role ID for the controller
     /// * `target` - hardware/software entity hosting P4 Runtime (e.g., "localhost:50051"). Used for logging.
+    ///
+    /// # Errors
+    /// Returns a [`p4ext::P4Error`] if the target's P4Runtime API version, queried through the
+    /// `Capabilities` RPC, is older than [`MIN_P4RUNTIME_VERSION`].
     pub async fn new(
         client: P4RuntimeClient,
         p4info: String,
@@ -254,7 +600,21 @@ impl SwitchClient {
         device_id: u64,
         role_id: u64,
         target: String,
-    ) -> Self {
+    ) -> Result<Self, p4ext::P4Error> {
+        // Negotiate the P4Runtime version before touching the pipeline, so we refuse to drive a
+        // target we can't speak to instead of failing partway through setup.
+        let capabilities_response = p4ext::get_capabilities(&target, &client)?;
+        let reported_version = capabilities_response.get_p4runtime_api_version();
+        let version = Self::parse_p4runtime_version(reported_version).ok_or_else(|| p4ext::P4Error{
+            message: format!("{}: could not parse P4Runtime API version {:?}", target, reported_version),
+        })?;
+        if version < MIN_P4RUNTIME_VERSION {
+            return Err(p4ext::P4Error{message: format!(
+                "{}: P4Runtime API version {:?} is older than the minimum {:?} this controller requires",
+                target, version, MIN_P4RUNTIME_VERSION,
+            )});
+        }
+
         p4ext::set_pipeline_config(
             &p4info,
             &json,
@@ -267,77 +627,233 @@ impl SwitchClient {
         );
 
         // Load a P4info struct from file to cache any necessary data structures.
-        let mut p4info_file = File::open(OsStr::new(&p4info))
-            .unwrap_or_else(|err| panic!("{}: could not open P4Info ({})", p4info, err));
-        let p4info_struct: proto::p4info::P4Info = Message::parse_from_reader(&mut p4info_file)
-            .unwrap_or_else(|err| panic!("{}: could not read P4Info ({})", p4info, err));
+        let p4info_struct = Self::read_p4info(&p4info);
+
+        // Refuse to drive a switch with a `dp2ddlog` crate regenerated from a different P4Info
+        // than the one we're about to push -- that combination silently decodes digests against
+        // a stale schema instead of failing loudly. Hash the raw file, matching how
+        // `p4info2ddlog` hashed it when it generated `dp2ddlog::P4INFO_HASH`.
+        let p4info_raw_bytes = std::fs::read(&p4info).unwrap_or_else(|err| panic!(
+            "{}: could not open P4Info ({})", p4info, err,
+        ));
+        if let Err(err) = dp2ddlog::check_p4info_hash(&p4info_raw_bytes) {
+            return Err(p4ext::P4Error{message: format!("{}: {}", p4info, err)});
+        }
 
         // Map packet metadata field names to packet_ids.
         // We do this in the constructor, to avoid computation per packet sent to the dataplane.
-        let mut packet_meta_field_to_id = HashMap::new();
-        for cm in p4info_struct.get_controller_packet_metadata().iter() {
-            if cm.get_preamble().get_name().eq("packet_out") {
-                for m in cm.get_metadata().iter() {
-                    packet_meta_field_to_id.insert(
-                        m.get_name().to_string(),
-                        m.get_id()
-                    );
-                }
-            }
-        }
+        let packet_meta_field_to_id = Self::packet_meta_field_to_id(&p4info_struct);
+
+        let capabilities = SwitchCapabilities {
+            digests: !p4info_struct.get_digests().is_empty(),
+            packet_out: !packet_meta_field_to_id.is_empty(),
+            multicast: true,
+        };
 
-        // Establish a connection to the switch to send packets.
-        let (mut sink, _receiver) = client.stream_channel().unwrap();
-        // Send a master arbitration update to establish this as backup with election id 1.
-        // The Tokio actor handling messages from the dataplane has a StreamChannel with election id 0.
-        use proto::p4runtime::Uint128;
-        let mut election_id = Uint128::new();
-        election_id.set_high(0);
-        election_id.set_low(1);
+        // Establish a connection to the switch to send packets, and to arbitrate mastership on.
+        // Start out bidding election id 1; `SwitchClient::promote` bids higher if asked to become
+        // primary.
+        let (mut sink, receiver) = client.stream_channel().unwrap();
+        let mastership = Arc::new(Mastership::new(1));
 
         let mut upd = MasterArbitrationUpdate::new();
         upd.set_device_id(device_id);
-        upd.set_election_id(election_id);
+        upd.set_election_id(u128_to_uint128(mastership.election_id().await));
 
         let mut req = StreamMessageRequest::new();
         req.set_arbitration(upd);
 
-        // Send the master arbitration update request to the switch.
-        // Retry using exponential backoff.
-        // TODO: Decompose this retry into a separate function.
-        let mut retries = 5;
-        let mut wait = 1000; // milliseconds
-        loop {
-            match sink.send((req.clone(), grpcio::WriteFlags::default())).await {
-                Err(e) => {
-                    if retries > 0 {
-                        error!("failed to configure backup stream through master arbitration: {:#?}", e);
+        // Send the master arbitration update request to the switch, retrying with exponential
+        // backoff; `watch_arbitration` reuses the same retry helper if the stream later drops.
+        send_arbitration_with_retry(&mut sink, req).await;
 
-                        retries -= 1;
-                        sleep(Duration::from_secs(wait)).await;
-                        wait *= 2;
-                    }
-                },
-                Ok(_) => break,
-            }
-        };
+        let packet_sink = Arc::new(Mutex::new(PacketSink(sink)));
+
+        // Seed the multicast-group cache from the device once, so `update_multicast` never has
+        // to issue its own wildcarded read. A failure here just leaves the cache empty; it's
+        // retried on the next promotion to primary.
+        let multicast_state = Arc::new(Mutex::new(SwitchState::new()));
+        if let Err(e) = multicast_state.lock().await.reconcile(device_id, &client).await {
+            warn!("{}: failed to seed multicast state cache: {:#?}", target, e);
+        }
+
+        // Track the election id the switch grants primary to, and react to it. Both tasks run
+        // for as long as this `SwitchClient` (and the `Arc<Mastership>` it holds) is alive.
+        tokio::spawn(watch_arbitration(
+            client.clone(),
+            device_id,
+            target.clone(),
+            mastership.clone(),
+            packet_sink.clone(),
+            receiver,
+        ));
+        let parked_writes = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(watch_promotion(
+            mastership.clone(),
+            parked_writes.clone(),
+            multicast_state.clone(),
+            client.clone(),
+            device_id,
+            role_id,
+            target.clone(),
+            p4info.clone(),
+            json.clone(),
+            cookie.clone(),
+            action.clone(),
+        ));
 
         // Wrap types from external crates in newtypes.
         let p4rc = P4RC(client);
-        let packet_sink = PacketSink(sink);
-
 
         Self {
             client: p4rc,
             p4info,
+            json,
+            cookie,
+            action,
             device_id,
             role_id,
             target,
             packet_meta_field_to_id,
             packet_sink,
+            known_entries: HashSet::new(),
+            cached_pipeline: CachedPipeline(RwLock::new(None)),
+            capabilities,
+            mastership,
+            parked_writes,
+            multicast_state,
+            multicast_rollback: Vec::new(),
+            known_entries_rollback: Vec::new(),
         }
     }
 
+    /// Parses a P4Runtime API version string of the form `"major.minor.patch"` into its
+    /// component numbers, for comparison against [`MIN_P4RUNTIME_VERSION`].
+    fn parse_p4runtime_version(version: &str) -> Option<(u32, u32, u32)> {
+        let mut parts = version.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        let patch = parts.next()?.parse().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some((major, minor, patch))
+    }
+
+    /// Reads and parses the P4Info binary file at `p4info_path`.
+    ///
+    /// # Arguments
+    /// * `p4info_path` - filepath for the P4Info binary file.
+    fn read_p4info(p4info_path: &str) -> proto::p4info::P4Info {
+        let mut p4info_file = File::open(OsStr::new(p4info_path))
+            .unwrap_or_else(|err| panic!("{}: could not open P4Info ({})", p4info_path, err));
+        Message::parse_from_reader(&mut p4info_file)
+            .unwrap_or_else(|err| panic!("{}: could not read P4Info ({})", p4info_path, err))
+    }
+
+    /// Maps each `packet_out` controller packet metadata field name to its id, from `p4info`.
+    ///
+    /// Done once, rather than per packet sent to the dataplane, so `build_outputs` can look up a
+    /// field's id without re-scanning the P4Info.
+    fn packet_meta_field_to_id(p4info: &proto::p4info::P4Info) -> HashMap<String, u32> {
+        let mut packet_meta_field_to_id = HashMap::new();
+        for cm in p4info.get_controller_packet_metadata().iter() {
+            if cm.get_preamble().get_name().eq("packet_out") {
+                for m in cm.get_metadata().iter() {
+                    packet_meta_field_to_id.insert(
+                        m.get_name().to_string(),
+                        m.get_id()
+                    );
+                }
+            }
+        }
+        packet_meta_field_to_id
+    }
+
+    /// Returns a channel reporting this controller's current primary/backup role for the switch,
+    /// and every later transition between them.
+    pub fn watch_mastership(&self) -> watch::Receiver<Role> {
+        self.mastership.watch()
+    }
+
+    /// Requests promotion to primary for this switch.
+    ///
+    /// Bumps this controller's election id above the highest one the switch has reported so far,
+    /// and resends a `MasterArbitrationUpdate` with it. The switch grants primary to whichever
+    /// live controller holds the highest election id, so this only requests promotion -- it
+    /// doesn't guarantee it, e.g. if another controller bids higher in the meantime. Call
+    /// `self.mastership.watch()` (via `Controller::watch_mastership`) to observe the outcome.
+    pub async fn promote(&mut self) -> Result<(), p4ext::P4Error> {
+        let election_id = self.mastership.promote().await;
+
+        let mut upd = MasterArbitrationUpdate::new();
+        upd.set_device_id(self.device_id);
+        upd.set_election_id(u128_to_uint128(election_id));
+
+        let mut req = StreamMessageRequest::new();
+        req.set_arbitration(upd);
+
+        self.packet_sink.lock().await.0.send((req, grpcio::WriteFlags::default())).await
+            .map_err(|e| p4ext::P4Error{message: format!("{}: failed to send promotion request ({})", self.target, e)})
+    }
+
+    /// Live-upgrades the pipeline this `SwitchClient` drives to the P4 program at `p4info`/`json`,
+    /// tagged with `cookie`, without the cold controller restart `SwitchClient::new` requires.
+    ///
+    /// Reads the pipeline currently installed on the device and diffs its P4Info against the new
+    /// one. If the two programs are equivalent (same tables, actions, digests, and controller
+    /// packet metadata), this only refreshes `packet_meta_field_to_id` from the new P4Info --
+    /// e.g. because only the cookie changed -- and returns without touching the switch. Otherwise
+    /// it pushes the new config with `VERIFY_AND_COMMIT`, which blocks until the switch confirms
+    /// it, then re-derives `packet_meta_field_to_id` and drops the cached `Switch` so the next
+    /// `push_outputs` rebuilds it from the newly installed P4Info.
+    ///
+    /// # Arguments
+    /// * `p4info` - filepath for the new P4Info binary file.
+    /// * `json` - filepath for the new compiled P4 program's JSON representation.
+    /// * `cookie` - opaque cookie identifying the new pipeline configuration.
+    pub async fn reconfigure_pipeline(
+        &mut self,
+        p4info: String,
+        json: String,
+        cookie: String,
+    ) -> Result<(), p4ext::P4Error> {
+        let new_p4info_struct = Self::read_p4info(&p4info);
+
+        let installed = p4ext::get_pipeline_config(self.device_id, &self.target, &self.client.0);
+        if !p4ext::pipeline_differs(installed.get_p4info(), &new_p4info_struct) {
+            debug!("{}: new pipeline program is unchanged; refreshing metadata cache only", self.target);
+            self.packet_meta_field_to_id = Self::packet_meta_field_to_id(&new_p4info_struct);
+            self.p4info = p4info;
+            self.json = json;
+            self.cookie = cookie;
+            return Ok(());
+        }
+
+        debug!("{}: pipeline program changed; reconfiguring", self.target);
+        p4ext::set_pipeline_config(
+            &p4info,
+            &json,
+            &cookie,
+            "verify-and-commit",
+            self.device_id,
+            self.role_id,
+            &self.target,
+            &self.client.0,
+        );
+
+        self.packet_meta_field_to_id = Self::packet_meta_field_to_id(&new_p4info_struct);
+        self.capabilities.digests = !new_p4info_struct.get_digests().is_empty();
+        self.capabilities.packet_out = !self.packet_meta_field_to_id.is_empty();
+        *self.cached_pipeline.0.write().await = None;
+
+        self.p4info = p4info;
+        self.json = json;
+        self.cookie = cookie;
+
+        Ok(())
+    }
+
     /// Configure the digest notification level on the switch.
     ///
     /// The `DigestEntry` configuration is described [here](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-digestentry).
@@ -352,6 +868,11 @@ impl SwitchClient {
         max_list_size: i32,
         ack_timeout_ns: i64,
     ) -> Result<(), p4ext::P4Error> {
+        if !self.capabilities.digests {
+            warn!("{}: target's P4 program declares no digests; skipping digest configuration", self.target);
+            return Ok(());
+        }
+
         // Read P4Info from file.
         let p4info_str: &str = &self.p4info;
         let mut p4info_file = File::open(OsStr::new(p4info_str))
@@ -372,13 +893,7 @@ impl SwitchClient {
             );
         }
 
-        let digest_res = p4ext::write(
-            digest_updates,
-            self.device_id,
-            self.role_id,
-            &self.target,
-            &self.client.0
-        );
+        let digest_res = self.guarded_write(digest_updates).await;
 
         if digest_res.is_err() {
             let e = digest_res.err().unwrap(); // safe because of `is_err` check
@@ -395,11 +910,65 @@ impl SwitchClient {
     /// * `delta` - DDlog output relations.
     #[instrument]
     pub async fn push_outputs(&mut self, delta: &DeltaMap<DDValue>) -> Result<(), p4ext::P4Error> {
+        let (updates, packet_outs) = self.build_outputs(delta).await;
+        self.send_outputs(updates, packet_outs).await
+    }
+
+    /// Returns the compiled `Switch` for the pipeline currently installed on the device.
+    ///
+    /// Rebuilds it from P4Info -- parsing the whole thing and reconstructing the table/action
+    /// maps -- only when the installed pipeline's cookie differs from the one the cached `Switch`
+    /// was built from, or there is no cached `Switch` yet. The read-lock fast path returns the
+    /// cached value without re-parsing; the write-lock slow path populates it on a miss, so
+    /// concurrent callers don't each redo the O(tables) conversion.
+    async fn cached_switch(&self) -> Arc<p4ext::Switch> {
+        let pipeline = p4ext::get_pipeline_config(self.device_id, &self.target, &self.client.0);
+        let cookie = pipeline.get_cookie().get_cookie();
+
+        if let Some((cached_cookie, switch)) = self.cached_pipeline.0.read().await.as_ref() {
+            if *cached_cookie == cookie {
+                return switch.clone();
+            }
+        }
+
+        let mut cached = self.cached_pipeline.0.write().await;
+        if let Some((cached_cookie, switch)) = cached.as_ref() {
+            if *cached_cookie == cookie {
+                return switch.clone();
+            }
+        }
+
+        let switch: Arc<p4ext::Switch> = Arc::new(pipeline.get_p4info().into());
+        *cached = Some((cookie, switch.clone()));
+        switch
+    }
+
+    /// Translate `delta`'s output relations into the P4 Runtime table-entry and multicast-group
+    /// updates and packet-outs that [`Self::push_outputs`] would send to the switch, without
+    /// sending them.
+    ///
+    /// Every table-entry and multicast update from one `delta` is returned in a single `Vec`, so
+    /// `send_outputs` can write the whole transaction as one `WriteRequest` -- a DDlog delta that
+    /// touches N entries shouldn't become N separate gRPC writes that can partially fail.
+    ///
+    /// Split out from `push_outputs` so a caller that needs to durably log a batch before it's
+    /// written -- see `ControllerActor`'s write-ahead log -- can do so without re-deriving it.
+    ///
+    /// # Arguments
+    /// * `delta` - DDlog output relations.
+    async fn build_outputs(
+        &mut self,
+        delta: &DeltaMap<DDValue>,
+    ) -> (Vec<proto::p4runtime::Update>, Vec<proto::p4runtime::PacketOut>) {
         let mut updates = Vec::new();
         let mut packet_outs = Vec::new();
 
-        let pipeline = p4ext::get_pipeline_config(self.device_id, &self.target, &self.client.0);
-        let switch: p4ext::Switch = pipeline.get_p4info().into();
+        // Start this batch with no pending rollback; `update_multicast` and the `known_entries`
+        // bookkeeping below fill these back in as they eagerly commit what they touch.
+        self.multicast_rollback.clear();
+        self.known_entries_rollback.clear();
+
+        let switch = self.cached_switch().await;
 
         for (_, output_map) in (*delta).clone().into_iter() {
             for (value, weight) in output_map {
@@ -407,17 +976,34 @@ impl SwitchClient {
                 
                 match record {
                     Record::NamedStruct(output_name, output_records) => {
+                        // A program driving a fleet of switches tags each output relation with a
+                        // "device_id" field naming which one it's for; skip records meant for a
+                        // different device. A relation with no such field is assumed to apply to
+                        // every device, for backward compatibility with single-device programs.
+                        if let Some(record_device_id) = Self::record_device_id(&output_records) {
+                            if record_device_id != self.device_id {
+                                continue;
+                            }
+                        }
+
                         // Check if the record corresponds to the multicast group.
                         // We assume that there a relevant DDlog relation's name includes "multicast".
                         // A DDlog relation that does not update multicast should not include "multicast" in its name.
                         if output_name.as_ref().to_lowercase().contains("multicast") {
-                            self.update_multicast(output_records.clone(), weight).await;
+                            if let Some(update) = self.update_multicast(output_records.clone(), weight).await {
+                                updates.push(update);
+                            }
                         }
 
                         // Check for output relations that contain packets as Records.
                         // Convert those packets to byte-vectors, and add them to the packet queue.
                         // This queue is sent after updates are pushed to the switch.
-                        if output_name.as_ref().to_lowercase().contains("packet") {
+                        if output_name.as_ref().to_lowercase().contains("packet") && !self.capabilities.packet_out {
+                            warn!(
+                                "{}: target declares no packet_out controller metadata; dropping packet output from {}",
+                                self.target, output_name,
+                            );
+                        } else if output_name.as_ref().to_lowercase().contains("packet") {
                             // The output record corresponding to a packet should be a Record::Array.
                             // Any other output records correspond to fields in the 'packet_out' header.
                             // These are stored as PacketMetadata.
@@ -516,9 +1102,26 @@ impl SwitchClient {
                         }
 
                         // If we found a table and action, construct a P4 table entry update.
+                        // A positive weight means the row was inserted or kept by DDlog: INSERT
+                        // if we haven't pushed this key to the switch before, MODIFY if we have.
+                        // A negative weight means DDlog retracted the row, so DELETE it and
+                        // forget it, so a later re-insertion of the same key is seen as an INSERT
+                        // again rather than a MODIFY.
                         if let Some(table_action) = action_opt {
+                            let key = (table_id, format!("{:?}", field_match_vec));
+                            let existed_before = self.known_entries.contains(&key);
+                            let update_type = if weight < 0 {
+                                self.known_entries.remove(&key);
+                                proto::p4runtime::Update_Type::DELETE
+                            } else if self.known_entries.insert(key.clone()) {
+                                proto::p4runtime::Update_Type::INSERT
+                            } else {
+                                proto::p4runtime::Update_Type::MODIFY
+                            };
+                            self.known_entries_rollback.push((key, existed_before));
+
                             let update = p4ext::build_table_entry_update(
-                                proto::p4runtime::Update_Type::INSERT,
+                                update_type,
                                 table_id,
                                 table_action,
                                 field_match_vec,
@@ -535,28 +1138,69 @@ impl SwitchClient {
             }
         }
 
-        let write_res = p4ext::write(
-            updates,
-            self.device_id,
-            self.role_id,
-            &self.target,
-            &self.client.0,
-        );
+        (updates, packet_outs)
+    }
+
+    /// Write a batch of table-entry and multicast-group updates built by [`Self::build_outputs`]
+    /// to the switch in a single `WriteRequest`, along with any packet-outs found in the same
+    /// output relations.
+    ///
+    /// # Arguments
+    /// * `updates` - table-entry and multicast-group updates to write as one transaction.
+    /// * `packet_outs` - packets to send over the stream channel.
+    async fn send_outputs(
+        &mut self,
+        updates: Vec<proto::p4runtime::Update>,
+        packet_outs: Vec<proto::p4runtime::PacketOut>,
+    ) -> Result<(), p4ext::P4Error> {
+        let write_res = self.guarded_write(updates).await;
         if write_res.is_err() {
             error!("could not write updates to P4 Runtime: {:#?}",  write_res.as_ref().err());
+
+            // The write never reached the switch, so undo the eager `multicast_state` updates
+            // `update_multicast` made while building this batch -- otherwise the in-memory mirror
+            // would permanently diverge from the switch's actual (unwritten) multicast groups.
+            // Restore in reverse order, so a group touched more than once in this batch ends up
+            // back at its state from before the *first* touch, not an intermediate one.
+            let mut multicast_state = self.multicast_state.lock().await;
+            for (mcast_id, previous_replicas) in self.multicast_rollback.drain(..).rev() {
+                multicast_state.set_replicas(mcast_id, previous_replicas);
+            }
+            drop(multicast_state);
+
+            // Same rollback for `known_entries`: otherwise a key that was never actually written
+            // would be left "known", so the next delta touching it emits MODIFY instead of
+            // INSERT, which the switch rejects for a key it's never seen, permanently wedging it.
+            for (key, existed_before) in self.known_entries_rollback.drain(..).rev() {
+                if existed_before {
+                    self.known_entries.insert(key);
+                } else {
+                    self.known_entries.remove(&key);
+                }
+            }
+
             return write_res;
         }
+        self.multicast_rollback.clear();
+        self.known_entries_rollback.clear();
 
-        // Send packets found in output relations to the switch.
+        // Send packets found in output relations to the switch, unless this controller is backup
+        // and the switch would reject them anyway. Unlike table-entry updates, a parked
+        // packet-out would just be stale by the time this controller is promoted, so it's dropped
+        // rather than queued.
         if !packet_outs.is_empty() {
-            // Send packets to the switch.
-            for packet_out in packet_outs {
-                let mut req = StreamMessageRequest::new();
-                req.set_packet(packet_out);
-
-                let req_res = self.packet_sink.0.send((req, grpcio::WriteFlags::default())).await;
-                if req_res.is_err() {
-                    error!("failed to send request over stream channel: {:#?}", req_res.err());
+            if self.mastership.role() != Role::Primary {
+                warn!("{}: backup controller; dropping {} packet-out(s)", self.target, packet_outs.len());
+            } else {
+                let mut packet_sink = self.packet_sink.lock().await;
+                for packet_out in packet_outs {
+                    let mut req = StreamMessageRequest::new();
+                    req.set_packet(packet_out);
+
+                    let req_res = packet_sink.0.send((req, grpcio::WriteFlags::default())).await;
+                    if req_res.is_err() {
+                        error!("failed to send request over stream channel: {:#?}", req_res.err());
+                    }
                 }
             }
         }
@@ -564,7 +1208,63 @@ impl SwitchClient {
         Ok(())
     }
 
-    /// Update the multicast group entry using P4 Runtime.
+    /// Writes `updates` via `p4ext::write` if this controller currently holds primary for the
+    /// switch, else parks them in `self.parked_writes` to flush once `watch_promotion` sees it
+    /// win mastership. Centralizes the mastership check so no write path -- `send_outputs`,
+    /// `configure_digests` -- can slip a write past a switch that would reject it from a backup
+    /// controller.
+    ///
+    /// # Arguments
+    /// * `updates` - table-entry, digest-entry, or multicast-group updates to write.
+    async fn guarded_write(&self, updates: Vec<proto::p4runtime::Update>) -> Result<(), p4ext::P4Error> {
+        if self.mastership.role() != Role::Primary {
+            warn!("{}: backup controller; parking {} update(s) until promoted to primary", self.target, updates.len());
+            self.parked_writes.lock().await.push(updates);
+            return Ok(());
+        }
+
+        p4ext::write(updates, self.device_id, self.role_id, &self.target, &self.client.0)
+    }
+
+    /// Resend a batch of updates that were already built by a previous run of
+    /// [`Self::build_outputs`] -- e.g. to replay a write-ahead log record left over from a crash.
+    ///
+    /// Re-learns each update's `known_entries` key before sending, so a later live
+    /// [`Self::push_outputs`] recognizes the key and sends MODIFY instead of re-inserting it.
+    /// The updates are sent exactly as they were built, so the switch sees the same
+    /// INSERT/MODIFY/DELETE it saw the first time; their update types are not re-derived against
+    /// the switch's current table state.
+    ///
+    /// # Arguments
+    /// * `updates` - previously built table-entry updates to resend.
+    pub async fn replay_updates(&mut self, updates: Vec<proto::p4runtime::Update>) -> Result<(), p4ext::P4Error> {
+        for update in &updates {
+            self.learn_known_entry(update);
+        }
+        self.send_outputs(updates, Vec::new()).await
+    }
+
+    /// Record `update`'s key in `known_entries`, as a live push would after building it, so a
+    /// later push recognizes the key instead of re-inserting it.
+    ///
+    /// # Arguments
+    /// * `update` - a previously built table-entry update.
+    fn learn_known_entry(&mut self, update: &proto::p4runtime::Update) {
+        if update.get_field_type() == proto::p4runtime::Update_Type::DELETE {
+            return;
+        }
+
+        let table_entry = update.get_entity().get_table_entry();
+        let key = (table_entry.get_table_id(), format!("{:?}", table_entry.get_field_match().to_vec()));
+        self.known_entries.insert(key);
+    }
+
+    /// Build the P4 Runtime update for a multicast-group output relation, to be batched into the
+    /// same write as the table-entry updates built alongside it in [`Self::build_outputs`].
+    ///
+    /// Updates the in-memory multicast-state cache eagerly, before the batch is actually written --
+    /// the same way `known_entries` is updated eagerly for table entries just below -- so a second
+    /// DDlog delta touching the same group in the same transaction sees the pending change.
     ///
     /// # Arguments
     /// * `recs` - Vector of tuples of (Name, Record). The second element in a NamedStruct.
@@ -578,10 +1278,10 @@ impl SwitchClient {
         &mut self,
         recs: Vec<(Cow<'static, str>, Record)>,
         weight: isize,
-    ) {
+    ) -> Option<proto::p4runtime::Update> {
         if recs.len() != 2 {
             error!("multicast relation should include exactly 2 fields!");
-            return;
+            return None;
         }
 
         // P4 Runtime requires multicast ID greater than 0 for a valid write,
@@ -604,89 +1304,64 @@ impl SwitchClient {
 
         if mcast_id == 0 {
             error!("multicast relation does not contain an 'id' field");
-            return;
+            return None;
         }
 
         if mcast_port == u32::MAX {
             error!("multicast relation does not contain a 'port' field");
-            return;
+            return None;
         }
 
-        // We read all current multicast entities using group id 0.
-        // We then find the replicas for the desired multicast group.
-        // Since this search is wild-carded, we can safely unwrap the result.
-        let mcast_entries = p4ext::read(
-            vec![p4ext::build_multicast_read(0)],
-            self.device_id,
-            &self.client.0,
-        ).await.unwrap();
-
-        // We find the replicas for the current multicast group.
-        let mut replicas = Vec::new();
-        for mcast_ent in mcast_entries.iter() {
-            let mge = mcast_ent
-                .get_packet_replication_engine_entry()
-                .get_multicast_group_entry();
-            if mge.get_multicast_group_id() == mcast_id {
-                replicas = mge.get_replicas().to_vec();
-            }
-        }
+        // Diff against the in-memory mirror of the switch's multicast groups instead of reading
+        // the device -- this is the only per-update cost, in contrast to the wildcarded read this
+        // replaced.
+        let mut replicas = self.multicast_state.lock().await.replicas(mcast_id);
+        let previous_replicas = replicas.clone();
 
-        // No replicas means this is a new multicast group.
-        // In this case, the update type is an INSERT.
-        // Else, it is a MODIFY.
-        let mcast_update_type = if replicas.is_empty() {
-            proto::p4runtime::Update_Type::INSERT
-        } else {
-            proto::p4runtime::Update_Type::MODIFY
-        };
+        // No replicas means this is a new multicast group, so the update type is an INSERT.
+        let group_existed = !replicas.is_empty();
 
         // A non-negative weight means we insert this port in the multicast group.
         // Else, we delete this port from the multicast group.
         if weight >= 0 {
+            if replicas.iter().any(|r| r.get_egress_port() == mcast_port) {
+                return None;
+            }
+
             let mut new_replica = proto::p4runtime::Replica::new();
             new_replica.set_egress_port(mcast_port);
 
-            let new_replica_instance: u32 = replicas.len() as u32 + 1;
-            new_replica.set_instance(new_replica_instance);
+            // Assign the next unused instance rather than renumbering the existing replicas, so
+            // adding or removing a port never has to rewrite every other replica's instance.
+            let next_instance = replicas.iter().map(|r| r.get_instance()).max().unwrap_or(0) + 1;
+            new_replica.set_instance(next_instance);
 
             replicas.push(new_replica);
         } else {
-            // Sort the replicas in increasing order of instance.
-            replicas.sort_by(|a, b| a.instance.cmp(&b.instance));
-
-            // Adjust the instance for replicas with different port.
-            // This avoids gaps in the ordering of replicas.
-            let mut num_deleted = 0;
-            for r in replicas.iter_mut() {
-                if r.egress_port == mcast_port {
-                    num_deleted += 1;
-                } else {
-                    r.instance -= num_deleted;
-                }
-            }
-
-            // Remove replicas with matching port.
-            replicas.retain(|r| r.egress_port != mcast_port);
+            // Remove replicas with matching port, leaving every other replica's instance as-is.
+            replicas.retain(|r| r.get_egress_port() != mcast_port);
         }
 
-        // Push the multicast update to the switch.
+        // Removing the last port should DELETE the group rather than MODIFY it down to an empty
+        // replica list: an empty multicast group is stale state the switch has no reason to keep
+        // around, and some targets reject (or silently ignore) a MODIFY with no replicas.
+        let mcast_update_type = if !group_existed {
+            proto::p4runtime::Update_Type::INSERT
+        } else if replicas.is_empty() {
+            proto::p4runtime::Update_Type::DELETE
+        } else {
+            proto::p4runtime::Update_Type::MODIFY
+        };
+
         let mcast_update = p4ext::build_multicast_write(
             mcast_update_type,
             mcast_id,
-            replicas,
+            replicas.clone(),
         );
 
-        let write_res = p4ext::write(
-            vec![mcast_update],
-            self.device_id,
-            self.role_id,
-            &self.target,
-            &self.client.0,
-        );
-        if write_res.is_err() {
-            error!("could not push multicast update to switch: {:#?}", write_res.err());
-        }
+        self.multicast_state.lock().await.set_replicas(mcast_id, replicas);
+        self.multicast_rollback.push((mcast_id, previous_replicas));
+        Some(mcast_update)
     }
 
     /// Convert a DDlog Record and P4Info Actions to a P4Runtime TableAction.
@@ -830,11 +1505,13 @@ impl SwitchClient {
                     let value = Self::record_to_bytestring(&t[0]);
                     ternary_match.set_value(value);
 
-                    let mask =Self::record_to_u128(&t[1]);
-                    if mask == 0 {
+                    // Arbitrary-precision, like `value` above, so a wide field's mask isn't
+                    // truncated to 128 bits.
+                    let mask = Self::record_to_bytestring(&t[1]);
+                    if mask.iter().all(|&b| b == 0) {
                         return None
                     }
-                    ternary_match.set_mask(Self::u128_to_bytestring(mask));
+                    ternary_match.set_mask(mask);
                 } else {
                     error!("Record for a Field Match of type Ternary must be a Tuple");
                     return None;
@@ -900,8 +1577,22 @@ impl SwitchClient {
         Some(field_match)
     }
 
-    /// Extracts and returns a numerical value from a DDlog record.  Only properly supports numeric
-    /// types (like boolean and integer), and returns 0 for everything else.
+    /// Returns the P4 device id an output relation's fields declare, by looking for a field whose
+    /// name contains "device" (not case-sensitive), or `None` if it doesn't declare one.
+    ///
+    /// # Arguments
+    /// * `output_records` - the fields of a `Record::NamedStruct` output relation.
+    fn record_device_id(output_records: &[(Cow<'static, str>, Record)]) -> Option<u64> {
+        output_records.iter()
+            .find(|(name, _)| name.as_ref().to_lowercase().contains("device"))
+            .map(|(_, record)| Self::record_to_u128(record) as u64)
+    }
+
+    /// Extracts and returns a numerical value from a DDlog record, for fields that are always
+    /// narrow enough to fit a `u128` -- multicast ids/ports, match priorities, LPM prefix
+    /// lengths. Only properly supports numeric types (like boolean and integer), and returns 0
+    /// for everything else. Match and action *values* should go through
+    /// [`Self::record_to_bytestring`] instead, which doesn't cap width at 128 bits.
     ///
     /// # Arguments
     /// * `r` - the record to convert.
@@ -918,37 +1609,33 @@ impl SwitchClient {
         0
     }
 
-    /// Converts a `u128` into a bytestring as specified in P4Runtime 1.3.0 section 8.4
-    /// "Bytestrings".  This representation uses the minimum number of bytes to represent a given
-    /// number in big-endian order.  (As an exception to the minimum-length rule, zero is
-    /// represented by a single 0-byte).
-    ///
-    /// # Arguments
-    /// * `r` - the value to convert.
-    fn u128_to_bytestring(mut value: u128) -> Vec<u8> {
-        let mut v: Vec<u8> = Vec::new();
-        loop {
-            v.push((value & 0xff) as u8);
-            value >>= 8;
-            if value == 0 {
-                v.reverse();
-                return v
-            }
-        }
-    }
-
     /// Convert a DDlog record's value into a bytestring as specified in P4Runtime 1.3.0 section
     /// 8.4 "Bytestrings".  This representation uses the minimum number of bytes to represent a
     /// given number in big-endian order.  (As an exception to the minimum-length rule, zero is
     /// represented by a single 0-byte).
     ///
-    /// Only supports numeric types (like boolean and integer).
+    /// Unlike [`Self::record_to_u128`], walks a `Record::Int`'s big-integer magnitude directly
+    /// rather than going through a 128-bit intermediate, so P4 fields wider than 128 bits encode
+    /// correctly instead of being truncated. A `Record::String` is taken as a raw byte value
+    /// rather than a number, for opaque byte-array/string match and action-parameter keys.
+    ///
     /// This returns an empty byte vector for an unsupported type.
     ///
     /// # Arguments
     /// * `r` - the record to convert.
     fn record_to_bytestring(r: &Record) -> Vec<u8> {
-        Self::u128_to_bytestring(Self::record_to_u128(r))
+        match r {
+            Record::Bool(b) => vec![if *b { 1 } else { 0 }],
+            Record::Int(i) => {
+                let (_, bytes) = i.to_bytes_be();
+                if bytes.is_empty() { vec![0] } else { bytes }
+            },
+            Record::String(s) => s.as_bytes().to_vec(),
+            _ => {
+                error!("attempted to extract bytestring from unsupported record type: {:#?}", r);
+                Vec::new()
+            },
+        }
     }
 
     /// Retrieve a P4 table with the provided name.
@@ -972,14 +1659,21 @@ impl SwitchClient {
     }
 }
 
-/// Processes DDlog input relations and pushes them to the P4 switch.
+/// Processes DDlog input relations and pushes them to a fleet of P4 switches.
 struct ControllerActor {
     /// Receives messages from the public-facing handle.
     receiver: mpsc::Receiver<ControllerActorMessage>,
-    /// Client for the P4-enabled switch.
-    switch_client: SwitchClient,
+    /// Client for each P4-enabled switch this controller drives, keyed by `device_id`.
+    switch_clients: HashMap<u64, SwitchClient>,
     /// Handle to the running DDlog program.
     program: ControllerProgram,
+    /// Write-ahead log of batches pushed to the switches, if the controller was created with
+    /// [`Controller::restore_from_log`]. `None` means the controller isn't durably logging, as
+    /// created by [`Controller::new`].
+    wal: Option<Arc<Mutex<WriteAheadLog>>>,
+    /// Notified with a batch's sequence number once every device's share of it is confirmed, so
+    /// the background compaction task spawned by `restore_from_log` can drop it from the log.
+    compaction_tx: Option<mpsc::Sender<u64>>,
 }
 
 /// Message from the controller actor.
@@ -990,29 +1684,51 @@ enum ControllerActorMessage {
         _respond_to: oneshot::Sender<DeltaMap<DDValue>>,
         /// Running DDlog program.
         hddlog: Arc<HDDlog>,
-        /// Filepath to OVSDB server.
-        server: String,
-        /// Name of OVS database.
-        database: String,
+        /// (server, database) pair for every OVSDB this controller should read inputs from, e.g.
+        /// one per switch in a multi-switch fabric. All of them feed the same DDlog program.
+        ovsdb_endpoints: Vec<(String, String)>,
+    },
+    /// Requests promotion to primary for one switch this controller drives.
+    Promote {
+        /// ID of the device to request promotion for.
+        device_id: u64,
+        /// Channel used to report whether the promotion request was sent successfully.
+        respond_to: oneshot::Sender<Result<(), String>>,
+    },
+    /// Requests a channel reporting one switch's current and future primary/backup role.
+    WatchMastership {
+        /// ID of the device to watch mastership for.
+        device_id: u64,
+        /// Channel used to deliver the mastership-watch channel.
+        respond_to: oneshot::Sender<Result<watch::Receiver<Role>, String>>,
     },
 }
 
 impl ControllerActor {
-    /// Create a new actor that processes DDlog inputs and pushes them to the P4 switch.
+    /// Create a new actor that processes DDlog inputs and pushes them to the P4 switches.
     ///
     /// # Arguments
     /// * `receiver` - receives messages from the public controller handle.
-    /// * `switch_client` - client for the P4 switch.
+    /// * `switch_clients` - client for each P4 switch this controller drives, keyed by
+    ///   `device_id`.
     /// * `program` - handle for the DDlog program.
+    /// * `wal` - write-ahead log to append batches to before pushing them, if the controller was
+    ///   created with [`Controller::restore_from_log`].
+    /// * `compaction_tx` - notified with a batch's sequence number once every device's share of it
+    ///   is confirmed, so the background compaction task can drop it from `wal`.
     fn new(
         receiver: mpsc::Receiver<ControllerActorMessage>,
-        switch_client: SwitchClient,
+        switch_clients: HashMap<u64, SwitchClient>,
         program: ControllerProgram,
+        wal: Option<Arc<Mutex<WriteAheadLog>>>,
+        compaction_tx: Option<mpsc::Sender<u64>>,
     ) -> Self {
         ControllerActor {
             receiver,
-            switch_client,
+            switch_clients,
             program,
+            wal,
+            compaction_tx,
         }
     }
 
@@ -1027,40 +1743,61 @@ impl ControllerActor {
     /// 
     /// # Arguments
     /// * `msg` - message from the public controller actor.
-    async fn handle_message(&mut self, msg: ControllerActorMessage) {        
+    async fn handle_message(&mut self, msg: ControllerActorMessage) {
         match msg {
-            ControllerActorMessage::InputMessage {_respond_to, hddlog, server, database} => {
+            ControllerActorMessage::InputMessage {_respond_to, hddlog, ovsdb_endpoints} => {
                 let (digest_tx, mut rx) = mpsc::channel::<Option<Update<DDValue>>>(1);
-                let ovsdb_tx = mpsc::Sender::clone(&digest_tx);
-
-                // Start streaming messages from the dataplane.
-                // Set the configuration as a notification per-digest.
-                // TODO: Retry the configuration if it errors.
-                let config_res = self.switch_client.configure_digests(0, 1, 1).await;
-                if config_res.is_err() {
-                    error!("could not configure digests: {:#?}", config_res);
+
+                // Start one dataplane response actor per device, each sharing that device's
+                // `SwitchClient::mastership` so its arbitration responses update the same
+                // primary/backup state `SwitchClient::guarded_write` already checks.
+                for switch_client in self.switch_clients.values_mut() {
+                    // Set the digest notification configuration per-device.
+                    // TODO: Retry the configuration if it errors.
+                    let config_res = switch_client.configure_digests(0, 1, 1).await;
+                    if config_res.is_err() {
+                        error!("could not configure digests for device {}: {:#?}", switch_client.device_id, config_res);
+                    }
+
+                    let (sink, receiver) = switch_client.client.0.stream_channel().unwrap();
+                    let mut digest_actor = DataplaneResponseActor::new(
+                        sink,
+                        receiver,
+                        digest_tx.clone(),
+                        switch_client.device_id,
+                        switch_client.mastership.clone(),
+                    );
+                    tokio::spawn(async move { digest_actor.run().await });
                 }
 
-                // Start the dataplane response actor.
-                let (sink, receiver) = self.switch_client.client.0.stream_channel().unwrap();
-                let mut digest_actor = DataplaneResponseActor::new(sink, receiver, digest_tx);
-                tokio::spawn(async move { digest_actor.run().await });
-
-                // Start processing inputs from OVSDB.
-                let ctx = ovsdb_client::context::OvsdbContext::new(
-                    hddlog,
-                    DeltaMap::<DDValue>::new(),
-                    database.clone(),
-                );
-
-                tokio::spawn(async move {
-                    ovsdb_client::process_ovsdb_inputs(
-                        ctx,
-                        server,
-                        database,
-                        ovsdb_tx,
-                    ).await
-                });
+                // Start processing inputs from OVSDB: one task per (server, database) endpoint --
+                // ordinarily just one, but a fabric manifest may name a separate OVSDB per switch
+                // -- all reporting into the same `digest_tx`, so that no matter how many databases
+                // feed it, the shared `hddlog` sees one unified input stream.
+                for (server, database) in ovsdb_endpoints {
+                    let ctx = ovsdb_client::context::OvsdbContext::new(
+                        hddlog.clone(),
+                        DeltaMap::<DDValue>::new(),
+                        database.clone(),
+                    );
+                    let ovsdb_tx = digest_tx.clone();
+
+                    // The controller doesn't yet push DDlog output relations back into OVSDB; keep
+                    // the sending half alive so the receiver isn't immediately closed.
+                    let (output_tx, output_rx) = mpsc::channel::<Vec<Update<DDValue>>>(1);
+
+                    tokio::spawn(async move {
+                        let _output_tx = output_tx;
+                        ovsdb_client::process_ovsdb_inputs(
+                            ctx,
+                            server,
+                            database,
+                            ovsdb_tx,
+                            None,
+                            output_rx,
+                        ).await
+                    });
+                }
 
                 // Process each input.
                 while let Some(inp_opt) = rx.recv().await {
@@ -1069,16 +1806,69 @@ impl ControllerActor {
                     }
 
                     let ddlog_res = self.program.apply_updates(vec![inp_opt.unwrap()]);
-                    if ddlog_res.is_ok() {
-                        let p4_res = self.switch_client.push_outputs(&ddlog_res.unwrap()).await;
+                    let (transaction_id, delta) = match ddlog_res {
+                        Ok(result) => result,
+                        Err(e) => {
+                            error!("could not apply changes to ddlog input relation: {:#?}", e);
+                            continue;
+                        },
+                    };
+
+                    // Build and push each device's share of the delta separately --
+                    // `SwitchClient::build_outputs` filters out records tagged for a different
+                    // device -- and only enqueue the batch for compaction once every device that
+                    // had output confirmed its write, so a crash can't lose a batch one device
+                    // never received.
+                    let mut last_sequence = None;
+                    let mut all_confirmed = true;
+                    for switch_client in self.switch_clients.values_mut() {
+                        let (updates, packet_outs) = switch_client.build_outputs(&delta).await;
+                        if updates.is_empty() && packet_outs.is_empty() {
+                            continue;
+                        }
+
+                        // Durably log the batch before sending it, so a crash between the two is
+                        // recoverable by replaying the log on the next `restore_from_log`.
+                        let sequence = match &self.wal {
+                            Some(wal) => match wal.lock().await.append(transaction_id, switch_client.device_id, &updates) {
+                                Ok(sequence) => Some(sequence),
+                                Err(e) => {
+                                    error!("failed to append to write-ahead log: {:#?}", e);
+                                    None
+                                },
+                            },
+                            None => None,
+                        };
+
+                        let p4_res = switch_client.send_outputs(updates, packet_outs).await;
                         if p4_res.is_err() {
-                            error!("could not push digest output relation to switch: {:#?}", p4_res.err());
+                            error!("could not push digest output relation to device {}: {:#?}", switch_client.device_id, p4_res.err());
+                            all_confirmed = false;
+                        } else if let Some(sequence) = sequence {
+                            last_sequence = Some(sequence);
+                        }
+                    }
+
+                    if all_confirmed {
+                        if let (Some(sequence), Some(compaction_tx)) = (last_sequence, &self.compaction_tx) {
+                            let _ = compaction_tx.send(sequence).await;
                         }
-                    } else {
-                        error!("could not apply changes to ddlog input relation: {:#?}", ddlog_res.err());
                     }
                 };
             },
+            ControllerActorMessage::Promote { device_id, respond_to } => {
+                let result = match self.switch_clients.get_mut(&device_id) {
+                    Some(switch_client) => switch_client.promote().await.map_err(|e| format!("{:#?}", e)),
+                    None => Err(format!("no switch client for device {}", device_id)),
+                };
+                let _ = respond_to.send(result);
+            },
+            ControllerActorMessage::WatchMastership { device_id, respond_to } => {
+                let result = self.switch_clients.get(&device_id)
+                    .map(|switch_client| switch_client.watch_mastership())
+                    .ok_or_else(|| format!("no switch client for device {}", device_id));
+                let _ = respond_to.send(result);
+            },
         }
     }
 }
@@ -1090,7 +1880,14 @@ struct DataplaneResponseActor {
     /// Receives messages from the data plane.
     receiver: ClientDuplexReceiver<StreamMessageResponse>,
     /// Sends DDlog updates to the controller actor.
-    to_controller: mpsc::Sender<Option<Update<DDValue>>>
+    to_controller: mpsc::Sender<Option<Update<DDValue>>>,
+    /// ID of the P4 device this stream is arbitrating for, rather than the hardcoded 0 this actor
+    /// used to send regardless of which device the controller was actually configured for.
+    device_id: u64,
+    /// Shared with `SwitchClient`, so the election id this stream bids and the primary/backup
+    /// role it observes agree with the rest of the controller rather than tracking their own,
+    /// inconsistent view of mastership.
+    mastership: Arc<Mastership>,
 }
 
 impl DataplaneResponseActor {
@@ -1100,19 +1897,26 @@ impl DataplaneResponseActor {
     /// * `to_data_plane` - sends messages to the data plane.
     /// * `receiver` - receives messages from the data plane.
     /// * `to_controller` - sends DDlog updates to the controller actor.
+    /// * `device_id` - ID of the P4 device this stream arbitrates for.
+    /// * `mastership` - shared mastership tracker this stream's arbitration responses update.
     fn new(
         to_data_plane: StreamingCallSink<StreamMessageRequest>,
         receiver: ClientDuplexReceiver<StreamMessageResponse>,
-        to_controller: mpsc::Sender<Option<Update<DDValue>>>
+        to_controller: mpsc::Sender<Option<Update<DDValue>>>,
+        device_id: u64,
+        mastership: Arc<Mastership>,
     ) -> Self {
-        Self { to_data_plane, receiver, to_controller }
+        Self { to_data_plane, receiver, to_controller, device_id, mastership }
     }
 
-    /// Run the actor indefinitely. Handle each received message. 
+    /// Run the actor indefinitely. Handle each received message.
     async fn run(&mut self) {
-        // Send a master arbitration update. This lets the actor properly stream responses from the dataplane.
+        // Send a master arbitration update, bidding this controller's current election id, so
+        // this stream properly streams responses from the dataplane and its arbitration
+        // responses reflect this controller's real primary/backup status.
         let mut update = MasterArbitrationUpdate::new();
-        update.set_device_id(0);
+        update.set_device_id(self.device_id);
+        update.set_election_id(u128_to_uint128(self.mastership.election_id().await));
         let mut smr = StreamMessageRequest::new();
         smr.set_arbitration(update);
         let req_result = self.to_data_plane.send((smr, grpcio::WriteFlags::default())).await;
@@ -1145,8 +1949,8 @@ impl DataplaneResponseActor {
                 match p4_update_opt.unwrap() {
                     digest(d) => {
                         for data in d.get_data().iter() {
-                            let dd_update_opt = digest_to_ddlog(d.get_digest_id(), data);
-                            
+                            let dd_update_opt = digest_to_ddlog(d.get_digest_id(), data, self.device_id);
+
                             let channel_res = self.to_controller.send(dd_update_opt).await;
                             if channel_res.is_err() {
                                 error!("could not send response over channel: {:#?}", channel_res);
@@ -1154,7 +1958,7 @@ impl DataplaneResponseActor {
                         }
                     },
                     packet(p) => {
-                        let dd_update_opt = packet_in_to_ddlog(p);
+                        let dd_update_opt = packet_in_to_ddlog(p, self.device_id);
                         debug!("received packetin update: {:#?}", dd_update_opt);
 
                         let channel_res = self.to_controller.send(dd_update_opt).await;
@@ -1163,7 +1967,10 @@ impl DataplaneResponseActor {
                         }
                     }
                     error(e) => error!("received error from p4runtime streaming channel: {:#?}", e),
-                    // no action for arbitration, idle timeout, or other
+                    arbitration(update) => {
+                        self.mastership.observe(uint128_to_u128(update.get_election_id())).await;
+                    },
+                    // no action for idle timeout or other
                     m => debug!("received message from p4runtime streaming channel: {:#?}", m),
                 };
             }
@@ -0,0 +1,127 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Tracks a controller's primary/backup role for a switch, from the election ids carried in
+//! `MasterArbitrationUpdate` messages.
+//!
+//! The P4Runtime arbitration protocol grants primary to whichever live controller is bidding the
+//! highest election id; the switch echoes that id back to every controller on the stream channel
+//! so each can tell whether it currently holds primary. [`Mastership`] keeps the id this
+//! controller is bidding, the highest one observed from the switch, and the [`Role`] that implies,
+//! and exposes a `watch` channel so other tasks -- `SwitchClient`'s `watch_promotion`, callers of
+//! `Controller::watch_mastership` -- can react to a role change instead of polling for one.
+
+use tokio::sync::{watch, Mutex};
+
+/// Whether a controller currently holds primary for a switch.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// This controller is bidding the highest election id the switch has seen, so it may issue
+    /// writes and packet-outs.
+    Primary,
+    /// Another controller -- or no controller yet -- holds primary; this controller must not
+    /// write to the switch.
+    Backup,
+}
+
+/// This controller's election id, and the highest one the switch has reported, guarded together
+/// so a concurrent `observe` and `promote` can't race.
+#[derive(Debug)]
+struct State {
+    /// The election id this controller is currently bidding.
+    our_election_id: u128,
+    /// The highest election id the switch has reported granting primary to, ours or another
+    /// controller's.
+    highest_seen: u128,
+}
+
+/// Tracks this controller's primary/backup role for a single switch.
+#[derive(Debug)]
+pub struct Mastership {
+    state: Mutex<State>,
+    role_tx: watch::Sender<Role>,
+}
+
+impl Mastership {
+    /// Creates a tracker for a controller starting out bidding `initial_election_id`, e.g. the
+    /// low, backup-by-default id `SwitchClient::new` sends in its first `MasterArbitrationUpdate`.
+    pub fn new(initial_election_id: u128) -> Self {
+        let (role_tx, _) = watch::channel(Role::Backup);
+        Mastership {
+            state: Mutex::new(State {
+                our_election_id: initial_election_id,
+                highest_seen: initial_election_id,
+            }),
+            role_tx,
+        }
+    }
+
+    /// Returns the election id this controller is currently bidding, to resend in a
+    /// `MasterArbitrationUpdate`.
+    pub async fn election_id(&self) -> u128 {
+        self.state.lock().await.our_election_id
+    }
+
+    /// Returns this controller's current primary/backup role.
+    ///
+    /// Reads the `watch` channel's latest value rather than locking `state`, so callers on a
+    /// write path -- `SwitchClient::guarded_write` -- can check it without an `await`.
+    pub fn role(&self) -> Role {
+        *self.role_tx.borrow()
+    }
+
+    /// Returns a channel reporting this controller's current role, and every later transition
+    /// between primary and backup.
+    pub fn watch(&self) -> watch::Receiver<Role> {
+        self.role_tx.subscribe()
+    }
+
+    /// Records the election id the switch just reported holding primary, from a
+    /// `MasterArbitrationUpdate` response, updating this controller's role if that id is ours.
+    pub async fn observe(&self, elected_id: u128) {
+        let mut state = self.state.lock().await;
+        if elected_id > state.highest_seen {
+            state.highest_seen = elected_id;
+        }
+        let role = if elected_id == state.our_election_id { Role::Primary } else { Role::Backup };
+        drop(state);
+
+        self.role_tx.send_if_modified(|current| {
+            if *current == role {
+                false
+            } else {
+                *current = role;
+                true
+            }
+        });
+    }
+
+    /// Bumps this controller's election id above the highest the switch has reported so far, so
+    /// resending a `MasterArbitrationUpdate` with it bids for primary. Returns the new id to send.
+    ///
+    /// Doesn't update the role directly -- the switch's next arbitration response, observed
+    /// through [`Self::observe`], is what actually grants primary.
+    pub async fn promote(&self) -> u128 {
+        let mut state = self.state.lock().await;
+        state.our_election_id = state.highest_seen + 1;
+        state.highest_seen = state.our_election_id;
+        state.our_election_id
+    }
+}
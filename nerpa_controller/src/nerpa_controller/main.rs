@@ -24,37 +24,72 @@ extern crate proto;
 extern crate protobuf;
 
 use clap::{App, Arg};
-use nerpa_controller::{
-    Controller,
-    SwitchClientCommonState,
-};
-use std::sync::Arc;
+use grpcio::{ChannelBuilder, EnvBuilder};
+use nerpa_controller::{Controller, SwitchClient};
+use proto::p4runtime_grpc::P4RuntimeClient;
+use serde::Deserialize;
 use std::fs::File;
+use std::sync::Arc;
 
 // Import the function to run a DDlog program.
 // Note that the crate name changes with the Nerpa program's name.
 // The Nerpa programmer must rename this import.
 use snvs_ddlog::run;
 
+/// One switch's entry in a [`FabricManifest`]: everything a [`SwitchClient`] needs to drive it,
+/// plus the OVSDB it reports dataplane-originated inputs through.
+#[derive(Deserialize)]
+struct SwitchManifest {
+    /// Name used only for log messages; doesn't need to be unique to any other identifier ofp4 or
+    /// OVSDB knows about.
+    name: String,
+    /// P4Runtime gRPC target, e.g. `"leaf1.fabric.example:50051"`. A remote node is just a target
+    /// this controller can reach over the network -- there's no separate process to spawn there.
+    target: String,
+    /// Filepath for P4info binary file.
+    p4info: String,
+    /// Filepath for JSON representation of compiled P4 program.
+    json: String,
+    /// ID of the P4-enabled device.
+    device_id: u64,
+    /// Desired role ID for the controller.
+    #[serde(default)]
+    role_id: u64,
+    /// Filepath for this switch's OVSDB server.
+    ovsdb: String,
+    /// Name of this switch's OVS database.
+    ovsdb_database: String,
+}
+
+/// A fabric manifest: every switch one controller process should drive, read from `--manifest`.
+/// The single-switch CLI flags build the degenerate one-entry manifest of the same shape.
+#[derive(Deserialize)]
+struct FabricManifest {
+    switches: Vec<SwitchManifest>,
+}
+
 #[tokio::main]
 pub async fn main() {
     const FILE_DIR_ARG: &str = "file-directory";
     const FILE_NAME_ARG: &str = "file-name";
     const DDLOG_RECORD: &str = "ddlog-record";
+    const MANIFEST_ARG: &str = "manifest";
+    const TARGET_ARG: &str = "target";
+    const DEVICE_ID_ARG: &str = "device-id";
+    const ROLE_ID_ARG: &str = "role-id";
+    const OVSDB_ARG: &str = "ovsdb";
 
     let matches = App::new("nerpa_controller")
         .version(env!("CARGO_PKG_VERSION"))
         .about("Starts the controller program")
         .arg(
             Arg::with_name(FILE_DIR_ARG)
-                .help("Directory path with input files (*.p4info.bin, *.json, *.dl)")
-                .required(true)
+                .help("Directory path with input files (*.p4info.bin, *.json, *.dl); ignored with --manifest")
                 .index(1),
         )
         .arg(
             Arg::with_name(FILE_NAME_ARG)
-                .help("Filename before the extension: {file-name}.p4info.bin, {file-name}.dl")
-                .required(true)
+                .help("Filename before the extension: {file-name}.p4info.bin, {file-name}.dl; ignored with --manifest")
                 .index(2),
         )
         .arg(
@@ -64,19 +99,45 @@ pub async fn main() {
                 .value_name("FILE.TXT")
                 .help("File to record DB changes to replay later for debugging"),
         )
+        .arg(
+            Arg::with_name(MANIFEST_ARG)
+                .long("manifest")
+                .takes_value(true)
+                .value_name("FILE.JSON")
+                .help("Fabric manifest listing every switch to drive; switches this controller over \
+                       more than one device instead of the single local one the positional \
+                       arguments describe"),
+        )
+        .arg(
+            Arg::with_name(TARGET_ARG)
+                .long("target")
+                .takes_value(true)
+                .default_value("localhost:50051")
+                .help("P4Runtime gRPC target for the single switch to drive; ignored with --manifest"),
+        )
+        .arg(
+            Arg::with_name(DEVICE_ID_ARG)
+                .long("device-id")
+                .takes_value(true)
+                .default_value("0")
+                .help("ID of the single switch to drive; ignored with --manifest"),
+        )
+        .arg(
+            Arg::with_name(ROLE_ID_ARG)
+                .long("role-id")
+                .takes_value(true)
+                .default_value("0")
+                .help("Desired role ID for the single switch to drive; ignored with --manifest"),
+        )
+        .arg(
+            Arg::with_name(OVSDB_ARG)
+                .long("ovsdb")
+                .takes_value(true)
+                .default_value("unix:nerpa.sock")
+                .help("Filepath for the single switch's OVSDB server; ignored with --manifest"),
+        )
         .get_matches();
 
-    // Validate CLI arguments.
-    let file_dir_opt = matches.value_of(FILE_DIR_ARG);
-    if file_dir_opt.is_none() {
-        panic!("missing required argument: file-directory");
-    }
-
-    let file_name_opt = matches.value_of(FILE_NAME_ARG);
-    if file_name_opt.is_none() {
-        panic!("missing required argument: file-name");
-    }
-
     let mut record_file = matches.value_of_os(DDLOG_RECORD).map(
         |filename| match File::create(filename) {
             Ok(file) => file,
@@ -84,46 +145,76 @@ pub async fn main() {
         }
     );
 
-    // Extract arguments.
-    let file_dir = String::from(file_dir_opt.unwrap());
-    let file_name = String::from(file_name_opt.unwrap());
-
-    // Run controller.
-    run_controller(file_dir, file_name, &mut record_file).await
-}
+    // Build the fabric manifest: either read it from --manifest, or synthesize the degenerate
+    // one-entry manifest the positional arguments and single-switch flags describe.
+    let manifest = match matches.value_of(MANIFEST_ARG) {
+        Some(manifest_path) => {
+            let contents = std::fs::read_to_string(manifest_path)
+                .unwrap_or_else(|err| panic!("{}: read failed ({})", manifest_path, err));
+            serde_json::from_str(&contents)
+                .unwrap_or_else(|err| panic!("{}: invalid fabric manifest ({})", manifest_path, err))
+        },
+        None => {
+            let file_dir = matches.value_of(FILE_DIR_ARG)
+                .unwrap_or_else(|| panic!("missing required argument: file-directory (or --manifest)"));
+            let file_name = matches.value_of(FILE_NAME_ARG)
+                .unwrap_or_else(|| panic!("missing required argument: file-name (or --manifest)"));
+            let device_id = matches.value_of(DEVICE_ID_ARG).unwrap().parse()
+                .unwrap_or_else(|err| panic!("invalid --device-id: {}", err));
+            let role_id = matches.value_of(ROLE_ID_ARG).unwrap().parse()
+                .unwrap_or_else(|err| panic!("invalid --role-id: {}", err));
+            FabricManifest {
+                switches: vec![SwitchManifest {
+                    name: file_name.to_string(),
+                    target: matches.value_of(TARGET_ARG).unwrap().to_string(),
+                    p4info: format!("{}/{}.p4info.bin", file_dir, file_name),
+                    json: format!("{}/{}.json", file_dir, file_name),
+                    device_id,
+                    role_id,
+                    ovsdb: matches.value_of(OVSDB_ARG).unwrap().to_string(),
+                    ovsdb_database: file_name.to_string(),
+                }],
+            }
+        },
+    };
 
-async fn run_controller(
-    file_dir: String,
-    file_name: String,
-    record_file: &mut Option<File>,
-) {
     // Run the DDlog program. This computes initial contents to push across switches.
-    let (mut hddlog, initial_contents) = run(1, false).unwrap();
-    hddlog.record_commands(record_file);
-
-    // Define values that are common across all the switch clients.
-    let p4info = format!("{}/{}.p4info.bin", file_dir, file_name);
-    let json = format!("{}/{}.json", file_dir, file_name);
-    let cookie = String::from("");
-    let action = String::from("verify-and-commit");
-
-    let common_state = SwitchClientCommonState {
-        initial_contents,
-        p4info,
-        json,
-        cookie,
-        action,
-    };
+    let (mut hddlog, _initial_contents) = run(1, false).unwrap();
+    hddlog.record_commands(&mut record_file);
 
-    // Instantiate controller.
-    // We store the DDlog program on the heap. This lets us safely pass
-    // references to heap memory to both the controller and OVSDB client.
+    // We store the DDlog program on the heap. This lets us safely pass references to heap memory
+    // to both the controller and the OVSDB clients.
     let controller_hddlog = Arc::new(hddlog);
     let ovsdb_hddlog = controller_hddlog.clone();
-    let nerpa_controller = Controller::new(common_state, controller_hddlog).unwrap();
 
-    // Start streaming inputs from OVSDB and from the dataplane.
-    let server = String::from("unix:nerpa.sock");
-    let database = file_name.clone();
-    nerpa_controller.stream_inputs(ovsdb_hddlog, server, database).await;
+    // Connect a SwitchClient worker for every switch in the manifest -- on the local host, a
+    // remote node, or a mix of both, since each is just a P4Runtime gRPC target this process
+    // dials out to.
+    let mut switch_clients = Vec::new();
+    let mut ovsdb_endpoints = Vec::new();
+    for switch in manifest.switches {
+        let env = Arc::new(EnvBuilder::new().build());
+        let channel = ChannelBuilder::new(env).connect(&switch.target);
+        let client = P4RuntimeClient::new(channel);
+
+        let switch_client = SwitchClient::new(
+            client,
+            switch.p4info,
+            switch.json,
+            String::from(""),
+            String::from("verify-and-commit"),
+            switch.device_id,
+            switch.role_id,
+            switch.target.clone(),
+        ).await.unwrap_or_else(|err| panic!("{} ({}): failed to connect ({})", switch.name, switch.target, err));
+        switch_clients.push(switch_client);
+        ovsdb_endpoints.push((switch.ovsdb, switch.ovsdb_database));
+    }
+
+    // Instantiate controller, multiplexing DDlog output relations to the right switch client.
+    let nerpa_controller = Controller::new(switch_clients, controller_hddlog).unwrap();
+
+    // Start streaming inputs from every switch's OVSDB and from the dataplane; all of them report
+    // back into the one shared DDlog program.
+    nerpa_controller.stream_inputs(ovsdb_hddlog, ovsdb_endpoints).await;
 }
@@ -0,0 +1,84 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! An in-memory mirror of multicast-group replica state installed on a switch.
+//!
+//! `SwitchClient::update_multicast` used to issue a wildcarded read of every multicast group on
+//! the device before computing each single update -- an O(groups) round-trip that doesn't scale
+//! with the number of groups or the rate of updates. [`SwitchState`] instead caches the replicas
+//! of each group in memory, seeded once with [`SwitchState::reconcile`], so a later update diffs
+//! against the cache. `SwitchClient` re-reads the device into the cache only when this controller
+//! is promoted to primary; re-establishing the stream channel after a disconnect does not re-read
+//! it.
+
+use proto::p4runtime::Replica;
+use proto::p4runtime_grpc::P4RuntimeClient;
+use std::collections::HashMap;
+
+/// Caches the replicas of each multicast group last observed on a switch.
+#[derive(Debug, Default)]
+pub struct SwitchState {
+    multicast_groups: HashMap<u32, Vec<Replica>>,
+}
+
+impl SwitchState {
+    /// Returns an empty cache, as if no multicast groups are installed. Call [`Self::reconcile`]
+    /// before relying on it to actually reflect the switch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the cache with the multicast groups currently installed on `device_id`, read
+    /// fresh from the device with a single wildcarded read.
+    ///
+    /// Call this once at startup, and again after an event that may have left the cache stale --
+    /// a stream reconnect, or this controller being promoted to primary.
+    pub async fn reconcile(
+        &mut self,
+        device_id: u64,
+        client: &P4RuntimeClient,
+    ) -> Result<(), p4ext::P4Error> {
+        let entries = p4ext::read(vec![p4ext::build_multicast_read(0)], device_id, client).await?;
+
+        let mut multicast_groups: HashMap<u32, Vec<Replica>> = HashMap::new();
+        for entry in entries {
+            let mge = entry.get_packet_replication_engine_entry().get_multicast_group_entry();
+            multicast_groups.insert(mge.get_multicast_group_id(), mge.get_replicas().to_vec());
+        }
+        self.multicast_groups = multicast_groups;
+        Ok(())
+    }
+
+    /// Returns the replicas currently cached for multicast group `mcast_id`, or an empty `Vec` if
+    /// the cache has no record of that group.
+    pub fn replicas(&self, mcast_id: u32) -> Vec<Replica> {
+        self.multicast_groups.get(&mcast_id).cloned().unwrap_or_default()
+    }
+
+    /// Records `replicas` as the cached state of multicast group `mcast_id`, e.g. once a write for
+    /// it has succeeded. An empty `replicas` drops the group from the cache entirely.
+    pub fn set_replicas(&mut self, mcast_id: u32, replicas: Vec<Replica>) {
+        if replicas.is_empty() {
+            self.multicast_groups.remove(&mcast_id);
+        } else {
+            self.multicast_groups.insert(mcast_id, replicas);
+        }
+    }
+}
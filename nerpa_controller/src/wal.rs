@@ -0,0 +1,216 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A write-ahead log of P4 Runtime write batches.
+//!
+//! `ControllerActor` durably appends the batch of [`Update`]s that `SwitchClient::push_outputs`
+//! derives from each committed DDlog transaction *before* writing it to the switch. If the
+//! controller crashes or restarts before confirming the write, [`WriteAheadLog::replay`] returns
+//! every batch that hasn't been compacted yet, so the caller can push it again. A record holds the
+//! updates exactly as they were built, so pushing it again (see `SwitchClient::replay_updates`)
+//! resends the same INSERT/MODIFY/DELETE operations; the INSERT-vs-MODIFY choice is not re-made
+//! against the switch's current table state.
+//!
+//! A controller driving more than one P4 device shares a single log across all of them; each
+//! [`LogRecord`] is tagged with the `device_id` its batch was built for, so replay resends it to
+//! the right `SwitchClient`.
+//!
+//! The log is a flat, append-only file of length-prefixed records; [`WriteAheadLog::compact`]
+//! rewrites it to drop every record up to and including a given sequence number, once the switch
+//! has confirmed those batches landed.
+
+use anyhow::Context;
+use proto::p4runtime::Update;
+use protobuf::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// One durably-logged batch of table-entry updates.
+#[derive(Debug)]
+pub struct LogRecord {
+    /// This record's position in the log, assigned in the order batches were appended.
+    pub sequence: u64,
+    /// The DDlog transaction id that produced `updates` (see
+    /// `ControllerProgram::apply_updates`).
+    pub transaction_id: u64,
+    /// The P4 device `updates` were built for -- a controller driving a fleet of switches appends
+    /// one record per device per transaction, so replay can resend each batch to the right one.
+    pub device_id: u64,
+    /// The P4 Runtime updates that `SwitchClient::push_outputs` built from the transaction.
+    pub updates: Vec<Update>,
+}
+
+/// An append-only on-disk log of [`LogRecord`]s.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: BufWriter<File>,
+    next_sequence: u64,
+}
+
+impl WriteAheadLog {
+    /// Opens the write-ahead log at `path`, creating it if it doesn't exist, and positions it to
+    /// append after whatever records are already there.
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let next_sequence = match Self::replay(&path) {
+            Ok(records) => records.last().map_or(0, |r| r.sequence + 1),
+            Err(_) => 0,
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .with_context(|| format!("{}: open failed", path.display()))?;
+
+        Ok(WriteAheadLog { path, file: BufWriter::new(file), next_sequence })
+    }
+
+    /// Durably appends `updates`, tagged with `transaction_id` and the `device_id` they were built
+    /// for, to the log and returns the sequence number assigned to the new record. Must be called
+    /// before the batch is written to the switch, so that a crash between appending and writing
+    /// is recoverable by replay.
+    pub fn append(&mut self, transaction_id: u64, device_id: u64, updates: &[Update]) -> anyhow::Result<u64> {
+        let sequence = self.next_sequence;
+
+        write_record(&mut self.file, sequence, transaction_id, device_id, updates)
+            .with_context(|| format!("{}: append failed", self.path.display()))?;
+        self.file.flush().with_context(|| format!("{}: flush failed", self.path.display()))?;
+        self.file.get_ref().sync_data()
+            .with_context(|| format!("{}: fsync failed", self.path.display()))?;
+
+        self.next_sequence = sequence + 1;
+        Ok(sequence)
+    }
+
+    /// Reads every [`LogRecord`] in the log at `path`, in the order they were appended.
+    ///
+    /// Returns an empty `Vec` if `path` doesn't exist yet (a controller starting for the first
+    /// time has nothing to replay).
+    pub fn replay<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<LogRecord>> {
+        let path = path.as_ref();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => Err(error).with_context(|| format!("{}: open failed", path.display()))?,
+        };
+
+        let mut reader = BufReader::new(file);
+        let mut records = Vec::new();
+        while let Some(record) = read_record(&mut reader)
+            .with_context(|| format!("{}: read failed", path.display()))? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Rewrites the log in place, dropping every record with `sequence <= up_to_sequence`.
+    ///
+    /// Call this only after the switch has confirmed (via a successful `p4ext::write`) that those
+    /// batches were applied; records not yet confirmed must stay in the log so they're replayed
+    /// on the next restart.
+    pub fn compact(&mut self, up_to_sequence: u64) -> anyhow::Result<()> {
+        let kept: Vec<LogRecord> = Self::replay(&self.path)?
+            .into_iter()
+            .filter(|record| record.sequence > up_to_sequence)
+            .collect();
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        {
+            let tmp_file = OpenOptions::new().create(true).write(true).truncate(true).open(&tmp_path)
+                .with_context(|| format!("{}: create failed", tmp_path.display()))?;
+            let mut writer = BufWriter::new(tmp_file);
+            for record in &kept {
+                write_record(&mut writer, record.sequence, record.transaction_id, record.device_id, &record.updates)?;
+            }
+            writer.flush().with_context(|| format!("{}: flush failed", tmp_path.display()))?;
+            writer.get_ref().sync_data()
+                .with_context(|| format!("{}: fsync failed", tmp_path.display()))?;
+        }
+
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to rename {} to {}", tmp_path.display(), self.path.display()))?;
+
+        let file = OpenOptions::new().append(true).open(&self.path)
+            .with_context(|| format!("{}: reopen failed", self.path.display()))?;
+        self.file = BufWriter::new(file);
+
+        Ok(())
+    }
+}
+
+/// Writes one length-prefixed record: `sequence`, `transaction_id`, `device_id`, the number of
+/// updates, then each update's length-prefixed protobuf encoding.
+fn write_record(
+    writer: &mut impl Write,
+    sequence: u64,
+    transaction_id: u64,
+    device_id: u64,
+    updates: &[Update],
+) -> anyhow::Result<()> {
+    writer.write_all(&sequence.to_le_bytes())?;
+    writer.write_all(&transaction_id.to_le_bytes())?;
+    writer.write_all(&device_id.to_le_bytes())?;
+    writer.write_all(&(updates.len() as u32).to_le_bytes())?;
+    for update in updates {
+        let bytes = update.write_to_bytes().context("failed to encode update")?;
+        writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        writer.write_all(&bytes)?;
+    }
+    Ok(())
+}
+
+/// Reads one record written by `write_record`, or `None` at a clean end of file.
+fn read_record(reader: &mut impl Read) -> anyhow::Result<Option<LogRecord>> {
+    let mut sequence_buf = [0u8; 8];
+    match reader.read_exact(&mut sequence_buf) {
+        Ok(()) => (),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => Err(error)?,
+    }
+    let sequence = u64::from_le_bytes(sequence_buf);
+
+    let mut transaction_id_buf = [0u8; 8];
+    reader.read_exact(&mut transaction_id_buf).context("truncated transaction id")?;
+    let transaction_id = u64::from_le_bytes(transaction_id_buf);
+
+    let mut device_id_buf = [0u8; 8];
+    reader.read_exact(&mut device_id_buf).context("truncated device id")?;
+    let device_id = u64::from_le_bytes(device_id_buf);
+
+    let mut count_buf = [0u8; 4];
+    reader.read_exact(&mut count_buf).context("truncated update count")?;
+    let count = u32::from_le_bytes(count_buf);
+
+    let mut updates = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut len_buf = [0u8; 4];
+        reader.read_exact(&mut len_buf).context("truncated update length")?;
+        let len = u32::from_le_bytes(len_buf) as usize;
+
+        let mut bytes = vec![0u8; len];
+        reader.read_exact(&mut bytes).context("truncated update")?;
+        updates.push(Update::parse_from_bytes(&bytes).context("failed to decode update")?);
+    }
+
+    Ok(Some(LogRecord { sequence, transaction_id, device_id, updates }))
+}
@@ -20,10 +20,10 @@ SOFTWARE.
 
 use bmv2_packet::*;
 use hwaddr::HwAddr;
-use nanomsg::{Protocol, Socket};
 use packet::Builder;
 use std::collections::HashSet;
 use std::env;
+use std::time::Duration;
 
 fn test_packet(dst: HwAddr, src: HwAddr) -> packet::Result<Frame> {
     Ok(Frame(packet::ether::Builder::default().destination(dst)?.source(src)?
@@ -36,36 +36,36 @@ fn main() {
     if env::args().len() != 2 {
         eprintln!("test-snvs, for testing the snvs controller.\n\
 usage: {} ENDPOINT\n\
-where ENDPOINT is the same nanomsg endpoint passed to bmv2 on --packet-in,\n\
-e.g. \"ipc://bmv2.ipc\" for a Unix domain socket in the current directory.",
+where ENDPOINT is the endpoint passed to bmv2 on --packet-in, e.g.\n\
+\"ipc://bmv2.ipc\" for a Unix domain socket in the current directory, or\n\
+\"vsock://CID:PORT\" for an AF_VSOCK endpoint if bmv2 is running in another VM.",
                   env::args().nth(0).unwrap());
         std::process::exit(1);
     }
 
-    let mut s = Socket::new(Protocol::Pair).unwrap();
-    s.connect(&env::args().nth(1).unwrap()).unwrap();
+    let mut transport = bmv2_packet::connect(&env::args().nth(1).unwrap()).unwrap();
 
     let e0: HwAddr = [0x00, 0x11, 0x11, 0x00, 0x00, 0x00].into();
     let e1: HwAddr = [0x00, 0x22, 0x22, 0x00, 0x00, 0x00].into();
     let p0 = test_packet(e0, e1).unwrap();
     let p1 = test_packet(e1, e0).unwrap();
-    
-    s.set_receive_timeout(1000).unwrap();
 
-    // Send 'p0' on port 0 and it should be received on ports 1, 2, and 3.
-    // Do it twice: the second time should have the same effect.
-    for _i in 0..=1 {
-        let replies: HashSet<Bmv2Message> = send_and_receive(&mut s, Bmv2Message::PacketIn { port: 0, payload: p0.clone() }).into_iter().collect();
-        assert_eq!(replies, vec![Bmv2Message::PacketOut { port: 1, payload: p0.clone() },
-                                 Bmv2Message::PacketOut { port: 2, payload: p0.clone() },
-                                 Bmv2Message::PacketOut { port: 3, payload: p0.clone() }].into_iter().collect());
-    }
+    // Send 'p0' on port 0 and it should be received on ports 1, 2, and 3. Send it again right
+    // away, as a redundant link bouncing the same frame back might: the flood suppressor should
+    // recognize the repeat and suppress it rather than re-flooding it out every port again.
+    let flood_suppressor = FloodSuppressor::new(Duration::from_secs(1));
+    let replies: HashSet<Bmv2Message> = send_and_receive_with_flood_suppression(&mut *transport, Bmv2Message::PacketIn { port: 0, payload: p0.clone() }, &flood_suppressor).into_iter().collect();
+    assert_eq!(replies, vec![Bmv2Message::PacketOut { port: 1, payload: p0.clone() },
+                             Bmv2Message::PacketOut { port: 2, payload: p0.clone() },
+                             Bmv2Message::PacketOut { port: 3, payload: p0.clone() }].into_iter().collect());
+    let replies: HashSet<Bmv2Message> = send_and_receive_with_flood_suppression(&mut *transport, Bmv2Message::PacketIn { port: 0, payload: p0.clone() }, &flood_suppressor).into_iter().collect();
+    assert_eq!(replies, HashSet::new(), "repeated broadcast should not grow the flood");
 
     // Send 'p1' on port 1 with destination MAC as the Ethernet
     // address we just learned on port 0.  It should be received just
     // on port 0.  Again, we might as well do it twice.
     for _i in 0..=1 {
-        let replies: HashSet<Bmv2Message> = send_and_receive(&mut s, Bmv2Message::PacketIn { port: 1, payload: p1.clone() }).into_iter().collect();
+        let replies: HashSet<Bmv2Message> = send_and_receive(&mut *transport, Bmv2Message::PacketIn { port: 1, payload: p1.clone() }).into_iter().collect();
         assert_eq!(replies, vec![Bmv2Message::PacketOut { port: 0, payload: p1.clone() }].into_iter().collect());
     }
 
@@ -73,7 +73,7 @@ e.g. \"ipc://bmv2.ipc\" for a Unix domain socket in the current directory.",
     // only on port 1 because the destination MAC was learned in the
     // previous step.
     for _i in 0..=1 {
-        let replies: HashSet<Bmv2Message> = send_and_receive(&mut s, Bmv2Message::PacketIn { port: 0, payload: p0.clone() }).into_iter().collect();
+        let replies: HashSet<Bmv2Message> = send_and_receive(&mut *transport, Bmv2Message::PacketIn { port: 0, payload: p0.clone() }).into_iter().collect();
         assert_eq!(replies, vec![Bmv2Message::PacketOut { port: 1, payload: p0.clone() }].into_iter().collect());
     }
 
@@ -0,0 +1,352 @@
+/*
+Copyright (c) 2026 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! `--log-target` and the `tracing_subscriber` writer it selects.
+//!
+//! Once `Daemonize` detaches the controlling terminal, `stderr` is typically closed or pointed
+//! at `/dev/null`, so anything logged there after that point is lost exactly when it matters
+//! most. `--log-target syslog` keeps `tracing` output flowing to the system log across the
+//! double-fork by routing it to the local logd (over the conventional `/dev/log` Unix datagram
+//! socket) or, via `--syslog-remote`, to a remote RFC 5424 endpoint.
+
+use anyhow::{anyhow, Context, Result};
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use tracing::Level;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Where `--log-target` sends `tracing` output. Falls back to the settings file's `log_file`
+/// (i.e. `File`) if not given, or `Stderr` if that's not given either; see `main`'s merge into
+/// `DaemonSettings`.
+#[derive(Debug, Clone)]
+pub enum LogTarget {
+    /// The process's standard error, colorized if it's a tty.  Useless once daemonized unless
+    /// `Daemonize`'s `--no-detach` is in play.
+    Stderr,
+    /// The local logd via `/dev/log`, or the endpoint given by `--syslog-remote` if `/dev/log`
+    /// can't be reached.
+    Syslog,
+    /// A file, reopened in place on `SIGHUP` (see [`ReopenableFile`]) so `logrotate` can rename
+    /// it out from under the daemon without restarting it.
+    File(PathBuf),
+}
+
+impl FromStr for LogTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stderr" => Ok(LogTarget::Stderr),
+            "syslog" => Ok(LogTarget::Syslog),
+            _ => match s.strip_prefix("file:") {
+                Some(path) => Ok(LogTarget::File(PathBuf::from(path))),
+                None => Err(anyhow!(
+                    "{s:?}: expected \"stderr\", \"syslog\", or \"file:<path>\""
+                )),
+            },
+        }
+    }
+}
+
+/// The syslog facility to tag outgoing messages with, selectable with `--syslog-facility`.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Facility {
+    #[default]
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    /// The facility's numeric code, as used in a syslog PRI value (RFC 5424 section 6.2.1).
+    fn code(self) -> u8 {
+        match self {
+            Facility::Daemon => 3,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+impl FromStr for Facility {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "daemon" => Ok(Facility::Daemon),
+            "local0" => Ok(Facility::Local0),
+            "local1" => Ok(Facility::Local1),
+            "local2" => Ok(Facility::Local2),
+            "local3" => Ok(Facility::Local3),
+            "local4" => Ok(Facility::Local4),
+            "local5" => Ok(Facility::Local5),
+            "local6" => Ok(Facility::Local6),
+            "local7" => Ok(Facility::Local7),
+            _ => Err(anyhow!("{s:?}: expected \"daemon\" or \"local0\".. \"local7\"")),
+        }
+    }
+}
+
+/// A syslog severity, as used in a syslog PRI value (RFC 5424 section 6.2.1).  `tracing::Level`
+/// has no `FATAL`/`EMERG`/`ALERT`/`CRIT` equivalent, so only the bottom five severities are ever
+/// produced.
+#[derive(Debug, Clone, Copy)]
+enum Severity {
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl From<&Level> for Severity {
+    fn from(level: &Level) -> Self {
+        match *level {
+            Level::ERROR => Severity::Err,
+            Level::WARN => Severity::Warning,
+            Level::INFO => Severity::Notice,
+            Level::DEBUG => Severity::Info,
+            Level::TRACE => Severity::Debug,
+        }
+    }
+}
+
+/// Where a [`SyslogWriter`] sends its datagrams: the local logd, or (if `/dev/log` couldn't be
+/// reached) a remote endpoint given by `--syslog-remote`.
+enum SyslogSink {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    /// Wrapped in a `Mutex` because, unlike the datagram sockets above, a `TcpStream` send can
+    /// interleave partial writes from concurrent callers without one.
+    Tcp(Mutex<TcpStream>),
+}
+
+impl SyslogSink {
+    /// Connects to `/dev/log`, falling back to `remote` (`"udp:<host>:<port>"` or
+    /// `"tcp:<host>:<port>"`) if that fails.
+    fn connect(remote: Option<&str>) -> Result<SyslogSink> {
+        match UnixDatagram::unbound().and_then(|sock| {
+            sock.connect("/dev/log")?;
+            Ok(sock)
+        }) {
+            Ok(sock) => Ok(SyslogSink::Unix(sock)),
+            Err(error) => {
+                let remote = remote.ok_or_else(|| {
+                    anyhow!("/dev/log unavailable ({error}) and no --syslog-remote given")
+                })?;
+                Self::connect_remote(remote)
+            }
+        }
+    }
+
+    fn connect_remote(remote: &str) -> Result<SyslogSink> {
+        if let Some(addr) = remote.strip_prefix("udp:") {
+            let sock = UdpSocket::bind("0.0.0.0:0")
+                .context("failed to bind local UDP socket for syslog")?;
+            sock.connect(addr)
+                .with_context(|| format!("{addr}: connect failed"))?;
+            Ok(SyslogSink::Udp(sock))
+        } else if let Some(addr) = remote.strip_prefix("tcp:") {
+            let stream = TcpStream::connect(addr).with_context(|| format!("{addr}: connect failed"))?;
+            Ok(SyslogSink::Tcp(Mutex::new(stream)))
+        } else {
+            Err(anyhow!("--syslog-remote {remote:?}: expected \"udp:<host>:<port>\" or \"tcp:<host>:<port>\""))
+        }
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        match self {
+            SyslogSink::Unix(sock) => sock.send(message).map(|_| ()),
+            SyslogSink::Udp(sock) => sock.send(message).map(|_| ()),
+            SyslogSink::Tcp(stream) => {
+                // RFC 6587 octet-counted framing, since a TCP stream has no datagram boundaries.
+                let mut stream = stream.lock().unwrap();
+                stream.write_all(format!("{} ", message.len()).as_bytes())?;
+                stream.write_all(message)
+            }
+        }
+    }
+}
+
+/// Builds one [`SyslogWriter`] per event for a `tracing_subscriber` `fmt` layer, tagged with that
+/// event's level so the message's PRI carries the right severity (see [`Severity::from`]).
+#[derive(Clone)]
+pub struct SyslogMakeWriter {
+    sink: Arc<SyslogSink>,
+    facility: Facility,
+    tag: String,
+}
+
+impl SyslogMakeWriter {
+    pub fn new(facility: Facility, remote: Option<&str>) -> Result<Self> {
+        let sink = SyslogSink::connect(remote)?;
+        let tag = std::env::args().next()
+            .and_then(|arg0| PathBuf::from(arg0).file_name().map(|name| name.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| "ofp4".to_string());
+        Ok(SyslogMakeWriter { sink: Arc::new(sink), facility, tag })
+    }
+
+    fn writer_for(&self, severity: Severity) -> SyslogWriter {
+        SyslogWriter {
+            sink: self.sink.clone(),
+            pri: self.facility.code() * 8 + severity as u8,
+            tag: self.tag.clone(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogMakeWriter {
+    type Writer = SyslogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.writer_for(Severity::Notice)
+    }
+
+    fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+        self.writer_for(Severity::from(meta.level()))
+    }
+}
+
+/// A syslog writer for one event.  Each `write()` call is sent at once as its own syslog message,
+/// with its own header; `flush()` does nothing.
+pub struct SyslogWriter {
+    sink: Arc<SyslogSink>,
+    pri: u8,
+    tag: String,
+}
+
+impl fmt::Debug for SyslogWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyslogWriter").field("pri", &self.pri).finish()
+    }
+}
+
+impl io::Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // RFC 5424, with TIMESTAMP and HOSTNAME left as the "-" NILVALUE: the receiving logd
+        // (or, for `--syslog-remote`, the upstream relay) stamps those on arrival, same as it
+        // would for any other local process logging through `/dev/log`.
+        let header = format!("<{}>1 - - {} {} - - ", self.pri, self.tag, process::id());
+        let mut message = header.into_bytes();
+        message.extend_from_slice(buf);
+        self.sink
+            .send(&message)
+            .map_err(|error| io::Error::new(error.kind(), format!("syslog send failed: {error}")))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The `--log-file`/`file:<path>` handle passed to `tracing_subscriber`, kept around so that the
+/// `SIGHUP` handler installed by `spawn_signal_handler` can reopen it in place (e.g. after
+/// `logrotate` renames the old file out from under it) without tearing down and rebuilding the
+/// subscriber.
+#[derive(Clone)]
+pub struct ReopenableFile {
+    path: PathBuf,
+    file: Arc<Mutex<std::fs::File>>,
+}
+
+impl ReopenableFile {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)
+            .with_context(|| format!("{}: open failed", path.display()))?;
+        Ok(ReopenableFile { path, file: Arc::new(Mutex::new(file)) })
+    }
+
+    pub fn reopen(&self) -> Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .with_context(|| format!("{}: open failed", self.path.display()))?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+}
+
+impl io::Write for ReopenableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for ReopenableFile {
+    type Writer = ReopenableFile;
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// Installs the global `tracing_subscriber` for `target`, returning the [`ReopenableFile`] for
+/// `main`'s `spawn_signal_handler` to reopen on `SIGHUP` (for log rotation) if `target` is
+/// `LogTarget::File`, or `None` for `Stderr`/`Syslog`, neither of which needs reopening.
+pub fn init(target: &LogTarget, facility: Facility, syslog_remote: Option<&str>) -> Result<Option<ReopenableFile>> {
+    match target {
+        LogTarget::Stderr => {
+            tracing_subscriber::fmt()
+                .with_writer(io::stderr)
+                .with_ansi(unsafe { libc::isatty(libc::STDERR_FILENO) } == 1)
+                .init();
+            Ok(None)
+        }
+        LogTarget::Syslog => {
+            let writer = SyslogMakeWriter::new(facility, syslog_remote)
+                .context("failed to set up --log-target syslog")?;
+            tracing_subscriber::fmt()
+                .with_writer(writer)
+                .with_ansi(false)
+                .init();
+            Ok(None)
+        }
+        LogTarget::File(path) => {
+            let log_file = ReopenableFile::open(path.clone())?;
+            tracing_subscriber::fmt()
+                .with_writer(log_file.clone())
+                .with_ansi(false)
+                .init();
+            Ok(Some(log_file))
+        }
+    }
+}
@@ -21,9 +21,13 @@ SOFTWARE.
 //! `ofp4` provides a P4Runtime interface to Open vSwitch.  It accepts P4Runtime connections from a
 //! controller and connects to an Open vSwitch instance over OpenFlow and OVSDB.
 
+mod log_target;
+
 use anyhow::{anyhow, Context, Result};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
 
 use daemon::{Daemonize, Daemonizing};
 
@@ -33,9 +37,12 @@ use differential_datalog::program::{IdxId, RelId, Update};
 use differential_datalog::record::{Record, RelIdentifier, UpdCmd};
 use differential_datalog::{DeltaMap, DDlog, DDlogDynamic, DDlogInventory};
 
-use futures_util::{FutureExt, SinkExt, TryFutureExt, TryStreamExt};
+use futures_channel::mpsc;
+
+use futures_util::{FutureExt, SinkExt, StreamExt, TryFutureExt, TryStreamExt};
 
 use grpcio::{
+    CertificateRequestType,
     ChannelBuilder,
     DuplexSink,
     Environment,
@@ -43,6 +50,8 @@ use grpcio::{
     RpcContext,
     RpcStatusCode,
     ServerBuilder,
+    ServerCredentials,
+    ServerCredentialsBuilder,
     ServerStreamingSink,
     UnarySink,
 };
@@ -54,6 +63,8 @@ use ovs::{
     ofp_bundle::*,
     ofp_flow::{FlowMod, FlowModCommand},
     ofp_msgs::OfpType,
+    ofp_packet,
+    ofp_stats::FlowStatsRequest,
     rconn::Rconn
 };
 
@@ -69,30 +80,48 @@ use proto::p4runtime::{
     ForwardingPipelineConfig_Cookie,
     GetForwardingPipelineConfigRequest,
     GetForwardingPipelineConfigResponse,
+    MasterArbitrationUpdate,
+    PacketIn,
+    PacketMetadata,
+    PacketOut,
     PacketReplicationEngineEntry,
     PacketReplicationEngineEntry_oneof_type,
+    IdleTimeoutNotification,
     ReadRequest,
     ReadResponse,
     SetForwardingPipelineConfigRequest,
+    SetForwardingPipelineConfigRequest_Action,
     SetForwardingPipelineConfigResponse,
     StreamMessageRequest,
     StreamMessageResponse,
+    TableEntry_IdleTimeout,
+    Uint128,
     Update_Type,
     WriteRequest,
+    WriteRequest_Atomicity,
     WriteResponse,
 };
 use proto::p4runtime_grpc::{P4Runtime, create_p4_runtime};
 
-use protobuf::{Message, well_known_types::Any};
+use protobuf::{Message, RepeatedField, well_known_types::Any};
 
 use ofp4dl_ddlog::typedefs::ofp4lib::{flow_t, multicast_group_t};
-use std::collections::{BTreeSet, HashMap};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use signal_hook::{consts::signal::*, iterator::Signals};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
 use std::default::Default;
 use std::convert::TryInto;
-use std::fs::{File, OpenOptions};
-use std::io::stderr;
-use std::path::PathBuf;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use tracing::{event, error, info, instrument, Level, span, warn};
 
@@ -107,6 +136,22 @@ struct Config {
     flow_idxid: IdxId,
     flow_relid: RelId,
     multicast_group_relid: RelId,
+
+    // Maps a `controller_packet_metadata` field's name (e.g. `"ingress_port"`) to its id, for the
+    // P4Info's `"packet_in"` and `"packet_out"` metadata schemas.  Empty if the P4 program doesn't
+    // define the corresponding schema, in which case packet-in/packet-out just isn't supported.
+    packet_in_metadata: HashMap<String, u32>,
+    packet_out_metadata: HashMap<String, u32>,
+}
+
+/// Looks up `name` (`"packet_in"` or `"packet_out"`) among `p4info`'s `controller_packet_metadata`
+/// schemas and maps each of its fields' names to their ids, for translating between a P4Runtime
+/// `PacketMetadata` and the OpenFlow concept (e.g. ingress port) it represents.
+fn packet_metadata_ids(p4info: &P4Info, name: &str) -> HashMap<String, u32> {
+    p4info.get_controller_packet_metadata().iter()
+        .find(|cpm| cpm.get_preamble().name == name)
+        .map(|cpm| cpm.get_metadata().iter().map(|m| (m.name.clone(), m.id)).collect())
+        .unwrap_or_default()
 }
 
 impl Config {
@@ -121,8 +166,13 @@ impl Config {
             .iter()
             .map(|a| (a.get_preamble().id, a.into()))
             .collect();
+        let action_profile_by_id: HashMap<u32, p4ext::ActionProfile> = p4info
+            .get_action_profiles()
+            .iter()
+            .map(|ap| (ap.get_preamble().id, ap.into()))
+            .collect();
         let table_schemas = p4info.get_tables().iter()
-            .map(|table| p4ext::Table::new_from_proto(table, &action_by_id))
+            .map(|table| p4ext::Table::new_from_proto(table, &action_by_id, &action_profile_by_id))
             .map(|table| (table.preamble.id, table))
             .collect();
 
@@ -133,6 +183,8 @@ impl Config {
         let multicast_group_relid = hddlog.inventory.get_table_id(&multicast_group_relname).ddlog_map_error()?;
 
         Ok(Config {
+            packet_in_metadata: packet_metadata_ids(&p4info, "packet_in"),
+            packet_out_metadata: packet_metadata_ids(&p4info, "packet_out"),
             p4info: p4info.clone(),
             module,
             cookie: fpc.get_cookie().get_cookie(),
@@ -144,30 +196,265 @@ impl Config {
     }
 }
 
+/// P4Runtime mastership state for one `role_id`: whichever currently-connected `stream_channel`
+/// offered the highest `election_id` is the primary, and everyone else is a backup.  `notify`
+/// holds a sender for every stream currently arbitrating under this role (keyed by a per-stream id
+/// assigned when it first arbitrates), so that when the primary changes, `stream_channel` can push
+/// the new arbitration to every other connection sharing the role.
+#[derive(Default)]
+struct RoleState {
+    primary_election_id: Option<(u64, u64)>,
+    notify: HashMap<u64, mpsc::UnboundedSender<StreamMessageResponse>>,
+
+    /// The stream id (a key into `notify`) that currently holds `primary_election_id`, so that
+    /// unsolicited pushes meant only for the primary -- e.g. `IdleTimeoutNotification` -- know
+    /// which sender to use instead of every sender in `notify`.
+    primary_stream_id: Option<u64>,
+}
+
+/// Builds the `MasterArbitrationUpdate` that tells a stream arbitrating under `role_id` who the
+/// primary is: `status` is `OK` if the recipient *is* `election_id`'s owner, `ALREADY_EXISTS`
+/// otherwise -- P4Runtime's way of saying "someone else already holds mastership".
+fn make_arbitration(device_id: u64, role_id: u64, election_id: (u64, u64), is_primary: bool) -> MasterArbitrationUpdate {
+    let mut arbitration = MasterArbitrationUpdate::new();
+    arbitration.set_device_id(device_id);
+    arbitration.set_role_id(role_id);
+
+    let mut uint128 = Uint128::new();
+    uint128.set_high(election_id.0);
+    uint128.set_low(election_id.1);
+    arbitration.set_election_id(uint128);
+
+    let mut status = proto::status::Status::new();
+    status.set_code((if is_primary { RpcStatusCode::OK } else { RpcStatusCode::ALREADY_EXISTS }).into());
+    arbitration.set_status(status);
+
+    arbitration
+}
+
+/// Counters and histograms for the `/metrics` admin endpoint (see `render_metrics`), kept on
+/// `State` and updated under its mutex by whatever code path already holds it, so a scrape never
+/// observes a torn update.
+#[derive(Default)]
+struct Metrics {
+    read_total: HashMap<i32, u64>,
+    write_total: HashMap<i32, u64>,
+    set_forwarding_pipeline_config_total: HashMap<i32, u64>,
+    ddlog_commit_seconds_sum: f64,
+    ddlog_commit_seconds_count: u64,
+    // How many `ConfigChangeJob`s `config_change_worker` merged into each DDlog transaction it
+    // committed; the average (`_sum` / `_count`) shows how much batching is amortizing commits.
+    config_change_batch_size_sum: u64,
+    config_change_batch_size_count: u64,
+    flow_mods_flushed_total: u64,
+    active_streams: i64,
+}
+
+impl Metrics {
+    fn record_read(&mut self, code: RpcStatusCode) {
+        *self.read_total.entry(code.into()).or_insert(0) += 1;
+    }
+
+    fn record_write(&mut self, code: RpcStatusCode) {
+        *self.write_total.entry(code.into()).or_insert(0) += 1;
+    }
+
+    fn record_set_forwarding_pipeline_config(&mut self, code: RpcStatusCode) {
+        *self.set_forwarding_pipeline_config_total.entry(code.into()).or_insert(0) += 1;
+    }
+
+    fn record_ddlog_commit(&mut self, elapsed: Duration) {
+        self.ddlog_commit_seconds_sum += elapsed.as_secs_f64();
+        self.ddlog_commit_seconds_count += 1;
+    }
+
+    fn record_flow_mods_flushed(&mut self, count: u64) {
+        self.flow_mods_flushed_total += count;
+    }
+
+    fn record_config_change_batch(&mut self, batch_size: usize) {
+        self.config_change_batch_size_sum += batch_size as u64;
+        self.config_change_batch_size_count += 1;
+    }
+}
+
+/// The `RpcStatusCode` that `result` will be reported to its caller as, for recording in
+/// `Metrics`.
+fn rpc_result_code<T>(result: &Result<T, grpcio::RpcStatus>) -> RpcStatusCode {
+    match result {
+        Ok(_) => RpcStatusCode::OK,
+        Err(status) => status.status,
+    }
+}
+
+/// A pending flow-table change recorded as an `ovs-ofctl` flow spec and the command to apply it
+/// with, rather than as an encoded [`Ofpbuf`] -- `Ofpbuf` owns raw OVS memory and isn't `Clone`,
+/// but `run_server` now drives several bridges (see `BridgeConn`) from the one queue in `State`,
+/// and each bridge needs its own freshly encoded copy of the same message.
+#[derive(Clone)]
+struct PendingFlowMod {
+    command: PendingFlowModCommand,
+    spec: String,
+}
+
+#[derive(Clone, Copy)]
+enum PendingFlowModCommand {
+    Add,
+    DeleteStrict,
+}
+
+impl PendingFlowMod {
+    /// Builds a `PendingFlowMod` from `spec`, validating it eagerly so a malformed flow is
+    /// reported once here rather than on every bridge's later re-encode.
+    fn new(command: PendingFlowModCommand, spec: String) -> Result<PendingFlowMod> {
+        let openflow_command = match command {
+            PendingFlowModCommand::Add => FlowModCommand::Add,
+            PendingFlowModCommand::DeleteStrict => FlowModCommand::Delete { strict: true },
+        };
+        match FlowMod::parse(&spec, Some(openflow_command)) {
+            Ok(_) => Ok(PendingFlowMod { command, spec }),
+            Err(s) => Err(anyhow!("{spec}: {s}")),
+        }
+    }
+
+    fn encode(&self) -> Ofpbuf {
+        let openflow_command = match self.command {
+            PendingFlowModCommand::Add => FlowModCommand::Add,
+            PendingFlowModCommand::DeleteStrict => FlowModCommand::Delete { strict: true },
+        };
+        let (flow_mod, _) = FlowMod::parse(&self.spec, Some(openflow_command))
+            .expect("PendingFlowMod::new already validated this spec");
+        flow_mod.encode(OFP_PROTOCOL)
+    }
+}
+
+/// A pending packet-out recorded as its payload and `ovs-ofctl` actions string rather than an
+/// encoded [`Ofpbuf`], for the same reason as [`PendingFlowMod`].
+#[derive(Clone)]
+struct PendingPacketOut {
+    payload: Vec<u8>,
+    actions: String,
+}
+
+impl PendingPacketOut {
+    fn encode(&self) -> Result<Ofpbuf> {
+        let po = ofp_packet::PacketOut::new(&self.payload, ovs::sys::ofp_port_OFPP_CONTROLLER as u32, &self.actions)?;
+        Ok(po.encode(OFP_PROTOCOL))
+    }
+}
+
+/// One OVS switch this daemon drives.  Every bridge is sent the same flows and multicast groups
+/// computed from the single DDlog program (see `State::pending_flow_mods`) -- the P4Info schema
+/// this daemon understands has no notion of which bridge a table entry belongs to, so there's no
+/// way to partition `Flow` by bridge without extending it (e.g. with a bridge-id column) in
+/// `ofp4dl_ddlog`, which lives outside this crate.  What *is* per-bridge is the OpenFlow
+/// connection itself: each bridge gets its own [`Rconn`], reconnect/resync state, and bundle
+/// sequence, all driven by the same OVS `poll_loop` in `run_server`.
+struct BridgeConn {
+    bridge_id: u64,
+    rconn: Rconn,
+    last_connection_seqno: u32,
+    last_config_seqno: u64,
+    bundle_id: u32,
+}
+
+impl BridgeConn {
+    fn new(bridge_id: u64, remote: &str) -> BridgeConn {
+        let mut rconn = Rconn::new(0, 0, ovs::rconn::DSCP_DEFAULT, OFP_VERSION.into());
+        rconn.connect(remote, None);
+        BridgeConn { bridge_id, rconn, last_connection_seqno: 0, last_config_seqno: 0, bundle_id: 0 }
+    }
+}
+
+/// A `BridgeConn`'s connection state, mirrored into `State::bridges` once per iteration of
+/// `run_server`'s loop, so that the control socket's `status` and `reconnect` commands (see
+/// `handle_control_request`) can inspect and influence a bridge's `Rconn` without touching it
+/// directly -- only `run_server`'s thread may do that.
+#[derive(Default)]
+struct BridgeStatus {
+    connected: bool,
+    connection_seqno: u32,
+
+    // Set by the control socket's `reconnect` command; checked and cleared at the top of
+    // `run_server`'s loop, which is the only thread allowed to call `rconn.reconnect()`.
+    force_reconnect: bool,
+}
+
 struct State {
     hddlog: HDDlog,
     latch: Latch,
-    pending_flow_mods: Vec<Ofpbuf>,
+    metrics: Metrics,
+    pending_flow_mods: Vec<PendingFlowMod>,
+    // Packet-outs queued by `stream_channel` for `run_server` to send, since only `run_server`'s
+    // thread may call `rconn.send()`.  Unlike `pending_flow_mods`, these aren't reissued on
+    // reconnect -- a packet-out is a one-shot action, not state to resync.
+    pending_packet_outs: Vec<PendingPacketOut>,
 
     // Configuration state.
-    device_id: u64,
+    // The daemon's fully resolved runtime settings (CLI flags over settings file over built-in
+    // defaults; see `main` and `DaemonSettings`), kept around so the control socket's `status`
+    // command can report exactly what's active.
+    settings: DaemonSettings,
     config: Option<Config>,
     config_seqno: u64,
+    // Where `SetForwardingPipelineConfig` persists the pipeline config and table state (see
+    // `save_config`/`load_config`), so that a restart can reload them (see `main`).  `None` means
+    // the daemon was started without `--config-file`, so `VERIFY_AND_SAVE` and
+    // `RECONCILE_AND_COMMIT` aren't available.
+    config_file: Option<PathBuf>,
 
     // Table state.
     multicast_groups: HashMap<MulticastGroupId, BTreeSet<Replica>>,
-    table_entries: HashMap<TableKey, TableValue>
+    table_entries: HashMap<TableKey, TableValue>,
+
+    // Counters.  `table_key_cookies` records which OVS flow cookies a given table entry's flows
+    // were tagged with, so that `cookie_counters` and `cookie_idle_age`, which are filled in by
+    // `run_server` from periodic flow-stats replies, can be attributed back to a `TableKey` for a
+    // `Read` RPC or an idle-timeout check.
+    table_key_cookies: HashMap<TableKey, Vec<u64>>,
+    cookie_counters: HashMap<u64, proto::p4runtime::CounterData>,
+    cookie_idle_age: HashMap<u64, i32>,
+
+    // Entries that `due_idle_timeouts` has already reported an `IdleTimeoutNotification` for,
+    // since the last time they were hit (or rewritten).  Keeps a flow that stays idle from being
+    // reported again on every poll.
+    idle_notified: HashSet<TableKey>,
+
+    // Arbitration state, keyed by role_id (see `stream_channel`).
+    roles: HashMap<u64, RoleState>,
+    next_stream_id: u64,
+
+    // Set by the `SIGTERM`/`SIGINT` handler installed in `main` (see `spawn_signal_handler`) and
+    // checked at the top of `run_server`'s loop, so that the signal handler itself -- which must
+    // stay async-signal-safe -- only has to flip a flag and wake `latch`, leaving the actual
+    // teardown (flushing the pending bundle, resolving `daemonizing`, releasing `rconn`) to the
+    // thread that owns them.
+    shutting_down: bool,
+
+    // Keyed by `BridgeConn::bridge_id`; see `BridgeStatus`.
+    bridges: HashMap<u64, BridgeStatus>,
+
+    // The producer side of the queue that `config_change_worker` drains; see `ConfigChangeJob`.
+    // P4Runtime write handlers validate a write and apply its in-memory effect against `State`
+    // synchronously, same as always, then send its DDlog commands down this channel instead of
+    // committing them before returning, so a write RPC never calls `transaction_commit_dump_changes`
+    // itself.  A worker holds `State`'s mutex while it commits, so a write RPC can still wait for
+    // that commit to finish before it gets the mutex.
+    config_changes: Sender<ConfigChangeJob>,
 }
 
 impl State {
-    fn new(hddlog: HDDlog, device_id: u64)
+    fn new(hddlog: HDDlog, settings: DaemonSettings, config_file: Option<PathBuf>, config_changes: Sender<ConfigChangeJob>)
            -> State {
-        let (pending_flow_mods, config, config_seqno,
-             multicast_groups, table_entries) = Default::default();
+        let (pending_flow_mods, pending_packet_outs, config, config_seqno,
+             multicast_groups, table_entries, table_key_cookies, cookie_counters, cookie_idle_age,
+             idle_notified, roles, next_stream_id, shutting_down, bridges) = Default::default();
         State {
             latch: Latch::new(),
-            hddlog, device_id,
-            pending_flow_mods, config, config_seqno, multicast_groups, table_entries,
+            metrics: Metrics::default(),
+            hddlog, settings, config_file, roles, next_stream_id, shutting_down, bridges, config_changes,
+            pending_flow_mods, pending_packet_outs, config, config_seqno, multicast_groups, table_entries,
+            table_key_cookies, cookie_counters, cookie_idle_age, idle_notified,
         }
     }
 
@@ -228,18 +515,102 @@ impl State {
             if !target.value.metadata.is_empty() && target.value.metadata != value.metadata {
                 continue;
             }
+            // The pipeline's P4Info doesn't model direct or indirect meters yet (see
+            // `p4ext::Table`), so there's nothing to map onto OVS meter bands here.
             // XXX meter_config
-            // XXX counter_data
-            // XXX idle_timeout_ns?
-            // XXX time_since_last_hit?
             let (unknown_fields, cached_size) = Default::default();
-            let te = TableEntry { key: key.clone(), value: value.clone() }; 
+            let te = TableEntry { key: key.clone(), value: value.clone() };
+            let mut p_te: proto::p4runtime::TableEntry = (&te).into();
+            p_te.set_counter_data(self.counter_data(key));
+            if value.idle_timeout_ns != 0 {
+                if let Some(elapsed_ns) = self.idle_age_ns(key) {
+                    let (unknown_fields, cached_size) = Default::default();
+                    p_te.set_time_since_last_hit(TableEntry_IdleTimeout { elapsed_ns, unknown_fields, cached_size });
+                }
+            }
             entities.push(Entity {
-                entity: Some(Entity_oneof_entity::table_entry((&te).into())),
+                entity: Some(Entity_oneof_entity::table_entry(p_te)),
                 unknown_fields, cached_size });
         }
         entities
     }
+
+    /// Sums the OVS flow statistics of every flow tagged with one of `key`'s cookies (see
+    /// `table_key_cookies`) into a single `CounterData`, for a `TableEntry`'s `Read` reply.
+    fn counter_data(&self, key: &TableKey) -> proto::p4runtime::CounterData {
+        let (mut packet_count, mut byte_count) = (0, 0);
+        for cookie in self.table_key_cookies.get(key).into_iter().flatten() {
+            if let Some(counters) = self.cookie_counters.get(cookie) {
+                packet_count += counters.packet_count;
+                byte_count += counters.byte_count;
+            }
+        }
+        let (unknown_fields, cached_size) = Default::default();
+        proto::p4runtime::CounterData { byte_count, packet_count, unknown_fields, cached_size }
+    }
+
+    /// Returns the time elapsed, in nanoseconds, since a packet last matched any of `key`'s flows
+    /// (see `table_key_cookies`), based on the idle ages from the most recent flow-stats poll.
+    /// Returns `None` if none of `key`'s flows have been polled yet.
+    fn idle_age_ns(&self, key: &TableKey) -> Option<i64> {
+        self.table_key_cookies.get(key).into_iter().flatten()
+            .filter_map(|cookie| self.cookie_idle_age.get(cookie))
+            .min()
+            .map(|&idle_age| idle_age as i64 * 1_000_000_000)
+    }
+
+    /// Looks up the idle timeout that a re-sent flow tagged with `cookie` should be given, by
+    /// finding the `TableKey` that currently owns the cookie (see `table_key_cookies`) and
+    /// checking its `TableValue::idle_timeout_ns`.  Returns `None` -- no timeout -- if the cookie
+    /// isn't (or is no longer precisely) associated with a table entry, e.g. because it last
+    /// came from an ambiguous `write_rollback_on_error` batch.
+    fn idle_timeout_seconds_for_cookie(&self, cookie: u64) -> Option<u16> {
+        let key = self.table_key_cookies.iter()
+            .find(|(_, cookies)| cookies.contains(&cookie))
+            .map(|(key, _)| key)?;
+        match self.table_entries.get(key)?.idle_timeout_ns {
+            0 => None,
+            idle_timeout_ns => Some(idle_timeout_seconds(idle_timeout_ns)),
+        }
+    }
+
+    /// Checks every table entry with a nonzero idle timeout against `idle_age_ns`, and returns the
+    /// ones that have just now exceeded their idle timeout -- marking each one in `idle_notified`
+    /// so that it's reported exactly once per idle period, not on every poll that finds it still
+    /// idle.  An entry that's hit again afterward drops back out of `idle_notified`, so a later
+    /// idle period reports it again.
+    fn due_idle_timeouts(&mut self) -> Vec<TableEntry> {
+        let keys: Vec<TableKey> = self.table_entries.keys().cloned().collect();
+        let mut due = Vec::new();
+        for key in keys {
+            let value = self.table_entries[&key].clone();
+            if value.idle_timeout_ns == 0 {
+                continue;
+            }
+            match self.idle_age_ns(&key) {
+                Some(idle_age_ns) if idle_age_ns >= value.idle_timeout_ns => {
+                    if self.idle_notified.insert(key.clone()) {
+                        due.push(TableEntry { key, value });
+                    }
+                },
+                _ => { self.idle_notified.remove(&key); },
+            }
+        }
+        due
+    }
+
+    /// Pushes `response` to role_id 0's primary, if one is currently connected -- for unsolicited
+    /// messages, like `PacketIn` and `IdleTimeoutNotification`, that a controller expects to
+    /// receive exactly once rather than on every backup's stream.  This daemon doesn't track which
+    /// role wrote a given table entry or otherwise owns packet processing, so role 0 (P4Runtime's
+    /// default, device-wide role) is used for all of them.
+    fn notify_primary(&self, response: StreamMessageResponse) {
+        if let Some(role) = self.roles.get(&0) {
+            if let Some(notify) = role.primary_stream_id.and_then(|id| role.notify.get(&id)) {
+                let _ = notify.unbounded_send(response);
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -306,6 +677,41 @@ fn server_streaming_result<T: Send + 'static>(
     }
 }
 
+/// The DDlog commands (and the resulting in-memory state change) that one `Update` entity
+/// resolves to, without applying either. Splitting this out of commitment lets a
+/// `ROLLBACK_ON_ERROR` batch validate every entity up front and only then open the single
+/// transaction that commits all of them -- or none, if any entity turned out invalid.
+#[allow(clippy::large_enum_variant)]
+enum EntityWrite {
+    MulticastGroup {
+        multicast_group_id: u32,
+        new_replicas: BTreeSet<Replica>,
+        commands: Vec<Update<DDValue>>,
+    },
+    TableEntry {
+        key: TableKey,
+        new_value: Option<TableValue>,
+        commands: Vec<UpdCmd>,
+    },
+}
+
+/// One or more `EntityWrite`s' DDlog commands, queued by a P4Runtime write handler for
+/// `config_change_worker` to commit. A write RPC's response never depended on the commit
+/// actually happening -- `validate_entity`/`validate_write` already decided success or failure,
+/// and the in-memory effect (`apply_entity_write`) is applied synchronously before this is sent --
+/// so the only thing left for the worker to do is run the DDlog transaction and turn its delta
+/// into flow mods, off of the thread that's holding a P4Runtime client waiting on a reply.
+struct ConfigChangeJob {
+    typed_commands: Vec<Update<DDValue>>,
+    dynamic_commands: Vec<UpdCmd>,
+    // The single `TableEntry` write this job resulted from, and its idle timeout, so the worker
+    // can attribute the delta's new flow cookies back to `table_key_cookies` the same way
+    // `write_rollback_on_error` already did inline. `None` when the job has no such entry to
+    // attribute to (a `MulticastGroup` write) or, once batching merges more than one job into a
+    // single transaction, when the merged delta spans more than one table entry.
+    cookie_attribution: Option<(TableKey, i64)>,
+}
+
 impl P4RuntimeService {
     fn validate_write(op: Update_Type, entity_exists: bool) -> Result<()> {
         match (op, entity_exists) {
@@ -317,7 +723,7 @@ impl P4RuntimeService {
         }
     }
 
-    fn write_entity(op: Update_Type, entity: Option<&Entity>, state: &mut State) -> Result<()> {
+    fn validate_entity(op: Update_Type, entity: Option<&Entity>, state: &State) -> Result<EntityWrite> {
         let config = &state.config.as_ref().unwrap();
         match entity {
             None => Err(Error(RpcStatusCode::INVALID_ARGUMENT))?,
@@ -339,11 +745,11 @@ impl P4RuntimeService {
 
                 let new_value = match op {
                     Update_Type::UNSPECIFIED => unreachable!(),
-                    Update_Type::INSERT | Update_Type::MODIFY => &mge.replicas,
-                    Update_Type::DELETE => &no_values,
+                    Update_Type::INSERT | Update_Type::MODIFY => mge.replicas,
+                    Update_Type::DELETE => no_values.clone(),
                 };
 
-                // Commit the operation to DDlog.
+                // Compute the DDlog commands this operation would apply.
                 let mut commands = Vec::with_capacity(2);
                 for insertion in new_value.difference(old_value) {
                     commands.push(Update::Insert {
@@ -354,7 +760,7 @@ impl P4RuntimeService {
                         }.into_ddvalue()
                     });
                 }
-                for deletion in old_value.difference(new_value) {
+                for deletion in old_value.difference(&new_value) {
                     commands.push(Update::DeleteValue {
                         relid: config.multicast_group_relid,
                         v: multicast_group_t {
@@ -363,23 +769,12 @@ impl P4RuntimeService {
                         }.into_ddvalue()
                     });
                 }
-                let delta = {
-                    let hddlog = &state.hddlog;
-
-                    hddlog.transaction_start().ddlog_map_error()?;
-                    hddlog.apply_updates(&mut commands.into_iter()).ddlog_map_error()?;
-                    hddlog.transaction_commit_dump_changes().ddlog_map_error()?
-                };
-                delta_to_flow_mods(&delta, config.flow_relid, &mut state.pending_flow_mods);
-                state.latch.set();
 
-                // Commit the operation to our internal representation.
-                if new_value.is_empty() {
-                    state.multicast_groups.remove(&mge.multicast_group_id);
-                } else {
-                    state.multicast_groups.insert(mge.multicast_group_id, mge.replicas);
-                }
-                Ok(())
+                Ok(EntityWrite::MulticastGroup {
+                    multicast_group_id: mge.multicast_group_id,
+                    new_replicas: new_value,
+                    commands,
+                })
             },
             Some(Entity { entity: Some(Entity_oneof_entity::table_entry(te)), .. }) => {
                 let te: TableEntry = te.try_into()?;
@@ -396,7 +791,7 @@ impl P4RuntimeService {
                 let old_value = state.table_entries.get(&te.key);
                 Self::validate_write(op, old_value.is_some())?;
 
-                // Commit the operation to DDlog.
+                // Compute the DDlog commands this operation would apply.
                 let mut commands = Vec::with_capacity(2);
                 if let Some(old_value) = old_value {
                     let old_te = TableEntry { key: te.key.clone(), value: old_value.clone() };
@@ -407,34 +802,204 @@ impl P4RuntimeService {
                     let new_record = te.to_record(table, &table_name).unwrap();
                     commands.push(UpdCmd::Insert(RelIdentifier::RelId(relid), new_record));
                 }
-                let delta = {
-                    let hddlog = &state.hddlog;
 
-                    hddlog.transaction_start().ddlog_map_error()?;
-                    hddlog.apply_updates_dynamic(&mut commands.into_iter()).ddlog_map_error()?;
-                    hddlog.transaction_commit_dump_changes().ddlog_map_error()?
-                };
-                delta_to_flow_mods(&delta, config.flow_relid, &mut state.pending_flow_mods);
-                state.latch.set();
+                let new_value = if op == Update_Type::DELETE { None } else { Some(te.value) };
+                Ok(EntityWrite::TableEntry { key: te.key, new_value, commands })
+            },
+            _ => Err(Error(RpcStatusCode::UNIMPLEMENTED))?
+        }
+    }
 
-                // Commit the operation to our internal representation.
-                if op == Update_Type::DELETE {
-                    state.table_entries.remove(&te.key);
+    /// Applies the in-memory half of an `EntityWrite` to `state`.  Callers apply it as soon as the
+    /// write has validated, before `config_change_worker` commits the matching DDlog commands; a
+    /// commit that later fails does not undo it.
+    fn apply_entity_write(write: EntityWrite, state: &mut State) {
+        match write {
+            EntityWrite::MulticastGroup { multicast_group_id, new_replicas, .. } => {
+                if new_replicas.is_empty() {
+                    state.multicast_groups.remove(&multicast_group_id);
                 } else {
-                    state.table_entries.insert(te.key, te.value);
+                    state.multicast_groups.insert(multicast_group_id, new_replicas);
                 }
+            },
+            EntityWrite::TableEntry { key, new_value, .. } => {
+                // A rewrite or deletion means the entry is no longer idle at its old timeout, so
+                // forget that it was ever reported.
+                state.idle_notified.remove(&key);
+                match new_value {
+                    Some(new_value) => { state.table_entries.insert(key, new_value); },
+                    None => { state.table_entries.remove(&key); },
+                }
+            },
+        }
+    }
+
+    /// Translates a P4Runtime `PacketOut` into an OpenFlow packet-out and queues it for
+    /// `run_server` to send, using the P4Info's `"packet_out"` controller packet metadata schema to
+    /// find the `egress_port` the client asked for.  Does nothing (beyond logging) if there's no
+    /// pipeline configured yet, the schema has no `egress_port` field, or the request didn't supply
+    /// it, since there's then no way to know where OVS should send the packet.
+    fn queue_packet_out(state: &mut State, packet: &PacketOut) {
+        let config = match &state.config {
+            Some(config) => config,
+            None => { warn!("dropping packet-out received before pipeline config"); return; },
+        };
+        let egress_port_id = match config.packet_out_metadata.get("egress_port") {
+            Some(&id) => id,
+            None => { warn!("P4Info has no packet_out.egress_port metadata; dropping packet-out"); return; },
+        };
+        let egress_port = packet.get_metadata().iter()
+            .find(|m| m.get_metadata_id() == egress_port_id)
+            .and_then(|m| FieldValue::try_from(&m.get_value().to_vec()).ok());
+        let egress_port = match egress_port {
+            Some(egress_port) => egress_port.0 as u32,
+            None => { warn!("packet-out is missing egress_port metadata; dropping it"); return; },
+        };
 
-                Ok(())
+        let actions = format!("output:{egress_port}");
+        let payload = packet.get_payload().to_vec();
+        match ofp_packet::PacketOut::new(&payload, ovs::sys::ofp_port_OFPP_CONTROLLER as u32, &actions) {
+            Ok(_) => {
+                state.pending_packet_outs.push(PendingPacketOut { payload, actions });
+                state.latch.set();
+            },
+            Err(err) => warn!("failed to build packet-out: {err}"),
+        }
+    }
+
+    /// Records that `key`'s flows are now exactly `new_cookies`, or that `key` has no flows at all
+    /// if `new_cookies` is empty -- called only when `new_cookies` was collected from a delta that
+    /// is known to have resulted entirely from `key`'s own write, so that the association is
+    /// precise rather than a guess.
+    fn set_table_key_cookies(state: &mut State, key: &TableKey, new_cookies: Vec<u64>) {
+        if new_cookies.is_empty() {
+            state.table_key_cookies.remove(key);
+        } else {
+            state.table_key_cookies.insert(key.clone(), new_cookies);
+        }
+    }
+
+    /// Splits `write` into a `ConfigChangeJob`'s two command lists and its cookie attribution
+    /// (`Some` only for a `TableEntry` write, same limit `write_rollback_on_error` has always
+    /// observed for a batch).  Doesn't touch DDlog or `state` -- that's `config_change_worker`'s
+    /// job once the job reaches it over `State::config_changes`.
+    fn entity_write_to_job(write: &EntityWrite) -> ConfigChangeJob {
+        match write {
+            EntityWrite::MulticastGroup { commands, .. } => ConfigChangeJob {
+                typed_commands: commands.clone(),
+                dynamic_commands: Vec::new(),
+                cookie_attribution: None,
+            },
+            EntityWrite::TableEntry { key, new_value, commands } => ConfigChangeJob {
+                typed_commands: Vec::new(),
+                dynamic_commands: commands.clone(),
+                cookie_attribution: Some((key.clone(), new_value.as_ref().map_or(0, |v| v.idle_timeout_ns))),
             },
-            _ => Err(Error(RpcStatusCode::UNIMPLEMENTED))?
         }
     }
 
+    /// Validates a single entity and, if valid, applies its in-memory effect to `state` and
+    /// queues its DDlog commands for `config_change_worker` to commit, for
+    /// `WriteRequest_Atomicity::CONTINUE_ON_ERROR`: a failure here leaves every other entity's
+    /// write (already queued, or still to come) untouched.
+    fn write_entity(op: Update_Type, entity: Option<&Entity>, state: &mut State) -> Result<()> {
+        let write = Self::validate_entity(op, entity, state)?;
+        let job = Self::entity_write_to_job(&write);
+        Self::apply_entity_write(write, state);
+        state.config_changes.send(job).expect("config_change_worker outlives every State handle");
+        Ok(())
+    }
+
+    /// Implements `WriteRequest_Atomicity::CONTINUE_ON_ERROR`: every update is validated and
+    /// queued independently, and the caller gets back one status per update.
+    fn write_continue_on_error(updates: Vec<proto::p4runtime::Update>, state: &mut State) -> Vec<RpcStatusCode> {
+        let mut errors = Vec::with_capacity(updates.len());
+        for proto::p4runtime::Update { field_type: op, entity, .. } in updates {
+            let code = match Self::write_entity(op, entity.as_ref(), state) {
+                Err(error) => {
+                    warn!("{error:?}");
+                    match error.downcast_ref::<Error>() {
+                        Some(Error(code)) => *code,
+                        _ => RpcStatusCode::UNKNOWN
+                    }
+                },
+                Ok(()) => RpcStatusCode::OK,
+            };
+            errors.push(code);
+        }
+        errors
+    }
+
+    /// Implements `WriteRequest_Atomicity::ROLLBACK_ON_ERROR` (the P4Runtime default): every
+    /// update is validated and applied in order, exactly like `write_entity` does one at a time
+    /// for `CONTINUE_ON_ERROR` -- so e.g. an INSERT followed by a MODIFY of the same key in the
+    /// same batch validates the MODIFY against the INSERT that precedes it, not against
+    /// whatever `state` looked like before the batch started. The one `ConfigChangeJob` carrying
+    /// every update's commands is only queued once the whole batch has validated and applied
+    /// cleanly; if any update fails partway through, the three maps it mutates in `state`
+    /// (`table_entries`, `multicast_groups`, `idle_notified`) are restored from the snapshot
+    /// taken before this batch began, so a bad update anywhere in the batch leaves
+    /// DDlog and `state` exactly as they were, and a good batch can't be torn apart by the worker
+    /// into separate transactions.
+    fn write_rollback_on_error(updates: Vec<proto::p4runtime::Update>, state: &mut State) -> Result<Vec<RpcStatusCode>> {
+        let saved_table_entries = state.table_entries.clone();
+        let saved_multicast_groups = state.multicast_groups.clone();
+        let saved_idle_notified = state.idle_notified.clone();
+
+        let mut typed_commands = Vec::new();
+        let mut dynamic_commands = Vec::new();
+        let mut table_entry_writes: Vec<(TableKey, i64)> = Vec::new();
+        let mut num_updates = 0;
+
+        for proto::p4runtime::Update { field_type: op, entity, .. } in updates {
+            num_updates += 1;
+            let write = match Self::validate_entity(op, entity.as_ref(), state) {
+                Ok(write) => write,
+                Err(error) => {
+                    state.table_entries = saved_table_entries;
+                    state.multicast_groups = saved_multicast_groups;
+                    state.idle_notified = saved_idle_notified;
+                    return Err(error);
+                }
+            };
+
+            match &write {
+                EntityWrite::MulticastGroup { commands, .. } => typed_commands.extend(commands.iter().cloned()),
+                EntityWrite::TableEntry { key, new_value, commands } => {
+                    dynamic_commands.extend(commands.iter().cloned());
+                    table_entry_writes.push((key.clone(), new_value.as_ref().map_or(0, |v| v.idle_timeout_ns)));
+                },
+            }
+            Self::apply_entity_write(write, state);
+        }
+
+        // Unlike a lone `write_entity` job, this job's delta can mix the flows of several
+        // entities together, so it can only be attributed back to a single `TableKey` when this
+        // batch wrote exactly one table entry.  For a larger batch, whatever cookies were
+        // previously recorded for its table entries are dropped here rather than risk mismatching
+        // them to the wrong key, and `config_change_worker` installs any new flows with no idle
+        // timeout.
+        let cookie_attribution = match table_entry_writes.as_slice() {
+            [(key, idle_timeout_ns)] => Some((key.clone(), *idle_timeout_ns)),
+            entries => {
+                for (key, _) in entries {
+                    state.table_key_cookies.remove(key);
+                }
+                None
+            },
+        };
+
+        state.config_changes.send(ConfigChangeJob { typed_commands, dynamic_commands, cookie_attribution })
+            .expect("config_change_worker outlives every State handle");
+
+        Ok(vec![RpcStatusCode::OK; num_updates])
+    }
+
     #[instrument(name = "Read", err, skip(self))]
     fn do_read(&mut self, req: ReadRequest) -> Result<Vec<ReadResponse>, grpcio::RpcStatus> {
         let _span = span!(Level::INFO, "read").entered();
         let state = self.state.lock().unwrap();
-        if req.device_id != state.device_id {
+        if req.device_id != state.settings.device_id {
             return Err(grpcio::RpcStatus::new(RpcStatusCode::NOT_FOUND));
         }
 
@@ -460,32 +1025,39 @@ impl P4RuntimeService {
     fn do_write(&mut self, req: WriteRequest) -> Result<WriteResponse, grpcio::RpcStatus> {
         let _span = span!(Level::INFO, "write").entered();
         let mut state = self.state.lock().unwrap();
-        if req.device_id != state.device_id {
+        if req.device_id != state.settings.device_id {
             return Err(grpcio::RpcStatus::new(RpcStatusCode::NOT_FOUND));
         }
         if state.config.is_none() {
             return Err(grpcio::RpcStatus::new(RpcStatusCode::FAILED_PRECONDITION));
         }
 
-        // XXX role
-        // XXX election_id
-        // XXX atomicity
+        let election_id = (req.get_election_id().get_high(), req.get_election_id().get_low());
+        match state.roles.get(&req.get_role_id()) {
+            None => return Err(grpcio::RpcStatus::new(RpcStatusCode::FAILED_PRECONDITION)),
+            Some(role) if role.primary_election_id == Some(election_id) => (),
+            Some(_) => return Err(grpcio::RpcStatus::new(RpcStatusCode::PERMISSION_DENIED)),
+        }
 
-        let mut errors = Vec::with_capacity(req.updates.len());
-        for proto::p4runtime::Update { field_type: op, entity, .. } in req.updates {
-            let code = match Self::write_entity(op, entity.as_ref(), &mut state) {
-                Err(error) => {
-                    warn!("{error:?}");
-                    match error.downcast_ref::<Error>() {
-                        Some(Error(code)) => *code,
-                        _ => RpcStatusCode::UNKNOWN
-                    }
+        let errors = match req.get_atomicity() {
+            WriteRequest_Atomicity::DATAPLANE_ATOMIC =>
+                return Err(grpcio::RpcStatus::new(RpcStatusCode::UNIMPLEMENTED)),
+            WriteRequest_Atomicity::CONTINUE_ON_ERROR =>
+                Self::write_continue_on_error(req.updates, &mut state),
+            WriteRequest_Atomicity::ROLLBACK_ON_ERROR =>
+                match Self::write_rollback_on_error(req.updates, &mut state) {
+                    Ok(errors) => errors,
+                    Err(error) => {
+                        warn!("{error:?}");
+                        let code = match error.downcast_ref::<Error>() {
+                            Some(Error(code)) => *code,
+                            _ => RpcStatusCode::UNKNOWN
+                        };
+                        return Err(grpcio::RpcStatus::new(code));
+                    },
                 },
-                Ok(()) => RpcStatusCode::OK,
-            };
-            errors.push(code);
-        }
-        if errors.iter().all(|&code| code != RpcStatusCode::OK) {
+        };
+        if errors.iter().any(|&code| code != RpcStatusCode::OK) {
             let (message, unknown_fields, cached_size) = Default::default();
             let details = proto::status::Status {
                 code: RpcStatusCode::UNKNOWN.into(),
@@ -509,7 +1081,7 @@ impl P4RuntimeService {
         -> Result<GetForwardingPipelineConfigResponse, grpcio::RpcStatus>
     {
         let state = self.state.lock().unwrap();
-        if req.device_id != state.device_id {
+        if req.device_id != state.settings.device_id {
             return Err(grpcio::RpcStatus::new(RpcStatusCode::NOT_FOUND));
         }
         let config = match state.config {
@@ -526,36 +1098,102 @@ impl P4RuntimeService {
             ..Default::default()})
     }
 
+    /// Reinstalls every `entity` from a saved snapshot (see `save_config`/`load_config`) through
+    /// the normal single-entity write path (`write_entity`), choosing `INSERT` or `MODIFY` for each
+    /// one depending on whether its key already exists in `state` -- e.g. it won't, right after a
+    /// restart replays a snapshot into an empty `state`, but may already, during a
+    /// `RECONCILE_AND_COMMIT` that only actually changes some of the saved entries.  An entity
+    /// that's no longer valid under `state`'s current pipeline (e.g. its table was removed) is
+    /// skipped with a warning rather than failing the whole reconcile.
+    fn reconcile_entities(state: &mut State, entities: Vec<Entity>) {
+        for entity in entities {
+            let op = match &entity.entity {
+                Some(Entity_oneof_entity::table_entry(te)) => {
+                    let te: Result<TableEntry, _> = te.try_into();
+                    match te {
+                        Ok(te) if state.table_entries.contains_key(&te.key) => Update_Type::MODIFY,
+                        _ => Update_Type::INSERT,
+                    }
+                },
+                Some(Entity_oneof_entity::packet_replication_engine_entry(
+                    PacketReplicationEngineEntry {
+                        field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(mge)), ..
+                    })) => {
+                    if state.multicast_groups.contains_key(&mge.multicast_group_id) {
+                        Update_Type::MODIFY
+                    } else {
+                        Update_Type::INSERT
+                    }
+                },
+                _ => continue,
+            };
+            if let Err(error) = Self::write_entity(op, Some(&entity), state) {
+                warn!("failed to reconcile saved entity {entity:?}: {error:?}");
+            }
+        }
+    }
+
     #[instrument(name = "SetForwardingPipelineConfig", err, skip(self))]
     fn do_set_forwarding_pipeline_config(&mut self, req: SetForwardingPipelineConfigRequest)
         -> Result<SetForwardingPipelineConfigResponse, grpcio::RpcStatus>
     {
-        // XXX check action, device_id, role, election_id
+        // XXX check device_id, role, election_id
+        let invalid = || grpcio::RpcStatus::new(RpcStatusCode::INVALID_ARGUMENT);
 
         let mut state = self.state.lock().unwrap();
-        match Config::new(req.get_config(), &state.hddlog) {
-            Ok(config) => {
+        match req.get_action() {
+            SetForwardingPipelineConfigRequest_Action::VERIFY => {
+                Config::new(req.get_config(), &state.hddlog).map_err(|_| invalid())?;
+                Ok(SetForwardingPipelineConfigResponse::new())
+            },
+            SetForwardingPipelineConfigRequest_Action::VERIFY_AND_SAVE => {
+                Config::new(req.get_config(), &state.hddlog).map_err(|_| invalid())?;
+                let config_file = state.config_file.clone().ok_or_else(invalid)?;
+                save_config(&config_file, req.get_config(), &state).map_err(|_| invalid())?;
+                Ok(SetForwardingPipelineConfigResponse::new())
+            },
+            SetForwardingPipelineConfigRequest_Action::VERIFY_AND_COMMIT => {
+                let config = Config::new(req.get_config(), &state.hddlog).map_err(|_| invalid())?;
+                if let Some(config_file) = state.config_file.clone() {
+                    save_config(&config_file, req.get_config(), &state).map_err(|_| invalid())?;
+                }
                 state.config = Some(config);
                 state.config_seqno += 1;
                 state.latch.set();
                 Ok(SetForwardingPipelineConfigResponse::new())
             },
-            Err(_) => Err(grpcio::RpcStatus::new(RpcStatusCode::INVALID_ARGUMENT))
+            SetForwardingPipelineConfigRequest_Action::RECONCILE_AND_COMMIT => {
+                let config_file = state.config_file.clone().ok_or_else(invalid)?;
+                let (saved_fpc, entities) = load_config(&config_file).map_err(|_| invalid())?;
+                if saved_fpc.get_cookie().get_cookie() != req.get_config().get_cookie().get_cookie() {
+                    return Err(invalid());
+                }
+                let config = Config::new(req.get_config(), &state.hddlog).map_err(|_| invalid())?;
+                state.config = Some(config);
+                state.config_seqno += 1;
+                Self::reconcile_entities(&mut state, entities);
+                state.latch.set();
+                Ok(SetForwardingPipelineConfigResponse::new())
+            },
+            _ => Err(grpcio::RpcStatus::new(RpcStatusCode::UNIMPLEMENTED)),
         }
     }
-
-        }
+}
 
 impl<'a> P4Runtime for P4RuntimeService {
     fn write(&mut self, ctx: RpcContext, req: WriteRequest, sink: UnarySink<WriteResponse>) {
-        unary_result(&ctx, sink, self.do_write(req));
+        let result = self.do_write(req);
+        self.state.lock().unwrap().metrics.record_write(rpc_result_code(&result));
+        unary_result(&ctx, sink, result);
     }
 
     fn read(&mut self,
             ctx: RpcContext,
             req: ReadRequest,
             sink: ServerStreamingSink<ReadResponse>) {
-        server_streaming_result(&ctx, sink, self.do_read(req));
+        let result = self.do_read(req);
+        self.state.lock().unwrap().metrics.record_read(rpc_result_code(&result));
+        server_streaming_result(&ctx, sink, result);
     }
 
     fn set_forwarding_pipeline_config(
@@ -563,7 +1201,9 @@ impl<'a> P4Runtime for P4RuntimeService {
         ctx: RpcContext,
         req: SetForwardingPipelineConfigRequest,
         sink: UnarySink<SetForwardingPipelineConfigResponse>) {
-        unary_result(&ctx, sink, self.do_set_forwarding_pipeline_config(req))
+        let result = self.do_set_forwarding_pipeline_config(req);
+        self.state.lock().unwrap().metrics.record_set_forwarding_pipeline_config(rpc_result_code(&result));
+        unary_result(&ctx, sink, result)
     }
 
     fn get_forwarding_pipeline_config(
@@ -577,14 +1217,107 @@ impl<'a> P4Runtime for P4RuntimeService {
     fn stream_channel(
         &mut self,
         ctx: RpcContext,
-        mut stream: RequestStream<StreamMessageRequest>,
+        stream: RequestStream<StreamMessageRequest>,
         mut sink: DuplexSink<StreamMessageResponse>) {
+        let state = self.state.clone();
+        state.lock().unwrap().metrics.active_streams += 1;
         let f = async move {
-            while let Some(n) = stream.try_next().await? {
-                let mut reply = StreamMessageResponse::new();
-                reply.set_arbitration(n.get_arbitration().clone());
-                sink.send((reply, grpcio::WriteFlags::default())).await?;
+            // Events pushed here by another `stream_channel` task (via `state.roles`) when this
+            // stream's role gets a new primary; merged below with `stream` itself so we can react
+            // to either without blocking on the other.
+            let (push_tx, push_rx) = mpsc::unbounded();
+
+            enum Event {
+                Request(StreamMessageRequest),
+                Push(StreamMessageResponse),
+            }
+            let requests = stream.map_ok(Event::Request);
+            let pushes = push_rx.map(|r| Ok(Event::Push(r)));
+            let mut events = futures_util::stream::select(requests, pushes);
+
+            // Set once this stream has registered itself under a role, so it's only registered
+            // (and only ever removed) once, no matter how many arbitration updates it sends.
+            let mut registered: Option<(u64, u64)> = None;
+
+            while let Some(event) = events.try_next().await? {
+                match event {
+                    Event::Push(reply) => {
+                        sink.send((reply, grpcio::WriteFlags::default())).await?;
+                    },
+                    Event::Request(req) => {
+                        if req.has_packet() {
+                            let mut state = state.lock().unwrap();
+                            Self::queue_packet_out(&mut state, req.get_packet());
+                            continue;
+                        }
+                        if !req.has_arbitration() {
+                            continue;
+                        }
+                        let update = req.get_arbitration();
+                        let device_id = update.get_device_id();
+                        let role_id = update.get_role_id();
+                        let election_id = (update.get_election_id().get_high(), update.get_election_id().get_low());
+
+                        let mut state = state.lock().unwrap();
+                        let stream_id = match registered {
+                            Some((_, stream_id)) => stream_id,
+                            None => {
+                                let stream_id = state.next_stream_id;
+                                state.next_stream_id += 1;
+                                stream_id
+                            },
+                        };
+
+                        let role = state.roles.entry(role_id).or_default();
+                        if registered.is_none() {
+                            role.notify.insert(stream_id, push_tx.clone());
+                            registered = Some((role_id, stream_id));
+                        }
+
+                        let is_new_primary = match role.primary_election_id {
+                            Some(primary_election_id) => election_id > primary_election_id,
+                            None => true,
+                        };
+                        if is_new_primary {
+                            role.primary_election_id = Some(election_id);
+                            role.primary_stream_id = Some(stream_id);
+                        }
+                        let primary_election_id = role.primary_election_id.unwrap();
+                        let is_primary = election_id == primary_election_id;
+
+                        if is_new_primary {
+                            // A new primary took over: tell every other stream sharing this role
+                            // that it's (still, or newly) a backup. A dead receiver means that
+                            // stream has already disconnected, so drop it while we're here.
+                            let mut backup_reply = StreamMessageResponse::new();
+                            backup_reply.set_arbitration(make_arbitration(device_id, role_id, primary_election_id, false));
+                            role.notify.retain(|&other_stream_id, notify| {
+                                other_stream_id == stream_id ||
+                                    notify.unbounded_send(backup_reply.clone()).is_ok()
+                            });
+                        }
+                        drop(state);
+
+                        let mut reply = StreamMessageResponse::new();
+                        reply.set_arbitration(make_arbitration(device_id, role_id, primary_election_id, is_primary));
+                        sink.send((reply, grpcio::WriteFlags::default())).await?;
+                    },
+                }
+            }
+
+            {
+                let mut state = state.lock().unwrap();
+                if let Some((role_id, stream_id)) = registered {
+                    if let Some(role) = state.roles.get_mut(&role_id) {
+                        role.notify.remove(&stream_id);
+                        if role.primary_stream_id == Some(stream_id) {
+                            role.primary_stream_id = None;
+                        }
+                    }
+                }
+                state.metrics.active_streams -= 1;
             }
+
             sink.close().await?;
             Ok(())
         }
@@ -627,200 +1360,1071 @@ fn flow_record_to_string(record: &Record) -> Option<&String> {
     record_as_string(record.get_struct_field("flow")?)
 }
 
-fn flow_record_to_flow_mod(record: &Record) -> Result<FlowMod> {
+fn flow_record_to_flow_mod(record: &Record, state: &State) -> Result<PendingFlowMod> {
     let flow = flow_record_to_string(&record).ok_or(anyhow!("Flow record {record} lacks 'flow' field"))?;
-    match FlowMod::parse(flow, Some(FlowModCommand::Add)) {
-        Ok((flow, _)) => Ok(flow),
-        Err(s) => Err(anyhow!("{flow}: {s}"))
+    // Tag with the same cookie `delta_to_flow_mods` would have used for this flow, so that a
+    // flow resent here after an OVS reconnect still correlates with `State::table_key_cookies`,
+    // and look back up whatever idle timeout that table entry currently has.
+    let cookie = flow_cookie(flow);
+    let spec = match state.idle_timeout_seconds_for_cookie(cookie) {
+        Some(seconds) => format!("cookie=0x{cookie:x},idle_timeout={seconds},{flow}"),
+        None => format!("cookie=0x{cookie:x},{flow}"),
+    };
+    PendingFlowMod::new(PendingFlowModCommand::Add, spec)
+}
+
+/// Appends `msg`, length-prefixed, to `w`, so that `read_message` can later tell where it ends
+/// without needing to know the file's total message count up front.
+fn write_message(w: &mut impl Write, msg: &impl Message) -> Result<()> {
+    let bytes = msg.write_to_bytes()?;
+    w.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    w.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads one message written by `write_message` from `r`, or `None` at a clean end of stream.
+fn read_message<M: Message>(r: &mut impl Read) -> Result<Option<M>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => Err(error)?,
+    }
+    let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+    r.read_exact(&mut bytes)?;
+    Ok(Some(M::parse_from_bytes(&bytes)?))
+}
+
+/// Saves `fpc` together with every table entry and multicast group currently in `state` to
+/// `path`, in the style of an embedded key-value store's state snapshot, so that `load_config` can
+/// restore them later -- on a restart (see `main`) or a `RECONCILE_AND_COMMIT` request (see
+/// `do_set_forwarding_pipeline_config`).
+fn save_config(path: &Path, fpc: &ForwardingPipelineConfig, state: &State) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path).with_context(|| format!("{}: create failed", path.display()))?);
+    write_message(&mut w, fpc)?;
+    for entity in state.read_table_entries(&Default::default()) {
+        write_message(&mut w, &entity)?;
+    }
+    for entity in state.read_multicast_groups(0) {
+        write_message(&mut w, &entity)?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// Loads the `ForwardingPipelineConfig` and entities that `save_config` wrote to `path`.
+fn load_config(path: &Path) -> Result<(ForwardingPipelineConfig, Vec<Entity>)> {
+    let mut r = BufReader::new(File::open(path).with_context(|| format!("{}: open failed", path.display()))?);
+    let fpc = read_message(&mut r)?.ok_or_else(|| anyhow!("{}: empty config file", path.display()))?;
+    let mut entities = Vec::new();
+    while let Some(entity) = read_message(&mut r)? {
+        entities.push(entity);
+    }
+    Ok((fpc, entities))
+}
+
+/// Renders `state`'s `Metrics`, plus a couple of gauges read directly off `state` itself, as a
+/// Prometheus text-exposition-format document.
+fn render_metrics(state: &State) -> String {
+    let metrics = &state.metrics;
+    let mut out = String::new();
+
+    out.push_str("# HELP ofp4_read_requests_total Read RPCs, by final gRPC status code.\n");
+    out.push_str("# TYPE ofp4_read_requests_total counter\n");
+    for (code, count) in &metrics.read_total {
+        out.push_str(&format!("ofp4_read_requests_total{{code=\"{code}\"}} {count}\n"));
+    }
+
+    out.push_str("# HELP ofp4_write_requests_total Write RPCs, by final gRPC status code.\n");
+    out.push_str("# TYPE ofp4_write_requests_total counter\n");
+    for (code, count) in &metrics.write_total {
+        out.push_str(&format!("ofp4_write_requests_total{{code=\"{code}\"}} {count}\n"));
+    }
+
+    out.push_str("# HELP ofp4_set_forwarding_pipeline_config_requests_total \
+                  SetForwardingPipelineConfig RPCs, by final gRPC status code.\n");
+    out.push_str("# TYPE ofp4_set_forwarding_pipeline_config_requests_total counter\n");
+    for (code, count) in &metrics.set_forwarding_pipeline_config_total {
+        out.push_str(&format!("ofp4_set_forwarding_pipeline_config_requests_total{{code=\"{code}\"}} {count}\n"));
+    }
+
+    out.push_str("# HELP ofp4_ddlog_commit_seconds Latency of DDlog transaction_commit_dump_changes calls.\n");
+    out.push_str("# TYPE ofp4_ddlog_commit_seconds summary\n");
+    out.push_str(&format!("ofp4_ddlog_commit_seconds_sum {}\n", metrics.ddlog_commit_seconds_sum));
+    out.push_str(&format!("ofp4_ddlog_commit_seconds_count {}\n", metrics.ddlog_commit_seconds_count));
+
+    out.push_str("# HELP ofp4_config_change_batch_size Jobs merged into each DDlog transaction by a config change worker.\n");
+    out.push_str("# TYPE ofp4_config_change_batch_size summary\n");
+    out.push_str(&format!("ofp4_config_change_batch_size_sum {}\n", metrics.config_change_batch_size_sum));
+    out.push_str(&format!("ofp4_config_change_batch_size_count {}\n", metrics.config_change_batch_size_count));
+
+    out.push_str("# HELP ofp4_flow_mods_flushed_total Flow mods sent to OVS after an OVS reconnect.\n");
+    out.push_str("# TYPE ofp4_flow_mods_flushed_total counter\n");
+    out.push_str(&format!("ofp4_flow_mods_flushed_total {}\n", metrics.flow_mods_flushed_total));
+
+    out.push_str("# HELP ofp4_active_streams Open P4Runtime StreamChannel connections.\n");
+    out.push_str("# TYPE ofp4_active_streams gauge\n");
+    out.push_str(&format!("ofp4_active_streams {}\n", metrics.active_streams));
+
+    out.push_str("# HELP ofp4_table_entries Currently installed table entries.\n");
+    out.push_str("# TYPE ofp4_table_entries gauge\n");
+    out.push_str(&format!("ofp4_table_entries {}\n", state.table_entries.len()));
+
+    out.push_str("# HELP ofp4_multicast_groups Currently configured multicast groups.\n");
+    out.push_str("# TYPE ofp4_multicast_groups gauge\n");
+    out.push_str(&format!("ofp4_multicast_groups {}\n", state.multicast_groups.len()));
+
+    out
+}
+
+/// Serves one `GET /metrics` request on `stream` with `state`'s current `render_metrics` output,
+/// ignoring the request's path -- this is the only document the endpoint has to offer.
+fn handle_metrics_request(mut stream: TcpStream, state: &Arc<Mutex<State>>) -> Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(stream.try_clone()?).read_line(&mut request_line)?;
+
+    let body = render_metrics(&state.lock().unwrap());
+    write!(stream,
+           "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+           body.len(), body)?;
+    Ok(())
+}
+
+/// Spawns a background thread that serves Prometheus text-format metrics (see `render_metrics`)
+/// over plain HTTP on `addr`, one connection at a time, for as long as the process runs.
+fn serve_metrics(addr: &str, state: Arc<Mutex<State>>) -> Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("{addr}: bind failed"))?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => if let Err(error) = handle_metrics_request(stream, &state) {
+                    warn!("failed to serve metrics request: {error:?}");
+                },
+                Err(error) => warn!("metrics listener accept failed: {error:?}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Renders the daemon's current `Flow` relation (see `flow_record_to_flow_mod`) as OpenFlow
+/// `ovs-ofctl`-style text, for the control socket's `dump-flows` command.  Returns an empty list
+/// if there's no pipeline configured yet.
+fn dump_flows(state: &State) -> Vec<String> {
+    let config = match &state.config {
+        Some(config) => config,
+        None => return Vec::new(),
+    };
+    state.hddlog.dump_index_dynamic(config.flow_idxid).unwrap_or_default().into_iter()
+        .filter_map(|record| flow_record_to_flow_mod(&record, state).ok())
+        .map(|pending| ovs::ofp_print::Printer(pending.encode().as_slice()).to_string())
+        .collect()
+}
+
+/// Executes one control-socket request (see `serve_control_socket`) against `state` and returns
+/// the JSON reply.
+fn handle_control_request(state: &Arc<Mutex<State>>, request: &Value) -> Value {
+    match request.get("command").and_then(Value::as_str) {
+        Some("dump-flows") => {
+            let state = state.lock().unwrap();
+            json!({ "flows": dump_flows(&state) })
+        },
+        Some("status") => {
+            let state = state.lock().unwrap();
+            let bridges: Vec<Value> = state.bridges.iter().map(|(bridge_id, status)| json!({
+                "bridge_id": bridge_id,
+                "connected": status.connected,
+                "connection_seqno": status.connection_seqno,
+            })).collect();
+            json!({
+                "device_id": state.settings.device_id,
+                "ovs_remotes": state.settings.ovs_remotes,
+                "p4_addr": state.settings.p4_addr,
+                "p4_port": state.settings.p4_port,
+                "log_file": state.settings.log_file,
+                "ddlog_record": state.settings.ddlog_record,
+                "bridges": bridges,
+                "config_seqno": state.config_seqno,
+                "pending_flow_mods": state.pending_flow_mods.len(),
+            })
+        },
+        // Reconnects every bridge, or only `bridge_id` if the request names one.
+        Some("reconnect") => {
+            let mut state = state.lock().unwrap();
+            match request.get("bridge_id").and_then(Value::as_u64) {
+                Some(bridge_id) => { state.bridges.entry(bridge_id).or_default().force_reconnect = true; },
+                None => for status in state.bridges.values_mut() { status.force_reconnect = true; },
+            }
+            state.latch.set();
+            json!({})
+        },
+        Some("ddlog-profile") => {
+            let state = state.lock().unwrap();
+            json!({ "profile": state.hddlog.profile().to_string() })
+        },
+        Some(other) => json!({ "error": format!("unknown command '{other}'") }),
+        None => json!({ "error": "request is missing a 'command' field" }),
+    }
+}
+
+/// Serves one control-socket connection: reads newline-delimited JSON requests from `stream` and
+/// writes back one newline-delimited JSON reply per request, until the peer closes its end.
+fn handle_control_connection(stream: UnixStream, state: &Arc<Mutex<State>>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let response = match serde_json::from_str::<Value>(line.trim()) {
+            Ok(request) => handle_control_request(state, &request),
+            Err(error) => json!({ "error": format!("invalid JSON request: {error}") }),
+        };
+        writeln!(writer, "{response}")?;
+    }
+}
+
+/// Spawns a background thread that listens on `path` for newline-delimited JSON requests (see
+/// `handle_control_request`) and serves each connection on its own thread -- an admin/debugging
+/// surface that doesn't require a P4Runtime client, mirroring `serve_metrics`.  Any stale socket
+/// file left behind by a previous run is removed first, the way OVS's own `unixctl` sockets are.
+fn serve_control_socket(path: PathBuf, state: Arc<Mutex<State>>) -> Result<()> {
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).with_context(|| format!("{}: bind failed", path.display()))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let state = state.clone();
+                    thread::spawn(move || {
+                        if let Err(error) = handle_control_connection(stream, &state) {
+                            warn!("control socket connection failed: {error:?}");
+                        }
+                    });
+                },
+                Err(error) => warn!("control socket accept failed: {error:?}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Installs handlers for `SIGTERM`, `SIGINT`, and `SIGHUP` on a background thread, in the style of
+/// `daemon::cleanup`'s fatal-signal handler.  `SIGTERM`/`SIGINT` ask `run_server`'s loop to wind
+/// down cleanly by setting `State::shutting_down` and waking `state.latch` -- the same self-pipe
+/// `run_server` already blocks on, so this needs no separate plumbing into `ovs::poll_loop`.
+/// `SIGHUP` reopens `log_file` (if any), for log rotation, and reconciles `config_file` (if any)
+/// back into `state` -- the same path `main` uses to restore a saved config at startup -- so an
+/// operator can edit the saved snapshot and have it re-applied without restarting the daemon.
+fn spawn_signal_handler(state: Arc<Mutex<State>>, config_file: Option<PathBuf>,
+                        log_file: Option<log_target::ReopenableFile>) -> Result<()> {
+    let mut signals = Signals::new(&[SIGTERM, SIGINT, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGHUP => {
+                    if let Some(ref log_file) = log_file {
+                        if let Err(error) = log_file.reopen() {
+                            warn!("failed to reopen log file: {error:?}");
+                        }
+                    }
+                    if let Some(ref config_file) = config_file {
+                        match load_config(config_file) {
+                            Ok((_fpc, entities)) => {
+                                let mut state = state.lock().unwrap();
+                                info!("SIGHUP: reconciling {} saved entities from {}",
+                                      entities.len(), config_file.display());
+                                P4RuntimeService::reconcile_entities(&mut state, entities);
+                            },
+                            Err(error) => warn!("{}: failed to reload config ({error:?})", config_file.display()),
+                        }
+                    }
+                },
+                SIGTERM | SIGINT => {
+                    info!("received signal {signal}, shutting down");
+                    let mut state = state.lock().unwrap();
+                    state.shutting_down = true;
+                    state.latch.set();
+                },
+                _ => unreachable!(),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The shape of a `--config` settings file (see `FileConfig::load`). Every field is optional and,
+/// if given, overrides the corresponding built-in default -- but is itself overridden by the
+/// matching CLI flag, if the CLI flag is also given; see `main`'s merge into `DaemonSettings`.
+/// Covers the log target, record target, and OVS remote set plus the rest of the startup
+/// settings, so a deployment can template its whole switch/P4Runtime topology in one file instead
+/// of a long command line.  The file is read once, at startup; `spawn_signal_handler`'s `SIGHUP`
+/// handling does not re-read it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FileConfig {
+    ovs_remotes: Option<Vec<String>>,
+    p4_addr: Option<String>,
+    p4_port: Option<u16>,
+    device_id: Option<u64>,
+    log_file: Option<PathBuf>,
+    ddlog_record: Option<PathBuf>,
+}
+
+impl FileConfig {
+    /// Reads and parses `path` as TOML. Dhall was also suggested as a format for this file, but
+    /// it'd pull in a much heavier dependency for a need that's so far hypothetical, so only TOML
+    /// is implemented; add a Dhall branch here (keyed off the file extension, say) if that
+    /// actually becomes wanted.
+    fn load(path: &Path) -> Result<FileConfig> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("{}: read failed", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("{}: failed to parse as TOML", path.display()))
+    }
+}
+
+/// The daemon's fully resolved runtime settings, after merging a `--config` settings file (see
+/// `FileConfig`) over built-in defaults and then CLI flags over that. Stored in `State` so the
+/// control socket's `status` command (see `handle_control_request`) can report exactly what's
+/// active, including values that came from the settings file rather than the command line.
+#[derive(Debug, Clone)]
+struct DaemonSettings {
+    ovs_remotes: Vec<String>,
+    p4_addr: String,
+    p4_port: u16,
+    device_id: u64,
+    log_file: Option<PathBuf>,
+    ddlog_record: Option<PathBuf>,
+}
+
+const DEFAULT_P4_ADDR: &str = "127.0.0.1";
+const DEFAULT_P4_PORT: u16 = 50051;
+const DEFAULT_DEVICE_ID: u64 = 1;
+
+/// TLS/mutual-TLS settings for the P4Runtime listening socket, flattened into `Args`.  Absent
+/// `--tls-cert`, the server listens in plaintext, matching every `ofp4` invocation before this was
+/// added.
+#[derive(Parser, Debug)]
+struct Tls {
+    /// PEM file with the server's TLS certificate chain.  Enables TLS; requires `--tls-key`.
+    #[clap(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key matching `--tls-cert`.
+    #[clap(long)]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file of CA certificates trusted to sign a client certificate.  When given, the server
+    /// requires and verifies a client certificate for mutual TLS; otherwise any client that trusts
+    /// `--tls-cert` can connect.
+    #[clap(long)]
+    tls_client_ca: Option<PathBuf>,
+}
+
+impl Tls {
+    /// Builds grpcio server credentials from these settings, or `None` if TLS wasn't requested (no
+    /// `--tls-cert`).
+    fn server_credentials(&self) -> Result<Option<ServerCredentials>> {
+        let (cert_path, key_path) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            (None, None) => return Ok(None),
+            _ => return Err(anyhow!("--tls-cert and --tls-key must be given together")),
+        };
+        let cert = std::fs::read(cert_path).with_context(|| format!("{}: read failed", cert_path.display()))?;
+        let key = std::fs::read(key_path).with_context(|| format!("{}: read failed", key_path.display()))?;
+        let mut builder = ServerCredentialsBuilder::new().add_cert(cert, key);
+        if let Some(ca_path) = &self.tls_client_ca {
+            let ca = std::fs::read(ca_path).with_context(|| format!("{}: read failed", ca_path.display()))?;
+            builder = builder.root_cert(ca, CertificateRequestType::RequestAndRequireClientCertificateAndVerify);
+        }
+        Ok(Some(builder.build()))
     }
 }
 
 #[derive(Parser, Debug)]
 #[clap(version, about)]
 struct Args {
-    /// OVS remote to connect, e.g. "unix:/path/to/ovs/tutorial/sandbox/br0.mgmt"
-    ovs_remote: String,
-
-    /// P4Runtime connection listening port
-    #[clap(long, default_value = "50051")]
-    p4_port: u16,
+    /// Runs an offline subcommand instead of the daemon proper (currently just `replay`); absent,
+    /// `ofp4` runs the daemon using the rest of these arguments.
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// OVS remote(s) to connect, one per bridge, e.g. "unix:/path/to/ovs/tutorial/sandbox/br0.mgmt".
+    /// Every bridge is driven from the same DDlog program (see `BridgeConn`); give more than one to
+    /// fan the same flows and multicast groups out to more than one OVS instance.  A remote may be
+    /// prefixed with "<bridge-id>=" to set the P4Runtime-visible bridge id explicitly (e.g.
+    /// "2=unix:/path/br1.mgmt"); otherwise bridges are numbered sequentially (see
+    /// `parse_ovs_remote`).  May instead (or additionally) be given as `ovs_remotes` in `--config`'s
+    /// settings file; either way, at least one is required.
+    #[clap(required_unless_present_any = ["command", "settings_file"])]
+    ovs_remotes: Vec<String>,
+
+    /// P4Runtime connection listening port.  Defaults to the settings file's `p4_port`, or 50051
+    /// if that's not given either.
+    #[clap(long)]
+    p4_port: Option<u16>,
 
-    /// P4Runtime connection bind address
-    #[clap(long, default_value = "127.0.0.1")]
-    p4_addr: String,
+    /// P4Runtime connection bind address.  Defaults to the settings file's `p4_addr`, or
+    /// "127.0.0.1" if that's not given either.
+    #[clap(long)]
+    p4_addr: Option<String>,
 
-    /// P4Runtime device ID
-    #[clap(long, default_value = "1")]
-    device_id: u64,
+    /// P4Runtime device ID.  Defaults to the settings file's `device_id`, or 1 if that's not given
+    /// either.
+    #[clap(long)]
+    device_id: Option<u64>,
 
     #[clap(flatten)]
     daemonize: Daemonize,
 
-    /// File to write logs to
+    #[clap(flatten)]
+    tls: Tls,
+
+    /// File to write logs to.  Falls back to the settings file's `log_file` if not given; see
+    /// `DaemonSettings`.  Equivalent to `--log-target file:<path>`; if both are given,
+    /// `--log-target` wins.
     #[clap(long)]
     log_file: Option<PathBuf>,
 
-    /// File to write DDlog replay log to
+    /// Where to send `tracing` output: `stderr` (the default, unless `--log-file` is given),
+    /// `syslog` (the local logd via `/dev/log`, or `--syslog-remote` if that's unreachable), or
+    /// `file:<path>` (equivalent to `--log-file`).  Useful for keeping logs flowing once
+    /// `Daemonize` detaches the controlling terminal, since stderr is usually closed or
+    /// redirected to `/dev/null` at that point.
+    #[clap(long)]
+    log_target: Option<log_target::LogTarget>,
+
+    /// Syslog facility to tag `--log-target syslog` messages with.
+    #[clap(long, default_value = "daemon")]
+    syslog_facility: log_target::Facility,
+
+    /// Remote syslog endpoint to fall back to if `--log-target syslog` can't reach the local
+    /// `/dev/log`, as `"udp:<host>:<port>"` or `"tcp:<host>:<port>"`.
+    #[clap(long)]
+    syslog_remote: Option<String>,
+
+    /// File to write DDlog replay log to.  Falls back to the settings file's `ddlog_record` if not
+    /// given; see `DaemonSettings`.
+    #[clap(long)]
+    ddlog_record: Option<PathBuf>,
+
+    /// TOML file of settings (`ovs_remotes`, `p4_addr`, `p4_port`, `device_id`, `log_file`,
+    /// `ddlog_record`; see `FileConfig`) to use as a base layer under the CLI flags above, so a
+    /// deployment can template its switch/P4Runtime topology declaratively instead of assembling a
+    /// long command line.  The resolved result is kept in `State` (see `DaemonSettings`) for the
+    /// control socket's `status` command.  The file is read once, at startup; `SIGHUP` does not
+    /// re-read it.
+    #[clap(long = "config")]
+    settings_file: Option<PathBuf>,
+
+    /// File to persist the forwarding pipeline config and table state to, so they survive a
+    /// restart.  Required for `SetForwardingPipelineConfig`'s `VERIFY_AND_SAVE` and
+    /// `RECONCILE_AND_COMMIT` actions.
+    #[clap(long)]
+    config_file: Option<PathBuf>,
+
+    /// Address to serve Prometheus text-format metrics on, e.g. "127.0.0.1:9090".  Metrics are
+    /// disabled unless this is given.
+    #[clap(long)]
+    metrics_addr: Option<String>,
+
+    /// Unix domain socket to listen on for newline-delimited JSON control requests (`dump-flows`,
+    /// `status`, `reconnect`, `ddlog-profile`), for debugging and automation.  Disabled unless
+    /// this is given.
+    #[clap(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Number of worker threads that commit queued config changes to DDlog (see
+    /// `config_change_worker`).  A worker holds `State`'s mutex for the whole commit, so only one
+    /// transaction is in flight at a time, and the P4Runtime service threads and `run_server`'s
+    /// OpenFlow loop wait for the mutex while it runs.
+    #[clap(long, default_value = "2")]
+    config_change_workers: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Replays a command log recorded with `--ddlog-record`, printing the `FlowMod`s each
+    /// recorded transaction would have produced; see `run_replay`.
+    Replay(ReplayArgs),
+}
+
+#[derive(Parser, Debug)]
+struct ReplayArgs {
+    /// Command log previously recorded with `--ddlog-record`.
+    record_file: PathBuf,
+
+    /// The P4 module name the trace was recorded under (`p4info.pkg_info.name`, see `Config::new`)
+    /// -- needed to find the `Flow` relation (`<module>::Flow`) since replay has no
+    /// `ForwardingPipelineConfig` to read it from.
+    module: String,
+
+    /// Pause after each transaction and print the resulting `Flow` relation (the same table
+    /// `dump-flows` reads, see `dump_flows`), instead of running straight through the whole log.
     #[clap(long)]
-    ddlog_record: Option<PathBuf>
+    step: bool,
+}
+
+/// Replays `args.record_file` -- a command log recorded by a previous run's `--ddlog-record` --
+/// offline: instantiates a fresh `ofp4dl_ddlog` program, feeds the recorded transactions back in
+/// the order they were committed, and after each commit prints the `FlowMod` sequence
+/// `delta_to_flow_mods` derives from that transaction's delta, in `ovs::ofp_print` textual form --
+/// what `run_server` would have sent to OVS, except that no flow carries an `idle_timeout`,
+/// reproduced from nothing but the log, with no OVS connection of any kind. With `--step`, pauses after each transaction for a line on stdin
+/// and dumps the resulting `Flow` relation, so a developer can diff the exact flow table a
+/// production daemon produced at each point of a captured control-plane trace.
+fn run_replay(args: ReplayArgs) -> Result<()> {
+    let (hddlog, _init_state) = ofp4dl_ddlog::run(1, false).ddlog_map_error()?;
+    let flow_relname = format!("{}::Flow", args.module);
+    let flow_relid = hddlog.inventory.get_table_id(&flow_relname).ddlog_map_error()? as RelId;
+
+    let file = File::open(&args.record_file)
+        .with_context(|| format!("{}: open failed", args.record_file.display()))?;
+    let commands = ofp4dl_ddlog::cmd_parser::parse_commands(BufReader::new(file))
+        .with_context(|| format!("{}: failed to parse recorded commands", args.record_file.display()))?;
+
+    // Tracks the `Flow` relation's current contents ourselves, by applying each transaction's
+    // delta to it in turn, rather than re-querying DDlog for it -- there's no loaded
+    // `Config::flow_idxid` to dump an index by outside of a running daemon with a pipeline
+    // configured, and the log's deltas already carry everything needed to reconstruct it.
+    let mut current_flows = BTreeSet::new();
+
+    let mut transaction = Vec::new();
+    let mut transaction_no = 0;
+    for command in commands {
+        match command {
+            ofp4dl_ddlog::cmd_parser::Command::Start => transaction.clear(),
+            ofp4dl_ddlog::cmd_parser::Command::Update(upd_cmd) => transaction.push(upd_cmd),
+            ofp4dl_ddlog::cmd_parser::Command::Commit => {
+                transaction_no += 1;
+                hddlog.transaction_start().ddlog_map_error()?;
+                hddlog.apply_updates_dynamic(&mut transaction.drain(..)).ddlog_map_error()?;
+                let delta = hddlog.transaction_commit_dump_changes().ddlog_map_error()?;
+
+                let mut flow_mods = Vec::new();
+                let mut new_cookies = Vec::new();
+                delta_to_flow_mods(&delta, flow_relid, &mut flow_mods, &mut new_cookies, 0);
+                println!("-- transaction {transaction_no} --");
+                for pending in &flow_mods {
+                    println!("{}", ovs::ofp_print::Printer(pending.encode().as_slice()));
+                }
+
+                for (&rel, changes) in delta.iter() {
+                    if rel == flow_relid {
+                        for (val, weight) in changes.iter() {
+                            let flow = flow_t::from_ddvalue_ref(val);
+                            match weight {
+                                1 => { current_flows.insert(flow.flow.clone()); },
+                                -1 => { current_flows.remove(&flow.flow); },
+                                _ => unreachable!()
+                            }
+                        }
+                    }
+                }
+
+                if args.step {
+                    println!("-- Flow relation after transaction {transaction_no} --");
+                    for flow in &current_flows {
+                        println!("{flow}");
+                    }
+                    println!("-- press Enter to continue --");
+                    let mut line = String::new();
+                    std::io::stdin().read_line(&mut line)?;
+                }
+            },
+            ofp4dl_ddlog::cmd_parser::Command::Other => (),
+        }
+    }
+    Ok(())
+}
+
+/// Splits one `ovs_remotes` argument into a bridge id and an OVS remote.  `arg` may be prefixed
+/// with "<bridge-id>=" to set the id explicitly, in which case `*next_id` is bumped past it so
+/// that later, unprefixed arguments don't collide with it; otherwise the bridge gets `*next_id`,
+/// which is then incremented.
+fn parse_ovs_remote(arg: &str, next_id: &mut u64) -> (u64, String) {
+    match arg.split_once('=') {
+        Some((id, remote)) if id.parse::<u64>().is_ok() => {
+            let id = id.parse().unwrap();
+            *next_id = (*next_id).max(id + 1);
+            (id, remote.to_string())
+        },
+        _ => {
+            let id = *next_id;
+            *next_id += 1;
+            (id, arg.to_string())
+        },
+    }
+}
+
+/// Opens a DDlog transaction, applies `typed_commands` and `dynamic_commands`, and commits,
+/// recording the commit's latency in `state.metrics`. Factored out of `config_change_worker` so the `?`
+/// short-circuit on a DDlog failure doesn't have to fight the borrow of `state.hddlog` held across
+/// the whole transaction.
+fn commit_config_change(state: &mut State, typed_commands: Vec<Update<DDValue>>,
+                        dynamic_commands: Vec<UpdCmd>) -> Result<DeltaMap<DDValue>> {
+    let hddlog = &state.hddlog;
+    hddlog.transaction_start().ddlog_map_error()?;
+    if !typed_commands.is_empty() {
+        hddlog.apply_updates(&mut typed_commands.into_iter()).ddlog_map_error()?;
+    }
+    if !dynamic_commands.is_empty() {
+        hddlog.apply_updates_dynamic(&mut dynamic_commands.into_iter()).ddlog_map_error()?;
+    }
+    let start = Instant::now();
+    let delta = hddlog.transaction_commit_dump_changes().ddlog_map_error()?;
+    state.metrics.record_ddlog_commit(start.elapsed());
+    Ok(delta)
+}
+
+/// Drains `jobs` forever, each time taking the first queued `ConfigChangeJob` with a blocking
+/// `recv()` and then, without blocking again, pulling in whatever else has arrived in the
+/// meantime with `try_recv()` -- so a burst of writes that land close together rides in the same
+/// DDlog transaction and amortizes one `transaction_commit_dump_changes` call across all of them,
+/// while a lone write still commits right away instead of waiting for company. Several of these
+/// can run at once (see `--config-change-workers`), but each one holds `state`'s mutex from
+/// `transaction_start` through updating `pending_flow_mods` and `table_key_cookies` and waking
+/// `latch`, so their transactions run one at a time.
+fn config_change_worker(state: Arc<Mutex<State>>, jobs: Receiver<ConfigChangeJob>) {
+    while let Ok(first) = jobs.recv() {
+        let mut batch = vec![first];
+        while let Ok(job) = jobs.try_recv() {
+            batch.push(job);
+        }
+
+        let mut typed_commands = Vec::new();
+        let mut dynamic_commands = Vec::new();
+        for job in &batch {
+            typed_commands.extend(job.typed_commands.iter().cloned());
+            dynamic_commands.extend(job.dynamic_commands.iter().cloned());
+        }
+        // A merged delta can only be attributed back to a single `TableKey` when every job in the
+        // batch agrees on one -- any `MulticastGroup` job (`None`) or more than one distinct
+        // `TableEntry` job spoils it, same as `write_rollback_on_error` already observes for a
+        // single RPC's batch.
+        let cookie_attribution = match batch.as_slice() {
+            [job] => job.cookie_attribution.clone(),
+            _ => {
+                let first = batch[0].cookie_attribution.clone();
+                if first.is_some() && batch.iter().all(|job| job.cookie_attribution == first) {
+                    first
+                } else {
+                    None
+                }
+            },
+        };
+
+        let mut state = state.lock().unwrap();
+        let delta = match commit_config_change(&mut state, typed_commands, dynamic_commands) {
+            Ok(delta) => delta,
+            Err(error) => { error!("config change worker failed to commit DDlog transaction: {error:?}"); continue; },
+        };
+        state.metrics.record_config_change_batch(batch.len());
+
+        let idle_timeout_ns = cookie_attribution.as_ref().map_or(0, |(_, idle_timeout_ns)| *idle_timeout_ns);
+        let mut new_cookies = Vec::new();
+        if let Some(config) = &state.config {
+            let flow_relid = config.flow_relid;
+            delta_to_flow_mods(&delta, flow_relid, &mut state.pending_flow_mods, &mut new_cookies, idle_timeout_ns);
+        }
+        if let Some((key, _)) = &cookie_attribution {
+            P4RuntimeService::set_table_key_cookies(&mut state, key, new_cookies);
+        }
+        state.latch.set();
+    }
+}
+
+/// Spawns `num_workers` instances of `config_change_worker`, all draining the same `jobs` queue.
+fn spawn_config_change_workers(state: Arc<Mutex<State>>, jobs: Receiver<ConfigChangeJob>, num_workers: usize) {
+    for _ in 0..num_workers.max(1) {
+        let state = state.clone();
+        let jobs = jobs.clone();
+        thread::spawn(move || config_change_worker(state, jobs));
+    }
 }
 
 // Runs the server main loop, servicing P4Runtime requests from `state` and applying them to OVS
-// via `rconn`.  After initialization completes, finishes daemonization using `daemonizing`, if it
-// is not `None`.
-fn run_server(state: Arc<Mutex<State>>, mut rconn: Rconn, mut daemonizing: Option<Daemonizing>) -> Result<()> {
-    let mut last_connection_seqno = 0;
-    let mut last_config_seqno = 0;
-    let mut bundle_id = 0;
+// via `bridges`.  After initialization completes, finishes daemonization using `daemonizing`, if
+// it is not `None`.
+// How often to ask OVS for flow statistics, to keep `State::cookie_counters` fresh for `Read`
+// RPCs.  There's no push notification for counters, so this just has to poll.
+const STATS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+fn run_server(state: Arc<Mutex<State>>, mut bridges: Vec<BridgeConn>, mut daemonizing: Option<Daemonizing>) -> Result<()> {
+    let mut next_stats_poll = Instant::now();
     loop {
-        rconn.run();
-        while let Some(msg) = rconn.recv() {
-            match OfpType::decode(msg.as_slice()) {
-                Ok(OfpType(ovs::sys::ofptype_OFPTYPE_BUNDLE_CONTROL)) => {
-                    if let Ok(bcm) = BundleCtrlMsg::decode(msg.as_slice()) {
-                        if bcm.type_ == OFPBCT_COMMIT_REPLY {
-                            // Our request to commit a bundle succeeded or failed.  Either way,
-                            // we've finished starting up, so it's time to detach from the
-                            // foreground session.
-                            if let Some(daemonizing) = daemonizing.take() {
-                                daemonizing.finish();
+        if state.lock().unwrap().shutting_down {
+            let mut state = state.lock().unwrap();
+            for bridge in &mut bridges {
+                if bridge.rconn.connected() && !state.pending_flow_mods.is_empty() {
+                    let flags = ovs::ofp_bundle::OFPBF_ATOMIC | ovs::ofp_bundle::OFPBF_ORDERED;
+                    bridge.bundle_id += 1;
+                    let bundle = ovs::ofp_bundle::BundleSequence::new(bridge.bundle_id, flags, OFP_VERSION,
+                                                                      state.pending_flow_mods.iter().map(PendingFlowMod::encode));
+                    for msg in bundle {
+                        bridge.rconn.send(msg).unwrap();
+                    }
+                }
+            }
+            // A daemon that's told to stop before it ever finished starting up (e.g. it never
+            // got a bundle commit reply) still has to resolve `daemonizing`, or the parent process
+            // that's waiting on it would hang forever.
+            if let Some(daemonizing) = daemonizing.take() {
+                daemonizing.finish();
+            }
+            info!("shut down");
+            return Ok(());
+        }
+
+        for bridge in &mut bridges {
+            bridge.rconn.run();
+            while let Some(msg) = bridge.rconn.recv() {
+                match OfpType::decode(msg.as_slice()) {
+                    Ok(OfpType(ovs::sys::ofptype_OFPTYPE_BUNDLE_CONTROL)) => {
+                        if let Ok(bcm) = BundleCtrlMsg::decode(msg.as_slice()) {
+                            if bcm.type_ == OFPBCT_COMMIT_REPLY {
+                                // Our request to commit a bundle to some bridge succeeded or
+                                // failed.  Either way, we've finished starting up, so it's time to
+                                // detach from the foreground session.
+                                if let Some(daemonizing) = daemonizing.take() {
+                                    daemonizing.finish();
+                                }
                             }
                         }
-                    }
-                },
-                _ => println!("received message {}", ovs::ofp_print::Printer(msg.as_slice()))
+                    },
+                    Ok(OfpType(ovs::sys::ofptype_OFPTYPE_FLOW_STATS_REPLY)) => {
+                        match ovs::ofp_stats::decode_flow_stats_reply(msg.as_slice()) {
+                            Ok(stats) => {
+                                let mut state = state.lock().unwrap();
+                                for stat in stats {
+                                    let (unknown_fields, cached_size) = Default::default();
+                                    state.cookie_counters.insert(stat.cookie, proto::p4runtime::CounterData {
+                                        byte_count: stat.byte_count as i64,
+                                        packet_count: stat.packet_count as i64,
+                                        unknown_fields, cached_size
+                                    });
+                                    state.cookie_idle_age.insert(stat.cookie, stat.idle_age);
+                                }
+
+                                let due = state.due_idle_timeouts();
+                                if !due.is_empty() {
+                                    let mut notification = IdleTimeoutNotification::new();
+                                    notification.set_table_entry(due.iter().map(|te| te.into()).collect());
+                                    let mut response = StreamMessageResponse::new();
+                                    response.set_idle_timeout_notification(notification);
+                                    state.notify_primary(response);
+                                }
+                            },
+                            Err(err) => warn!("failed to decode flow stats reply: {err}")
+                        }
+                    },
+                    Ok(OfpType(ovs::sys::ofptype_OFPTYPE_PACKET_IN)) => {
+                        match ovs::ofp_packet::decode_packet_in(msg.as_slice()) {
+                            Ok(pin) => {
+                                let state = state.lock().unwrap();
+                                let config = match &state.config {
+                                    Some(config) => config,
+                                    None => { warn!("dropping packet-in received before pipeline config"); continue; },
+                                };
+                                let ingress_port_id = match config.packet_in_metadata.get("ingress_port") {
+                                    Some(&id) => id,
+                                    None => { warn!("P4Info has no packet_in.ingress_port metadata; dropping packet-in"); continue; },
+                                };
+
+                                let mut metadatum = PacketMetadata::new();
+                                metadatum.set_metadata_id(ingress_port_id);
+                                metadatum.set_value(FieldValue::from(pin.in_port as u128).into());
+
+                                let mut p_pin = PacketIn::new();
+                                p_pin.set_payload(pin.packet);
+                                p_pin.set_metadata(RepeatedField::from_vec(vec![metadatum]));
+
+                                let mut response = StreamMessageResponse::new();
+                                response.set_packet(p_pin);
+                                state.notify_primary(response);
+                            },
+                            Err(err) => warn!("failed to decode packet-in: {err}")
+                        }
+                    },
+                    _ => println!("received message from bridge {}: {}", bridge.bridge_id,
+                                   ovs::ofp_print::Printer(msg.as_slice()))
+                }
             }
         }
 
-        state.lock().unwrap().latch.poll();
-        if rconn.connected() {
+        // Reconnect whichever bridges the control socket asked to reconnect (see
+        // `handle_control_request`).
+        let to_reconnect: Vec<u64> = {
             let mut state = state.lock().unwrap();
+            bridges.iter()
+                .filter(|bridge| state.bridges.entry(bridge.bridge_id).or_default().force_reconnect)
+                .map(|bridge| bridge.bridge_id)
+                .collect()
+        };
+        for bridge in &mut bridges {
+            if to_reconnect.contains(&bridge.bridge_id) {
+                let mut state = state.lock().unwrap();
+                state.bridges.entry(bridge.bridge_id).or_default().force_reconnect = false;
+                drop(state);
+                info!("control socket requested reconnect of bridge {}", bridge.bridge_id);
+                bridge.rconn.reconnect();
+            }
+        }
+
+        {
+            let mut state = state.lock().unwrap();
+            for bridge in &bridges {
+                let status = state.bridges.entry(bridge.bridge_id).or_default();
+                status.connected = bridge.rconn.connected();
+                status.connection_seqno = bridge.rconn.connection_seqno();
+            }
+        }
 
+        state.lock().unwrap().latch.poll();
+        {
+            let mut state = state.lock().unwrap();
             let flags = ovs::ofp_bundle::OFPBF_ATOMIC | ovs::ofp_bundle::OFPBF_ORDERED;
-            if rconn.connection_seqno() == last_connection_seqno &&
-                state.config_seqno == last_config_seqno
-            {
-                // Send pending flow mods, if any.
-                if !state.pending_flow_mods.is_empty() {
-                    bundle_id += 1;
-                    let bundle = ovs::ofp_bundle::BundleSequence::new(bundle_id, flags, OFP_VERSION,
-                                                                      state.pending_flow_mods.drain(..));
+            for bridge in &mut bridges {
+                if !bridge.rconn.connected() {
+                    continue;
+                }
+
+                if bridge.rconn.connection_seqno() == bridge.last_connection_seqno &&
+                    state.config_seqno == bridge.last_config_seqno
+                {
+                    // Send pending flow mods, if any.
+                    if !state.pending_flow_mods.is_empty() {
+                        bridge.bundle_id += 1;
+                        let bundle = ovs::ofp_bundle::BundleSequence::new(bridge.bundle_id, flags, OFP_VERSION,
+                                                                          state.pending_flow_mods.iter().map(PendingFlowMod::encode));
+                        for msg in bundle {
+                            bridge.rconn.send(msg).unwrap();
+                        }
+                    }
+                } else {
+                    // This bridge just (re)connected, or the pipeline config changed.  Send it all
+                    // the flows.  Pending flow mods, if any, are discarded below (without being
+                    // sent to this bridge) because the full collection of flows already includes
+                    // them.
+                    //
+                    // Compose a sequence of flow_mods starting with one to delete all the existing
+                    // flows, then add in all the flows we do want.  We're going to put all of these
+                    // together into an atomic bundle, so we shouldn't change the treatment of all
+                    // the packets in the middle.
+                    let mut flow_mods = Vec::new();
+                    flow_mods.push(FlowMod::parse("", Some(FlowModCommand::Delete { strict: false })).unwrap().0.encode(OFP_PROTOCOL));
+                    if let Some(ref config) = state.config {
+                        flow_mods.extend(state.hddlog.dump_index_dynamic(config.flow_idxid).unwrap().into_iter()
+                                         .filter_map(|record| match flow_record_to_flow_mod(&record, &state) {
+                                             Ok(pending) => Some(pending.encode()),
+                                             Err(err) => { event!(Level::ERROR, "flow failed to parse: {err}"); None }
+                                         }));
+                    };
+
+                    state.metrics.record_flow_mods_flushed(flow_mods.len() as u64);
+
+                    bridge.bundle_id += 1;
+                    let bundle = ovs::ofp_bundle::BundleSequence::new(bridge.bundle_id, flags, OFP_VERSION, flow_mods.into_iter());
                     for msg in bundle {
-                        rconn.send(msg).unwrap();
+                        bridge.rconn.send(msg).unwrap();
                     }
-                }
-            } else {
-                // We just reconnected.  Send all the flows.  Discard pending flow mods, if any,
-                // because the full collection of flows includes them.
-                state.pending_flow_mods.clear();
-
-                // Compose a sequence of flow_mods starting with one to delete all the existing
-                // flows, then add in all the flows we do want.  We're going to put all of these
-                // together into an atomic bundle, so we shouldn't change the treatment of all the
-                // packets in the middle.
-                let mut flow_mods = Vec::new();
-                flow_mods.push(FlowMod::parse("", Some(FlowModCommand::Delete { strict: false })).unwrap().0);
-                if let Some(ref config) = state.config {
-                    flow_mods.extend(state.hddlog.dump_index_dynamic(config.flow_idxid).unwrap().into_iter()
-                                     .filter_map(|record| match flow_record_to_flow_mod(&record) {
-                                         Ok(fm) => Some(fm),
-                                         Err(err) => { event!(Level::ERROR, "flow failed to parse: {err}"); None }
-                                     }));
-                };
 
-                // Encode the flow_mods into OpenFlow and send them.
-                let flow_mods = flow_mods.into_iter().map(|fm| fm.encode(OFP_PROTOCOL));
-                bundle_id += 1;
-                let bundle = ovs::ofp_bundle::BundleSequence::new(bundle_id, flags, OFP_VERSION, flow_mods);
-                for msg in bundle {
-                    rconn.send(msg).unwrap();
+                    bridge.last_connection_seqno = bridge.rconn.connection_seqno();
+                    bridge.last_config_seqno = state.config_seqno;
                 }
 
-                last_connection_seqno = rconn.connection_seqno();
-                last_config_seqno = state.config_seqno;
+                // Packet-outs aren't part of the resynced state above -- they're one-shot actions,
+                // not flows to keep installed -- so just send whatever's pending, unbundled.
+                for pending in &state.pending_packet_outs {
+                    match pending.encode() {
+                        Ok(msg) => { bridge.rconn.send(msg).unwrap(); },
+                        Err(err) => warn!("failed to encode packet-out: {err}")
+                    }
+                }
             }
-        } else {
-            // We're disconnected.  We can't send pending flow mods.  When we reconnect, we'll send
-            // everything.
-            let mut state = state.lock().unwrap();
+
+            // Every connected bridge has now seen (or, if disconnected, can't use) this round's
+            // pending flow mods and packet-outs.  A dropped packet-out to a disconnected bridge is
+            // just lost; a dropped flow mod isn't, because a bridge that reconnects gets the full
+            // flow set resent above.
             state.pending_flow_mods.clear();
+            state.pending_packet_outs.clear();
+        }
+
+        if Instant::now() >= next_stats_poll {
+            for bridge in &mut bridges {
+                if bridge.rconn.connected() {
+                    match FlowStatsRequest::parse("") {
+                        Ok(fsr) => { bridge.rconn.send(fsr.encode(OFP_PROTOCOL)).unwrap(); },
+                        Err(err) => warn!("failed to build flow stats request: {err}")
+                    }
+                }
+            }
+            next_stats_poll = Instant::now() + STATS_POLL_INTERVAL;
         }
 
         state.lock().unwrap().latch.wait();
-        rconn.run_wait();
-        rconn.recv_wait();
+        for bridge in &mut bridges {
+            bridge.rconn.run_wait();
+            bridge.rconn.recv_wait();
+        }
+        ovs::poll_loop::timer_wait(next_stats_poll.saturating_duration_since(Instant::now()).as_millis() as i64);
         ovs::poll_loop::block();
     }
 }
 
 fn main() -> Result<()> {
     log_panics::init();
-    let Args { ovs_remote, p4_port, p4_addr, device_id,
-               daemonize, log_file, ddlog_record } = Args::parse();
-    if let Some(log_file) = log_file {
-        let writer = OpenOptions::new().create(true).append(true).open(log_file)?;
-        tracing_subscriber::fmt()
-            .with_writer(writer)
-            .with_ansi(false)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_writer(stderr)
-            .with_ansi(unsafe { libc::isatty(libc::STDERR_FILENO) } == 1)
-            .init();
+    let args = Args::parse();
+    if let Some(Command::Replay(replay_args)) = args.command {
+        return run_replay(replay_args);
+    }
+    let Args { ovs_remotes, p4_port, p4_addr, device_id,
+               daemonize, tls, log_file, log_target, syslog_facility, syslog_remote, ddlog_record,
+               config_file, metrics_addr, control_socket, config_change_workers, settings_file, .. } = args;
+
+    let file_settings = match &settings_file {
+        Some(path) => FileConfig::load(path)?,
+        None => FileConfig::default(),
+    };
+    let settings = DaemonSettings {
+        ovs_remotes: if !ovs_remotes.is_empty() { ovs_remotes } else { file_settings.ovs_remotes.unwrap_or_default() },
+        p4_addr: p4_addr.or(file_settings.p4_addr).unwrap_or_else(|| DEFAULT_P4_ADDR.to_string()),
+        p4_port: p4_port.or(file_settings.p4_port).unwrap_or(DEFAULT_P4_PORT),
+        device_id: device_id.or(file_settings.device_id).unwrap_or(DEFAULT_DEVICE_ID),
+        log_file: log_file.or(file_settings.log_file),
+        ddlog_record: ddlog_record.or(file_settings.ddlog_record),
     };
+    if settings.ovs_remotes.is_empty() {
+        return Err(anyhow!("at least one OVS remote is required, via positional arguments or \
+                             the settings file's 'ovs_remotes'"));
+    }
+
+    let log_target = log_target.unwrap_or_else(|| match &settings.log_file {
+        Some(path) => log_target::LogTarget::File(path.clone()),
+        None => log_target::LogTarget::Stderr,
+    });
+    let log_file = log_target::init(&log_target, syslog_facility, syslog_remote.as_deref())?;
     grpcio::redirect_log();
     let (daemonizing, _cleanup) = unsafe { daemonize.start() };
     let daemonizing = Some(daemonizing);
 
     let env = Arc::new(Environment::new(1));
     let (mut hddlog, _init_state) = ofp4dl_ddlog::run(1, false).ddlog_map_error()?;
-    if let Some(ref ddlog_record) = ddlog_record {
+    if let Some(ref ddlog_record) = settings.ddlog_record {
         let mut record = Some(File::create(ddlog_record).with_context(|| format!("{}: open failed", ddlog_record.display()))?);
         hddlog.record_commands(&mut record);
     }
 
-    let state = Arc::new(Mutex::new(State::new(hddlog, device_id)));
+    let (config_change_tx, config_change_rx) = unbounded();
+    let state = Arc::new(Mutex::new(State::new(hddlog, settings.clone(), config_file.clone(), config_change_tx)));
+    spawn_config_change_workers(state.clone(), config_change_rx, config_change_workers);
+    if let Some(ref config_file) = config_file {
+        if config_file.exists() {
+            let (fpc, entities) = load_config(config_file)
+                .with_context(|| format!("{}: failed to load saved config", config_file.display()))?;
+            let mut state = state.lock().unwrap();
+            let config = Config::new(&fpc, &state.hddlog)
+                .with_context(|| format!("{}: saved config is no longer valid", config_file.display()))?;
+            info!("Restoring saved P4 module '{}' and {} entities", config.module, entities.len());
+            state.config = Some(config);
+            state.config_seqno += 1;
+            P4RuntimeService::reconcile_entities(&mut state, entities);
+        }
+    }
+    if let Some(ref metrics_addr) = metrics_addr {
+        serve_metrics(metrics_addr, state.clone())?;
+    }
+    if let Some(control_socket) = control_socket {
+        serve_control_socket(control_socket, state.clone())?;
+    }
+    spawn_signal_handler(state.clone(), config_file, log_file)?;
     let service = create_p4_runtime(P4RuntimeService::new(state.clone()));
     let ch_builder = ChannelBuilder::new(env.clone());
-    let mut server = ServerBuilder::new(env)
+    let server_builder = ServerBuilder::new(env)
         .register_service(service)
-        .bind(p4_addr, p4_port)
-        .channel_args(ch_builder.build_args())
-        .build()
-        .unwrap();
+        .channel_args(ch_builder.build_args());
+    let server_builder = match tls.server_credentials()? {
+        Some(creds) => server_builder.bind_secure(settings.p4_addr.clone(), settings.p4_port, creds),
+        None => server_builder.bind(settings.p4_addr.clone(), settings.p4_port),
+    };
+    let mut server = server_builder.build().unwrap();
     server.start();
 
-    if p4_port == 0 {
+    if settings.p4_port == 0 {
         for (addr, port) in server.bind_addrs() {
             event!(Level::INFO, "Listening on {addr}:{port}");
         }
     }
 
-    let mut rconn = Rconn::new(0, 0, ovs::rconn::DSCP_DEFAULT, OFP_VERSION.into());
-    rconn.connect(&ovs_remote, None);
+    let mut next_bridge_id = 0;
+    let bridges: Vec<BridgeConn> = settings.ovs_remotes.iter()
+        .map(|arg| parse_ovs_remote(arg, &mut next_bridge_id))
+        .map(|(bridge_id, remote)| BridgeConn::new(bridge_id, &remote))
+        .collect();
+
+    let result = run_server(state, bridges, daemonizing);
+    // `bridges` was owned by `run_server` and is dropped along with it on return, above; tear down
+    // the gRPC server here too, so a clean `SIGTERM`/`SIGINT` shutdown releases both before `main`
+    // exits.
+    drop(server);
+    result
+}
+
+/// Derives a stable OpenFlow cookie from a `Flow` relation row's `ovs-ofctl` flow spec.  The same
+/// spec always hashes to the same cookie, so a flow keeps its cookie whether it's installed from
+/// an incremental `delta` or resent in full after an OVS reconnect (see `flow_record_to_flow_mod`).
+fn flow_cookie(flow: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    flow.hash(&mut hasher);
+    hasher.finish()
+}
 
-    run_server(state, rconn, daemonizing)
+/// Converts a P4Runtime `idle_timeout_ns` into the nearest OpenFlow idle timeout that's no
+/// shorter, in whole seconds, clamped to what `idle_timeout` can represent in a flow spec.
+fn idle_timeout_seconds(idle_timeout_ns: i64) -> u16 {
+    let seconds = (idle_timeout_ns + 999_999_999) / 1_000_000_000;
+    seconds.clamp(0, u16::MAX as i64) as u16
 }
 
-/// Converts the `delta` of changes to DDlog output relations (particularly `Flow`) into OpenFlow
-/// [`FlowMod`] messages and appends those messages to `flow_mods`.
+/// Converts the `delta` of changes to DDlog output relations (particularly `Flow`) into
+/// [`PendingFlowMod`]s and appends them to `flow_mods` -- one queue shared by every bridge (see
+/// `BridgeConn`), since this daemon has no way to tell from `delta` alone which bridge a flow is
+/// meant for -- tagging each with a cookie (see `flow_cookie`) so that a later flow-stats reply
+/// can be attributed back to it. Every cookie from a newly inserted flow is appended to
+/// `new_cookies`, for the caller to associate with whatever entity this delta resulted from.
+/// `idle_timeout_ns`, if nonzero, is applied to every newly inserted flow; pass 0 if the delta
+/// mixes entities with different (or no) idle timeouts, since there's no way to tell from `delta`
+/// alone which flow came from which entity.
 fn delta_to_flow_mods(delta: &DeltaMap<DDValue>,
                       flow_relid: RelId,
-                      flow_mods: &mut Vec<Ofpbuf>) {
+                      flow_mods: &mut Vec<PendingFlowMod>,
+                      new_cookies: &mut Vec<u64>,
+                      idle_timeout_ns: i64) {
     for (&rel, changes) in delta.iter() {
         if rel == flow_relid {
             for (val, weight) in changes.iter() {
                 let command = match weight {
-                    1 => FlowModCommand::Add,
-                    -1 => FlowModCommand::Delete { strict: true },
+                    1 => PendingFlowModCommand::Add,
+                    -1 => PendingFlowModCommand::DeleteStrict,
                     _ => unreachable!()
                 };
 
                 let flow = flow_t::from_ddvalue_ref(val);
-                match FlowMod::parse(&flow.flow, Some(command)) {
-                    Ok((flow_mod, _)) => flow_mods.push(flow_mod.encode(OFP_PROTOCOL)),
-                    Err(s) => warn!("{flow}: {s}")
+                let cookie = flow_cookie(&flow.flow);
+                if weight == 1 {
+                    new_cookies.push(cookie);
+                }
+                let spec = if weight == 1 && idle_timeout_ns != 0 {
+                    format!("cookie=0x{cookie:x},idle_timeout={},{}", idle_timeout_seconds(idle_timeout_ns), flow.flow)
+                } else {
+                    format!("cookie=0x{cookie:x},{}", flow.flow)
+                };
+                match PendingFlowMod::new(command, spec) {
+                    Ok(pending) => flow_mods.push(pending),
+                    Err(error) => warn!("{error:?}")
                 };
             }
         }
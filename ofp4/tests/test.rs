@@ -1,8 +1,8 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use daemon::Cleanup;
 use futures_util::{sink::SinkExt, stream::StreamExt};
-use grpcio::{ChannelBuilder, EnvBuilder};
-use p4ext::{MatchField, Table};
+use grpcio::{ChannelBuilder, EnvBuilder, RpcStatusCode};
+use p4ext::Table;
 use proto::p4info::P4Info;
 use proto::p4runtime::{
     Action,
@@ -12,8 +12,11 @@ use proto::p4runtime::{
     FieldMatch_Exact,
     FieldMatch_oneof_field_match_type,
     ForwardingPipelineConfig,
+    GetForwardingPipelineConfigRequest,
     MasterArbitrationUpdate,
     MulticastGroupEntry,
+    PacketMetadata,
+    PacketOut,
     PacketReplicationEngineEntry,
     PacketReplicationEngineEntry_oneof_type,
     Replica,
@@ -42,6 +45,8 @@ use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, ExitStatus, Stdio};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
 use tracing::{debug, info};
 use tracing_test::traced_test;
 
@@ -240,19 +245,103 @@ where P: AsRef<Path>,
     }
 }
 
+/// Tails an append-only log file, broadcasting each complete line as it's written, so a caller can
+/// `wait_for_line` a regex instead of reading the whole file once and hoping the line it wants has
+/// already landed. That single-shot read is exactly the race `start_switch` used to run waiting for
+/// ofp4 to log its P4Runtime listening address: ofp4 detaches immediately on startup, and its
+/// launcher (the `Child` our `Command` actually waits on) can exit before the detached daemon has
+/// gotten as far as opening its P4Runtime socket and logging where.
+struct LogTail {
+    lines: broadcast::Sender<String>,
+    _task: tokio::task::JoinHandle<()>,
+}
+
+impl LogTail {
+    /// Starts tailing `path` from the beginning, retrying its initial open (since the process
+    /// that'll create it may not have run yet) for up to `open_timeout`.
+    async fn open(path: &Path, open_timeout: Duration) -> Result<LogTail> {
+        let deadline = tokio::time::Instant::now() + open_timeout;
+        let file = loop {
+            match File::open(path) {
+                Ok(file) => break file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && tokio::time::Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                },
+                Err(e) => Err(e).with_context(|| format!("{}: open failed", path.display()))?,
+            }
+        };
+
+        let (tx, _) = broadcast::channel(1024);
+        let tx2 = tx.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            use std::io::BufRead;
+            // Start reading only once the first `wait_for_line` has subscribed, so it sees every
+            // line from the start of the file.
+            while tx2.receiver_count() == 0 {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let mut reader = std::io::BufReader::new(file);
+            let mut line = String::new();
+            loop {
+                match reader.read_line(&mut line) {
+                    Ok(0) => std::thread::sleep(Duration::from_millis(20)),
+                    // No newline yet: the line is only partly written, so keep reading onto it.
+                    Ok(_) if !line.ends_with('\n') => (),
+                    Ok(_) => {
+                        let _ = tx2.send(line.trim_end().to_string());
+                        line.clear();
+                    },
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(LogTail { lines: tx, _task: task })
+    }
+
+    /// Waits up to `timeout` for a line matching `re`, and returns it.  The tail doesn't start
+    /// reading until the first call subscribes, so the first call sees every line from the start
+    /// of the file; a later call doesn't see lines that were read before it subscribed.
+    async fn wait_for_line(&self, re: &Regex, timeout: Duration) -> Result<String> {
+        let mut rx = self.lines.subscribe();
+        let wait = async {
+            loop {
+                match rx.recv().await {
+                    Ok(line) if re.is_match(&line) => return Ok(line),
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) =>
+                        return Err(anyhow!("log tail ended before a line matched {:?}", re.as_str())),
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait).await
+            .with_context(|| format!("timed out waiting for a line matching {:?}", re.as_str()))?
+    }
+}
+
 const DEVICE_ID: u64 = 1;
 
 fn election_id() -> Uint128 {
     Uint128 { high: 0, low: 1, ..Default::default() }
 }
 
-async fn start_ofp4(p4info: P4Info) -> Result<(Cleanup, PathBuf, P4RuntimeClient)> {
-    grpcio::redirect_log();
-    
-    let mut cleanup = Cleanup::new()?;
-    if let Ok(_) = std::env::var("KEEP_TMPDIR") {
-        cleanup.keep_temp_dirs();
-    }
+/// Starts one switch's `ovsdb-server`/`ovs-vswitchd`/ofp4 triple inside a fresh temporary
+/// directory registered with `cleanup` (which keeps its pidfiles, sockets, and logs from
+/// colliding with any other switch's), configures its `br0` bridge with `ports`, and
+/// `cables[port]`, if present, gives the `options:stream` to set on that port's interface (used by
+/// `start_fabric` to wire a dummy "cable" to another switch's port instead of a normal interface).
+/// If `tls` is given, a `(server certificate path, server key path)` pair, ofp4's P4Runtime
+/// listener is started with `--tls-cert`/`--tls-key` and the client connects over TLS trusting
+/// that certificate; otherwise the connection is plaintext.  Connects a P4Runtime session to the
+/// new ofp4 instance and installs `p4info` into it.  Returns the temporary directory (for
+/// `trace_flow`) and the connected client.
+async fn start_switch(
+    ports: &[String],
+    cables: &HashMap<String, String>,
+    tls: Option<(&Path, &Path)>,
+    p4info: &P4Info,
+    cleanup: &mut Cleanup,
+) -> Result<(PathBuf, P4RuntimeClient)> {
     let tmp_dir = cleanup.create_temp_dir(".")?;
 
     // Create OVS configuration database.
@@ -265,10 +354,14 @@ async fn start_ofp4(p4info: P4Info) -> Result<(Cleanup, PathBuf, P4RuntimeClient
     // Use ovs-vsctl to configure OVS.
     let mut command = ovs_command("ovs-vsctl", &tmp_dir);
     command.args(["--no-wait", "--", "add-br", "br0"]);
-    for port in 1..=4 {
-        let portname = format!("p{port}");
-        command.args(["--", "add-port", "br0", &portname,
-                      "--", "set", "Interface", &portname, &format!("ofport_request={port}")]);
+    for (i, port) in ports.iter().enumerate() {
+        let ofport = i + 1;
+        command.args(["--", "add-port", "br0", port,
+                      "--", "set", "Interface", port, &format!("ofport_request={ofport}")]);
+        if let Some(stream) = cables.get(port) {
+            command.args(["--", "set", "Interface", port, "type=dummy",
+                          &format!("options:stream={stream}")]);
+        }
     }
     command.run()?;
 
@@ -292,35 +385,53 @@ async fn start_ofp4(p4info: P4Info) -> Result<(Cleanup, PathBuf, P4RuntimeClient
     let mut remote_arg = OsString::from("unix:");
     remote_arg.push(tmp_dir.join("br0.mgmt"));
     cleanup.register_pidfile(tmp_dir.join("ofp4.pid"))?;
-    Command::new(env!("CARGO_BIN_EXE_ofp4"))
+    let mut ofp4_command = Command::new(env!("CARGO_BIN_EXE_ofp4"));
+    ofp4_command
         .arg("--log-file=ofp4.log")
         .arg("--ddlog-record=ddlog.txt")
         .current_dir(&tmp_dir)
         .arg(remote_arg)
         .arg("--p4-port=0")
         .arg("--detach").arg("--pidfile=ofp4.pid")
-        .arg(&format!("--device-id={DEVICE_ID}"))
-        .run()?;
+        .arg(&format!("--device-id={DEVICE_ID}"));
+    if let Some((cert_path, key_path)) = tls {
+        ofp4_command.arg("--tls-cert").arg(cert_path).arg("--tls-key").arg(key_path);
+    }
+    ofp4_command.run()?;
 
-    // ofp4 printed to its log the P4Runtime port where it's listening.  Read this out and parse it
-    // as `p4_port`, so we can connect back to it.
+    // ofp4 printed to its log the P4Runtime port where it's listening.  Tail the log for that
+    // line and parse it as `p4_port`, so we can connect back to it.  A tail (rather than a single
+    // read-the-whole-file-now) matters because ofp4 detaches immediately on startup, so by the
+    // time its launcher `Command` has exited, the detached daemon may not yet have reached the
+    // point of opening its P4Runtime socket and logging where.
     //
     // (We could tell it a port to listen, but in practice that prevents reliably running tests in
     // parallel, even choosing a random port.  The address space is not big enough.)
-    let ofp4_log = String::from_utf8(std::fs::read(tmp_dir.join("ofp4.log"))?)?;
     let re = Regex::new("(?m)Listening on (.*):([0-9]+)$").unwrap();
-    let (p4_addr, p4_port) = match re.captures(&ofp4_log) {
+    let log_tail = LogTail::open(&tmp_dir.join("ofp4.log"), Duration::from_secs(10)).await?;
+    let line = log_tail.wait_for_line(&re, Duration::from_secs(10)).await?;
+    let (p4_addr, p4_port) = match re.captures(&line) {
         None => Err(anyhow!("ofp4 failed to log its listening address and port"))?,
-        Some(c) => (c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str())
+        Some(c) => (c.get(1).unwrap().as_str().to_string(), c.get(2).unwrap().as_str().to_string())
     };
     let p4_port: u16 = p4_port.parse().unwrap();
     info!("ofp4 is listening on port {p4_port}");
 
     // Connect to ofp4.
     info!("Connect to ofp4");
-    let env = Arc::new(EnvBuilder::new().build());
-    let ch = ChannelBuilder::new(env).connect(&format!("{}:{}", p4_addr, p4_port));
-    let client = P4RuntimeClient::new(ch);
+    let target = format!("{}:{}", p4_addr, p4_port);
+    let client = match tls {
+        Some((cert_path, _key_path)) => {
+            let root_certs = std::fs::read(cert_path)?;
+            let config = p4ext::channel::TlsConfig { root_certs, client_identity: None, target_name_override: None };
+            p4ext::channel::connect_tls(&target, &config)
+        },
+        None => {
+            let env = Arc::new(EnvBuilder::new().build());
+            let ch = ChannelBuilder::new(env).connect(&target);
+            P4RuntimeClient::new(ch)
+        },
+    };
 
     // Start a StreamChannel.
     let (mut tx, mut rx) = client.stream_channel()?;
@@ -356,16 +467,320 @@ async fn start_ofp4(p4info: P4Info) -> Result<(Cleanup, PathBuf, P4RuntimeClient
         device_id: DEVICE_ID,
         action: SetForwardingPipelineConfigRequest_Action::VERIFY_AND_SAVE,
         config: Some(ForwardingPipelineConfig {
-            p4info: Some(p4info).into(),
+            p4info: Some(p4info.clone()).into(),
             ..Default::default()
         }).into(),
         ..Default::default()
     };
     client.set_forwarding_pipeline_config(&sfpcr)?;
 
+    Ok((tmp_dir, client))
+}
+
+async fn start_ofp4(p4info: P4Info) -> Result<(Cleanup, PathBuf, P4RuntimeClient)> {
+    grpcio::redirect_log();
+
+    let mut cleanup = Cleanup::new()?;
+    if let Ok(_) = std::env::var("KEEP_TMPDIR") {
+        cleanup.keep_temp_dirs();
+    }
+
+    let ports: Vec<String> = (1..=4).map(|port| format!("p{port}")).collect();
+    let (tmp_dir, client) = start_switch(&ports, &HashMap::new(), None, &p4info, &mut cleanup).await?;
     Ok((cleanup, tmp_dir, client))
 }
 
+/// Generates a throwaway self-signed TLS certificate and RSA key (PEM) for `common_name`, using
+/// the system `openssl` binary, writing them to `<tmp_dir>/<common_name>-cert.pem` and
+/// `-key.pem`.  Returns their paths.
+fn generate_self_signed_cert(tmp_dir: &Path, common_name: &str) -> Result<(PathBuf, PathBuf)> {
+    let cert_path = tmp_dir.join(format!("{common_name}-cert.pem"));
+    let key_path = tmp_dir.join(format!("{common_name}-key.pem"));
+    ovs_command("openssl", tmp_dir)
+        .args(["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1"])
+        .arg("-subj").arg(format!("/CN={common_name}"))
+        .arg("-keyout").arg(&key_path)
+        .arg("-out").arg(&cert_path)
+        .run_nocapture()?;
+    Ok((cert_path, key_path))
+}
+
+/// Like `start_ofp4`, but serves P4Runtime over TLS with a throwaway self-signed server
+/// certificate, and connects trusting that certificate.  Also returns the server's certificate
+/// path and the address it's listening on, so a test can try connecting with a *different*
+/// trusted root and confirm that ofp4 rejects it.
+async fn start_ofp4_tls(p4info: P4Info) -> Result<(Cleanup, PathBuf, P4RuntimeClient, PathBuf, String)> {
+    grpcio::redirect_log();
+
+    let mut cleanup = Cleanup::new()?;
+    if let Ok(_) = std::env::var("KEEP_TMPDIR") {
+        cleanup.keep_temp_dirs();
+    }
+
+    let cert_dir = cleanup.create_temp_dir(".")?;
+    let (cert_path, key_path) = generate_self_signed_cert(&cert_dir, "ofp4-test-server")?;
+
+    let ports: Vec<String> = (1..=4).map(|port| format!("p{port}")).collect();
+    let (tmp_dir, client) = start_switch(&ports, &HashMap::new(), Some((&cert_path, &key_path)), &p4info, &mut cleanup).await?;
+
+    let ofp4_log = String::from_utf8(std::fs::read(tmp_dir.join("ofp4.log"))?)?;
+    let re = Regex::new("(?m)Listening on (.*):([0-9]+)$").unwrap();
+    let target = match re.captures(&ofp4_log) {
+        None => Err(anyhow!("ofp4 failed to log its listening address and port"))?,
+        Some(c) => format!("{}:{}", c.get(1).unwrap().as_str(), c.get(2).unwrap().as_str()),
+    };
+
+    Ok((cleanup, tmp_dir, client, cert_path, target))
+}
+
+/// One switch in a simulated multi-switch `Fabric`: a name (used to key `Fabric`'s client and
+/// `trace_flow` lookups) and the names of the ports it exposes on its `br0` bridge.
+struct FabricSwitch {
+    name: String,
+    ports: Vec<String>,
+}
+
+impl FabricSwitch {
+    fn new<S: Into<String>>(name: S, ports: &[&str]) -> FabricSwitch {
+        FabricSwitch { name: name.into(), ports: ports.iter().map(|port| port.to_string()).collect() }
+    }
+}
+
+/// A point-to-point cable between a `(switch, port)` on one `FabricSwitch` and a `(switch, port)`
+/// on another, wired up by `start_fabric` when the fabric starts.
+struct FabricLink {
+    a: (String, String),
+    b: (String, String),
+}
+
+impl FabricLink {
+    fn new(a: (&str, &str), b: (&str, &str)) -> FabricLink {
+        FabricLink { a: (a.0.into(), a.1.into()), b: (b.0.into(), b.1.into()) }
+    }
+}
+
+/// A running multi-switch fabric started by `start_fabric`: one `ovsdb-server`/`ovs-vswitchd`/ofp4
+/// triple per `FabricSwitch`, each in its own temporary directory, wired together according to the
+/// `FabricLink`s it was started with.
+struct Fabric {
+    _cleanup: Cleanup,
+    switches: HashMap<String, (PathBuf, P4RuntimeClient)>,
+}
+
+impl Fabric {
+    /// Returns the `P4RuntimeClient` connected to `switch`'s ofp4 instance.
+    fn client(&self, switch: &str) -> &P4RuntimeClient {
+        &self.switches[switch].1
+    }
+
+    /// Like the free-standing `trace_flow`, but against `switch`'s bridge.
+    fn trace_flow<'a, I, S>(&self, switch: &str, args: I) -> Result<(String, String)>
+    where I: IntoIterator<Item = S>,
+          S: AsRef<OsStr>
+    {
+        trace_flow(&self.switches[switch].0, args)
+    }
+}
+
+/// Brings up a simulated multi-switch fabric: one ofp4 instance per `FabricSwitch` in `switches`,
+/// each with `p4info` installed, with `links` wired up as point-to-point cables between them.
+///
+/// Real OVS `patch` ports only connect bridges inside a single `ovs-vswitchd`/datapath, but each
+/// switch here is its own `ovs-vswitchd` process (its own OVSDB, management socket, and pidfiles,
+/// so N switches need N times the plumbing that `start_ofp4` sets up for one). To still get a wire
+/// between two switches, a linked port's interface is given `type=dummy` with an `options:stream`
+/// pointing at a Unix domain socket private to that link -- one endpoint listens (`punix:`) and the
+/// other connects (`unix:`) -- which is how OVS's own dummy netdev provider simulates a cable
+/// between independent `ovs-vswitchd` processes.
+async fn start_fabric(switches: Vec<FabricSwitch>, links: Vec<FabricLink>, p4info: P4Info) -> Result<Fabric> {
+    grpcio::redirect_log();
+
+    let mut cleanup = Cleanup::new()?;
+    if let Ok(_) = std::env::var("KEEP_TMPDIR") {
+        cleanup.keep_temp_dirs();
+    }
+
+    // For each (switch, port) that's an endpoint of a link, work out the `options:stream` value
+    // for its dummy interface: the `a` endpoint listens, the `b` endpoint connects to it.
+    let mut cables: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for (i, link) in links.iter().enumerate() {
+        let socket_name = format!("../cable{i}.sock");
+        cables.entry(link.a.0.clone()).or_default().insert(link.a.1.clone(), format!("punix:{socket_name}"));
+        cables.entry(link.b.0.clone()).or_default().insert(link.b.1.clone(), format!("unix:{socket_name}"));
+    }
+
+    let mut clients = HashMap::new();
+    for switch in &switches {
+        let empty = HashMap::new();
+        let switch_cables = cables.get(&switch.name).unwrap_or(&empty);
+        let (tmp_dir, client) = start_switch(&switch.ports, switch_cables, None, &p4info, &mut cleanup).await?;
+        clients.insert(switch.name.clone(), (tmp_dir, client));
+    }
+
+    Ok(Fabric { _cleanup: cleanup, switches: clients })
+}
+
+/// A value to match or to pass as an action parameter.  Each variant encodes to its own type's
+/// width in big-endian bytes (`1u16` becomes `[0, 1]`), so tests can write `1u16` instead of
+/// `vec![0, 1]`; [`TestSwitch::insert`] passes those bytes on as they are, without fitting them to
+/// the width the P4Info gives the field or parameter.
+#[derive(Clone, Debug)]
+enum MatchValue {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    Bytes(Vec<u8>),
+}
+
+impl From<bool> for MatchValue {
+    fn from(b: bool) -> Self { MatchValue::Bool(b) }
+}
+impl From<u8> for MatchValue {
+    fn from(n: u8) -> Self { MatchValue::U8(n) }
+}
+impl From<u16> for MatchValue {
+    fn from(n: u16) -> Self { MatchValue::U16(n) }
+}
+impl From<u32> for MatchValue {
+    fn from(n: u32) -> Self { MatchValue::U32(n) }
+}
+impl From<Vec<u8>> for MatchValue {
+    fn from(bytes: Vec<u8>) -> Self { MatchValue::Bytes(bytes) }
+}
+
+impl MatchValue {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MatchValue::Bool(b) => vec![*b as u8],
+            MatchValue::U8(n) => vec![*n],
+            MatchValue::U16(n) => n.to_be_bytes().into(),
+            MatchValue::U32(n) => n.to_be_bytes().into(),
+            MatchValue::Bytes(bytes) => bytes.clone(),
+        }
+    }
+}
+
+/// A declarative wrapper around [`start_ofp4`] that knows a switch's P4Info, so that tests can
+/// insert table entries and check the resulting flows by name instead of repeating the
+/// `Entity`/`TableEntry`/`FieldMatch` boilerplate that `snvs()` used to write out by hand.
+struct TestSwitch {
+    _cleanup: Cleanup,
+    tmp_dir: PathBuf,
+    client: P4RuntimeClient,
+    tables: HashMap<String, Table>,
+    actions: HashMap<String, p4ext::Action>,
+}
+
+impl TestSwitch {
+    /// Starts a switch running the P4 program whose P4Info is the parsed contents of `p4info`.
+    async fn start(p4info: P4Info) -> Result<TestSwitch> {
+        let actions: HashMap<String, p4ext::Action> = p4info.get_actions().iter()
+            .map(|a| (a.get_preamble().name.clone(), a.into()))
+            .collect();
+        let action_by_id: HashMap<u32, p4ext::Action> = p4info.get_actions().iter()
+            .map(|a| (a.get_preamble().id, a.into()))
+            .collect();
+        let action_profile_by_id: HashMap<u32, p4ext::ActionProfile> = p4info.get_action_profiles().iter()
+            .map(|ap| (ap.get_preamble().id, ap.into()))
+            .collect();
+        let tables: HashMap<String, Table> = p4info.get_tables().iter()
+            .map(|t| Table::new_from_proto(t, &action_by_id, &action_profile_by_id))
+            .map(|t| (t.preamble.name.clone(), t))
+            .collect();
+
+        let (cleanup, tmp_dir, client) = start_ofp4(p4info).await?;
+        Ok(TestSwitch { _cleanup: cleanup, tmp_dir, client, tables, actions })
+    }
+
+    /// Inserts an entry into `table` (named as in the P4Info, e.g. `"SnvsIngress.InputVlan"`)
+    /// that matches `matches` (field name, value) and, on a match, runs `action` (named as in the
+    /// P4Info) with `params` in the order the P4Info declares them.
+    fn insert<'a>(
+        &self,
+        table: &str,
+        matches: impl IntoIterator<Item = (&'a str, MatchValue)>,
+        action: &str,
+        params: impl IntoIterator<Item = MatchValue>,
+    ) -> Result<()> {
+        let table = self.tables.get(table).ok_or_else(|| anyhow!("no table named {table}"))?;
+        let field_matches = matches.into_iter().map(|(name, value)| {
+            let mf = table.match_fields.iter().find(|mf| mf.preamble.name == name)
+                .ok_or_else(|| anyhow!("table {} has no match field named {name}", table.preamble.name))?;
+            let exact = FieldMatch_Exact { value: value.to_bytes(), ..Default::default() };
+            Ok(FieldMatch {
+                field_id: mf.preamble.id,
+                field_match_type: Some(FieldMatch_oneof_field_match_type::exact(exact)).into(),
+                ..Default::default()
+            })
+        }).collect::<Result<Vec<_>>>()?;
+
+        let action = self.actions.get(action).ok_or_else(|| anyhow!("no action named {action}"))?;
+        let action_params = params.into_iter().zip(action.params.iter()).map(|(value, param)| {
+            let (unknown_fields, cached_size) = Default::default();
+            proto::p4runtime::Action_Param { param_id: param.preamble.id, value: value.to_bytes(), unknown_fields, cached_size }
+        }).collect();
+        let action = Action {
+            action_id: action.preamble.id,
+            params: RepeatedField::from_vec(action_params),
+            ..Default::default()
+        };
+
+        let te = TableEntry {
+            table_id: table.preamble.id,
+            field_match: RepeatedField::from_vec(field_matches),
+            action: Some(TableAction { field_type: Some(TableAction_oneof_type::action(action)), ..Default::default() }).into(),
+            priority: 50,
+            ..Default::default()
+        };
+        self.write(Entity_oneof_entity::table_entry(te))
+    }
+
+    /// Inserts a multicast group entry with the given `id` that replicates to `ports`.
+    fn multicast_group(&self, id: u32, ports: impl IntoIterator<Item = u32>) -> Result<()> {
+        let replicas = ports.into_iter().map(|egress_port| {
+            Replica { egress_port, instance: 1, ..Default::default() }
+        }).collect();
+        let mge = MulticastGroupEntry {
+            multicast_group_id: id,
+            replicas: RepeatedField::from_vec(replicas),
+            ..Default::default()
+        };
+        let pree = PacketReplicationEngineEntry {
+            field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(mge)),
+            ..Default::default()
+        };
+        self.write(Entity_oneof_entity::packet_replication_engine_entry(pree))
+    }
+
+    fn write(&self, entity: Entity_oneof_entity) -> Result<()> {
+        let entity = Entity { entity: Some(entity).into(), ..Default::default() };
+        let update = Update { field_type: Update_Type::INSERT, entity: Some(entity).into(), ..Default::default() };
+        let wr = WriteRequest {
+            device_id: DEVICE_ID,
+            election_id: Some(election_id()).into(),
+            updates: RepeatedField::from_vec(vec![update]),
+            atomicity: WriteRequest_Atomicity::DATAPLANE_ATOMIC,
+            ..Default::default()
+        };
+        self.client.write(&wr)?;
+        Ok(())
+    }
+
+    /// Traces a packet matching `args` (in `ovs-ofctl` flow syntax) and waits, up to `wait_until`'s
+    /// usual timeout, until the resulting datapath actions equal `expect`.  This replaces
+    /// polling-by-sleep: ofp4 does not yet wait for OpenFlow flow table changes to commit before a
+    /// `Write` RPC returns success (https://github.com/vmware/nerpa/issues/86), so the flow may not
+    /// be installed yet by the time a test's trace would otherwise run.
+    fn commit(&self, args: impl IntoIterator<Item = impl AsRef<OsStr>> + Clone, expect: &str) -> Result<()> {
+        wait_until(|| match trace_flow(&self.tmp_dir, args.clone()) {
+            Ok((_, actions)) if actions == expect => Complete(Ok(())),
+            Ok(_) => Incomplete,
+            Err(e) => Complete(Err(e)),
+        })?
+    }
+}
+
 #[tokio::test]
 #[traced_test]
 async fn wire() -> Result<()> {
@@ -378,107 +793,242 @@ async fn wire() -> Result<()> {
     Ok(())
 }
 
-#[tokio::test]
-#[traced_test]
-async fn snvs() -> Result<()> {
-    let p4info: P4Info = Message::parse_from_bytes(include_bytes!("../snvs.p4info.bin"))?;
-    let actions: HashMap<String, u32> = p4info.get_actions().iter()
-        .map(|action| { let p = action.get_preamble(); (p.name.clone(), p.id) })
-        .collect();
-    let action_by_id: HashMap<u32, p4ext::Action> = p4info
-        .get_actions()
-        .iter()
-        .map(|a| (a.get_preamble().id, a.into()))
-        .collect();
-    let tables: HashMap<String, Table> = p4info.get_tables().iter()
-        .map(|table| p4ext::Table::new_from_proto(table, &action_by_id))
-        .map(|table| (table.preamble.name.clone(), table))
-        .collect();
-
-    let (_cleanup, tmp_dir, client) = start_ofp4(p4info).await?;
-
-    // Add a multicast group entry, with ID 1, that contains ports 1, 2, 3, and 4.
-    fn replica(egress_port: u32, instance: u32) -> Replica {
-        Replica { egress_port, instance, ..Default::default() }
-    }
-    let mge = MulticastGroupEntry {
-        multicast_group_id: 1,
-        replicas: RepeatedField::from_vec(vec![
-            replica(1, 1),
-            replica(2, 1),
-            replica(3, 1),
-            replica(4, 1),
-        ]),
+/// Arbitrates on a fresh `StreamChannel` from `client` with the given `election_id`, and returns
+/// the channel halves (so the caller can keep it open, or read further pushes from it) along with
+/// the `MasterArbitrationUpdate` ofp4 sent back in reply.
+async fn arbitrate(
+    client: &P4RuntimeClient,
+    election_id: Uint128,
+) -> Result<(
+    grpcio::StreamingCallSink<StreamMessageRequest>,
+    grpcio::ClientDuplexReceiver<StreamMessageResponse>,
+    MasterArbitrationUpdate,
+)> {
+    let (mut tx, mut rx) = client.stream_channel()?;
+    let mau = MasterArbitrationUpdate {
+        device_id: DEVICE_ID,
+        election_id: Some(election_id).into(),
         ..Default::default()
     };
-    let pree = PacketReplicationEngineEntry {
-        field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(mge)),
+    let smr = StreamMessageRequest {
+        update: Some(StreamMessageRequest_oneof_update::arbitration(mau)),
         ..Default::default()
     };
-    let entity = Entity {
-        entity: Some(Entity_oneof_entity::packet_replication_engine_entry(pree)).into(),
+    tx.send((smr, grpcio::WriteFlags::default())).await?;
+    let reply = rx.next().await.unwrap()?;
+    let arbitration = match reply.update {
+        Some(StreamMessageResponse_oneof_update::arbitration(a)) => a,
+        _ => Err(anyhow!("expected an arbitration reply"))?,
+    };
+    Ok((tx, rx, arbitration))
+}
+
+/// Sends `payload` as a `PacketOut` over an already-arbitrated `tx` (see [`arbitrate`]), tagged
+/// with `metadata` as (metadata_id, value) pairs.  The caller is responsible for knowing which
+/// field IDs the P4Info's `packet_out` controller packet metadata schema actually uses.
+async fn send_packet_out(
+    tx: &mut grpcio::StreamingCallSink<StreamMessageRequest>,
+    payload: Vec<u8>,
+    metadata: Vec<(u32, Vec<u8>)>,
+) -> Result<()> {
+    let metadata = metadata.into_iter()
+        .map(|(metadata_id, value)| PacketMetadata { metadata_id, value, ..Default::default() })
+        .collect();
+    let po = PacketOut {
+        payload,
+        metadata: RepeatedField::from_vec(metadata),
         ..Default::default()
     };
-    let mge_update = Update {
-        field_type: Update_Type::INSERT,
-        entity: Some(entity).into(),
+    let smr = StreamMessageRequest {
+        update: Some(StreamMessageRequest_oneof_update::packet(po)),
         ..Default::default()
     };
+    tx.send((smr, grpcio::WriteFlags::default())).await?;
+    Ok(())
+}
 
-    // Add tagged VLAN with ID 1.
-    let table = &tables["SnvsIngress.InputVlan"];
-    let mfs = &table.match_fields;
-    fn exact_fm(mf: &MatchField, value: Vec<u8>) -> FieldMatch {
-        let exact = FieldMatch_Exact { value, ..Default::default() };
-        FieldMatch {
-            field_id: mf.preamble.id,
-            field_match_type: Some(FieldMatch_oneof_field_match_type::exact(exact)).into(),
-            ..Default::default()
+/// Waits, up to `timeout`, for the next `PacketIn` pushed on `rx` (skipping over any other kind of
+/// push, e.g. an `IdleTimeoutNotification`, in between), and returns its payload and metadata as
+/// (metadata_id, value) pairs.
+async fn recv_packet_in(
+    rx: &mut grpcio::ClientDuplexReceiver<StreamMessageResponse>,
+    timeout: Duration,
+) -> Result<(Vec<u8>, Vec<(u32, Vec<u8>)>)> {
+    let wait = async {
+        loop {
+            let reply = rx.next().await.ok_or_else(|| anyhow!("stream closed before a packet-in arrived"))??;
+            if let Some(StreamMessageResponse_oneof_update::packet(pin)) = reply.update {
+                let metadata = pin.get_metadata().iter()
+                    .map(|m| (m.get_metadata_id(), m.get_value().to_vec()))
+                    .collect();
+                return Ok((pin.get_payload().to_vec(), metadata));
+            }
         }
-    }
-    let fms = vec![exact_fm(&mfs[0], vec![0, 1]),
-                   exact_fm(&mfs[1], vec![1])];
-    let action = Action {
-        action_id: actions["SnvsIngress.UseTaggedVlan"],
-        ..Default::default()
     };
-    let table_action = TableAction {
-        field_type: Some(TableAction_oneof_type::action(action)),
+    tokio::time::timeout(timeout, wait).await
+        .with_context(|| "timed out waiting for a packet-in".to_string())?
+}
+
+#[tokio::test]
+#[traced_test]
+async fn election() -> Result<()> {
+    let p4info: P4Info = Message::parse_from_bytes(include_bytes!("../wire.p4info.bin"))?;
+    let (_cleanup, _tmp_dir, client) = start_ofp4(p4info).await?;
+
+    // A connection offering a higher election ID than the one `start_ofp4` already registered
+    // (low=1) becomes primary...
+    let (_tx_a, mut rx_a, arb_a) = arbitrate(&client, Uint128 { low: 2, ..Default::default() }).await?;
+    assert_eq!(arb_a.get_status().code, RpcStatusCode::OK.into());
+
+    // ...and one offering a lower election ID is told someone else already holds mastership.
+    let (_tx_b, _rx_b, arb_b) = arbitrate(&client, Uint128 { low: 1, ..Default::default() }).await?;
+    assert_eq!(arb_b.get_status().code, RpcStatusCode::ALREADY_EXISTS.into());
+
+    // The backup's writes are rejected, since it isn't primary.
+    let wr = WriteRequest {
+        device_id: DEVICE_ID,
+        election_id: Some(Uint128 { low: 1, ..Default::default() }).into(),
         ..Default::default()
     };
-    let te = TableEntry {
-        table_id: table.preamble.id,
-        field_match: RepeatedField::from_vec(fms),
-        action: Some(table_action).into(),
-        priority: 50,
-        ..Default::default()
+    match client.write(&wr) {
+        Err(grpcio::Error::RpcFailure(status)) => assert_eq!(status.status, RpcStatusCode::PERMISSION_DENIED),
+        other => Err(anyhow!("expected a PERMISSION_DENIED write failure, got {:?}", other))?,
+    }
+
+    // A third connection offering a still-higher election ID takes over mastership, and the old
+    // primary is pushed an unsolicited update demoting it to backup.
+    let (_tx_c, _rx_c, arb_c) = arbitrate(&client, Uint128 { low: 3, ..Default::default() }).await?;
+    assert_eq!(arb_c.get_status().code, RpcStatusCode::OK.into());
+
+    let demotion = rx_a.next().await.unwrap()?;
+    let demotion = match demotion.update {
+        Some(StreamMessageResponse_oneof_update::arbitration(a)) => a,
+        _ => Err(anyhow!("expected an arbitration reply"))?,
     };
-    let entity = Entity {
-        entity: Some(Entity_oneof_entity::table_entry(te)).into(),
-        ..Default::default()
+    assert_eq!(demotion.get_status().code, RpcStatusCode::ALREADY_EXISTS.into());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[traced_test]
+async fn packet_out_without_metadata_is_dropped() -> Result<()> {
+    // A full PacketOut/PacketIn round trip needs a P4 program with a `CONTROLLER`-bound action,
+    // declaring `packet_in`/`packet_out` controller packet metadata in its P4Info; neither
+    // `wire.p4info` nor `snvs.p4info`, the only P4Info fixtures checked into this crate, has one.
+    // Until one does, exercise `send_packet_out` against the path that's reachable without it:
+    // ofp4 should log and drop an arriving `PacketOut` rather than panicking when the pipeline's
+    // P4Info has no `egress_port` metadata to tell it where to send the packet.
+    let p4info: P4Info = Message::parse_from_bytes(include_bytes!("../wire.p4info.bin"))?;
+    let (_cleanup, tmp_dir, client) = start_ofp4(p4info).await?;
+    let log_tail = LogTail::open(&tmp_dir.join("ofp4.log"), Duration::from_secs(5)).await?;
+
+    let (mut tx, mut rx, arbitration) = arbitrate(&client, election_id()).await?;
+    assert_eq!(arbitration.get_status().code, RpcStatusCode::OK.into());
+
+    let re = Regex::new("P4Info has no packet_out.egress_port metadata")?;
+    let (warning, ()) = tokio::try_join!(
+        log_tail.wait_for_line(&re, Duration::from_secs(5)),
+        send_packet_out(&mut tx, vec![0u8; 14], vec![]),
+    )?;
+    assert!(warning.contains("dropping packet-out"));
+
+    // Nothing in this pipeline forwards to the controller, so no `PacketIn` should show up either.
+    assert!(recv_packet_in(&mut rx, Duration::from_millis(200)).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[traced_test]
+async fn tls_cert_rejected() -> Result<()> {
+    let p4info: P4Info = Message::parse_from_bytes(include_bytes!("../wire.p4info.bin"))?;
+    let (mut cleanup, _tmp_dir, _client, _server_cert, target) = start_ofp4_tls(p4info).await?;
+
+    // Connecting while trusting a *different* self-signed certificate than the one ofp4 is
+    // actually serving must be rejected -- otherwise the TLS handshake isn't checking anything.
+    let wrong_cert_dir = cleanup.create_temp_dir(".")?;
+    let (wrong_cert, _wrong_key) = generate_self_signed_cert(&wrong_cert_dir, "someone-else")?;
+    let root_certs = std::fs::read(&wrong_cert)?;
+    let config = p4ext::channel::TlsConfig { root_certs, client_identity: None, target_name_override: None };
+    let bad_client = p4ext::channel::connect_tls(&target, &config);
+
+    let result = bad_client.get_forwarding_pipeline_config(&GetForwardingPipelineConfigRequest::default());
+    assert!(result.is_err(), "connecting with the wrong root certificate should have been rejected");
+
+    Ok(())
+}
+
+/// Regression test for a bug where a `ROLLBACK_ON_ERROR` batch validated every update against the
+/// state from before the batch started, instead of the state as mutated by the batch's own
+/// earlier updates. An `INSERT` of a multicast group followed by a `MODIFY` of that same group in
+/// one batch used to have its `MODIFY` rejected with `NOT_FOUND`, since the group didn't exist yet
+/// in the pre-batch snapshot it was (incorrectly) checked against.
+#[tokio::test]
+#[traced_test]
+async fn rollback_on_error_batch_sees_its_own_earlier_updates() -> Result<()> {
+    let p4info: P4Info = Message::parse_from_bytes(include_bytes!("../snvs.p4info.bin"))?;
+    let switch = TestSwitch::start(p4info).await?;
+
+    let mcast_id = 1;
+    let mge = |ports: &[u32]| -> MulticastGroupEntry {
+        let replicas = ports.iter().map(|&egress_port| Replica { egress_port, instance: 1, ..Default::default() }).collect();
+        MulticastGroupEntry { multicast_group_id: mcast_id, replicas: RepeatedField::from_vec(replicas), ..Default::default() }
     };
-    let te_update = Update {
-        field_type: Update_Type::INSERT,
-        entity: Some(entity).into(),
-        ..Default::default()
+    let update = |field_type: Update_Type, mge: MulticastGroupEntry| -> Update {
+        let pree = PacketReplicationEngineEntry {
+            field_type: Some(PacketReplicationEngineEntry_oneof_type::multicast_group_entry(mge)),
+            ..Default::default()
+        };
+        let entity = Entity { entity: Some(Entity_oneof_entity::packet_replication_engine_entry(pree)).into(), ..Default::default() };
+        Update { field_type, entity: Some(entity).into(), ..Default::default() }
     };
-    let updates = vec![te_update, mge_update];
+
     let wr = WriteRequest {
         device_id: DEVICE_ID,
         election_id: Some(election_id()).into(),
-        updates: RepeatedField::from_vec(updates),
-        atomicity: WriteRequest_Atomicity::DATAPLANE_ATOMIC,
+        updates: RepeatedField::from_vec(vec![
+            update(Update_Type::INSERT, mge(&[1, 2])),
+            update(Update_Type::MODIFY, mge(&[1, 2, 3, 4])),
+        ]),
+        atomicity: WriteRequest_Atomicity::ROLLBACK_ON_ERROR,
         ..Default::default()
     };
-    client.write(&wr)?;
+    switch.client.write(&wr)?;
+
+    // Add a tagged VLAN that floods through this multicast group, and check that the MODIFY's
+    // replicas took effect, not just the INSERT's.
+    switch.insert(
+        "SnvsIngress.InputVlan",
+        [("vlan_id", MatchValue::from(vec![0, 1])), ("is_tagged", MatchValue::from(true))],
+        "SnvsIngress.UseTaggedVlan",
+        [],
+    )?;
+    switch.commit(["in_port=p1,dl_vlan=1"], "2,3,4")?;
 
-    // XXX This should not be necessary, but ofp4 does not yet wait for OpenFlow flow table changes
-    // to commit before returning success.  See https://github.com/vmware/nerpa/issues/86.
-    std::thread::sleep(std::time::Duration::from_millis(100));
+    Ok(())
+}
+
+#[tokio::test]
+#[traced_test]
+async fn snvs() -> Result<()> {
+    let p4info: P4Info = Message::parse_from_bytes(include_bytes!("../snvs.p4info.bin"))?;
+    let switch = TestSwitch::start(p4info).await?;
+
+    // Add a multicast group entry, with ID 1, that contains ports 1, 2, 3, and 4.
+    switch.multicast_group(1, [1, 2, 3, 4])?;
+
+    // Add tagged VLAN with ID 1.
+    switch.insert(
+        "SnvsIngress.InputVlan",
+        [("vlan_id", MatchValue::from(vec![0, 1])), ("is_tagged", MatchValue::from(true))],
+        "SnvsIngress.UseTaggedVlan",
+        [],
+    )?;
 
     // Check that a packet received on port 1, in VLAN 1, will get broadcast to the other ports in
     // the VLAN.
-    assert_eq!(trace_flow(&tmp_dir, ["in_port=p1,dl_vlan=1"])?.1, "2,3,4");
+    switch.commit(["in_port=p1,dl_vlan=1"], "2,3,4")?;
 
     Ok(())
 }
@@ -18,6 +18,7 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
  */
 use super::sys;
+use std::os::raw;
 use std::ptr::null;
 
 /// A `Latch` is an Open vSwitch implementation of a thread-safe, signal-safe doorbell that can be
@@ -40,6 +41,12 @@ impl Latch {
     pub fn set(&mut self) { unsafe { sys::latch_set(&mut self.0) } }
     pub fn is_set(&self) -> bool { unsafe { sys::latch_is_set(&self.0) } }
     pub fn wait(&self) { unsafe { sys::latch_wait_at(&self.0, null()) } }
+
+    /// The file descriptor that becomes readable when the latch is set, suitable for waiting on
+    /// with [`super::poll_loop::fd_wait`]. Exposed so code outside this module -- a reactor
+    /// bridging OVS's `poll_loop` to Rust futures, say -- can arm an OVS `poll_block` on the latch
+    /// without reaching into the underlying `sys::latch`.
+    pub fn fd(&self) -> raw::c_int { self.0.fds[0] }
 }
 
 impl Drop for Latch {
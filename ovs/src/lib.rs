@@ -68,11 +68,16 @@ pub mod sys {
 pub mod ds;
 pub mod latch;
 pub mod ofpbuf;
+pub mod ofpbuf_pool;
 pub mod ofp_bundle;
 pub mod ofp_errors;
 pub mod ofp_flow;
 pub mod ofp_msgs;
+pub mod ofp_packet;
 pub mod ofp_print;
 pub mod ofp_protocol;
+pub mod ofp_stats;
 pub mod poll_loop;
+pub mod rate_limit;
 pub mod rconn;
+pub mod reactor;
@@ -30,9 +30,11 @@ use super::ds::Ds;
 use super::ofpbuf::Ofpbuf;
 use super::ofp_errors;
 use super::ofp_msgs;
+use super::ofp_msgs::OfpType;
 use super::ofp_protocol::Version;
 
 use std::mem;
+use std::slice;
 
 use anyhow::Result;
 
@@ -197,3 +199,138 @@ impl<Inner: Iterator<Item=Ofpbuf>> Iterator for BundleSequence<Inner> {
         }
     }
 }
+
+/// Which request a [`BundleTransaction`] is currently waiting on a reply for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Outstanding {
+    Open,
+    Commit,
+    Discard,
+}
+
+/// A bundle whose control messages are confirmed against the switch's replies, unlike
+/// [`BundleSequence`], which fires `OFPBCT_OPEN_REQUEST`/`OFPBCT_COMMIT_REQUEST` without ever
+/// looking at what the switch says back.
+///
+/// The caller drives this with [`BundleTransaction::open`], then [`BundleTransaction::add`] for
+/// each message to include, then [`BundleTransaction::commit`], passing every message it receives
+/// from the switch in between to [`BundleTransaction::feed_reply`].  If the switch rejects a
+/// request, `feed_reply` returns `Err` and the bundle is already gone on the switch side, so there
+/// is no need to discard it; if the caller wants to abandon a still-open bundle for some other
+/// reason, [`BundleTransaction::discard`] does that.
+pub struct BundleTransaction {
+    bundle_id: u32,
+    flags: u16,
+    version: Version,
+    outstanding: Option<Outstanding>,
+}
+
+impl BundleTransaction {
+    /// Creates a transaction for `bundle_id`, which the caller must not reuse for another bundle
+    /// that's open concurrently on the same OpenFlow connection.  `flags` is a combination of the
+    /// `OFPBF_ATOMIC` and `OFPBF_ORDERED` bit-flags.
+    pub fn new(bundle_id: u32, flags: u16, version: Version) -> BundleTransaction {
+        BundleTransaction { bundle_id, flags, version, outstanding: None }
+    }
+
+    fn ctrl_msg(&self, type_: u16) -> Ofpbuf {
+        BundleCtrlMsg {
+            bundle_id: self.bundle_id,
+            flags: self.flags,
+            type_,
+        }.encode_request(self.version)
+    }
+
+    /// Returns the `OFPBCT_OPEN_REQUEST` message that opens the bundle.  The caller should wait
+    /// for it to be acknowledged through [`BundleTransaction::feed_reply`] before sending any
+    /// [`BundleTransaction::add`] messages.
+    pub fn open(&mut self) -> Ofpbuf {
+        self.outstanding = Some(Outstanding::Open);
+        self.ctrl_msg(OFPBCT_OPEN_REQUEST)
+    }
+
+    /// Returns a message that adds `msg` to the bundle.  The switch doesn't acknowledge individual
+    /// additions, so this doesn't change what [`BundleTransaction::feed_reply`] is waiting for.
+    pub fn add(&self, msg: &[u8]) -> Ofpbuf {
+        BundleAddMsg { bundle_id: self.bundle_id, flags: self.flags, msg }.encode(self.version)
+    }
+
+    /// Returns the `OFPBCT_COMMIT_REQUEST` message that commits the bundle, applying everything
+    /// added to it as a single transaction.  Call [`BundleTransaction::feed_reply`] to learn
+    /// whether the commit actually succeeded.
+    pub fn commit(&mut self) -> Ofpbuf {
+        self.outstanding = Some(Outstanding::Commit);
+        self.ctrl_msg(OFPBCT_COMMIT_REQUEST)
+    }
+
+    /// Returns the `OFPBCT_DISCARD_REQUEST` message that abandons the bundle, e.g. because the
+    /// caller decided not to commit it after all.  There's no need to call this after
+    /// [`BundleTransaction::feed_reply`] has already reported the bundle failed -- the switch
+    /// discards a bundle itself as soon as any request on it errors out.
+    pub fn discard(&mut self) -> Ofpbuf {
+        self.outstanding = Some(Outstanding::Discard);
+        self.ctrl_msg(OFPBCT_DISCARD_REQUEST)
+    }
+
+    /// Feeds an OpenFlow message received from the switch to the transaction. Returns `Ok(true)`
+    /// if `oh` was the reply this transaction is waiting on (there's nothing left outstanding),
+    /// `Ok(false)` if `oh` doesn't concern this transaction at all, and `Err` if `oh` reported that
+    /// the outstanding request failed.
+    pub fn feed_reply(&mut self, oh: &[u8]) -> Result<bool> {
+        let outstanding = match self.outstanding {
+            Some(outstanding) => outstanding,
+            None => return Ok(false),
+        };
+
+        let type_ = OfpType::decode(oh)?;
+        if type_.0 == sys::ofptype_OFPTYPE_ERROR {
+            // An error reported against our own open/commit/discard request means the switch has
+            // already thrown away the bundle; anything else on the connection isn't ours to report
+            // on, so leave `outstanding` alone and let the caller keep waiting for its real reply.
+            // The only way to tell which request an `OFPT_ERROR` concerns is the failed request's
+            // own header, which the switch embeds in the error message's body -- so decode that
+            // out and confirm it's actually the bundle-ctrl message this transaction sent before
+            // trusting the error at all.
+            let mut payload: sys::ofpbuf = unsafe { mem::zeroed() };
+            let error = unsafe {
+                sys::ofperr_decode_msg(oh.as_ptr() as *const sys::ofp_header, &mut payload as *mut sys::ofpbuf)
+            };
+            if error == 0 {
+                return Ok(false);
+            }
+            let failed_request = unsafe {
+                slice::from_raw_parts(payload.data as *const u8, payload.size as usize)
+            };
+            let concerns_us = OfpType::decode(failed_request).ok()
+                .filter(|t| t.0 == sys::ofptype_OFPTYPE_BUNDLE_CONTROL)
+                .and_then(|_| BundleCtrlMsg::decode(failed_request).ok())
+                .map_or(false, |bcm| bcm.bundle_id == self.bundle_id);
+            if !concerns_us {
+                return Ok(false);
+            }
+            self.outstanding = None;
+            return ofp_errors::parse(error).map(|_| false);
+        }
+
+        if type_.0 != sys::ofptype_OFPTYPE_BUNDLE_CONTROL {
+            return Ok(false);
+        }
+
+        let bcm = BundleCtrlMsg::decode(oh)?;
+        if bcm.bundle_id != self.bundle_id {
+            return Ok(false);
+        }
+
+        let expected_reply = match outstanding {
+            Outstanding::Open => OFPBCT_OPEN_REPLY,
+            Outstanding::Commit => OFPBCT_COMMIT_REPLY,
+            Outstanding::Discard => OFPBCT_DISCARD_REPLY,
+        };
+        if bcm.type_ != expected_reply {
+            return Ok(false);
+        }
+
+        self.outstanding = None;
+        Ok(true)
+    }
+}
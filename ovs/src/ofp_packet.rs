@@ -0,0 +1,132 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! OpenFlow packet-in and packet-out messages.
+
+use super::sys;
+
+use super::ofpbuf::Ofpbuf;
+use super::ofp_errors;
+use super::ofp_protocol::Protocol;
+
+use std::error;
+use std::ffi;
+use std::fmt;
+use std::os::raw;
+use std::ptr::null_mut;
+use std::slice;
+
+use anyhow::Result;
+
+/// A packet that OVS punted to the controller, decoded from an `OFPT_PACKET_IN` message.
+pub struct PacketIn {
+    /// The packet's raw bytes, as captured by OVS.
+    pub packet: Vec<u8>,
+    /// The OpenFlow port the packet arrived on.
+    pub in_port: u32,
+    /// The flow table that punted the packet, for a pipeline that installs rules in more than one
+    /// table.
+    pub table_id: u8,
+    /// The cookie of the flow that punted the packet, if any.
+    pub cookie: u64,
+}
+
+/// Decodes `oh` -- an OpenFlow message already known to be an `OFPT_PACKET_IN` -- into a
+/// [`PacketIn`].
+pub fn decode_packet_in(oh: &[u8]) -> Result<PacketIn> {
+    unsafe {
+        let mut pin: sys::ofputil_packet_in = std::mem::zeroed();
+        let (mut total_len, mut buffer_id): (u64, u32) = (0, 0);
+        ofp_errors::parse(sys::ofputil_decode_packet_in(
+            oh.as_ptr() as *const sys::ofp_header,
+            true,
+            &mut pin as *mut sys::ofputil_packet_in,
+            &mut total_len as *mut u64,
+            &mut buffer_id as *mut u32))?;
+        let packet = slice::from_raw_parts(pin.packet as *const u8, pin.packet_len).to_vec();
+        Ok(PacketIn { packet, in_port: pin.in_port, table_id: pin.table_id, cookie: pin.cookie })
+    }
+}
+
+#[derive(Debug)]
+pub struct PacketOutParseError(pub String);
+
+impl fmt::Display for PacketOutParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for PacketOutParseError {}
+
+/// A packet, and the actions to apply to it, to be sent to OVS as an `OFPT_PACKET_OUT` message.
+pub struct PacketOut {
+    po: sys::ofputil_packet_out,
+    // Kept alive because `po.packet` and `po.ofpacts` point into these.
+    _packet: Vec<u8>,
+    _ofpacts: Vec<u8>,
+}
+
+unsafe impl Send for PacketOut {}
+unsafe impl Sync for PacketOut {}
+impl PacketOut {
+    /// Builds a packet-out that executes `actions` -- in the same `ovs-ofctl` action syntax a
+    /// flow's `actions=` clause uses -- on `packet`, as if it had arrived on `in_port` (typically
+    /// `OFPP_CONTROLLER`, since the packet actually originated with the controller).
+    pub fn new(packet: &[u8], in_port: u32, actions: &str) -> Result<PacketOut> {
+        let cs = ffi::CString::new(actions)
+            .map_err(|_| PacketOutParseError("unexpected NUL in string".into()))?;
+
+        let mut ofpacts_buf: sys::ofpbuf = unsafe { std::mem::zeroed() };
+        unsafe { sys::ofpbuf_init(&mut ofpacts_buf as *mut sys::ofpbuf, 64); }
+        let parsed = unsafe { sys::parse_ofpacts(cs.as_ptr(), &mut ofpacts_buf as *mut sys::ofpbuf) };
+        let ofpacts: Vec<u8> = unsafe {
+            let bytes = slice::from_raw_parts(ofpacts_buf.data as *const u8, ofpacts_buf.size as usize).to_vec();
+            sys::ofpbuf_uninit(&mut ofpacts_buf as *mut sys::ofpbuf);
+            bytes
+        };
+        if parsed != null_mut() {
+            let msg = unsafe {
+                let msg = ffi::CStr::from_ptr(parsed).to_string_lossy().into();
+                libc::free(parsed as *mut ffi::c_void);
+                msg
+            };
+            Err(PacketOutParseError(msg))?;
+        }
+
+        let packet = packet.to_vec();
+        let po = sys::ofputil_packet_out {
+            packet: packet.as_ptr() as *const raw::c_void,
+            packet_len: packet.len(),
+            buffer_id: u32::MAX,
+            in_port,
+            ofpacts: ofpacts.as_ptr() as *mut sys::ofpact,
+            ofpacts_len: ofpacts.len(),
+        };
+        Ok(PacketOut { po, _packet: packet, _ofpacts: ofpacts })
+    }
+
+    pub fn encode(&self, protocol: Protocol) -> Ofpbuf {
+        unsafe {
+            let b = sys::ofputil_encode_packet_out(&self.po as *const sys::ofputil_packet_out, protocol.into());
+            Ofpbuf::from_ptr(b)
+        }
+    }
+}
@@ -21,6 +21,10 @@ use super::sys;
 
 use bitflags::bitflags;
 
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Protocol {
     OF10_STD = sys::ofputil_protocol_OFPUTIL_P_OF10_STD as isize,
@@ -100,6 +104,95 @@ bitflags! {
     }
 }
 
+/// Error parsing a user-supplied protocol or version string, e.g. from a CLI flag or config file.
+#[derive(Debug)]
+pub struct ProtocolParseError(pub String);
+
+impl fmt::Display for ProtocolParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for ProtocolParseError {}
+
+/// Per-protocol flow-format names, each naming exactly one [`Protocols`] bit. Mirrors the
+/// individual entries in OVS's `ofputil_protocol_names`.
+const PROTOCOL_NAMES: &[(&str, Protocols)] = &[
+    ("OpenFlow10-1.0", Protocols::OF10_STD),
+    ("OpenFlow10-1.0+table_id", Protocols::OF10_STD_TID),
+    ("NXM-1.0", Protocols::OF10_NXM),
+    ("NXM-1.0+table_id", Protocols::OF10_NXM_TID),
+    ("OpenFlow11-1.1", Protocols::OF11_STD),
+    ("OXM12", Protocols::OF12_OXM),
+    ("OXM13", Protocols::OF13_OXM),
+    ("OXM14", Protocols::OF14_OXM),
+    ("OXM15", Protocols::OF15_OXM),
+];
+
+/// Convenience abbreviations that each expand to a set of [`Protocols`] bits, so a caller doesn't
+/// have to spell out every flow-format variant for a given OpenFlow version.
+const PROTOCOL_ABBREVIATIONS: &[(&str, Protocols)] = &[
+    ("OpenFlow10", Protocols::OF10_STD_ANY),
+    ("NXM", Protocols::OF10_NXM_ANY),
+    ("OXM", Protocols::ANY_OXM),
+];
+
+impl FromStr for Protocols {
+    type Err = ProtocolParseError;
+
+    /// Parses a comma- or space-separated list of protocol tokens, where each token is either a
+    /// single flow-format name (see [`PROTOCOL_NAMES`]) or one of the convenience abbreviations
+    /// `"any"`, `"OpenFlow10"`, `"NXM"`, or `"OXM"` (see [`PROTOCOL_ABBREVIATIONS`]). Each token
+    /// ORs its bits into the result; an unrecognized token is an error. Mirrors OVS's
+    /// `ofputil_protocols_from_string`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut protocols = Protocols::empty();
+        for token in s.split(|c: char| c == ',' || c.is_whitespace()).filter(|t| !t.is_empty()) {
+            if token.eq_ignore_ascii_case("any") {
+                protocols |= Protocols::all();
+            } else if let Some((_, bits)) = PROTOCOL_ABBREVIATIONS.iter().find(|(name, _)| token.eq_ignore_ascii_case(name)) {
+                protocols |= *bits;
+            } else if let Some((_, bits)) = PROTOCOL_NAMES.iter().find(|(name, _)| token.eq_ignore_ascii_case(name)) {
+                protocols |= *bits;
+            } else {
+                return Err(ProtocolParseError(format!("{}: unknown OpenFlow protocol", token)));
+            }
+        }
+        Ok(protocols)
+    }
+}
+
+impl fmt::Display for Protocols {
+    /// Renders the set bits back into a comma-separated token list that [`Protocols::from_str`]
+    /// round-trips. Prefers the longest match at each step -- `"any"` if every bit is set,
+    /// otherwise each convenience abbreviation whose bits are fully present, then the individual
+    /// flow-format name for whatever's left -- so formatting a given set of bits always produces
+    /// the same string. Mirrors OVS's `ofputil_protocols_to_string`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if *self == Protocols::all() {
+            return write!(f, "any");
+        }
+
+        let mut remaining = *self;
+        let mut parts = Vec::new();
+        for (name, bits) in PROTOCOL_ABBREVIATIONS.iter() {
+            if !bits.is_empty() && remaining.contains(*bits) {
+                parts.push(*name);
+                remaining.remove(*bits);
+            }
+        }
+        for (name, bits) in PROTOCOL_NAMES.iter() {
+            if !bits.is_empty() && remaining.contains(*bits) {
+                parts.push(*name);
+                remaining.remove(*bits);
+            }
+        }
+
+        write!(f, "{}", parts.join(","))
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Version {
     OFP10 = sys::ofp_version_OFP10_VERSION as isize,
@@ -139,3 +232,136 @@ impl From<Version> for Versions {
         Versions { bits: (1 << (v as isize)) }
     }
 }
+
+/// Names for each individual [`Versions`] bit. Mirrors the entries in OVS's
+/// `ofputil_versions_from_string`/`ofputil_versions_to_string`.
+const VERSION_NAMES: &[(&str, Versions)] = &[
+    ("OpenFlow10", Versions::OFP10),
+    ("OpenFlow11", Versions::OFP11),
+    ("OpenFlow12", Versions::OFP12),
+    ("OpenFlow13", Versions::OFP13),
+    ("OpenFlow14", Versions::OFP14),
+    ("OpenFlow15", Versions::OFP15),
+];
+
+impl FromStr for Versions {
+    type Err = ProtocolParseError;
+
+    /// Parses a comma- or space-separated list of version tokens (`"OpenFlow10"` .. `"OpenFlow15"`,
+    /// or `"any"` for every version [`Self::SUPPORTED`]), OR-ing each token's bit into the result.
+    /// An unrecognized token is an error. Mirrors OVS's `ofputil_versions_from_string`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut versions = Versions::empty();
+        for token in s.split(|c: char| c == ',' || c.is_whitespace()).filter(|t| !t.is_empty()) {
+            if token.eq_ignore_ascii_case("any") {
+                versions |= Versions::SUPPORTED;
+            } else if let Some((_, bits)) = VERSION_NAMES.iter().find(|(name, _)| token.eq_ignore_ascii_case(name)) {
+                versions |= *bits;
+            } else {
+                return Err(ProtocolParseError(format!("{}: unknown OpenFlow version", token)));
+            }
+        }
+        Ok(versions)
+    }
+}
+
+impl fmt::Display for Versions {
+    /// Renders the set bits back into a comma-separated token list that [`Versions::from_str`]
+    /// round-trips: `"any"` if every bit in [`Self::SUPPORTED`] is set, else each version's name
+    /// in ascending order. Mirrors OVS's `ofputil_versions_to_string`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.contains(Versions::SUPPORTED) {
+            return write!(f, "any");
+        }
+
+        let parts: Vec<&str> = VERSION_NAMES.iter()
+            .filter(|(_, bits)| self.contains(*bits))
+            .map(|(name, _)| *name)
+            .collect();
+
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+impl Protocol {
+    /// The single wire version that speaks this flow format: the "STD"/"NXM" protocols are all
+    /// OpenFlow 1.0, and every OXM protocol names the version it's the OXM format for.
+    pub fn to_version(self) -> Version {
+        match self {
+            Protocol::OF10_STD | Protocol::OF10_STD_TID |
+            Protocol::OF10_NXM | Protocol::OF10_NXM_TID => Version::OFP10,
+            Protocol::OF11_STD => Version::OFP11,
+            Protocol::OF12_OXM => Version::OFP12,
+            Protocol::OF13_OXM => Version::OFP13,
+            Protocol::OF14_OXM => Version::OFP14,
+            Protocol::OF15_OXM => Version::OFP15,
+        }
+    }
+}
+
+impl Protocols {
+    /// The flow-format protocols usable to negotiate each version set in `versions`: OpenFlow 1.0
+    /// allows either the standard or NXM flow format (not their "_TID" variants, which apply only
+    /// once table-id support is separately enabled), while 1.1+ each have exactly one. Mirrors
+    /// OVS's `ofputil_protocols_from_version_bitmap`.
+    pub fn from_version_bitmap(versions: Versions) -> Protocols {
+        let mut protocols = Protocols::empty();
+        if versions.contains(Versions::OFP10) {
+            protocols |= Protocols::OF10_STD | Protocols::OF10_NXM;
+        }
+        if versions.contains(Versions::OFP11) {
+            protocols |= Protocols::OF11_STD;
+        }
+        if versions.contains(Versions::OFP12) {
+            protocols |= Protocols::OF12_OXM;
+        }
+        if versions.contains(Versions::OFP13) {
+            protocols |= Protocols::OF13_OXM;
+        }
+        if versions.contains(Versions::OFP14) {
+            protocols |= Protocols::OF14_OXM;
+        }
+        if versions.contains(Versions::OFP15) {
+            protocols |= Protocols::OF15_OXM;
+        }
+        protocols
+    }
+
+    /// The wire versions implied by this set of flow-format protocols: the reverse of
+    /// [`Protocols::from_version_bitmap`].
+    pub fn to_version_bitmap(self) -> Versions {
+        let mut versions = Versions::empty();
+        if self.intersects(Protocols::OF10_ANY) {
+            versions |= Versions::OFP10;
+        }
+        if self.intersects(Protocols::OF11_STD) {
+            versions |= Versions::OFP11;
+        }
+        if self.intersects(Protocols::OF12_OXM) {
+            versions |= Versions::OFP12;
+        }
+        if self.intersects(Protocols::OF13_OXM) {
+            versions |= Versions::OFP13;
+        }
+        if self.intersects(Protocols::OF14_OXM) {
+            versions |= Versions::OFP14;
+        }
+        if self.intersects(Protocols::OF15_OXM) {
+            versions |= Versions::OFP15;
+        }
+        versions
+    }
+}
+
+impl Versions {
+    /// The greatest version present in both `self` and `other`, or `None` if they share no
+    /// version -- exactly the rule a controller/switch handshake uses to pick the session
+    /// version from each side's supported-version bitmap.
+    pub fn highest_common(self, other: Versions) -> Option<Version> {
+        let common = self & other;
+        [Version::OFP15, Version::OFP14, Version::OFP13, Version::OFP12, Version::OFP11, Version::OFP10]
+            .iter()
+            .copied()
+            .find(|&version| common.contains(Versions::from(version)))
+    }
+}
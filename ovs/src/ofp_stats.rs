@@ -0,0 +1,125 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! OpenFlow flow statistics requests and replies.
+
+use super::sys;
+
+use super::ofpbuf::Ofpbuf;
+use super::ofp_errors::{self, Eof};
+use super::ofp_protocol::Protocol;
+
+use std::error;
+use std::ffi;
+use std::fmt;
+use std::os::raw;
+use std::ptr::null;
+
+use anyhow::Result;
+
+/// A request for the statistics of every flow matching a filter, in the same `ovs-ofctl
+/// dump-flows`-style syntax that [`super::ofp_flow::FlowMod`] parses for flow mods.
+pub struct FlowStatsRequest(sys::ofputil_flow_stats_request);
+
+#[derive(Debug)]
+pub struct FlowStatsRequestParseError(pub String);
+
+impl fmt::Display for FlowStatsRequestParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl error::Error for FlowStatsRequestParseError {}
+
+unsafe impl Send for FlowStatsRequest {}
+unsafe impl Sync for FlowStatsRequest {}
+impl FlowStatsRequest {
+    /// Parses `s` as a filter for the flows whose statistics are of interest.  An empty string
+    /// matches every flow in every table.
+    pub fn parse(s: &str) -> Result<FlowStatsRequest> {
+        // `struct match`'s wildcard-everything initial state is all-zero, the same as a
+        // default-initialized `ofputil_flow_stats_request`, so there's no analog of
+        // `minimatch_destroy` to call here the way `FlowMod` does for its own match.
+        let mut fsr: sys::ofputil_flow_stats_request = unsafe { std::mem::zeroed() };
+        let cs = ffi::CString::new(s)
+            .map_err(|_| FlowStatsRequestParseError("unexpected NUL in string".into()))?;
+        unsafe {
+            sys::parse_ofp_flow_stats_request_str(&mut fsr as *mut sys::ofputil_flow_stats_request,
+                                                   false, cs.as_ptr(), null(), null());
+        }
+        Ok(FlowStatsRequest(fsr))
+    }
+
+    pub fn encode(&self, protocol: Protocol) -> Ofpbuf {
+        unsafe {
+            let b = sys::ofputil_encode_flow_stats_request(&self.0 as *const sys::ofputil_flow_stats_request,
+                                                            protocol.into());
+            Ofpbuf::from_ptr(b)
+        }
+    }
+}
+
+/// One flow's statistics, as reported by OVS in a reply to a [`FlowStatsRequest`].
+pub struct FlowStats {
+    /// The flow's cookie, which a caller that tagged its flows with a distinguishing cookie can
+    /// use to correlate these statistics back to whatever installed the flow.
+    pub cookie: u64,
+    pub packet_count: u64,
+    pub byte_count: u64,
+    /// Seconds since a packet last matched this flow, or -1 if OVS doesn't track it for this flow.
+    pub idle_age: i32,
+}
+
+/// Decodes every [`FlowStats`] record carried by one flow-stats reply message `oh`, which may
+/// bundle statistics for any number of flows.
+pub fn decode_flow_stats_reply(oh: &[u8]) -> Result<Vec<FlowStats>> {
+    let mut stats = Vec::new();
+    unsafe {
+        let mut msg: sys::ofpbuf = std::mem::zeroed();
+        sys::ofpbuf_use_const(&mut msg as *mut sys::ofpbuf, oh.as_ptr() as *const raw::c_void, oh.len());
+        sys::ofpraw_pull_assert(&mut msg as *mut sys::ofpbuf);
+
+        loop {
+            let mut fs: sys::ofputil_flow_stats = std::mem::zeroed();
+            let mut ofpacts: sys::ofpbuf = std::mem::zeroed();
+            sys::ofpbuf_init(&mut ofpacts as *mut sys::ofpbuf, 64);
+            let retval = sys::ofputil_decode_flow_stats_reply(&mut fs as *mut sys::ofputil_flow_stats,
+                                                               &mut msg as *mut sys::ofpbuf,
+                                                               false,
+                                                               &mut ofpacts as *mut sys::ofpbuf);
+            sys::ofpbuf_uninit(&mut ofpacts as *mut sys::ofpbuf);
+
+            match ofp_errors::parse(retval) {
+                Ok(()) => stats.push(FlowStats {
+                    cookie: fs.cookie,
+                    packet_count: fs.packet_count,
+                    byte_count: fs.byte_count,
+                    idle_age: fs.idle_age,
+                }),
+                Err(error) if error.is::<Eof>() => break,
+                Err(error) => Err(error)?,
+            }
+        }
+
+        sys::ofpbuf_uninit(&mut msg as *mut sys::ofpbuf);
+    }
+    Ok(stats)
+}
@@ -0,0 +1,203 @@
+/*
+Copyright (c) 2026 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+
+//! A lock-free pool of reusable [`sys::ofpbuf`]s, to avoid per-message malloc/free churn on the
+//! hot encode path (e.g. `ofp_msgs`, `ofp_bundle`) when OpenFlow I/O is spread across threads.
+
+use super::sys;
+
+use libc;
+
+use std::ptr::null_mut;
+use std::slice;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+
+/// A free-list entry. Unlinking a node from [`OfpbufPool::head`] only gives its popping thread
+/// exclusive claim on `buf` (taken with `buf.swap`, never read again by anyone else); the node
+/// struct's own memory can still be observed by another thread's in-flight traversal, so it's
+/// reclaimed with `Guard::defer_destroy` rather than freed immediately. See
+/// `OfpbufPool::acquire`/`release`.
+struct Node {
+    buf: AtomicPtr<sys::ofpbuf>,
+    next: Atomic<Node>,
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let buf = self.buf.load(Ordering::Acquire);
+        if !buf.is_null() {
+            unsafe {
+                sys::ofpbuf_uninit(buf);
+                libc::free(buf as *mut _);
+            }
+        }
+    }
+}
+
+/// A lock-free free-list of [`sys::ofpbuf`]s, reclaimed with epoch-based garbage collection
+/// (`crossbeam_epoch`) instead of a mutex. `acquire()`/`release()` pin the current epoch and pop
+/// and push through [`Atomic`] pointers guarded by the resulting `Guard`. `acquire()` retires each
+/// node it pops with `guard.defer_destroy()`, so the node's memory is only freed once every thread
+/// that might still be mid-traversal of the free-list has advanced past the epoch it was visited
+/// in; the buffer the node held has already been handed out by then. A buffer that `release()`
+/// doesn't pool is freed at once.
+pub struct OfpbufPool {
+    head: Atomic<Node>,
+    /// Free-list entries beyond this are freed on `release()` instead of being pooled, so a burst
+    /// of releases can't grow the free-list without bound.
+    max_free: usize,
+    /// Approximate count of nodes currently on the free-list; see `release()` for why it can
+    /// briefly overshoot `max_free` under concurrent releases.
+    free_count: AtomicUsize,
+}
+
+impl OfpbufPool {
+    /// Creates a pool that keeps up to `max_free` released buffers around for reuse.
+    pub fn new(max_free: usize) -> Arc<OfpbufPool> {
+        Arc::new(OfpbufPool {
+            head: Atomic::null(),
+            max_free,
+            free_count: AtomicUsize::new(0),
+        })
+    }
+
+    /// Hands out a buffer, reused from the free-list if one is available, or freshly allocated
+    /// with `ofpbuf_new(min_size)` otherwise. `min_size` applies only to a fresh buffer; a reused
+    /// one keeps whatever allocation it had. The returned [`PooledOfpbuf`] returns the buffer to `self` when dropped instead of freeing it.
+    pub fn acquire(self: &Arc<Self>, min_size: usize) -> PooledOfpbuf {
+        let guard = &epoch::pin();
+        let mut head = self.head.load(Ordering::Acquire, guard);
+        loop {
+            let node = match unsafe { head.as_ref() } {
+                Some(node) => node,
+                None => {
+                    let buf = unsafe { sys::ofpbuf_new(min_size) };
+                    return PooledOfpbuf { buf, pool: self.clone() };
+                }
+            };
+
+            let next = node.next.load(Ordering::Acquire, guard);
+            match self.head.compare_exchange_weak(head, next, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(_) => {
+                    self.free_count.fetch_sub(1, Ordering::AcqRel);
+                    let buf = node.buf.swap(null_mut(), Ordering::AcqRel);
+                    // Safe to retire now: this thread alone unlinked `head`, so `buf` has already
+                    // been taken and nothing will read it through `node` again.
+                    unsafe { guard.defer_destroy(head) };
+                    return PooledOfpbuf { buf, pool: self.clone() };
+                }
+                Err(err) => head = err.current,
+            }
+        }
+    }
+
+    /// Returns `buf` to the free-list for reuse, after resetting its length with `ofpbuf_clear`
+    /// (which keeps the underlying allocation, unlike `ofpbuf_uninit`). If the free-list already
+    /// holds `max_free` buffers, `buf` is freed immediately instead.
+    fn release(&self, buf: *mut sys::ofpbuf) {
+        unsafe { sys::ofpbuf_clear(buf) };
+
+        // Optimistically claim a slot and back out if that overshoots `max_free`. `fetch_add`
+        // gives each concurrent release its own count, so only releases that saw room go on to
+        // push, and the free-list never holds more than `max_free` entries; the counter itself
+        // can read above `max_free` for a moment while a release backs out.
+        if self.free_count.fetch_add(1, Ordering::AcqRel) >= self.max_free {
+            self.free_count.fetch_sub(1, Ordering::AcqRel);
+            unsafe {
+                sys::ofpbuf_uninit(buf);
+                libc::free(buf as *mut _);
+            }
+            return;
+        }
+
+        let guard = &epoch::pin();
+        let mut new_node = Owned::new(Node { buf: AtomicPtr::new(buf), next: Atomic::null() });
+        let mut head = self.head.load(Ordering::Acquire, guard);
+        loop {
+            new_node.next.store(head, Ordering::Relaxed);
+            match self.head.compare_exchange_weak(head, new_node, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(_) => return,
+                Err(err) => {
+                    head = err.current;
+                    new_node = err.new;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for OfpbufPool {
+    fn drop(&mut self) {
+        // The last `Arc<OfpbufPool>` just went away, so no other thread can be pinning an epoch
+        // against this free-list; walk and free it directly instead of deferring.
+        let guard = unsafe { epoch::unprotected() };
+        let mut current = self.head.load(Ordering::Relaxed, guard);
+        while let Some(node) = unsafe { current.as_ref() } {
+            let next = node.next.load(Ordering::Relaxed, guard);
+            drop(unsafe { current.into_owned() });
+            current = next;
+        }
+    }
+}
+
+/// An [`sys::ofpbuf`] borrowed from an [`OfpbufPool`]. `Drop` returns it to the pool (after
+/// `ofpbuf_clear`) instead of freeing it, unlike [`super::ofpbuf::Ofpbuf`], which frees its buffer
+/// unconditionally on drop.
+pub struct PooledOfpbuf {
+    buf: *mut sys::ofpbuf,
+    pool: Arc<OfpbufPool>,
+}
+
+unsafe impl Send for PooledOfpbuf {}
+unsafe impl Sync for PooledOfpbuf {}
+
+impl PooledOfpbuf {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts((*self.buf).data as *const u8, (*self.buf).size as usize) }
+    }
+    pub fn as_ptr(&self) -> *const u8 {
+        unsafe { (*self.buf).data as *const u8 }
+    }
+    /// Detaches the underlying `ofpbuf` from the pool, e.g. to hand it to OVS code that takes
+    /// ownership. The caller becomes responsible for `ofpbuf_uninit`/freeing it; it will not be
+    /// returned to the pool.
+    pub unsafe fn leak(&mut self) -> *mut sys::ofpbuf {
+        let ptr = self.buf;
+        self.buf = null_mut();
+        ptr
+    }
+}
+
+impl From<PooledOfpbuf> for Vec<u8> {
+    fn from(buf: PooledOfpbuf) -> Vec<u8> {
+        buf.as_slice().into()
+    }
+}
+
+impl Drop for PooledOfpbuf {
+    fn drop(&mut self) {
+        if !self.buf.is_null() {
+            self.pool.release(self.buf);
+        }
+    }
+}
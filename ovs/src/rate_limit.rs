@@ -0,0 +1,142 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+use super::poll_loop;
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Instant;
+
+/// A token bucket that costs 1000 scaled tokens per event, so a whole token -- one allowed event
+/// -- is always `1000` in this representation. Keeping a fractional token around between refills
+/// (instead of rounding a sub-token refill down to zero) is what lets a low configured `rate`
+/// still behave smoothly rather than starving entirely between refills.
+const TOKEN_COST: i64 = 1000;
+
+/// A thread-safe token-bucket rate limiter, for throttling repeated events (reconnect storms,
+/// error logs, flow-mod floods) the way OVS's `VLOG_RATE_LIMIT_INIT(rate, burst)` throttles log
+/// spew. `check()` is lock-free: refilling the bucket and spending a token both go through atomic
+/// operations rather than a mutex, so it can be called from any thread without contention.
+pub struct RateLimiter {
+    /// Tokens available, scaled by [`TOKEN_COST`] so a single event's cost is a whole number.
+    tokens: AtomicI64,
+    /// Milliseconds since `epoch` as of the last refill.
+    last_refill_ms: AtomicI64,
+    /// Reference point `last_refill_ms` is measured from; never read directly.
+    epoch: Instant,
+    /// Scaled tokens added per second of elapsed time.
+    rate: i64,
+    /// Scaled token ceiling the bucket can hold.
+    burst: i64,
+    /// Number of `check()` calls that returned `false`, i.e. were throttled away.
+    suppressed: AtomicU64,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter that allows `rate` events per second on average, absorbing bursts of
+    /// up to `burst` events before it starts throttling.
+    pub fn new(rate: i64, burst: i64) -> Self {
+        let burst = burst * TOKEN_COST;
+        RateLimiter {
+            tokens: AtomicI64::new(burst),
+            last_refill_ms: AtomicI64::new(0),
+            epoch: Instant::now(),
+            rate: rate * TOKEN_COST,
+            burst,
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// Refills the bucket for however much time has elapsed since the last refill (by any thread),
+    /// then, if at least one token is available, atomically spends one and returns `true`.
+    /// Otherwise returns `false` and counts the event as suppressed. Callers should skip whatever
+    /// they were about to do (log a message, retry a connection, ...) when this returns `false`.
+    pub fn check(&self) -> bool {
+        self.refill();
+
+        let mut tokens = self.tokens.load(Ordering::Acquire);
+        loop {
+            if tokens < TOKEN_COST {
+                self.suppressed.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+
+            match self.tokens.compare_exchange_weak(
+                tokens, tokens - TOKEN_COST, Ordering::AcqRel, Ordering::Acquire,
+            ) {
+                Ok(_) => return true,
+                Err(observed) => tokens = observed,
+            }
+        }
+    }
+
+    /// The number of events suppressed by `check()` returning `false` so far. A caller that just
+    /// started succeeding again after a run of failures can read this to log a
+    /// "...N messages suppressed" summary, the way OVS's rate-limited logging does.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Adds tokens for the time elapsed since the last refill (by any thread) and caps the result
+    /// at `burst`. Two threads racing here can each observe a slightly stale `last_refill_ms` and
+    /// both add tokens for the same interval; the bucket ends up very slightly more generous than
+    /// the configured rate, which is an acceptable trade for not needing a lock.
+    fn refill(&self) {
+        let now_ms = self.epoch.elapsed().as_millis() as i64;
+        let last_ms = self.last_refill_ms.swap(now_ms, Ordering::AcqRel);
+        let elapsed_ms = now_ms - last_ms;
+        if elapsed_ms <= 0 {
+            return;
+        }
+
+        let added = elapsed_ms.saturating_mul(self.rate) / 1000;
+        if added == 0 {
+            return;
+        }
+
+        let mut tokens = self.tokens.load(Ordering::Acquire);
+        loop {
+            let refilled = (tokens + added).min(self.burst);
+            match self.tokens.compare_exchange_weak(
+                tokens, refilled, Ordering::AcqRel, Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(observed) => tokens = observed,
+            }
+        }
+    }
+
+    /// Milliseconds until the bucket will hold another whole token, or `0` if one is available
+    /// now. Based on the tokens last observed by `check()`/`refill()`, not a fresh refill, so it's
+    /// only a hint -- another thread may have spent or added tokens in the meantime.
+    fn until_next_token_ms(&self) -> i64 {
+        let tokens = self.tokens.load(Ordering::Acquire);
+        let short_by = TOKEN_COST - tokens;
+        if short_by <= 0 || self.rate <= 0 {
+            return 0;
+        }
+        (short_by * 1000 + self.rate - 1) / self.rate
+    }
+
+    /// Registers a wakeup, via [`poll_loop::timer_wait`], for whenever the bucket is expected to
+    /// have another token available, so a `poll_block` loop throttled by this limiter can sleep
+    /// precisely instead of busy-polling `check()`.
+    pub fn wait(&self) {
+        poll_loop::timer_wait(self.until_next_token_ms());
+    }
+}
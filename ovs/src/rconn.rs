@@ -25,7 +25,59 @@ use std::ffi;
 use std::io;
 use std::ptr::{null, null_mut};
 
-pub struct Rconn(*mut sys::rconn);
+/// An `Rconn`'s state, parsed from the raw string `rconn_get_state` returns (see `Rconn::state()`)
+/// so a caller doesn't have to string-match on OVS internals itself -- analogous to how a
+/// coroutine library enumerates `Suspended`/`Blocked`/`Running`/`Finished` instead of handing back
+/// whatever debug string its scheduler happens to use. Mirrors the `state_name()` entries in OVS's
+/// `lib/rconn.c`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RconnState {
+    /// `rconn_connect()` hasn't been called yet; there's nothing to connect to.
+    Void,
+    /// Waiting to retry a previously failed connection attempt.
+    Backoff,
+    /// A connection attempt is in progress.
+    Connecting,
+    /// Connected, and has sent or received data recently enough that there's no need to probe.
+    Active,
+    /// Connected, but quiet long enough that a probe is about to be sent to check the connection
+    /// is still alive.
+    Idle,
+    /// Not connected, and (unlike `Backoff`) not about to retry -- e.g. an unreliable `Rconn` that
+    /// gave up after `disconnect()`.
+    Disconnected,
+    /// `rconn_get_state()` returned a string this enum doesn't recognize, carried as-is instead of
+    /// losing it outright -- the same fallback `Protocols`/`Versions` parsing doesn't have the
+    /// luxury of, but `state()` is a plain debug string, not a hard error.
+    Unknown(String),
+}
+
+impl RconnState {
+    fn parse(state: &str) -> RconnState {
+        if state.eq_ignore_ascii_case("void") {
+            RconnState::Void
+        } else if state.eq_ignore_ascii_case("backoff") {
+            RconnState::Backoff
+        } else if state.eq_ignore_ascii_case("connecting") {
+            RconnState::Connecting
+        } else if state.eq_ignore_ascii_case("active") {
+            RconnState::Active
+        } else if state.eq_ignore_ascii_case("idle") {
+            RconnState::Idle
+        } else if state.eq_ignore_ascii_case("disconnected") {
+            RconnState::Disconnected
+        } else {
+            RconnState::Unknown(state.to_string())
+        }
+    }
+}
+
+/// Returned by [`Rconn::try_send`] when the transmit queue is already at the caller's
+/// `queue_limit`: carries the unsent `Ofpbuf` back so the caller can hold onto it and retry,
+/// mirroring `std::sync::mpsc::TrySendError::Full`.
+pub struct QueueFull(pub Ofpbuf);
+
+pub struct Rconn(*mut sys::rconn, Option<(RconnState, u32)>);
 
 pub const DSCP_DEFAULT: u8 = sys::DSCP_DEFAULT as u8;
 impl Rconn {
@@ -33,7 +85,7 @@ impl Rconn {
                dscp: u8, versions: Versions) -> Rconn {
         unsafe {
             Rconn(sys::rconn_create(inactivity_probe_interval, max_backoff, dscp,
-                                    versions.bits()))
+                                    versions.bits()), None)
         }
     }
 
@@ -101,7 +153,41 @@ impl Rconn {
             }
         }
     }
-    // XXX send_with_limit()
+    /// Like `send()`, but rejects the send with a `WouldBlock` error instead of handing `buf` to
+    /// OVS when `txqlen()` is already at or above `queue_limit` -- without this, a slow or stalled
+    /// switch connection lets its outbound queue grow without bound as a caller keeps calling
+    /// `send()`.
+    pub fn send_with_limit(&mut self, buf: Ofpbuf, queue_limit: u32) -> io::Result<()> {
+        if self.txqlen() >= queue_limit {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        self.send(buf)
+    }
+
+    /// Non-blocking variant of `send_with_limit()`: on backpressure, `buf` is handed back via
+    /// [`QueueFull`] instead of being dropped, so the caller can hold onto it and retry once
+    /// `txqlen()` shows room again (see `flush()` and `drain_wait()`).
+    pub fn try_send(&mut self, buf: Ofpbuf, queue_limit: u32) -> Result<io::Result<()>, QueueFull> {
+        if self.txqlen() >= queue_limit {
+            return Err(QueueFull(buf));
+        }
+        Ok(self.send(buf))
+    }
+
+    /// Drives the rconn's transmit queue down by running it, so a caller that got backpressure
+    /// from `send_with_limit()`/`try_send()` can check `txqlen()` again afterward to see whether
+    /// there's now room to retry.
+    pub fn flush(&mut self) {
+        self.run();
+    }
+
+    /// Like `recv_wait()`, but also arranges for the poll loop to wake once the rconn has made
+    /// progress draining its transmit queue, not just once there's something to `recv()` -- so a
+    /// sender blocked on backpressure isn't stuck waiting for an unrelated wakeup.
+    pub fn drain_wait(&mut self) {
+        unsafe { sys::rconn_run_wait(self.0) }
+        self.recv_wait();
+    }
 
     // XXX add_monitor()
 
@@ -129,6 +215,30 @@ impl Rconn {
     pub fn state(&self) -> String {
         unsafe { ffi::CStr::from_ptr(sys::rconn_get_state(self.0)).to_string_lossy().into() }
     }
+    /// The connection's current state, parsed into a [`RconnState`] instead of `state()`'s raw
+    /// string.
+    pub fn state_typed(&self) -> RconnState {
+        RconnState::parse(&self.state())
+    }
+    /// Reports a `(old, new)` [`RconnState`] transition if `state_typed()` or
+    /// `connection_seqno()` -- which OVS bumps on every connect/disconnect, even one that leaves
+    /// `state_typed()` reporting the same variant as before, e.g. a reconnect that lands back in
+    /// `Active` -- has changed since the last call to this method. Returns `None` if neither has,
+    /// including on the first call (there's nothing yet to compare against).
+    ///
+    /// Lets a supervisor react to, say, a switch going from `Active` to `Backoff` without polling
+    /// `state()`'s raw string itself on every tick.
+    pub fn poll_state_change(&mut self) -> Option<(RconnState, RconnState)> {
+        let new_state = self.state_typed();
+        let new_seqno = self.connection_seqno();
+        let transition = match &self.1 {
+            Some((old_state, old_seqno)) if *old_state == new_state && *old_seqno == new_seqno => None,
+            Some((old_state, _)) => Some(old_state.clone()),
+            None => None,
+        };
+        self.1 = Some((new_state.clone(), new_seqno));
+        transition.map(|old| (old, new_state))
+    }
     pub fn last_connection(&self) -> i64 { unsafe { sys::rconn_get_last_connection(self.0) } }
     pub fn last_disconnect(&self) -> i64 { unsafe { sys::rconn_get_last_disconnect(self.0) } }
     pub fn connection_seqno(&self) -> u32 { unsafe { sys::rconn_get_connection_seqno(self.0) } }
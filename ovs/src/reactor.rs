@@ -0,0 +1,98 @@
+/*
+Copyright (c) 2022 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+ */
+use super::latch::Latch;
+use super::poll_loop;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+/// A [`Waker`] that wakes its reactor from any thread by setting a [`Latch`], the way the
+/// [`Latch`] doc comment describes: an OVS `Rconn` (or anything else driven from a `poll_loop`)
+/// exposes no usable file descriptor of its own, so a future that wants to wake the loop has to go
+/// through this signal-safe doorbell instead.
+struct LatchWaker(Mutex<Latch>);
+
+impl Wake for LatchWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.lock().unwrap().set();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.lock().unwrap().set();
+    }
+}
+
+/// A single-threaded executor that runs futures on the same thread as an OVS `poll_loop`, so code
+/// holding an OVS object whose fd it can't get at (an `Rconn`, say) can still drive `async` tasks
+/// alongside it. Each spawned future is expected to register its own OVS wait conditions (via
+/// [`poll_loop::fd_wait`]/[`poll_loop::timer_wait`]) during its own `poll`; [`Reactor::run`] just
+/// arms the latch fd on top of whatever a future registered and calls [`poll_loop::block`] to
+/// sleep until one of them fires.
+pub struct Reactor {
+    waker: Arc<LatchWaker>,
+    tasks: Vec<Pin<Box<dyn Future<Output = ()>>>>,
+}
+
+impl Reactor {
+    pub fn new() -> Self {
+        Reactor {
+            waker: Arc::new(LatchWaker(Mutex::new(Latch::new()))),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Adds `future` to the set of tasks this reactor drives. It starts running the next time
+    /// [`Reactor::run`] polls.
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) {
+        self.tasks.push(Box::pin(future));
+    }
+
+    /// Runs every spawned future to completion.
+    ///
+    /// On each iteration, polls every still-pending task once, then -- if any remain -- arms
+    /// `poll_loop::fd_wait` on the latch fd (`POLLIN`), calls `poll_loop::block()` to sleep until
+    /// either an OVS fd a task registered or the latch fires, and drains the latch with
+    /// `Latch::poll()` before polling again. A wake from another thread (e.g. a Tokio task
+    /// completing) sets the latch, which breaks the `poll_block()` out immediately.
+    pub fn run(&mut self) {
+        let waker = Waker::from(self.waker.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            self.tasks.retain_mut(|task| task.as_mut().poll(&mut cx) == Poll::Pending);
+
+            if self.tasks.is_empty() {
+                return;
+            }
+
+            poll_loop::fd_wait(self.waker.0.lock().unwrap().fd(), libc::POLLIN);
+            poll_loop::block();
+            self.waker.0.lock().unwrap().poll();
+        }
+    }
+}
+
+impl Default for Reactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -26,11 +26,16 @@ use crate::EVENT_TYPE_UPDATE;
 use differential_datalog::api::HDDlog;
 use differential_datalog::ddval::DDValue;
 use differential_datalog::DeltaMap;
-use differential_datalog::program::Update;
+use differential_datalog::program::{RelId, Update};
 
+use serde_json::Value;
+
+use std::collections::{HashMap, HashSet};
 use std::ffi;
 use std::sync::Arc;
 
+use tracing::error;
+
 /// Context to interact with OVSDB.
 #[repr(C)]
 pub struct OvsdbContext {
@@ -48,8 +53,70 @@ pub struct OvsdbContext {
     /// State of OVSDB connection.
     pub state: Option<ConnectionState>,
 
+    /// Snapshot of the output-only rows OVSDB last acknowledged, in the same per-relation,
+    /// signed-weight shape a `DeltaMap` uses (a row with weight > 0 is present).
+    ///
+    /// `None` means the snapshot is stale -- it hasn't been fetched yet, a `JSONRPC_ERROR` forced
+    /// a reconnect since the last fetch, or a reconciliation diff was answered with an error -- so
+    /// the next [`OvsdbContext::reconcile_output_only_data`] diffs against an empty baseline, a
+    /// full resync (every row DDlog wants is sent as an insert), rather than against
+    /// possibly-outdated data. A reply moving `state` to [`ConnectionState::Update`] replaces it
+    /// with the rows that reply reports.
+    output_only_data: Option<HashMap<RelId, HashMap<DDValue, isize>>>,
+
+    /// Desired-state snapshots captured by [`OvsdbContext::reconcile_output_only_data`] when it
+    /// submitted a diff, keyed by that transaction's request id.
+    ///
+    /// On commit, [`OvsdbContext::process_txn_reply`] promotes the matching entry to
+    /// `output_only_data`; on abort, it drops the entry and invalidates `output_only_data`
+    /// instead, since it's no longer known which (if any) of the diff's operations the server
+    /// actually applied before erroring.
+    pending_reconcile_snapshots: HashMap<u64, HashMap<RelId, HashMap<DDValue, isize>>>,
+
     /// Database name.
     db_name: String,
+
+    /// Last transaction id acknowledged by the server, if any.
+    ///
+    /// When present, it is included in the next monitor request (as `monitor_cond_since`) so
+    /// that reconnects resume from this point instead of re-replicating the whole database.
+    pub last_txn_id: Option<String>,
+
+    /// Per-table conditions to restrict replication to matching rows.
+    ///
+    /// Each entry is a table name mapped to a list of OVSDB `where` clauses (already in the
+    /// JSON form OVSDB expects), passed through to `compose_monitor_request` unmodified. A table
+    /// with no entry here is monitored unconditionally.
+    pub table_conditions: std::collections::HashMap<String, Vec<Value>>,
+
+    /// Transactions submitted by [`OvsdbContext::send_output_updates`] that haven't received an
+    /// `EVENT_TYPE_TXN_REPLY` yet, keyed by the JSON-RPC request id `ovsdb_cs_send_transaction`
+    /// assigned them.
+    pending_txns: HashMap<u64, Vec<Update<DDValue>>>,
+
+    /// Tables and columns to subscribe to, set by [`OvsdbContext::set_subscription`].
+    ///
+    /// `None` (the default) subscribes to every column of every table in the schema, matching
+    /// this crate's original behavior.
+    subscription: Option<HashMap<String, ColumnSelection>>,
+}
+
+/// Which columns of a table to subscribe to in a monitor request.
+#[derive(Clone, Debug)]
+pub enum ColumnSelection {
+    /// Subscribe to every column of the table.
+    All,
+    /// Subscribe only to the named columns.
+    Columns(std::collections::HashSet<String>),
+}
+
+/// Outcome of a transaction submitted via [`OvsdbContext::send_output_updates`].
+#[derive(Debug)]
+pub enum TxnResult {
+    /// The server committed the batch of updates.
+    Committed(Vec<Update<DDValue>>),
+    /// The server aborted the batch; the transaction was not applied.
+    Aborted { updates: Vec<Update<DDValue>>, reason: String },
 }
 
 /// State of OVSDB connection.
@@ -93,7 +160,243 @@ impl OvsdbContext {
             prefix,
             input_relations: nerpa_rels::nerpa_input_relations(),
             state: Some(ConnectionState::Initial),
+            output_only_data: None,
+            pending_reconcile_snapshots: HashMap::new(),
             db_name: name,
+            last_txn_id: None,
+            table_conditions: std::collections::HashMap::new(),
+            pending_txns: HashMap::new(),
+            subscription: None,
+        }
+    }
+
+    /// Restricts the next monitor request to the given tables and columns, validating `spec`
+    /// against `schema_json` (the OVSDB schema, as returned by OVSDB's `get_schema` RPC).
+    ///
+    /// Tables absent from `spec` are skipped entirely instead of being monitored. Returns an
+    /// error, without changing the current subscription, if `spec` names a table or column that
+    /// doesn't exist in the schema.
+    ///
+    /// # Arguments
+    /// * `schema_json` - the OVSDB schema to validate `spec` against.
+    /// * `spec` - the tables/columns to subscribe to.
+    pub fn set_subscription(
+        &mut self,
+        schema_json: &Value,
+        spec: HashMap<String, ColumnSelection>,
+    ) -> Result<(), String> {
+        let tables = schema_json["tables"]
+            .as_object()
+            .ok_or_else(|| "schema has no \"tables\" object".to_string())?;
+
+        for (table, selection) in &spec {
+            let table_schema = tables
+                .get(table)
+                .ok_or_else(|| format!("{table}: no such table in schema"))?;
+            let columns = table_schema["columns"]
+                .as_object()
+                .ok_or_else(|| format!("{table}: schema has no \"columns\" object"))?;
+
+            if let ColumnSelection::Columns(names) = selection {
+                for name in names {
+                    if !columns.contains_key(name) {
+                        return Err(format!("{table}: no such column {name:?} in schema"));
+                    }
+                }
+            }
+        }
+
+        self.subscription = Some(spec);
+        Ok(())
+    }
+
+    /// Converts a batch of DDlog output-relation deltas into an OVSDB `transact` request and
+    /// submits it over `cs`.
+    ///
+    /// The eventual `EVENT_TYPE_TXN_REPLY` for this batch is correlated back to `updates` by its
+    /// request id; call [`OvsdbContext::process_txn_reply`] as usual to complete it and, on
+    /// success, get a [`TxnResult`] describing whether the server committed or aborted it.
+    ///
+    /// # Arguments
+    /// * `cs` - raw pointer to live OVSDB connection.
+    /// * `updates` - DDlog output-relation deltas to push back into OVSDB.
+    ///
+    /// # Safety
+    ///
+    /// This function is marked unsafe because it dereferences a possibly null raw pointer.
+    pub unsafe fn send_output_updates(
+        &mut self,
+        cs: *mut ovsdb_sys::ovsdb_cs,
+        updates: Vec<Update<DDValue>>,
+    ) -> Result<u64, String> {
+        if cs.is_null() {
+            return Err("needs non-nil client sync to send a transaction".to_string());
+        }
+
+        if updates.is_empty() {
+            return Err("no output updates to send".to_string());
+        }
+
+        let commands: Vec<_> = updates
+            .iter()
+            .map(|u| self.prog.convert_update_to_ddlog_command(u))
+            .collect();
+
+        let ops_str = ddlog_ovsdb_adapter::cmds_to_table_update_str(&self.prefix, &commands)
+            .map_err(|e| format!("could not convert output updates to OVSDB ops: {e}"))?;
+
+        let ops_cs = ffi::CString::new(ops_str)
+            .map_err(|e| format!("output ops contained a NUL byte: {e}"))?;
+        let ops_json = ovsdb_sys::json_from_string(ops_cs.as_ptr());
+
+        let request_id = ovsdb_sys::ovsdb_cs_send_transaction(cs, ops_json);
+        self.pending_txns.insert(request_id, updates);
+
+        Ok(request_id)
+    }
+
+    /// Pushes the incremental difference between what DDlog currently wants (`delta`) and what
+    /// OVSDB last acknowledged (`output_only_data`) as a single `transact` request, instead of
+    /// resending the whole output-only table on every call.
+    ///
+    /// Returns `Ok(None)` without submitting anything when there is no difference to reconcile.
+    /// Like [`OvsdbContext::send_output_updates`], the eventual `EVENT_TYPE_TXN_REPLY` should be
+    /// passed to [`OvsdbContext::process_txn_reply`] as usual; on success it advances
+    /// `output_only_data` to match what was just sent, and on a `JSONRPC_ERROR` it invalidates the
+    /// snapshot so the next call starts from a full resync instead of diverging from OVSDB.
+    ///
+    /// # Arguments
+    /// * `cs` - raw pointer to live OVSDB connection.
+    ///
+    /// # Safety
+    ///
+    /// This function is marked unsafe because it dereferences a possibly null raw pointer.
+    pub unsafe fn reconcile_output_only_data(
+        &mut self,
+        cs: *mut ovsdb_sys::ovsdb_cs,
+    ) -> Result<Option<u64>, String> {
+        let diff = self.diff_output_only_data();
+        if diff.is_empty() {
+            return Ok(None);
+        }
+
+        let snapshot = Self::snapshot_present_rows(&self.delta);
+        let request_id = self.send_output_updates(cs, diff)?;
+        self.pending_reconcile_snapshots.insert(request_id, snapshot);
+
+        Ok(Some(request_id))
+    }
+
+    /// Collects the `(relation, value)` pairs `delta` currently asserts are present, following
+    /// this crate's convention (see `push_outputs` in `nerpa_controller`) that a net positive
+    /// weight means inserted/kept and a non-positive one means retracted or simply absent.
+    fn present_rows(delta: &DeltaMap<DDValue>) -> HashSet<(RelId, DDValue)> {
+        delta.clone().into_iter()
+            .flat_map(|(relid, values)| {
+                values.into_iter()
+                    .filter(|(_, weight)| *weight > 0)
+                    .map(move |(v, _)| (relid, v))
+            })
+            .collect()
+    }
+
+    /// Same as [`Self::present_rows`], but over the `output_only_data`-shaped snapshot instead of
+    /// a live `DeltaMap`.
+    fn present_rows_in_snapshot(snapshot: &HashMap<RelId, HashMap<DDValue, isize>>) -> HashSet<(RelId, DDValue)> {
+        snapshot.iter()
+            .flat_map(|(relid, values)| {
+                values.iter()
+                    .filter(|(_, weight)| **weight > 0)
+                    .map(move |(v, _)| (*relid, v.clone()))
+            })
+            .collect()
+    }
+
+    /// Snapshots `delta`'s currently-present rows in the same shape `output_only_data` uses, so a
+    /// successful [`OvsdbContext::reconcile_output_only_data`] push can record exactly what it
+    /// sent as acknowledged.
+    fn snapshot_present_rows(delta: &DeltaMap<DDValue>) -> HashMap<RelId, HashMap<DDValue, isize>> {
+        let mut snapshot: HashMap<RelId, HashMap<DDValue, isize>> = HashMap::new();
+        for (relid, v) in Self::present_rows(delta) {
+            snapshot.entry(relid).or_default().insert(v, 1);
+        }
+        snapshot
+    }
+
+    /// Computes the incremental `Insert`/`DeleteValue` operations needed to bring the
+    /// output-only rows OVSDB last acknowledged (`output_only_data`) in line with what DDlog
+    /// currently wants (`delta`).
+    ///
+    /// A stale (`None`) snapshot is treated as an empty baseline, so every row `delta` wants comes
+    /// back as an `Insert` -- a full resync, expressed as the same incremental operation the
+    /// normal path emits.
+    fn diff_output_only_data(&self) -> Vec<Update<DDValue>> {
+        let wanted = Self::present_rows(&self.delta);
+        let acked = match &self.output_only_data {
+            Some(snapshot) => Self::present_rows_in_snapshot(snapshot),
+            None => HashSet::new(),
+        };
+
+        wanted.difference(&acked)
+            .map(|(relid, v)| Update::Insert { relid: *relid, v: v.clone() })
+            .chain(
+                acked.difference(&wanted)
+                    .map(|(relid, v)| Update::DeleteValue { relid: *relid, v: v.clone() })
+            )
+            .collect()
+    }
+
+    /// Parses an `OutputOnlyDataRequested` reply -- the initial report of what output-only rows
+    /// already exist in OVSDB -- into the `output_only_data` snapshot future pushes diff against.
+    ///
+    /// The reply's `result` is shaped like a `monitor`/`monitor_cond` table-updates payload (one
+    /// entry per subscribed table, each row keyed by UUID), so it's parsed with the same
+    /// `ddlog_ovsdb_adapter` machinery [`OvsdbContext::parse_updates`] uses for ordinary monitor
+    /// updates.
+    ///
+    /// # Safety
+    ///
+    /// This function is marked unsafe because it dereferences a possibly null raw pointer.
+    unsafe fn capture_output_only_data(&mut self, reply: *mut ovsdb_sys::jsonrpc_msg) -> Result<(), String> {
+        let reply_s = {
+            let reply_cs = ovsdb_sys::jsonrpc_msg_to_string(reply);
+            let s = ffi::CStr::from_ptr(reply_cs).to_str().map(str::to_string);
+            libc::free(reply_cs as *mut libc::c_void);
+            s.map_err(|e| format!("output-only-data reply was not valid UTF-8: {e}"))?
+        };
+
+        let reply_v: Value = serde_json::from_str(&reply_s)
+            .map_err(|e| format!("could not parse output-only-data reply as JSON: {e}"))?;
+        let result = reply_v.get("result")
+            .ok_or_else(|| "output-only-data reply has no \"result\"".to_string())?;
+
+        let commands = ddlog_ovsdb_adapter::cmds_from_table_updates_str(&self.prefix, &result.to_string())
+            .map_err(|e| format!("could not parse output-only-data rows: {e}"))?;
+
+        let mut snapshot: HashMap<RelId, HashMap<DDValue, isize>> = HashMap::new();
+        for command in &commands {
+            let update = self.prog.convert_update_command(command)
+                .map_err(|e| format!("could not convert output-only-data command: {e}"))?;
+            match update {
+                Update::Insert { relid, v } => { *snapshot.entry(relid).or_default().entry(v).or_insert(0) += 1; },
+                Update::DeleteValue { relid, v } => { *snapshot.entry(relid).or_default().entry(v).or_insert(0) -= 1; },
+                _ => {},
+            }
+        }
+
+        self.output_only_data = Some(snapshot);
+        Ok(())
+    }
+
+    /// Records a `last-txn-id` reported by the server, so that a subsequent monitor request (on
+    /// initial connection or reconnect) can resume from this point via `monitor_cond_since`
+    /// instead of requesting a full table dump.
+    ///
+    /// # Arguments
+    /// * `last_txn_id` - the transaction id to remember, or `None` if the server didn't report one.
+    pub fn set_last_txn_id(&mut self, last_txn_id: Option<String>) {
+        if let Some(id) = last_txn_id {
+            self.last_txn_id = Some(id);
         }
     }
 
@@ -111,7 +414,7 @@ impl OvsdbContext {
         &mut self,
         cs: *mut ovsdb_sys::ovsdb_cs,
         reply: *mut ovsdb_sys::jsonrpc_msg,
-    ) -> Result<(), String> {
+    ) -> Result<Option<TxnResult>, String> {
         if reply.is_null() {
             return Err(
                 "received a null transaction reply message".to_string()
@@ -120,6 +423,7 @@ impl OvsdbContext {
 
         // Dereferencing 'reply' is safe because of the null check.
         let reply_type = (*reply).type_;
+        let request_id = Self::json_request_id(reply);
 
         if reply_type == ovsdb_sys::jsonrpc_msg_type_JSONRPC_ERROR {
             // Convert the jsonrpc_msg to a *mut c_char.
@@ -132,6 +436,25 @@ impl OvsdbContext {
                 reply_s
             };
 
+            // If this error belongs to an outbound transaction we're tracking, surface it to
+            // the caller as an abort instead of just a connection-level error.
+            if let Some(id) = request_id {
+                if let Some(updates) = self.pending_txns.remove(&id) {
+                    if self.pending_reconcile_snapshots.remove(&id).is_some() {
+                        // It's not known which (if any) of this diff's operations the server
+                        // actually applied before erroring; invalidate the snapshot so the next
+                        // reconciliation starts from a full resync instead of silently
+                        // diverging from OVSDB.
+                        self.output_only_data = None;
+                    }
+                    return Ok(Some(TxnResult::Aborted { updates, reason: reply_s }));
+                }
+            }
+
+            // A force-reconnect invalidates any output-only-data snapshot too, since the next
+            // connection starts the `OutputOnlyDataRequested` handshake over from scratch.
+            self.output_only_data = None;
+
             // 'ovsdb_cs_force_reconnect' does not check for a null pointer.
             if cs.is_null() {
                 return Err(
@@ -144,6 +467,19 @@ impl OvsdbContext {
             return Err(reply_s);
         }
 
+        self.capture_last_txn_id(reply);
+
+        // If this reply corresponds to a batch submitted via `send_output_updates`, report its
+        // outcome instead of running it through the input-relation state machine below.
+        if let Some(id) = request_id {
+            if let Some(updates) = self.pending_txns.remove(&id) {
+                if let Some(snapshot) = self.pending_reconcile_snapshots.remove(&id) {
+                    self.output_only_data = Some(snapshot);
+                }
+                return Ok(Some(TxnResult::Committed(updates)));
+            }
+        }
+
         match self.state {
             Some(ConnectionState::Initial) => {
                 return Err(
@@ -151,7 +487,10 @@ impl OvsdbContext {
                 );
             },
             Some(ConnectionState::OutputOnlyDataRequested) => {
-                // TODO: Store and update 'output_only_data' on Context.
+                if let Err(err) = self.capture_output_only_data(reply) {
+                    error!(error = %err, "could not capture output-only data; starting from an empty snapshot");
+                    self.output_only_data = Some(HashMap::new());
+                }
 
                 self.state = Some(ConnectionState::Update);
             },
@@ -163,7 +502,23 @@ impl OvsdbContext {
             }
         }
 
-        Ok(())
+        Ok(None)
+    }
+
+    /// Extracts the numeric JSON-RPC request id from `reply`, if it has one.
+    ///
+    /// # Safety
+    ///
+    /// This function is marked unsafe because it dereferences a possibly null raw pointer.
+    unsafe fn json_request_id(reply: *mut ovsdb_sys::jsonrpc_msg) -> Option<u64> {
+        let id = (*reply).id;
+        if id.is_null() {
+            return None;
+        }
+
+        let id_cs = ovsdb_sys::json_to_string(id, 0);
+        let id_s = ffi::CStr::from_ptr(id_cs).to_str().ok()?;
+        id_s.parse().ok()
     }
 
     /// Process events from OVSDB. Convert them into DDlog updates.
@@ -171,7 +526,7 @@ impl OvsdbContext {
     /// # Arguments
     /// * `events`: events received from OVSDB.
     pub fn parse_updates(
-        &self,
+        &mut self,
         events: Vec<ovsdb_sys::ovsdb_cs_event>,
     ) -> Vec<Update<DDValue>> {
         let mut updates = Vec::new();
@@ -185,8 +540,15 @@ impl OvsdbContext {
                 continue;
             }
 
+            let update = unsafe { event.__bindgen_anon_1.update };
+
+            // `monitor_cond_since` updates carry the transaction id that produced them; track it
+            // so the next monitor request (e.g. after a reconnect) can resume from here.
+            if let Some(last_txn_id) = unsafe { Self::json_last_txn_id(update.last_txn_id) } {
+                self.last_txn_id = Some(last_txn_id);
+            }
+
             let table_updates_s = unsafe {
-                let update = event.__bindgen_anon_1.update;
                 let buf = ovsdb_sys::json_to_string(update.table_updates, 0);
 
                 ffi::CStr::from_ptr(buf).to_str().unwrap()
@@ -197,8 +559,8 @@ impl OvsdbContext {
                 table_updates_s
             );
 
-            if commands_res.is_err() {
-                println!("error extracting commands from table updates: {}", commands_res.unwrap_err());
+            if let Err(err) = commands_res {
+                error!(error = %err, "error extracting commands from table updates");
                 continue;
             }
 
@@ -209,11 +571,71 @@ impl OvsdbContext {
                 .collect();
 
             match updates_res {
-                Err(e) => println!("error converting update command: {}", e),
+                Err(e) => error!(error = %e, "error converting update command"),
                 Ok(mut r) => updates.append(&mut r),
             };
         }
 
         updates
     }
+
+    /// Extracts a `last-txn-id` UUID string out of a raw OVSDB `json` pointer, if it is non-null
+    /// and represents a JSON string.
+    ///
+    /// # Safety
+    ///
+    /// This function is marked unsafe because it dereferences a possibly null raw pointer.
+    /// Because it checks if this pointer is null, its behavior will be safe.
+    unsafe fn json_last_txn_id(json: *const ovsdb_sys::json) -> Option<String> {
+        if json.is_null() {
+            return None;
+        }
+
+        let s = ovsdb_sys::json_to_string(json, 0);
+        let s = ffi::CStr::from_ptr(s).to_str().ok()?;
+
+        // `json_to_string` of a JSON string literal produces a quoted string; strip the quotes.
+        let trimmed = s.trim_matches('"');
+        if trimmed.is_empty() || trimmed == "null" {
+            None
+        } else {
+            Some(trimmed.to_string())
+        }
+    }
+
+    /// Scans a transaction reply for a `last-txn-id`, as reported in `monitor_cond_since` replies,
+    /// and remembers it for the next monitor request.
+    fn capture_last_txn_id(&mut self, reply: *mut ovsdb_sys::jsonrpc_msg) {
+        let reply_s = unsafe {
+            let reply_cs = ovsdb_sys::jsonrpc_msg_to_string(reply);
+            let reply_s = ffi::CStr::from_ptr(reply_cs).to_str().map(str::to_string);
+            libc::free(reply_cs as *mut libc::c_void);
+            reply_s
+        };
+
+        let reply_s = match reply_s {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+
+        let reply_v: Value = match serde_json::from_str(&reply_s) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+
+        // The `monitor_cond_since` reply result has the shape `[found, last-txn-id, updates]`.
+        if let Some(result) = reply_v.get("result").and_then(Value::as_array) {
+            if let Some(found) = result.get(0).and_then(Value::as_bool) {
+                if !found {
+                    // The server couldn't resume from our `last_txn_id`; fall back to a full
+                    // monitor on the next reconnect.
+                    self.last_txn_id = None;
+                    return;
+                }
+            }
+            if let Some(last_txn_id) = result.get(1).and_then(Value::as_str) {
+                self.last_txn_id = Some(last_txn_id.to_string());
+            }
+        }
+    }
 }
\ No newline at end of file
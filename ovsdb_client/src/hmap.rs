@@ -24,6 +24,8 @@ extern crate ovsdb_sys;
 
 use std::{
     convert::TryInto,
+    ffi,
+    os::raw,
     ptr,
 };
 
@@ -85,16 +87,80 @@ fn hmap_next__(
     while i <= mask {
         let idx = i.try_into().unwrap();
 
-        /* Both dereferenced pointers are checked for null. */
+        /* 'hmap' was checked for null above, so the bucket array is safe to index;
+         * the bucket itself may still be empty, in which case it holds a null pointer. */
         unsafe {
-            let node = (*hmap).buckets.offset(idx);
-            if !node.is_null() {
-                return (*node);
+            let bucket = *(*hmap).buckets.offset(idx);
+            if !bucket.is_null() {
+                return bucket;
             }
         }
-        
+
         i += 1;
     }
 
     ptr::null()
 }
+
+/// Iterates over the nodes of an `hmap`, in the same arbitrary order as [`first`]/[`next`].
+///
+/// Subject to the same reallocation caveat documented on [`next`]: if the hash map is
+/// reallocated while iteration is in progress, some nodes may be skipped or visited twice.
+pub struct HmapIter {
+    hmap: *const ovsdb_sys::hmap,
+    cur: *const ovsdb_sys::hmap_node,
+}
+
+impl HmapIter {
+    pub fn new(hmap: *const ovsdb_sys::hmap) -> HmapIter {
+        HmapIter {
+            hmap,
+            cur: first(hmap),
+        }
+    }
+}
+
+impl Iterator for HmapIter {
+    type Item = *const ovsdb_sys::hmap_node;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cur.is_null() {
+            return None;
+        }
+
+        let node = self.cur;
+        self.cur = next(self.hmap, self.cur);
+        Some(node)
+    }
+}
+
+/// Iterates over the `(key, data)` pairs of a `shash`, by walking its underlying `hmap` and
+/// recovering each `shash_node` with [`shash`].
+///
+/// Subject to the same reallocation caveat as [`HmapIter`].
+pub struct ShashIter {
+    inner: HmapIter,
+}
+
+impl ShashIter {
+    pub fn new(hmap: *const ovsdb_sys::hmap) -> ShashIter {
+        ShashIter {
+            inner: HmapIter::new(hmap),
+        }
+    }
+}
+
+impl Iterator for ShashIter {
+    type Item = (&'static ffi::CStr, *const raw::c_void);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = shash(self.inner.next()?);
+
+        /* 'node' came from a live hmap node via 'shash', so it is non-null and its 'name'
+         * field points to a NUL-terminated string owned by the shash. */
+        unsafe {
+            let name = ffi::CStr::from_ptr((*node).name);
+            Some((name, (*node).data))
+        }
+    }
+}
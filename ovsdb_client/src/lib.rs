@@ -35,6 +35,8 @@ extern crate memoffset;
 
 #[allow(dead_code)]
 mod ovs_list;
+#[allow(dead_code)]
+mod hmap;
 /// Context that interacts with OVSDB.
 pub mod context;
 
@@ -46,6 +48,7 @@ use differential_datalog::ddval::DDValue;
 use differential_datalog::program::Update;
 
 use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
 
 /// Aliases for types in the ovs bindings.
 type EventType = ovs::sys::ovsdb_cs_event_ovsdb_cs_event_type;
@@ -60,11 +63,14 @@ const EVENT_TYPE_TXN_REPLY: EventType = ovs::sys::ovsdb_cs_event_ovsdb_cs_event_
 ///
 /// # Arguments
 /// * `schema_json` - OVSDB schema.
-/// * `_aux` - additional data for the request.
+/// * `aux` - pointer to the `OvsdbContext`, used to read per-table conditions and the last
+///   acknowledged transaction id.
 unsafe extern "C" fn compose_monitor_request(
     schema_json: *const ovs::sys::json,
-    _aux: *mut raw::c_void,
+    aux: *mut raw::c_void,
 ) -> *mut ovs::sys::json {
+    let ctx = &*(aux as *const context::OvsdbContext);
+
     let monitor_requests = ovs::sys::json_object_create();
 
     // Convert the bindgen-generated 'json' to a Rust 'str'.
@@ -75,12 +81,27 @@ unsafe extern "C" fn compose_monitor_request(
     let tables = &json_v["tables"].as_object().unwrap();
 
     for (tk, tv) in tables.iter() {
+        // Skip tables the caller didn't ask to subscribe to, when a subscription spec is set.
+        let column_selection = match &ctx.subscription {
+            None => None,
+            Some(spec) => match spec.get(tk.as_str()) {
+                Some(selection) => Some(selection),
+                None => continue,
+            },
+        };
+
         let to = &tv.as_object().unwrap();
         let cols = to["columns"].as_object().unwrap();
 
-        // Construct a JSON array of each column.
+        // Construct a JSON array of each column, restricted to `column_selection` if present.
         let subscribed_cols = ovs::sys::json_array_create_empty();
         for (ck, _cv) in cols.iter() {
+            if let Some(context::ColumnSelection::Columns(names)) = column_selection {
+                if !names.contains(ck.as_str()) {
+                    continue;
+                }
+            }
+
             let ck_cs = ffi::CString::new(ck.as_str()).unwrap();
             let ck_cp = ck_cs.as_ptr() as *const raw::c_char;
 
@@ -90,7 +111,8 @@ unsafe extern "C" fn compose_monitor_request(
             );
         }
 
-        // Map "columns": [<subscribed_cols>].
+        // Map "columns": [<subscribed_cols>], optionally restricted by a per-table "where"
+        // condition so the server only replicates rows a caller actually cares about.
         let monitor_request = ovs::sys::json_object_create();
         let columns_cs = ffi::CString::new("columns").unwrap();
         ovs::sys::json_object_put(
@@ -99,6 +121,18 @@ unsafe extern "C" fn compose_monitor_request(
             subscribed_cols,
         );
 
+        if let Some(conditions) = ctx.table_conditions.get(tk.as_str()) {
+            let where_array = ovs::sys::json_array_create_empty();
+            for condition in conditions {
+                let condition_s = ffi::CString::new(condition.to_string()).unwrap();
+                let condition_json = ovs::sys::json_from_string(condition_s.as_ptr());
+                ovs::sys::json_array_add(where_array, condition_json);
+            }
+
+            let where_cs = ffi::CString::new("where").unwrap();
+            ovs::sys::json_object_put(monitor_request, where_cs.as_ptr(), where_array);
+        }
+
         let table_cs = ffi::CString::new(tk.as_str()).unwrap();
         ovs::sys::json_object_put(
             monitor_requests,
@@ -107,14 +141,71 @@ unsafe extern "C" fn compose_monitor_request(
         );
     }
 
-    // Log the monitor request.
+    // Log the monitor request. `ovsdb_cs` itself remembers `ctx.last_txn_id` (via the
+    // OVSDB_CS_MONITOR_COND_SINCE monitor version) and resumes from it automatically; we just
+    // report it here for visibility into whether this is a full or incremental sync.
     let monitor_requests_cs = ovs::sys::json_to_string(monitor_requests, 0);
     let monitor_requests_s = ffi::CStr::from_ptr(monitor_requests_cs).to_str().unwrap();
-    println!("\nMonitoring the following OVSDB columns: {}\n", monitor_requests_s);
+    debug!(
+        last_txn_id = ctx.last_txn_id.as_deref().unwrap_or("<none, full sync>"),
+        monitor_requests = monitor_requests_s,
+        "composed OVSDB monitor_cond_since request",
+    );
 
     monitor_requests
 }
 
+/// Initializes OVS's native `vlog` module (`vlog_init()`), which the underlying C libraries (e.g.
+/// the stream/SSL layer) log through. It does not forward vlog output into the `tracing` facade:
+/// log lines from the C libraries still go wherever vlog itself is configured to write, separately
+/// from the structured, leveled events this crate emits through `tracing`.
+///
+/// An embedding controller should call this once at startup, after installing its own `tracing`
+/// subscriber (e.g. via `tracing_subscriber::fmt().init()`), and before calling
+/// [`process_ovsdb_inputs`].
+pub fn init_vlog_bridge() {
+    unsafe { ovs::sys::vlog_init() };
+}
+
+/// TLS material needed to connect to an `ssl:` OVSDB remote.
+///
+/// All three files are required by OVS's stream layer to establish a mutually-authenticated TLS
+/// connection: `private_key` and `certificate` identify this client, while `ca_cert` verifies the
+/// server's certificate.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// Path to this client's private key file, in PEM format.
+    pub private_key: String,
+    /// Path to this client's certificate file, in PEM format.
+    pub certificate: String,
+    /// Path to the CA certificate file used to verify the server.
+    pub ca_cert: String,
+    /// Whether to bootstrap (automatically accept and save) the CA certificate on first
+    /// connection, rather than requiring `ca_cert` to already exist.
+    pub bootstrap_ca: bool,
+}
+
+impl TlsConfig {
+    /// Configures the OVS stream-SSL subsystem to use this TLS material for subsequent `ssl:`
+    /// connections.
+    fn apply(&self) -> Result<(), String> {
+        let private_key_cs = ffi::CString::new(self.private_key.as_str())
+            .map_err(|e| format!("invalid private key path: {e}"))?;
+        let certificate_cs = ffi::CString::new(self.certificate.as_str())
+            .map_err(|e| format!("invalid certificate path: {e}"))?;
+        let ca_cert_cs = ffi::CString::new(self.ca_cert.as_str())
+            .map_err(|e| format!("invalid CA certificate path: {e}"))?;
+
+        unsafe {
+            ovs::sys::stream_ssl_set_private_key_file(private_key_cs.as_ptr());
+            ovs::sys::stream_ssl_set_certificate_file(certificate_cs.as_ptr());
+            ovs::sys::stream_ssl_set_ca_cert_file(ca_cert_cs.as_ptr(), self.bootstrap_ca);
+        }
+
+        Ok(())
+    }
+}
+
 /// A mutable, raw pointer to a live OVSDB connection.
 //
 // This is a "newtype" style struct, so we can define `Send` on it.
@@ -125,6 +216,25 @@ struct OvsdbCSPtr(*mut ovs::sys::ovsdb_cs);
 // It exists so that this type can be used in a function called by a Tokio actor.
 unsafe impl Send for OvsdbCSPtr{}
 
+impl Clone for OvsdbCSPtr {
+    fn clone(&self) -> Self {
+        OvsdbCSPtr(self.0)
+    }
+}
+impl Copy for OvsdbCSPtr {}
+
+/// Blocks the calling thread until there is work for the client-sync at `cs`: new socket data, a
+/// reconnect timer firing, or a pending transaction completing.
+///
+/// This registers `cs`'s file descriptors and timers with OVS's `poll_loop` machinery via
+/// `ovsdb_cs_wait`, then calls `poll_block`, which sleeps in `select`/`poll` until one of them is
+/// ready. It's meant to run on a dedicated blocking thread (via `spawn_blocking`), since it isn't
+/// async and would otherwise stall the Tokio runtime for as long as it sleeps.
+fn wait_for_ovsdb_cs(cs_ptr: OvsdbCSPtr) {
+    unsafe { ovs::sys::ovsdb_cs_wait(cs_ptr.0) };
+    ovs::poll_loop::block();
+}
+
 /// Process inputs from OVSDB.
 ///
 /// # Arguments
@@ -132,15 +242,30 @@ unsafe impl Send for OvsdbCSPtr{}
 /// * `server` - filepath to OVSDB server.
 /// * `database` - name for OVSDB database.
 /// * `respond_to` - sender for DDlog inputs (as updates) to an external program.
+/// * `tls` - TLS material to use if `server` is an `ssl:` remote; `None` otherwise.
+/// * `output_updates` - receiver of DDlog output-relation deltas to push back into OVSDB,
+///   making this a bidirectional sync layer. Pass a receiver that's simply never sent to if
+///   this connection should stay read-only.
 pub async fn process_ovsdb_inputs(
     mut ctx: context::OvsdbContext,
     server: String,
     database: String,
     respond_to: mpsc::Sender<Option<Update<DDValue>>>,
+    tls: Option<TlsConfig>,
+    mut output_updates: mpsc::Receiver<Vec<Update<DDValue>>>,
 ) -> Result<(), String> {
     let server_cs = ffi::CString::new(server.as_str()).unwrap();
     let database_cs = ffi::CString::new(database.as_str()).unwrap();
 
+    if server.starts_with("ssl:") {
+        match &tls {
+            Some(tls) => tls.apply()?,
+            None => return Err(format!(
+                "{server}: an ssl: remote requires TLS material (private key, certificate, CA cert)"
+            )),
+        }
+    }
+
     // Construct the client-sync here, so `ctx` can be passed when creating the connection.
     let cs_ptr = unsafe {
         let cs_ops = &ovs::sys::ovsdb_cs_ops {
@@ -157,26 +282,38 @@ pub async fn process_ovsdb_inputs(
         ovs::sys::ovsdb_cs_set_remote(cs, server_cs.as_ptr(), true);
         ovs::sys::ovsdb_cs_set_lock(cs, std::ptr::null());
 
+        // Use `monitor_cond_since` instead of plain `monitor`, so that `compose_monitor_request`'s
+        // per-table conditions are honored and reconnects resume from `ctx.last_txn_id` rather
+        // than re-replicating every row.
+        ovs::sys::ovsdb_cs_set_monitor_version(
+            cs,
+            ovs::sys::ovsdb_cs_monitor_version_OVSDB_CS_MONITOR_COND_SINCE,
+        );
+
         OvsdbCSPtr(cs)
     };
 
     loop {
+        // Drain any output updates the controller has queued up for us, and submit them as a
+        // single `transact` request each so the crate can stream updates in both directions.
+        while let Ok(updates) = output_updates.try_recv() {
+            let send_res = unsafe { ctx.send_output_updates(cs_ptr.0, updates) };
+            if let Err(err) = send_res {
+                error!(error = %err, "could not send output updates to OVSDB");
+            }
+        }
+
         let updates = unsafe {
             let mut event_updates = Vec::<ovs::sys::ovsdb_cs_event>::new();
             let cs = cs_ptr.0;
 
-            let mut events_list = &mut ovs_list::OvsList::default().as_ovs_list();
-            ovs::sys::ovsdb_cs_run(cs, events_list);
-
-            while !ovs_list::is_empty(events_list) {
-                events_list = ovs_list::remove(events_list).as_mut().unwrap();
-                let event = match ovs_list::to_event(events_list) {
-                    None => break,
-                    Some(e) => e,
-                };
+            let mut events_list = ovs_list::OvsList::default().as_ovs_list();
+            ovs::sys::ovsdb_cs_run(cs, &mut events_list);
 
+            for event in ovs_list::OvsListDrain::new(&mut events_list) {
                 match event.type_ {
                     EVENT_TYPE_RECONNECT => {
+                        info!("reconnecting to OVSDB server {}", server);
                         ctx.state = Some(context::ConnectionState::Initial);
                     },
                     EVENT_TYPE_LOCKED => {
@@ -187,17 +324,23 @@ pub async fn process_ovsdb_inputs(
                             event_updates = Vec::new();
                         }
 
-                        event_updates.push(event);
+                        event_updates.push(*event);
                         continue;
                     },
                     EVENT_TYPE_TXN_REPLY => {
-                        let reply_res = ctx.process_txn_reply(cs, event.__bindgen_anon_1.txn_reply);
-                        if reply_res.is_err() {
-                            println!("could not process txn reply with error: {:#?}", reply_res.err());
+                        match ctx.process_txn_reply(cs, event.__bindgen_anon_1.txn_reply) {
+                            Err(err) => warn!(error = %err, "could not process OVSDB transaction reply"),
+                            Ok(Some(context::TxnResult::Committed(updates))) => {
+                                debug!(count = updates.len(), "OVSDB committed output updates");
+                            },
+                            Ok(Some(context::TxnResult::Aborted { updates, reason })) => {
+                                warn!(count = updates.len(), reason = %reason, "OVSDB aborted output updates");
+                            },
+                            Ok(None) => {},
                         }
                     },
                     _ => {
-                        println!("received invalid event type from ovsdb");
+                        warn!(event_type = event.type_, "received invalid event type from OVSDB");
                         continue;
                     }
                 }
@@ -207,12 +350,17 @@ pub async fn process_ovsdb_inputs(
         };
 
         for update in updates {
-            let send_res = respond_to.send(Some(update)).await;
-            if send_res.is_err() {
-                println!("could not send update from ovsdb client to controller: {:#?}", send_res.err());
+            if let Err(err) = respond_to.send(Some(update)).await {
+                error!(error = %err, "could not send update from OVSDB client to controller");
             }
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(10 * 1000));
+        // Sleep exactly until `cs_ptr` has new work, instead of blocking the Tokio worker thread
+        // with a fixed-length sleep. `ovsdb_cs_wait`/`poll_block` aren't async, so run them on a
+        // blocking task; `spawn_blocking` returns as soon as `poll_block` wakes up.
+        let cs_ptr = cs_ptr;
+        if let Err(join_err) = tokio::task::spawn_blocking(move || wait_for_ovsdb_cs(cs_ptr)).await {
+            error!(error = %join_err, "ovsdb poll-wait task failed");
+        }
     }
 }
@@ -22,7 +22,7 @@ SOFTWARE.
 
 extern crate ovsdb_sys;
 
-use std::{
+use core::{
     cell::Cell as Mut,
     ptr,
 };
@@ -54,10 +54,12 @@ impl OvsList {
     }
 }
 
-/* Cast an ovs_list to an ovsdb_cs_event. */
-pub unsafe fn to_event(
-    list_ptr: *mut ovsdb_sys::ovs_list
-) -> Option<ovsdb_sys::ovsdb_cs_event> {
+/// Casts `list_ptr`, which must point to the `list_node` member of an `ovsdb_cs_event`, to the
+/// enclosing event, without copying it. Shared by [`to_event`] (which does copy, for callers that
+/// still want an owned value) and the borrowing iterators below. Read-only, so it takes `*const`
+/// like the other non-mutating helpers.
+#[inline]
+unsafe fn event_of<'a>(list_ptr: *const ovsdb_sys::ovs_list) -> Option<&'a ovsdb_sys::ovsdb_cs_event> {
     if list_ptr.is_null() {
         return None;
     }
@@ -66,25 +68,107 @@ pub unsafe fn to_event(
         .cast::<u8>()
         .wrapping_sub(offset_of!(ovsdb_sys::ovsdb_cs_event, list_node))
         .cast::<ovsdb_sys::ovsdb_cs_event>();
-    
+
     if event_ptr.is_null() {
         return None;
     }
 
-    Some(*event_ptr)
+    Some(&*event_ptr)
+}
+
+/* Cast an ovs_list to an ovsdb_cs_event. Read-only, like the C `inline` helper it replaces. */
+#[inline]
+pub unsafe fn to_event(
+    list_ptr: *const ovsdb_sys::ovs_list
+) -> Option<ovsdb_sys::ovsdb_cs_event> {
+    event_of(list_ptr).copied()
 }
 
+/// Unlinks `elem` from its list. Mutates the neighbors' `prev`/`next`, so unlike the other helpers
+/// here it needs `*mut`.
+#[inline]
 pub unsafe fn remove(
     elem: *mut ovsdb_sys::ovs_list
 ) -> *mut ovsdb_sys::ovs_list {
     (*(*elem).prev).next = (*elem).next;
     (*(*elem).next).prev = (*elem).prev;
-    
+
     (*elem).next
 }
 
+#[inline]
 pub unsafe fn is_empty(
-    list: *mut ovsdb_sys::ovs_list,
+    list: *const ovsdb_sys::ovs_list,
 ) -> bool {
-    (*list).next == list
+    (*list).next as *const _ == list
+}
+
+/// A safe read-only iterator over the `ovsdb_cs_event`s linked into the circular list headed by
+/// `head`. Walks `next` until it returns to `head` (`head` itself, the sentinel, is never
+/// yielded), borrowing each event via [`event_of`] instead of copying it like [`to_event`] does.
+pub struct OvsListCursor<'a> {
+    head: *const ovsdb_sys::ovs_list,
+    cur: *const ovsdb_sys::ovs_list,
+    _marker: core::marker::PhantomData<&'a ovsdb_sys::ovsdb_cs_event>,
+}
+
+impl<'a> OvsListCursor<'a> {
+    /// Returns a cursor over the list headed by `head`.
+    ///
+    /// # Safety
+    /// `head` must point to a valid, initialized `ovs_list` whose borrow outlives `'a`, and every
+    /// other node linked into it must be embedded in an `ovsdb_cs_event` as its `list_node`.
+    pub unsafe fn new(head: *const ovsdb_sys::ovs_list) -> OvsListCursor<'a> {
+        OvsListCursor { head, cur: head, _marker: core::marker::PhantomData }
+    }
+}
+
+impl<'a> Iterator for OvsListCursor<'a> {
+    type Item = &'a ovsdb_sys::ovsdb_cs_event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            let node = (*self.cur).next as *const ovsdb_sys::ovs_list;
+            if node == self.head {
+                return None;
+            }
+            self.cur = node;
+            event_of(node)
+        }
+    }
+}
+
+/// Like [`OvsListCursor`], but unlinks each node (via [`remove`]) as it's yielded instead of just
+/// walking past it, so a single pass over the list both consumes and empties it -- e.g. the events
+/// `ovsdb_cs_run` queues up should be processed exactly once per poll, not revisited on the next
+/// one.
+pub struct OvsListDrain<'a> {
+    head: *mut ovsdb_sys::ovs_list,
+    _marker: core::marker::PhantomData<&'a ovsdb_sys::ovsdb_cs_event>,
+}
+
+impl<'a> OvsListDrain<'a> {
+    /// Returns a draining iterator over the list headed by `head`.
+    ///
+    /// # Safety
+    /// Same requirements as [`OvsListCursor::new`].
+    pub unsafe fn new(head: *mut ovsdb_sys::ovs_list) -> OvsListDrain<'a> {
+        OvsListDrain { head, _marker: core::marker::PhantomData }
+    }
+}
+
+impl<'a> Iterator for OvsListDrain<'a> {
+    type Item = &'a ovsdb_sys::ovsdb_cs_event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            if is_empty(self.head) {
+                return None;
+            }
+            let node = (*self.head).next;
+            let event = event_of(node);
+            remove(node);
+            event
+        }
+    }
 }
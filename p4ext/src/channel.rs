@@ -0,0 +1,95 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! TLS and mutual-TLS channel construction for [`P4RuntimeClient`].
+//!
+//! Nothing here is P4Runtime-specific -- it's plain grpcio channel credential plumbing -- but
+//! callers connecting to a real switch agent need it, and shouldn't each have to learn grpcio's
+//! credential builder API to meet that agent's authentication requirements.
+
+use std::fs;
+use std::io;
+use std::sync::Arc;
+
+use grpcio::{Channel, ChannelBuilder, ChannelCredentialsBuilder, EnvBuilder};
+
+use proto::p4runtime_grpc::P4RuntimeClient;
+
+/// PEM-encoded material for a TLS or mutual-TLS connection.
+pub struct TlsConfig {
+    /// PEM-encoded root certificates trusted to sign the target's server certificate.
+    pub root_certs: Vec<u8>,
+    /// PEM-encoded client certificate and private key, for mutual TLS. `None` for server-only TLS.
+    pub client_identity: Option<(Vec<u8>, Vec<u8>)>,
+    /// Overrides the name used to verify the target's certificate, e.g. when `target` is an IP
+    /// address but the certificate was issued for a hostname.
+    pub target_name_override: Option<String>,
+}
+
+impl TlsConfig {
+    /// Reads a [`TlsConfig`] from PEM files on disk.
+    ///
+    /// # Arguments
+    /// * `root_certs_path` - path to a PEM file of root certificates to trust.
+    /// * `client_identity_paths` - paths to a `(cert, key)` PEM pair, for mutual TLS.
+    pub fn from_files(
+        root_certs_path: &str,
+        client_identity_paths: Option<(&str, &str)>,
+    ) -> io::Result<Self> {
+        let root_certs = fs::read(root_certs_path)?;
+        let client_identity = match client_identity_paths {
+            Some((cert_path, key_path)) => Some((fs::read(cert_path)?, fs::read(key_path)?)),
+            None => None,
+        };
+
+        Ok(TlsConfig { root_certs, client_identity, target_name_override: None })
+    }
+}
+
+/// Connects to `target`, the entity hosting P4 Runtime, over TLS, verifying its certificate
+/// against `config.root_certs` and presenting `config.client_identity` for mutual TLS if set.
+pub fn connect_tls(target: &str, config: &TlsConfig) -> P4RuntimeClient {
+    let env = Arc::new(EnvBuilder::new().build());
+
+    let mut creds_builder = ChannelCredentialsBuilder::new()
+        .root_cert(config.root_certs.clone());
+    if let Some((cert, key)) = &config.client_identity {
+        creds_builder = creds_builder.cert(cert.clone(), key.clone());
+    }
+    let creds = creds_builder.build();
+
+    let mut ch_builder = ChannelBuilder::new(env);
+    if let Some(name) = &config.target_name_override {
+        ch_builder = ch_builder.override_ssl_target(name.clone());
+    }
+    let ch: Channel = ch_builder.secure_connect(target, creds);
+
+    P4RuntimeClient::new(ch)
+}
+
+/// Connects to `target` in plaintext, with no transport security.
+///
+/// Intended for test harnesses running against a switch agent on localhost; do not use this
+/// against a production switch.
+pub fn connect_insecure(target: &str) -> P4RuntimeClient {
+    let env = Arc::new(EnvBuilder::new().build());
+    let ch = ChannelBuilder::new(env).connect(target);
+    P4RuntimeClient::new(ch)
+}
@@ -0,0 +1,112 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Bit-width-aware encoding of high-level values into [`FieldValue`]s and [`FieldMatchType`]s.
+//!
+//! A P4 field's bit width only appears in p4info, as [`MatchField::bit_width`] or
+//! [`Param::bit_width`]; [`FieldValue`] itself is just an arbitrary-precision byte string with no
+//! notion of the width of the field it fills. This module is the width-checked bridge between a
+//! plain `u128` (an integer, an IPv4/IPv6 address, or a MAC address all fit) or an arbitrary byte
+//! string and the [`FieldMatchType`] oneof a [`FieldMatch`](crate::FieldMatch) needs, enforcing
+//! the P4Runtime invariants a switch expects on write: no value wider than its field, and no
+//! ternary value with 1-bits outside its mask. Decoding a switch's response back into a
+//! [`FieldMatch`] already happens on read, via [`MatchField::from_record`] and
+//! `TryFrom<&proto::p4runtime::FieldMatch>`; [`decode_u128`] complements those for callers that
+//! want the inverse of `u128`-based encoding.
+
+use crate::{Error, FieldMatchType, FieldValue, MatchField, Param, Result};
+
+use grpcio::RpcStatusCode;
+
+use anyhow::Context;
+
+/// Checks that `value` fits within `bit_width` bits, for use as a match field's or an action
+/// parameter's value.
+fn check_width(name: &str, bit_width: i32, value: &FieldValue) -> Result<()> {
+    if value.bit_length() > bit_width as u32 {
+        Err(Error(RpcStatusCode::OUT_OF_RANGE))
+            .context(format!("{} is {} bits wide, but value {} needs {}", name, bit_width, value, value.bit_length()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Encodes an exact-match value against `match_field`, rejecting a value wider than its field.
+pub fn exact(match_field: &MatchField, value: FieldValue) -> Result<FieldMatchType> {
+    check_width(&match_field.preamble.name, match_field.bit_width, &value)?;
+    Ok(FieldMatchType::Exact(value))
+}
+
+/// Encodes a ternary match against `match_field`, rejecting a `value` or `mask` wider than the
+/// field, or a `value` with 1-bits outside `mask`.
+pub fn ternary(match_field: &MatchField, value: FieldValue, mask: FieldValue) -> Result<FieldMatchType> {
+    check_width(&match_field.preamble.name, match_field.bit_width, &value)?;
+    check_width(&match_field.preamble.name, match_field.bit_width, &mask)?;
+    if value.has_bits_outside(&mask) {
+        return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+            .context(format!("{}: value {} has 1-bits outside mask {}", match_field.preamble.name, value, mask));
+    }
+    Ok(FieldMatchType::Ternary { value, mask })
+}
+
+/// Encodes a longest-prefix-match against `match_field`, rejecting a `value` wider than the field
+/// or a `plen` longer than the field. Per the P4Runtime read/write symmetry rule, only the
+/// high-order `plen` bits of `value` may be nonzero, so this clears the rest rather than
+/// rejecting them, relying on `FieldValue::clear_low_bits` to zero those low-order bits in place
+/// rather than shortening the value.
+pub fn lpm(match_field: &MatchField, value: FieldValue, plen: usize) -> Result<FieldMatchType> {
+    check_width(&match_field.preamble.name, match_field.bit_width, &value)?;
+    if plen > match_field.bit_width as usize {
+        return Err(Error(RpcStatusCode::OUT_OF_RANGE))
+            .context(format!("{}: prefix_len {} exceeds {}-bit field", match_field.preamble.name, plen, match_field.bit_width));
+    }
+    let value = value.clear_low_bits(match_field.bit_width as u32 - plen as u32);
+    Ok(FieldMatchType::LPM { value, plen })
+}
+
+/// Encodes a range match against `match_field`, rejecting a `low` or `high` wider than the field,
+/// or a `high` less than `low`.
+pub fn range(match_field: &MatchField, low: FieldValue, high: FieldValue) -> Result<FieldMatchType> {
+    check_width(&match_field.preamble.name, match_field.bit_width, &low)?;
+    check_width(&match_field.preamble.name, match_field.bit_width, &high)?;
+    if high < low {
+        return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+            .context(format!("{}: range {}...{} has high less than low", match_field.preamble.name, low, high));
+    }
+    Ok(FieldMatchType::Range(low, high))
+}
+
+/// Encodes an action parameter's value, rejecting a value wider than `param`.
+pub fn action_param(param: &Param, value: FieldValue) -> Result<FieldValue> {
+    check_width(&param.preamble.name, param.bit_width, &value)?;
+    Ok(value)
+}
+
+/// Extracts a plain `u128` from `value`, the inverse of encoding a `u128` via [`FieldValue::from`].
+/// Returns `None` if `value` is wider than 128 bits.
+pub fn decode_u128(value: &FieldValue) -> Option<u128> {
+    let bytes: Vec<u8> = value.clone().into();
+    if bytes.len() > 16 {
+        return None;
+    }
+    let mut buf = [0u8; 16];
+    buf[16 - bytes.len()..].copy_from_slice(&bytes);
+    Some(u128::from_be_bytes(buf))
+}
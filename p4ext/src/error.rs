@@ -0,0 +1,93 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Structured decoding of batched [`Write`](crate::write) failures.
+//!
+//! A `Write` RPC can carry many [`Update`](proto::p4runtime::Update)s in one call. When the
+//! switch rejects one or more of them, the P4Runtime spec has it return `google.rpc.Status`
+//! with one `p4.v1.Error` packed into `details` per `Update`, in request order -- updates the
+//! switch accepted report `canonical_code` `OK`. [`decode_write_errors`] unpacks that into a
+//! `Vec<WriteError>` so a caller can tell which entity in the batch was rejected, and why,
+//! rather than retrying the whole batch blindly.
+
+use protobuf::Message;
+
+use proto::p4runtime::Error as P4RuntimeError;
+use proto::status::Status;
+
+use std::error;
+use std::fmt;
+
+/// One rejected [`Update`](proto::p4runtime::Update)'s outcome within a batched [`Write`](crate::write),
+/// aligned by `index` with the request's `updates`.
+#[derive(Clone, Debug)]
+pub struct WriteError {
+    /// Index into the `Write` request's `updates` this error corresponds to.
+    pub index: usize,
+    /// The canonical gRPC code (`google.rpc.Code`) the switch reported for this update.
+    pub canonical_code: i32,
+    /// Human-readable detail from the switch.
+    pub message: String,
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "update {}: {} (code {})", self.index, self.message, self.canonical_code)
+    }
+}
+
+impl error::Error for WriteError {}
+
+/// Failed to decode a batched `Write`'s error details.
+#[derive(Debug)]
+pub struct DecodeError(pub protobuf::ProtobufError);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to decode write error details: {}", self.0)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Decodes the `google.rpc.Status` details of a failed batched `Write` into one [`WriteError`]
+/// per rejected update, in the order the request's `updates` were sent.
+///
+/// `details` is the serialized `google.rpc.Status` carried in the RPC's `grpc-status-details-bin`
+/// trailer.
+pub fn decode_write_errors(details: &[u8]) -> Result<Vec<WriteError>, DecodeError> {
+    let status = Status::parse_from_bytes(details).map_err(DecodeError)?;
+
+    let mut errors = Vec::new();
+    for (index, any) in status.get_details().iter().enumerate() {
+        let p4_error: P4RuntimeError = any.unpack()
+            .map_err(DecodeError)?
+            .unwrap_or_default();
+        if p4_error.get_canonical_code() != 0 {
+            errors.push(WriteError {
+                index,
+                canonical_code: p4_error.get_canonical_code(),
+                message: p4_error.get_message().to_string(),
+            });
+        }
+    }
+
+    Ok(errors)
+}
@@ -32,9 +32,17 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use futures::{SinkExt, StreamExt};
+pub mod channel;
+pub mod codec;
+pub mod error;
+pub mod reconcile;
+pub mod session;
 
-use grpcio::{ChannelBuilder, EnvBuilder, WriteFlags, RpcStatusCode};
+use futures::{SinkExt, Stream, StreamExt};
+
+use grpcio::{CallOption, ChannelBuilder, EnvBuilder, WriteFlags, RpcStatusCode};
+
+use rand::random;
 
 use itertools::Itertools;
 
@@ -43,6 +51,8 @@ use anyhow::{Context, Result};
 use proto::p4info;
 
 use proto::p4runtime::{
+    CapabilitiesRequest,
+    CapabilitiesResponse,
     FieldMatch_Exact,
     FieldMatch_LPM,
     FieldMatch_Optional,
@@ -65,22 +75,30 @@ use proto::p4runtime::{
 
 use proto::p4runtime_grpc::P4RuntimeClient;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 use proto::p4types;
 
 use protobuf::{Message, RepeatedField};
 
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
+use std::cmp::min;
 use std::env;
 use std::ffi::OsStr;
 use std::fmt::{self, Display};
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::process::Command;
 use std::string::String;
 use std::sync::Arc;
+use std::time::Duration;
 
 use thiserror::Error;
 
+use tracing::{debug, warn};
+
 /// An annotation's [location](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-sourcelocation-message>) within a `.p4` file.
 #[derive(Clone, Debug, Default)]
 pub struct SourceLocation {
@@ -113,7 +131,7 @@ impl Display for SourceLocation {
 }
 
 /// Values in an [expression](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-structured-annotations) in a structured annotation.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ExpressionValue {
     /// String value.
     String(String),
@@ -146,7 +164,7 @@ impl Display for ExpressionValue {
 }
 
 /// Maps a name to a value. Possible data type in a structured annotation.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct KeyValuePair(String, ExpressionValue);
 
 impl From<&p4types::KeyValuePair> for KeyValuePair {
@@ -200,6 +218,120 @@ impl From<&p4types::StructuredAnnotation> for AnnotationValue {
 #[derive(Clone, Debug, Default)]
 pub struct Annotations(pub HashMap<String, (Option<SourceLocation>, AnnotationValue)>);
 
+/// Tokenizes the comma-separated argument list of an unstructured annotation's parenthesized
+/// body (the `foo, 42, "bar"` of `@my_anno(foo, 42, "bar")`) into [`ExpressionValue`]s, mirroring
+/// the argument forms P4Runtime's structured annotations already support: double-quoted strings
+/// with `\\`-escapes, decimal or `0x`-prefixed hexadecimal integers, and `true`/`false` booleans.
+///
+/// Returns `None` -- rather than a partial result -- as soon as any argument fails to match one
+/// of those forms, so the caller can fall back to treating the whole body as free-form text.
+struct AnnotationArgsParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> AnnotationArgsParser<'a> {
+    fn skip_ws(&mut self) {
+        while matches!(self.chars.peek(), Some(c) if c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn parse_string(&mut self) -> Option<ExpressionValue> {
+        self.chars.next(); // the opening quote
+        let mut s = String::new();
+        loop {
+            match self.chars.next()? {
+                '"' => return Some(ExpressionValue::String(s)),
+                '\\' => match self.chars.next()? {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    '\\' => s.push('\\'),
+                    '"' => s.push('"'),
+                    other => s.push(other),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Option<ExpressionValue> {
+        let mut tok = String::new();
+        if self.chars.peek() == Some(&'-') {
+            tok.push(self.chars.next().unwrap());
+        }
+        let is_hex = self.chars.peek() == Some(&'0') && {
+            let mut lookahead = self.chars.clone();
+            lookahead.next();
+            matches!(lookahead.peek(), Some('x') | Some('X'))
+        };
+        if is_hex {
+            tok.push(self.chars.next().unwrap()); // '0'
+            tok.push(self.chars.next().unwrap()); // 'x' or 'X'
+            let start = tok.len();
+            while matches!(self.chars.peek(), Some(c) if c.is_ascii_hexdigit()) {
+                tok.push(self.chars.next().unwrap());
+            }
+            if tok.len() == start {
+                return None;
+            }
+            return i64::from_str_radix(&tok[start..], 16).ok().map(ExpressionValue::Integer);
+        }
+        let start = tok.len();
+        while matches!(self.chars.peek(), Some(c) if c.is_ascii_digit()) {
+            tok.push(self.chars.next().unwrap());
+        }
+        if tok.len() == start {
+            return None;
+        }
+        tok.parse::<i64>().ok().map(ExpressionValue::Integer)
+    }
+
+    fn parse_word(&mut self) -> Option<ExpressionValue> {
+        let mut word = String::new();
+        while matches!(self.chars.peek(), Some(c) if c.is_alphanumeric() || *c == '_') {
+            word.push(self.chars.next().unwrap());
+        }
+        match word.as_str() {
+            "true" => Some(ExpressionValue::Bool(true)),
+            "false" => Some(ExpressionValue::Bool(false)),
+            _ => None,
+        }
+    }
+
+    fn parse_value(&mut self) -> Option<ExpressionValue> {
+        self.skip_ws();
+        match self.chars.peek()? {
+            '"' => self.parse_string(),
+            c if c.is_ascii_digit() || *c == '-' => self.parse_number(),
+            _ => self.parse_word(),
+        }
+    }
+
+    fn parse_args(mut self) -> Option<Vec<ExpressionValue>> {
+        let mut values = Vec::new();
+        self.skip_ws();
+        if self.chars.peek().is_none() {
+            return Some(values);
+        }
+        loop {
+            values.push(self.parse_value()?);
+            self.skip_ws();
+            match self.chars.next() {
+                Some(',') => continue,
+                None => return Some(values),
+                Some(_) => return None,
+            }
+        }
+    }
+}
+
+/// Parses an unstructured annotation's parenthesized body into expression values, if every
+/// argument is well-formed; see [`AnnotationArgsParser`].
+fn parse_annotation_args(body: &str) -> Option<Vec<ExpressionValue>> {
+    AnnotationArgsParser { chars: body.chars().peekable() }.parse_args()
+}
+
 fn parse_annotations<'a, T, U, V>(
     annotations: T,
     annotation_locs: U,
@@ -227,8 +359,11 @@ where
                 if s.contains("(") && s.ends_with(")") {
                     let index = s.find("(").unwrap();
                     let name = String::from(&s[0..index]);
-                    let value = s[index + 1..].strip_suffix(')').unwrap().into();
-                    (name, (source_location, Unstructured(value)))
+                    let body = s[index + 1..].strip_suffix(')').unwrap();
+                    let value = parse_annotation_args(body)
+                        .map(Expressions)
+                        .unwrap_or_else(|| Unstructured(body.to_string()));
+                    (name, (source_location, value))
                 } else {
                     (s.into(), (source_location, Empty))
                 }
@@ -290,6 +425,162 @@ impl Display for Annotations {
     }
 }
 
+/// The kind of value a single annotation argument is expected to hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueKind {
+    /// A double-quoted string, e.g. `"foo"`.
+    String,
+    /// A decimal or hex integer, e.g. `42` or `0x2a`.
+    Integer,
+    /// `true` or `false`.
+    Bool,
+}
+
+impl ValueKind {
+    fn matches(&self, value: &ExpressionValue) -> bool {
+        matches!(
+            (self, value),
+            (ValueKind::String, ExpressionValue::String(_))
+                | (ValueKind::Integer, ExpressionValue::Integer(_))
+                | (ValueKind::Bool, ExpressionValue::Bool(_))
+        )
+    }
+}
+
+impl Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ValueKind::String => "string",
+            ValueKind::Integer => "integer",
+            ValueKind::Bool => "bool",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The expected form of an annotation's value, as registered in an [`AnnotationSchemaRegistry`].
+#[derive(Clone, Debug)]
+pub enum AnnotationShape {
+    /// The annotation must appear bare, with no arguments, e.g. `@hidden`.
+    Empty,
+    /// The annotation must have exactly one argument of this kind, e.g. `@id(42)`.
+    Value(ValueKind),
+    /// The annotation must have a positional argument list with exactly one kind per position,
+    /// e.g. `@my_anno(1, 2, 3)`.
+    Expressions(Vec<ValueKind>),
+    /// The annotation must have a key/value argument list, with each listed key expected to have
+    /// a value of the given kind. Keys not listed here are rejected.
+    KeyValuePairs(HashMap<String, ValueKind>),
+}
+
+impl AnnotationShape {
+    fn check(&self, value: &AnnotationValue) -> std::result::Result<(), String> {
+        use AnnotationValue::*;
+        match (self, value) {
+            (AnnotationShape::Empty, Empty) => Ok(()),
+            (AnnotationShape::Empty, _) => Err("expected no arguments".to_string()),
+
+            (AnnotationShape::Value(kind), Expressions(values))
+                if values.len() == 1 && kind.matches(&values[0]) =>
+            {
+                Ok(())
+            }
+            (AnnotationShape::Value(kind), _) => Err(format!("expected a single {} argument", kind)),
+
+            (AnnotationShape::Expressions(kinds), Expressions(values))
+                if values.len() == kinds.len()
+                    && values.iter().zip(kinds).all(|(v, k)| k.matches(v)) =>
+            {
+                Ok(())
+            }
+            (AnnotationShape::Expressions(kinds), _) => {
+                Err(format!("expected {} positional argument(s)", kinds.len()))
+            }
+
+            (AnnotationShape::KeyValuePairs(expected), KeyValuePairs(kvs)) => {
+                for kv in kvs {
+                    match expected.get(&kv.0) {
+                        Some(kind) if kind.matches(&kv.1) => (),
+                        Some(kind) => return Err(format!("key \"{}\" expected a {} value", kv.0, kind)),
+                        None => return Err(format!("unexpected key \"{}\"", kv.0)),
+                    }
+                }
+                Ok(())
+            }
+            (AnnotationShape::KeyValuePairs(_), _) => Err("expected key/value arguments".to_string()),
+        }
+    }
+}
+
+/// Maps annotation names to their expected [`AnnotationShape`], so [`Annotations::validate`] can
+/// catch typos like `@nerpa_boool` and malformed arguments like `@max_group_size("x")` instead of
+/// letting them pass through silently until something downstream misbehaves.
+#[derive(Clone, Debug, Default)]
+pub struct AnnotationSchemaRegistry(HashMap<String, AnnotationShape>);
+
+impl AnnotationSchemaRegistry {
+    /// Returns an empty registry; register expected annotations with [`Self::register`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `name` (omitting the `@` prefix) as an expected annotation with the given
+    /// `shape`. Registering the same name again replaces its shape.
+    pub fn register(&mut self, name: &str, shape: AnnotationShape) -> &mut Self {
+        self.0.insert(name.to_string(), shape);
+        self
+    }
+}
+
+/// An error found while validating [`Annotations`] against an [`AnnotationSchemaRegistry`].
+#[derive(Clone, Debug)]
+pub struct AnnotationError {
+    /// Name of the offending annotation (omitting the `@` prefix).
+    pub name: String,
+    /// Location of the annotation in the `.p4` file, if known.
+    pub location: Option<SourceLocation>,
+    /// Description of what's wrong with it.
+    pub message: String,
+}
+
+impl Display for AnnotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "@{}", self.name)?;
+        if let Some(location) = &self.location {
+            write!(f, " ({})", location)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl Annotations {
+    /// Validates every annotation in `self` against `registry`, returning every annotation that
+    /// is either unregistered (most likely a typo) or whose value doesn't match its registered
+    /// [`AnnotationShape`].
+    pub fn validate(&self, registry: &AnnotationSchemaRegistry) -> std::result::Result<(), Vec<AnnotationError>> {
+        let mut errors = Vec::new();
+        for (name, (location, value)) in self.0.iter() {
+            match registry.0.get(name) {
+                None => errors.push(AnnotationError {
+                    name: name.clone(),
+                    location: location.clone(),
+                    message: format!("unknown annotation \"@{}\"", name),
+                }),
+                Some(shape) => {
+                    if let Err(message) = shape.check(value) {
+                        errors.push(AnnotationError { name: name.clone(), location: location.clone(), message });
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
 /// Documentation for a P4 entity.
 #[derive(Clone, Debug, Default)]
 pub struct Documentation {
@@ -340,6 +631,14 @@ impl From<&p4info::Preamble> for Preamble {
     }
 }
 
+/// A P4Info entity identified by a [`Preamble`], the common structure [`Selector`] queries
+/// against. Implemented by every leaf entity kind that carries one: [`Table`], [`MatchField`],
+/// [`Action`], [`Param`], [`ActionProfile`], and [`ActionRef`] (via its action's preamble).
+pub trait HasPreamble {
+    /// Returns this entity's preamble.
+    fn preamble(&self) -> &Preamble;
+}
+
 /// An enumeration of possible PSA match kinds. Described [here](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-match-format).
 #[derive(Clone, PartialEq, Eq)]
 pub enum MatchType {
@@ -449,6 +748,12 @@ impl MatchField {
     }
 }
 
+impl HasPreamble for MatchField {
+    fn preamble(&self) -> &Preamble {
+        &self.preamble
+    }
+}
+
 impl From<&p4info::MatchField> for MatchField {
     fn from(mf: &p4info::MatchField) -> Self {
         use p4info::MatchField_MatchType::*;
@@ -495,7 +800,13 @@ impl Display for MatchField {
 }
 
 /// How a [`FieldMatch`] matches against a [`MatchField`].
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+///
+/// Equality and hashing normalize [`Self::Ternary`]'s `value` to its bits under `mask` before
+/// comparing, since a switch is free to return any value in those don't-care bits when reading a
+/// ternary match back -- see [`FieldMatch::canonicalize`] for the rest of the P4Runtime read/write
+/// symmetry rules, which additionally require the associated [`MatchField`]'s `bit_width`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub enum FieldMatchType {
     /// Field must contain exactly `.0`.
     Exact(FieldValue),
@@ -537,6 +848,42 @@ impl Display for FieldMatchType {
         }
     }
 }
+impl PartialEq for FieldMatchType {
+    fn eq(&self, other: &Self) -> bool {
+        use FieldMatchType::*;
+        match (self, other) {
+            (Exact(a), Exact(b)) => a == b,
+            (Optional(a), Optional(b)) => a == b,
+            (Ternary { value: v1, mask: m1 }, Ternary { value: v2, mask: m2 }) =>
+                m1 == m2 && v1.and(m1) == v2.and(m2),
+            (LPM { value: v1, plen: p1 }, LPM { value: v2, plen: p2 }) => p1 == p2 && v1 == v2,
+            (Range(l1, h1), Range(l2, h2)) => l1 == l2 && h1 == h2,
+            _ => false,
+        }
+    }
+}
+impl Eq for FieldMatchType {}
+impl Hash for FieldMatchType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        use FieldMatchType::*;
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Exact(value) | Optional(value) => value.hash(state),
+            Ternary { value, mask } => {
+                mask.hash(state);
+                value.and(mask).hash(state);
+            }
+            LPM { value, plen } => {
+                plen.hash(state);
+                value.hash(state);
+            }
+            Range(low, high) => {
+                low.hash(state);
+                high.hash(state);
+            }
+        }
+    }
+}
 
 /// A predicate for matching against the value of a field extracted from a packet.  A [`TableKey`]
 /// matches a packet if all of its `FieldMatch`es evaluate to true.
@@ -554,13 +901,12 @@ impl Display for FieldMatchType {
 /// Based on the [P4Runtime
 /// specification](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-match-format).
 ///
-/// # To-do
-///
-/// Possibly, `FieldMatch` should take [read/write
+/// Use [`Self::canonicalize`] to fold a `FieldMatch` into the [read/write
 /// symmetry](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-read-write-symmetry)
-/// into account for the purpose of equality and hashing, for example by enforcing invariants in
-/// constructors.
+/// form the spec requires a switch to read back, including detecting when it's actually a
+/// don't-care predicate that shouldn't be a `FieldMatch` at all.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct FieldMatch {
     /// Identifies the corresponding [`MatchField`] by its [`Preamble::id`].
     pub field_id: u32,
@@ -579,7 +925,7 @@ impl FieldMatch {
                 => {
                     let value: FieldValue = value.try_into()?;
                     let mask: FieldValue = mask.try_into()?;
-                    if (value.0 & !mask.0) != 0 {
+                    if value.has_bits_outside(&mask) {
                         Err(Error(RpcStatusCode::INVALID_ARGUMENT))
                             .context(format!("P4 field value {} has 1-bits not in mask {}", value, mask))
                     } else {
@@ -594,7 +940,7 @@ impl FieldMatch {
                     if plen < 0 || plen > 128 {
                         Err(Error(RpcStatusCode::INVALID_ARGUMENT))
                             .context(format!("P4 prefix_len {} outside supported range [0,128]", plen))
-                    } else if plen < 128 && (value.0 >> plen) != 0 {
+                    } else if plen < 128 && value.bit_length() > plen as u32 {
                         Err(Error(RpcStatusCode::INVALID_ARGUMENT))
                             .context(format!("P4 field value {} has 1-bits not in prefix_len {}", value, plen))
                     } else {
@@ -606,7 +952,7 @@ impl FieldMatch {
                 => {
                     let low: FieldValue = low.try_into()?;
                     let high: FieldValue = high.try_into()?;
-                    if high.0 < low.0 {
+                    if high < low {
                         Err(Error(RpcStatusCode::INVALID_ARGUMENT))
                             .context(format!("P4 range match {}...{} has high less than low", low, high))
                     } else {
@@ -625,6 +971,42 @@ impl FieldMatch {
                 .context(format!("missing P4 FieldMatch"))
         }
     }
+
+    /// Returns the canonical form of `self` as a match against `match_field`, per the P4Runtime
+    /// read/write symmetry rule that a switch always reads back the canonical form of whatever
+    /// was written. Returns `None` if `self` is actually a don't-care predicate in disguise --
+    /// per [`FieldMatch`]'s own rule, callers should omit it from the `TableKey` entirely rather
+    /// than keep a `FieldMatch` that always matches.
+    pub fn canonicalize(&self, match_field: &MatchField) -> Option<FieldMatch> {
+        let bit_width = match_field.bit_width as u32;
+        let full_mask = FieldValue::all_ones(bit_width);
+
+        use FieldMatchType::*;
+        let match_type = match &self.match_type {
+            Exact(value) => Exact(value.clone()),
+            Optional(value) => Optional(value.clone()),
+            Ternary { value, mask } => {
+                if mask.is_zero() {
+                    return None;
+                }
+                Ternary { value: value.and(mask), mask: mask.clone() }
+            }
+            LPM { value, plen } => {
+                if *plen == 0 {
+                    return None;
+                }
+                let keep_bits = bit_width.saturating_sub(*plen as u32);
+                LPM { value: value.clear_low_bits(keep_bits), plen: *plen }
+            }
+            Range(low, high) => {
+                if low.is_zero() && *high == full_mask {
+                    return None;
+                }
+                Range(low.clone(), high.clone())
+            }
+        };
+        Some(FieldMatch { field_id: self.field_id, match_type })
+    }
 }
 impl TryFrom<&proto::p4runtime::FieldMatch> for FieldMatch {
     type Error = anyhow::Error;
@@ -640,17 +1022,17 @@ impl From<&FieldMatch> for proto::p4runtime::FieldMatch {
             field_id: fm.field_id,
             field_match_type: {
                 let (unknown_fields, cached_size) = Default::default();
-                Some(match fm.match_type {
+                Some(match &fm.match_type {
                     FieldMatchType::Exact(value) => FieldMatch_oneof_field_match_type::exact(
-                        FieldMatch_Exact { value: value.into(), unknown_fields, cached_size }),
+                        FieldMatch_Exact { value: value.clone().into(), unknown_fields, cached_size }),
                     FieldMatchType::Ternary { value, mask } => FieldMatch_oneof_field_match_type::ternary(
-                        FieldMatch_Ternary { value: value.into(), mask: mask.into(), unknown_fields, cached_size }),
+                        FieldMatch_Ternary { value: value.clone().into(), mask: mask.clone().into(), unknown_fields, cached_size }),
                     FieldMatchType::LPM { value, plen } => FieldMatch_oneof_field_match_type::lpm(
-                        FieldMatch_LPM { value: value.into(), prefix_len: plen as i32, unknown_fields, cached_size }),
+                        FieldMatch_LPM { value: value.clone().into(), prefix_len: *plen as i32, unknown_fields, cached_size }),
                     FieldMatchType::Range(low, high) => FieldMatch_oneof_field_match_type::range(
-                        FieldMatch_Range { low: low.into(), high: high.into(), unknown_fields, cached_size }),
+                        FieldMatch_Range { low: low.clone().into(), high: high.clone().into(), unknown_fields, cached_size }),
                     FieldMatchType::Optional(value) => FieldMatch_oneof_field_match_type::optional(
-                        FieldMatch_Optional { value: value.into(), unknown_fields, cached_size })
+                        FieldMatch_Optional { value: value.clone().into(), unknown_fields, cached_size })
                 })
             },
             unknown_fields, cached_size
@@ -668,6 +1050,7 @@ impl Display for FieldMatch {
 /// Based on the [P4Runtime
 /// specification](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-multicastgroupentry).
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Replica {
     /// The output port.
     pub egress_port: u32,
@@ -695,6 +1078,7 @@ impl From<&Replica> for proto::p4runtime::Replica {
 /// Based on the [P4Runtime
 /// specification](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-multicastgroupentry).
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct MulticastGroupEntry {
     /// Group ID.  A value of zero acts as a wildcard for read operations and is not acceptable for
     /// write operations.
@@ -724,60 +1108,188 @@ impl From<&MulticastGroupEntry> for proto::p4runtime::MulticastGroupEntry {
 
 /// A value of a packet field.  The field's width in bits is not specified.
 ///
-/// This is currently implement as `u128`, which is big enough for the values we care about
-/// currently.  An arbitrary-precision type would be more flexible.
+/// Stored as an arbitrary-precision big-endian byte string, canonicalized on construction by
+/// stripping leading zero bytes (the empty vector represents zero, matching the P4Runtime
+/// bytestring encoding of 0 below).
 ///
 /// Equivalent to the [P4Runtime bytestring
-/// type](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-bytestrings) except for the
-/// width restriction.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-pub struct FieldValue(pub u128);
-impl TryFrom<&Vec<u8>> for FieldValue {
-    type Error = anyhow::Error;
+/// type](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-bytestrings).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FieldValue(Vec<u8>);
 
-    fn try_from(fv: &Vec<u8>) -> Result<Self> {
-        if fv.is_empty() {
-            Err(Error(RpcStatusCode::INVALID_ARGUMENT))
-                .context(format!("0-length P4 field value"))
+impl FieldValue {
+    /// Returns the value 0.
+    pub fn zero() -> FieldValue {
+        FieldValue(Vec::new())
+    }
+
+    /// Returns true if this value is 0.
+    pub fn is_zero(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the minimum number of bits needed to represent this value, e.g. 0 for the value 0,
+    /// or 9 for the value 256.
+    pub fn bit_length(&self) -> u32 {
+        match self.0.first() {
+            None => 0,
+            Some(&first) => (self.0.len() as u32 - 1) * 8 + (8 - first.leading_zeros()),
+        }
+    }
+
+    /// Builds a [`FieldValue`] from a big-endian byte string, stripping any leading zero bytes.
+    fn from_bytes_canonical(mut bytes: Vec<u8>) -> FieldValue {
+        let nonzero = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+        bytes.drain(..nonzero);
+        FieldValue(bytes)
+    }
+
+    /// Returns the byte `n` positions before the end of the value's canonical big-endian
+    /// encoding, as if the encoding were zero-extended indefinitely to the left, e.g. `n == 0` is
+    /// the last byte.
+    fn byte_from_end(&self, n: usize) -> u8 {
+        if n < self.0.len() {
+            self.0[self.0.len() - 1 - n]
         } else {
-            let mut x = 0;
-            for &digit in fv {
-                if x >= (1u128 << 120) {
-                    return Err(Error(RpcStatusCode::OUT_OF_RANGE))
-                        .context(format!("P4 field value exceeds 128-bit maximum supported length"));
+            0
+        }
+    }
+
+    /// Returns `self & mask`, bitwise.
+    pub fn and(&self, mask: &FieldValue) -> FieldValue {
+        let len = std::cmp::min(self.0.len(), mask.0.len());
+        let bytes = (0..len)
+            .rev()
+            .map(|n| self.byte_from_end(n) & mask.byte_from_end(n))
+            .collect::<Vec<u8>>();
+        FieldValue::from_bytes_canonical(bytes)
+    }
+
+    /// Returns true if `self` has any bit set outside of `mask`.
+    pub fn has_bits_outside(&self, mask: &FieldValue) -> bool {
+        let len = std::cmp::max(self.0.len(), mask.0.len());
+        (0..len).any(|n| self.byte_from_end(n) & !mask.byte_from_end(n) != 0)
+    }
+
+    /// Clears the low-order `bits` bits of `self`, keeping the rest.
+    pub fn clear_low_bits(&self, bits: u32) -> FieldValue {
+        let clear_bytes = (bits / 8) as usize;
+        let clear_extra_bits = bits % 8;
+        let len = self.0.len();
+        // The lowest `clear_bytes` bytes go to zero outright (not dropped -- they're still
+        // significant bit positions, just zeroed ones) and the one byte straddling the boundary
+        // gets its low `clear_extra_bits` bits masked off; everything above that is untouched.
+        let boundary = len.saturating_sub(clear_bytes);
+        let bytes = (0..len)
+            .map(|i| {
+                if i + 1 == boundary {
+                    self.0[i] & !((1u16 << clear_extra_bits) - 1) as u8
+                } else if i >= boundary {
+                    0
+                } else {
+                    self.0[i]
                 }
-                x = (x << 8) | (digit as u128);
+            })
+            .collect::<Vec<u8>>();
+        FieldValue::from_bytes_canonical(bytes)
+    }
+
+    /// Returns a value with the low-order `bits` bits set, and the rest clear.
+    pub fn all_ones(bits: u32) -> FieldValue {
+        let mut bytes = vec![0xffu8; (bits as usize + 7) / 8];
+        if let Some(first) = bytes.first_mut() {
+            let extra_bits = bits % 8;
+            if extra_bits != 0 {
+                *first &= (1u16 << extra_bits) as u8 - 1;
             }
-            Ok(FieldValue(x))
         }
+        FieldValue::from_bytes_canonical(bytes)
+    }
+}
+
+impl TryFrom<&Vec<u8>> for FieldValue {
+    type Error = anyhow::Error;
+
+    fn try_from(fv: &Vec<u8>) -> Result<Self> {
+        Ok(FieldValue::from_bytes_canonical(fv.clone()))
     }
 }
 
 impl From<FieldValue> for Vec<u8> {
     fn from(fv: FieldValue) -> Vec<u8> {
-        let mut value = fv.0;
-        let mut v: Vec<u8> = Vec::new();
-        loop {
-            v.push((value & 0xff) as u8);
-            value >>= 8;
-            if value == 0 {
-                v.reverse();
-                return v
-            }
-        }
+        fv.0
+    }
+}
+
+impl From<u128> for FieldValue {
+    fn from(value: u128) -> FieldValue {
+        FieldValue::from_bytes_canonical(value.to_be_bytes().to_vec())
+    }
+}
+
+impl PartialOrd for FieldValue {
+    fn partial_cmp(&self, other: &FieldValue) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FieldValue {
+    fn cmp(&self, other: &FieldValue) -> std::cmp::Ordering {
+        self.0
+            .len()
+            .cmp(&other.0.len())
+            .then_with(|| self.0.cmp(&other.0))
     }
 }
 
 impl fmt::Display for FieldValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.0 == 0 {
+        if self.0.is_empty() {
             write!(f, "0")
         } else {
-            write!(f, "0x{:x}", self.0)
+            write!(f, "0x")?;
+            for (i, byte) in self.0.iter().enumerate() {
+                if i == 0 {
+                    write!(f, "{:x}", byte)?;
+                } else {
+                    write!(f, "{:02x}", byte)?;
+                }
+            }
+            Ok(())
         }
     }
 }
 
+// `FieldValue` serializes as the same hex string `Display` prints, rather than deriving the
+// default byte-array encoding, so a dumped pipeline snapshot reads the same as a log message.
+#[cfg(feature = "serde")]
+impl Serialize for FieldValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for FieldValue {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let digits = s.strip_prefix("0x").unwrap_or(&s);
+        let padded;
+        let digits = if digits.len() % 2 == 1 {
+            padded = format!("0{}", digits);
+            &padded
+        } else {
+            digits
+        };
+        let bytes = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|e| serde::de::Error::custom(format!("invalid P4 field value {:?}: {}", s, e)))?;
+        Ok(FieldValue::from_bytes_canonical(bytes))
+    }
+}
+
 /// Identifier for a P4Runtime multicast group.
 pub type MulticastGroupId = u32;
 
@@ -792,6 +1304,7 @@ pub type TableId = u32;
 /// type](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-action), which specifies
 /// what values are acceptable.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct ActionParam {
     /// Identifies the [`Param`] by its [`Preamble::id`].
     pub param_id: u32,
@@ -825,6 +1338,7 @@ impl From<&ActionParam> for proto::p4runtime::Action_Param {
 /// Based on [the `params` in P4Runtime
 /// `Action`](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-action-specification).
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct TableAction {
     /// Identifies the [`Action`] by its [`Preamble::id`].
     pub action_id: u32,
@@ -874,6 +1388,7 @@ pub struct Error(pub RpcStatusCode);
 
 /// Key data within a [`TableEntry`].
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct TableKey {
     /// Identifies the [`Table`] by its [`Preamble::id`].
     pub table_id: TableId,
@@ -895,7 +1410,7 @@ pub struct TableKey {
     pub is_default_action: bool,
 }
 #[derive(Clone, Debug, PartialEq)]
-
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 /// Value data within a [`TableEntry`].
 pub struct TableValue {
     /// The action to be taken when this entry is matched.  `None` is not allowed within a real
@@ -906,11 +1421,16 @@ pub struct TableValue {
     pub controller_metadata: u64,
 
     /// Arbitrary controller-specified metadata.
-    pub metadata: Vec<u8>
+    pub metadata: Vec<u8>,
+
+    /// Idle timeout, in nanoseconds, after which the entry should be reported as idle via an
+    /// `IdleTimeoutNotification`.  Zero means the entry never times out.
+    pub idle_timeout_ns: i64,
 }
 
 /// An entry within a [`Table`].
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct TableEntry {
     /// Key.
     pub key: TableKey,
@@ -953,6 +1473,7 @@ impl TableEntry {
                 },
                 controller_metadata: te.controller_metadata,
                 metadata: te.metadata.clone(),
+                idle_timeout_ns: te.idle_timeout_ns,
             }
         })
     }
@@ -966,7 +1487,7 @@ impl TryFrom<&proto::p4runtime::TableEntry> for TableEntry {
 }
 impl From<&TableEntry> for proto::p4runtime::TableEntry {
     fn from(te: &TableEntry) -> proto::p4runtime::TableEntry {
-        let (meter_config, counter_data, meter_counter_data, idle_timeout_ns, time_since_last_hit, unknown_fields, cached_size)
+        let (meter_config, counter_data, meter_counter_data, time_since_last_hit, unknown_fields, cached_size)
             = Default::default();
         proto::p4runtime::TableEntry {
             table_id: te.key.table_id,
@@ -978,7 +1499,7 @@ impl From<&TableEntry> for proto::p4runtime::TableEntry {
             counter_data,
             meter_counter_data,
             is_default_action: te.key.is_default_action,
-            idle_timeout_ns,
+            idle_timeout_ns: te.value.idle_timeout_ns,
             time_since_last_hit,
             metadata: te.value.metadata.clone(),
             unknown_fields,
@@ -990,6 +1511,64 @@ impl From<&TableEntry> for proto::p4runtime::TableEntry {
 #[cfg(feature = "ofp4")]
 use differential_datalog::record::{IntoRecord, Name, Record};
 
+#[cfg(feature = "ofp4")]
+use num_bigint::{BigInt, Sign};
+
+#[cfg(feature = "ofp4")]
+use num_traits::cast::ToPrimitive;
+
+/// Converts a [`FieldValue`] into the DDlog `Record::Int` that represents it.
+#[cfg(feature = "ofp4")]
+fn field_value_to_record(value: &FieldValue) -> Record {
+    Record::Int(BigInt::from_bytes_be(Sign::Plus, &Vec::from(value.clone())))
+}
+
+/// Inverts [`field_value_to_record`]: recovers the [`FieldValue`] a DDlog `Record::Int` or
+/// `Record::Bool` represents (the latter, since [`MatchField::is_nerpa_bool`] fields round-trip
+/// through `bool`).
+#[cfg(feature = "ofp4")]
+fn field_value_from_record(record: &Record) -> Result<FieldValue> {
+    match record {
+        Record::Bool(false) => Ok(FieldValue::zero()),
+        Record::Bool(true) => Ok(FieldValue::from(1u128)),
+        Record::Int(i) => {
+            let (sign, bytes) = i.to_bytes_be();
+            if sign == Sign::Minus {
+                return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("DDlog value {} is negative", i));
+            }
+            FieldValue::try_from(&bytes)
+        }
+        _ => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+            .context(format!("expected DDlog integer or boolean, got {:?}", record)),
+    }
+}
+
+/// Extracts a `u32` from a DDlog `Record::Int`, for fields that are always narrow enough to fit
+/// one -- LPM prefix lengths and match priorities.
+#[cfg(feature = "ofp4")]
+fn record_to_u32(record: &Record) -> Result<u32> {
+    match record {
+        Record::Int(i) => match i.to_u32() {
+            Some(v) => Ok(v),
+            None => Err(Error(RpcStatusCode::OUT_OF_RANGE))
+                .context(format!("DDlog value {} out of range for u32", i)),
+        },
+        _ => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+            .context(format!("expected DDlog integer, got {:?}", record)),
+    }
+}
+
+/// Looks up `name` among `fields`, the contents of a `Record::NamedStruct`.
+#[cfg(feature = "ofp4")]
+fn record_field<'a>(fields: &'a [(Name, Record)], name: &str) -> Result<&'a Record> {
+    match fields.iter().find(|(n, _)| n.as_ref() == name) {
+        Some((_, r)) => Ok(r),
+        None => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+            .context(format!("DDlog record is missing field {}", name)),
+    }
+}
+
 #[cfg(feature = "ofp4")]
 impl MatchField {
     /// Returns a Record for a DDlog value that matches FieldMatch 'fm' against MatchField 'self'.
@@ -999,19 +1578,19 @@ impl MatchField {
             Some(fm) => match (&self.match_type, &fm.match_type) {
                 (MatchType::Exact, FieldMatchType::Exact(value)) => Ok(
                     if self.is_nerpa_bool() {
-                        Record::Bool(value.0 != 0)
+                        Record::Bool(!value.is_zero())
                     } else {
-                        value.0.into_record()
+                        field_value_to_record(value)
                     }),
                 (MatchType::LPM, FieldMatchType::LPM { value, plen })
-                    => Ok(Record::Tuple(vec![value.0.into_record(), Record::Int((*plen).into())])),
+                    => Ok(Record::Tuple(vec![field_value_to_record(value), Record::Int((*plen).into())])),
                 (MatchType::Ternary, FieldMatchType::Ternary { value, mask })
-                    => Ok(Record::Tuple(vec![value.0.into_record(), mask.0.into_record()])),
+                    => Ok(Record::Tuple(vec![field_value_to_record(value), field_value_to_record(mask)])),
                 (MatchType::Range, FieldMatchType::Range(low, high))
-                    => Ok(Record::Tuple(vec![low.0.into_record(), high.0.into_record()])),
+                    => Ok(Record::Tuple(vec![field_value_to_record(low), field_value_to_record(high)])),
                 (MatchType::Optional, FieldMatchType::Optional(value))
                     => Ok(Record::NamedStruct(Name::from("ddlog_std::Some"),
-                                              vec![(Name::from("x"), value.0.into_record())])),
+                                              vec![(Name::from("x"), field_value_to_record(value))])),
                 (MatchType::Unspecified, _)
                     => Err(Error(RpcStatusCode::UNIMPLEMENTED))
                     .context(format!("unspecified match not supported")),
@@ -1037,6 +1616,58 @@ impl MatchField {
             }
         }
     }
+
+    /// Inverts [`Self::to_record`]: reconstructs the `FieldMatch` against `self` that `record`
+    /// encodes, passed through [`FieldMatch::canonicalize`]. Returns `None` for a
+    /// `ddlog_std::None` optional and for whatever `canonicalize` folds into a don't-care (a zero
+    /// ternary mask, a zero prefix length, or a range from 0 to the field's all-ones value).
+    /// `to_record`'s range don't-care, `(0, bit_width)`, is not of that form (except for a 1-bit
+    /// field), so it comes back as a `Range` match rather than `None`.
+    pub fn from_record(&self, record: &Record) -> Result<Option<FieldMatch>> {
+        fn tuple(record: &Record) -> Result<&Vec<Record>> {
+            match record {
+                Record::Tuple(elements) => Ok(elements),
+                _ => Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("expected DDlog tuple, got {:?}", record)),
+            }
+        }
+
+        let field_id = self.preamble.id;
+        let match_type = match self.match_type {
+            MatchType::Exact => FieldMatchType::Exact(field_value_from_record(record)?),
+            MatchType::LPM => {
+                let elements = tuple(record)?;
+                FieldMatchType::LPM {
+                    value: field_value_from_record(&elements[0])?,
+                    plen: record_to_u32(&elements[1])? as usize,
+                }
+            }
+            MatchType::Ternary => {
+                let elements = tuple(record)?;
+                FieldMatchType::Ternary {
+                    value: field_value_from_record(&elements[0])?,
+                    mask: field_value_from_record(&elements[1])?,
+                }
+            }
+            MatchType::Range => {
+                let elements = tuple(record)?;
+                FieldMatchType::Range(
+                    field_value_from_record(&elements[0])?,
+                    field_value_from_record(&elements[1])?)
+            }
+            MatchType::Optional => match record {
+                Record::NamedStruct(name, _) if name.as_ref() == "ddlog_std::None" => return Ok(None),
+                Record::NamedStruct(name, fields) if name.as_ref() == "ddlog_std::Some" =>
+                    FieldMatchType::Optional(field_value_from_record(record_field(fields, "x")?)?),
+                _ => return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("expected ddlog_std::Option NamedStruct, got {:?}", record)),
+            },
+            MatchType::Unspecified | MatchType::Other(_) => return Err(Error(RpcStatusCode::UNIMPLEMENTED))
+                .context(format!("match field {} not supported", self)),
+        };
+
+        Ok(FieldMatch { field_id, match_type }.canonicalize(self))
+    }
 }
 
 #[cfg(feature = "ofp4")]
@@ -1072,9 +1703,9 @@ impl TableEntry {
                             None => return Err(Error(RpcStatusCode::INVALID_ARGUMENT)).context(format!("table entry lacks argument for parameter {:?}", p))?
                         };
                         let record = if p.is_nerpa_bool() {
-                            Record::Bool(arg.value.0 != 0)
+                            Record::Bool(!arg.value.is_zero())
                         } else {
-                            Record::Int(arg.value.0.into())
+                            field_value_to_record(&arg.value)
                         };
                         param_values.push((Name::Owned(p.preamble.name.clone()), record));
                     }
@@ -1091,6 +1722,148 @@ impl TableEntry {
             Ok(Record::NamedStruct(Name::Owned(table.base_name().into()), values))
         }
     }
+
+    /// Returns the single [`ActionRef`] this table always uses, with `to_record`'s "single
+    /// no-arg action omitted" shortcut applied, if `table` qualifies: it has exactly one entry
+    /// action and that action takes no parameters.
+    fn omitted_action(table: &Table) -> Option<&ActionRef> {
+        let mut entry_actions = table.entry_actions();
+        match (entry_actions.next(), entry_actions.next()) {
+            (Some(ar), None) if ar.action.params.is_empty() => Some(ar),
+            _ => None,
+        }
+    }
+
+    /// Inverts [`Self::to_record`]: reconstructs the `TableEntry` within `table` that `record`
+    /// encodes, so a DDlog output relation generated from `table` can be pushed straight to the
+    /// switch without a hand-written translation layer.
+    pub fn from_record(record: &Record, table: &Table) -> Result<TableEntry> {
+        let omitted_action = Self::omitted_action(table);
+        let folded = table.is_nerpa_singleton()
+            && table.match_fields.len() == 1
+            && !table.has_priority()
+            && omitted_action.is_some();
+
+        let owned_fields;
+        let fields: &[(Name, Record)] = if folded {
+            owned_fields = vec![(Name::Owned(table.match_fields[0].preamble.name.clone()), record.clone())];
+            &owned_fields
+        } else {
+            match record {
+                Record::NamedStruct(_, fields) => fields,
+                _ => return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("expected DDlog named struct for table {}, got {:?}", table.preamble.name, record)),
+            }
+        };
+
+        let mut matches = Vec::new();
+        for mf in &table.match_fields {
+            let r = record_field(fields, &mf.preamble.name)?;
+            if let Some(fm) = mf.from_record(r)? {
+                matches.push(fm);
+            }
+        }
+
+        let priority = if table.has_priority() {
+            record_to_u32(record_field(fields, "priority")?)? as i32
+        } else {
+            0
+        };
+
+        let action = match omitted_action {
+            Some(ar) => TableAction { action_id: ar.action.preamble.id, params: Vec::new() },
+            None => match record_field(fields, "action")? {
+                Record::NamedStruct(name, param_fields) => {
+                    let ar = match table.entry_actions()
+                        .find(|ar| name.as_ref() == format!("{}Action{}", table.base_name(), ar.action.preamble.alias)) {
+                        Some(ar) => ar,
+                        None => return Err(Error(RpcStatusCode::NOT_FOUND))
+                            .context(format!("DDlog action {} not found in table {}", name, table.preamble.name)),
+                    };
+                    let mut params = Vec::new();
+                    for p in &ar.action.params {
+                        let pr = record_field(param_fields, &p.preamble.name)?;
+                        params.push(ActionParam { param_id: p.preamble.id, value: field_value_from_record(pr)? });
+                    }
+                    TableAction { action_id: ar.action.preamble.id, params }
+                }
+                other => return Err(Error(RpcStatusCode::INVALID_ARGUMENT))
+                    .context(format!("expected DDlog named struct for action, got {:?}", other)),
+            }
+        };
+
+        Ok(TableEntry {
+            key: TableKey {
+                table_id: table.preamble.id,
+                matches,
+                priority,
+                is_default_action: false,
+            },
+            value: TableValue {
+                action: Some(action),
+                controller_metadata: 0,
+                metadata: Vec::new(),
+                idle_timeout_ns: 0,
+            },
+        })
+    }
+}
+
+#[cfg(feature = "ofp4")]
+impl Switch {
+    /// Finds the `Table` that DDlog relation `relation_name` corresponds to. `p4_to_ddlog` names a
+    /// table's relation after [`Table::base_name`] (possibly with a suffix, e.g.
+    /// `<table>DefaultAction`), so matching is done the same way: the first table whose base name
+    /// appears in `relation_name`.
+    pub fn find_table(&self, relation_name: &str) -> Option<&Table> {
+        self.tables.iter().find(|t| relation_name.contains(t.base_name()))
+    }
+}
+
+/// Assembles the `Update`s that push one DDlog relation's delta to the switch, the reverse of what
+/// `p4_to_ddlog` derives a relation declaration from.  Each `(record, weight)` pair becomes one
+/// `TableEntry` update via [`TableEntry::from_record`]: a positive weight means the row was
+/// inserted or kept by DDlog (INSERT if `known_entries` hasn't seen this key before, MODIFY if it
+/// has), a negative weight means DDlog retracted it (DELETE, and forget the key so a later
+/// re-insertion is seen as an INSERT again).
+///
+/// `known_entries` carries the set of keys already pushed to the switch across calls -- a DDlog
+/// delta only contains what changed, not the state of every row still present, so the caller must
+/// reuse the same set across every delta for a given switch connection.
+#[cfg(feature = "ofp4")]
+pub fn assemble_updates<'a>(
+    switch: &Switch,
+    relation_name: &str,
+    records: impl IntoIterator<Item = (&'a Record, i64)>,
+    known_entries: &mut HashSet<(u32, String)>,
+) -> Result<Vec<proto::p4runtime::Update>> {
+    let table = switch.find_table(relation_name)
+        .ok_or(Error(RpcStatusCode::NOT_FOUND))
+        .with_context(|| format!("no table matches DDlog relation {}", relation_name))?;
+
+    let mut updates = Vec::new();
+    for (record, weight) in records {
+        let entry = TableEntry::from_record(record, table)?;
+        let key = (entry.key.table_id, format!("{:?}", entry.key.matches));
+        let update_type = if weight < 0 {
+            known_entries.remove(&key);
+            proto::p4runtime::Update_Type::DELETE
+        } else if known_entries.insert(key) {
+            proto::p4runtime::Update_Type::INSERT
+        } else {
+            proto::p4runtime::Update_Type::MODIFY
+        };
+
+        let mut entity = proto::p4runtime::Entity::new();
+        entity.set_table_entry((&entry).into());
+
+        let mut update = proto::p4runtime::Update::new();
+        update.set_field_type(update_type);
+        update.set_entity(entity);
+        updates.push(update);
+    }
+
+    Ok(updates)
 }
 
 fn parse_type_name(pnto: Option<&p4types::P4NamedType>) -> Option<String> {
@@ -1131,6 +1904,12 @@ impl Param {
     }
 }
 
+impl HasPreamble for Param {
+    fn preamble(&self) -> &Preamble {
+        &self.preamble
+    }
+}
+
 impl From<&p4info::Action_Param> for Param {
     fn from(ap: &p4info::Action_Param) -> Self {
         Param {
@@ -1170,7 +1949,13 @@ pub struct Action {
     pub params: Vec<Param>,
 }
 
-impl From<&p4info::Action> for Action {
+impl HasPreamble for Action {
+    fn preamble(&self) -> &Preamble {
+        &self.preamble
+    }
+}
+
+impl From<&p4info::Action> for Action {
     fn from(a: &p4info::Action) -> Self {
         Action {
             preamble: a.get_preamble().into(),
@@ -1228,6 +2013,59 @@ impl From<p4info::ActionRef_Scope> for Scope {
     }
 }
 
+/// A P4Runtime "action profile", which lets one or more [`Table`]s reference actions indirectly
+/// by a member id (selecting one action) rather than inlining the action into each table entry,
+/// or, if `with_selector` is set ("action selector"), by a group id selecting among several
+/// weighted members.
+///
+/// Described [here](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-action-profile).
+#[derive(Clone, Debug)]
+pub struct ActionProfile {
+    /// Identification.
+    pub preamble: Preamble,
+    /// IDs of the [`Table`]s whose `implementation` is this profile.
+    pub table_ids: Vec<u32>,
+    /// True if this is an action selector, whose members may be grouped into weighted groups;
+    /// false if it's a plain action profile, whose members may only be referenced directly.
+    pub with_selector: bool,
+    /// Maximum number of members the profile may hold.
+    pub size: i32,
+    /// Maximum number of members a single group may hold. Only meaningful if `with_selector`.
+    pub max_group_size: Option<i32>,
+}
+
+impl HasPreamble for ActionProfile {
+    fn preamble(&self) -> &Preamble {
+        &self.preamble
+    }
+}
+
+impl From<&p4info::ActionProfile> for ActionProfile {
+    fn from(ap: &p4info::ActionProfile) -> Self {
+        ActionProfile {
+            preamble: ap.get_preamble().into(),
+            table_ids: ap.get_table_ids().to_vec(),
+            with_selector: ap.with_selector,
+            size: ap.size,
+            max_group_size: if ap.with_selector && ap.max_group_size > 0 {
+                Some(ap.max_group_size)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl Display for ActionProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "action profile {}", self.preamble.name)?;
+        if self.with_selector {
+            write!(f, " (selector, max group size {:?})", self.max_group_size)?;
+        }
+        Ok(())
+    }
+}
+
 /// Represents an action that may be used in a [`Table`].
 ///
 /// Described within [this](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-table).
@@ -1241,6 +2079,12 @@ pub struct ActionRef {
     pub annotations: Annotations,
 }
 
+impl HasPreamble for ActionRef {
+    fn preamble(&self) -> &Preamble {
+        &self.action.preamble
+    }
+}
+
 impl ActionRef {
     /// Returns a new `ActionRef` based on `ar`.  The actions in the new `ActionRef` are looked up
     /// by ID in `actions` and cloned.
@@ -1284,7 +2128,9 @@ pub struct Table {
     /// Set of possible actions for the table.
     pub actions: Vec<ActionRef>,
     const_default_action: Option<Action>,
-    //action_profile: Option<ActionProfile>,
+    /// The action profile or action selector this table indirects its actions through, if any.
+    /// When set, table entries carry a member or group ID instead of an inline action.
+    pub implementation: Option<ActionProfile>,
     //direct_counter: Option<DirectCounter>,
     //direct_meter: Option<DirectMeter>,
     max_entries: Option<u64>,
@@ -1292,10 +2138,21 @@ pub struct Table {
     is_const_table: bool,
 }
 
+impl HasPreamble for Table {
+    fn preamble(&self) -> &Preamble {
+        &self.preamble
+    }
+}
+
 impl Table {
     /// Returns a new `Table` based on `t`.  The actions in the new `Table` are looked up by ID in
-    /// `actions` and cloned.
-    pub fn new_from_proto(t: &p4info::Table, actions: &HashMap<u32, Action>) -> Self {
+    /// `actions` and cloned; if `t` has an `implementation_id`, the action profile it names is
+    /// looked up by ID in `action_profiles` and cloned too.
+    pub fn new_from_proto(
+        t: &p4info::Table,
+        actions: &HashMap<u32, Action>,
+        action_profiles: &HashMap<u32, ActionProfile>,
+    ) -> Self {
         Table {
             preamble: t.get_preamble().into(),
             match_fields: t.get_match_fields().iter().map(|x| x.into()).collect(),
@@ -1305,6 +2162,11 @@ impl Table {
                 .map(|x| ActionRef::new_from_proto(x, actions))
                 .collect(),
             const_default_action: None, // XXX
+            implementation: if t.implementation_id != 0 {
+                action_profiles.get(&t.implementation_id).cloned()
+            } else {
+                None
+            },
             max_entries: if t.size > 0 {
                 Some(t.size as u64)
             } else {
@@ -1354,6 +2216,18 @@ impl Table {
     pub fn is_nerpa_singleton(&self) -> bool {
         self.preamble.annotations.0.contains_key("nerpa_singleton")
     }
+
+    /// Returns true if this table references actions indirectly through an action profile or
+    /// action selector, rather than inlining them into each table entry.
+    pub fn is_indirect(&self) -> bool {
+        self.implementation.is_some()
+    }
+
+    /// Returns true if this table's [`Self::implementation`] is an action selector, i.e. entries
+    /// reference a group of weighted members rather than a single member directly.
+    pub fn uses_selector(&self) -> bool {
+        self.implementation.as_ref().map_or(false, |ap| ap.with_selector)
+    }
 }
 
 impl Display for Table {
@@ -1371,6 +2245,9 @@ impl Display for Table {
         if let Some(a) = &self.const_default_action {
             write!(f, "\tconst default action {}", a)?;
         }
+        if let Some(ap) = &self.implementation {
+            write!(f, "\t{}", ap)?;
+        }
         if self.is_const_table {
             write!(f, "\tconst table")?;
         }
@@ -1381,6 +2258,107 @@ impl Display for Table {
     }
 }
 
+/// Matches `text` against a shell-style glob `pattern`: `*` matches any run of characters
+/// (including none), `?` matches exactly one character, and every other character matches itself.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[u8], text: &[u8]) -> bool {
+        match pattern.first() {
+            None => text.is_empty(),
+            Some(b'*') => {
+                matches(&pattern[1..], text) || (!text.is_empty() && matches(pattern, &text[1..]))
+            }
+            Some(b'?') => !text.is_empty() && matches(&pattern[1..], &text[1..]),
+            Some(&c) => !text.is_empty() && text[0] == c && matches(&pattern[1..], &text[1..]),
+        }
+    }
+    matches(pattern.as_bytes(), text.as_bytes())
+}
+
+/// One filtering predicate in a [`Selector`]'s pipeline.
+#[derive(Clone, Debug)]
+enum SelectorStep {
+    /// Keep entities whose name matches a glob pattern, per [`glob_match`].
+    NameGlob(String),
+    /// Keep entities carrying the named annotation, regardless of its value.
+    HasAnnotation(String),
+    /// Keep entities whose named annotation is a single-expression list equal to this value.
+    AnnotationEquals(String, ExpressionValue),
+    /// Keep entities whose named annotation is a key/value list containing this pair.
+    AnnotationKeyValue(String, String, ExpressionValue),
+}
+
+impl SelectorStep {
+    fn matches(&self, preamble: &Preamble) -> bool {
+        use AnnotationValue::*;
+        match self {
+            SelectorStep::NameGlob(pattern) => glob_match(pattern, &preamble.name),
+            SelectorStep::HasAnnotation(name) => preamble.annotations.0.contains_key(name),
+            SelectorStep::AnnotationEquals(name, expected) => matches!(
+                preamble.annotations.0.get(name),
+                Some((_, Expressions(values))) if values.len() == 1 && values[0] == *expected
+            ),
+            SelectorStep::AnnotationKeyValue(name, key, expected) => matches!(
+                preamble.annotations.0.get(name),
+                Some((_, KeyValuePairs(kvs)))
+                    if kvs.iter().any(|kv| &kv.0 == key && &kv.1 == expected)
+            ),
+        }
+    }
+}
+
+/// Selects P4Info entities by structured criteria instead of callers hand-walking
+/// [`Annotations`] themselves, e.g. "all tables annotated `@hidden`" or "all match fields whose
+/// `@nerpa_bool` is set".
+///
+/// Steps compose: each builder method below consumes `self` and returns a `Selector` that
+/// additionally requires its predicate, so `Selector::new().named("ipv4_*").annotated("hidden")`
+/// reads as a pipeline of narrowing filters. [`Self::select`] then runs the whole pipeline over
+/// any slice of entities implementing [`HasPreamble`] and returns the matching [`Preamble`]s.
+#[derive(Clone, Debug, Default)]
+pub struct Selector {
+    steps: Vec<SelectorStep>,
+}
+
+impl Selector {
+    /// Returns a `Selector` that matches every entity; chain the methods below to narrow it.
+    pub fn new() -> Self {
+        Selector::default()
+    }
+
+    /// Narrows to entities whose name matches `pattern`, a glob supporting `*` and `?`.
+    pub fn named(mut self, pattern: &str) -> Self {
+        self.steps.push(SelectorStep::NameGlob(pattern.to_string()));
+        self
+    }
+
+    /// Narrows to entities carrying the `name` annotation, regardless of its value.
+    pub fn annotated(mut self, name: &str) -> Self {
+        self.steps.push(SelectorStep::HasAnnotation(name.to_string()));
+        self
+    }
+
+    /// Narrows to entities whose `name` annotation is a single-expression list equal to `value`.
+    pub fn annotation_equals(mut self, name: &str, value: ExpressionValue) -> Self {
+        self.steps.push(SelectorStep::AnnotationEquals(name.to_string(), value));
+        self
+    }
+
+    /// Narrows to entities whose `name` annotation is a key/value list mapping `key` to `value`.
+    pub fn annotation_kv(mut self, name: &str, key: &str, value: ExpressionValue) -> Self {
+        self.steps.push(SelectorStep::AnnotationKeyValue(name.to_string(), key.to_string(), value));
+        self
+    }
+
+    /// Returns the preambles of every entity in `items` that matches every step of this selector.
+    pub fn select<'a, T: HasPreamble>(&self, items: &'a [T]) -> Vec<&'a Preamble> {
+        items
+            .iter()
+            .map(HasPreamble::preamble)
+            .filter(|preamble| self.steps.iter().all(|step| step.matches(preamble)))
+            .collect()
+    }
+}
+
 /// Represents a P4-programmable switch.
 pub struct Switch {
     /// Tables within a switch.
@@ -1394,10 +2372,15 @@ impl From<&p4info::P4Info> for Switch {
             .iter()
             .map(|x| (x.get_preamble().id, x.into()))
             .collect();
+        let action_profiles: HashMap<u32, ActionProfile> = p4i
+            .get_action_profiles()
+            .iter()
+            .map(|x| (x.get_preamble().id, x.into()))
+            .collect();
         let tables: Vec<Table> = p4i
             .get_tables()
             .iter()
-            .map(|x| Table::new_from_proto(x, &actions))
+            .map(|x| Table::new_from_proto(x, &actions, &action_profiles))
             .collect();
         Switch { tables }
     }
@@ -1525,20 +2508,69 @@ pub fn set_pipeline_config(
     target: &str,
     client: &P4RuntimeClient,
 ) {
+    let set_pipeline_request = build_set_pipeline_config_request(
+        p4info_str, json_str, cookie_str, action_str, device_id, role_id,
+    );
+    client
+        .set_forwarding_pipeline_config(&set_pipeline_request)
+        .unwrap_or_else(|err| panic!("{}: failed to set forwarding pipeline ({})", target, err));
+}
+
+/// Like [`set_pipeline_config`], but retries a retriable failure per `policy` instead of failing
+/// on the first one.
+pub async fn set_pipeline_config_retry(
+    p4info_str: &str,
+    json_str: &str,
+    cookie_str: &str,
+    action_str: &str,
+    device_id: u64,
+    role_id: u64,
+    target: &str,
+    client: &P4RuntimeClient,
+    policy: &RetryPolicy,
+) {
+    let set_pipeline_request = build_set_pipeline_config_request(
+        p4info_str, json_str, cookie_str, action_str, device_id, role_id,
+    );
+    retry_unary(policy, is_retriable, |opt| client.set_forwarding_pipeline_config_opt(&set_pipeline_request, opt))
+        .await
+        .unwrap_or_else(|err| panic!("{}: failed to set forwarding pipeline ({})", target, err));
+}
+
+/// Builds the `SetForwardingPipelineConfig` request shared by [`set_pipeline_config`] and
+/// [`set_pipeline_config_retry`].
+fn build_set_pipeline_config_request(
+    p4info_str: &str,
+    json_str: &str,
+    cookie_str: &str,
+    action_str: &str,
+    device_id: u64,
+    role_id: u64,
+) -> SetForwardingPipelineConfigRequest {
+    try_build_set_pipeline_config_request(p4info_str, json_str, cookie_str, action_str, device_id, role_id)
+        .unwrap_or_else(|err| panic!("{}", err.message))
+}
+
+/// Like [`build_set_pipeline_config_request`], but returns a [`P4Error`] instead of panicking when
+/// the p4info/JSON files can't be opened, parsed, or the cookie can't be parsed as a `u64` -- so a
+/// [`Controller`] supervising many switches can keep running when one device's config is bad.
+fn try_build_set_pipeline_config_request(
+    p4info_str: &str,
+    json_str: &str,
+    cookie_str: &str,
+    action_str: &str,
+    device_id: u64,
+    role_id: u64,
+) -> Result<SetForwardingPipelineConfigRequest, P4Error> {
     let p4info_os: &OsStr = OsStr::new(p4info_str);
     let mut p4info_file = fs::File::open(p4info_os)
-        .unwrap_or_else(|err| panic!("{}: could not open P4Info ({})", p4info_str, err));
+        .map_err(|err| P4Error{message: format!("{}: could not open P4Info ({})", p4info_str, err)})?;
     let p4info = Message::parse_from_reader(&mut p4info_file)
-        .unwrap_or_else(|err| panic!("{}: could not read P4Info ({})", p4info_str, err));
+        .map_err(|err| P4Error{message: format!("{}: could not read P4Info ({})", p4info_str, err)})?;
 
     let json_filename = OsStr::new(json_str);
-    let json = fs::read(json_filename).unwrap_or_else(|err| {
-        panic!(
-            "{}: could not read json data ({})",
-            json_filename.to_string_lossy(),
-            err
-        )
-    });
+    let json = fs::read(json_filename)
+        .map_err(|err| P4Error{message: format!("{}: could not read json data ({})", json_filename.to_string_lossy(), err)})?;
 
     let mut config = ForwardingPipelineConfig::new();
     config.set_p4_device_config(json);
@@ -1546,7 +2578,9 @@ pub fn set_pipeline_config(
 
     if cookie_str != "" {
         let mut cookie_jar = ForwardingPipelineConfig_Cookie::new();
-        cookie_jar.set_cookie(str::parse::<u64>(&cookie_str).unwrap());
+        let cookie = str::parse::<u64>(&cookie_str)
+            .map_err(|err| P4Error{message: format!("{}: invalid cookie ({})", cookie_str, err)})?;
+        cookie_jar.set_cookie(cookie);
         config.set_cookie(cookie_jar);
     }
 
@@ -1563,9 +2597,290 @@ pub fn set_pipeline_config(
     set_pipeline_request.set_device_id(device_id);
     set_pipeline_request.set_role_id(role_id);
     set_pipeline_request.set_config(config);
+    Ok(set_pipeline_request)
+}
+
+/// Like [`set_pipeline_config_retry`], but returns a [`P4Error`] instead of panicking on a
+/// request-building or RPC failure, so a [`Controller`] supervising many switches can keep running
+/// when one device is unreachable or misconfigured.
+pub async fn try_set_pipeline_config_retry(
+    p4info_str: &str,
+    json_str: &str,
+    cookie_str: &str,
+    action_str: &str,
+    device_id: u64,
+    role_id: u64,
+    target: &str,
+    client: &P4RuntimeClient,
+    policy: &RetryPolicy,
+) -> Result<(), P4Error> {
+    let set_pipeline_request = try_build_set_pipeline_config_request(
+        p4info_str, json_str, cookie_str, action_str, device_id, role_id,
+    )?;
+    retry_unary(policy, is_retriable, |opt| client.set_forwarding_pipeline_config_opt(&set_pipeline_request, opt))
+        .await
+        .map(|_w| ())
+        .map_err(|err| P4Error{message: format!("{}: failed to set forwarding pipeline ({})", target, err)})
+}
+
+/// Query a target's supported P4Runtime API version.
+///
+/// Calls the [`Capabilities` RPC](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-capabilities-rpc).
+///
+/// # Arguments
+/// * `target` - hardware/software entity hosting P4 Runtime, used for error messages.
+/// * `client` - P4 Runtime client.
+pub fn get_capabilities(
+    target: &str,
+    client: &P4RuntimeClient,
+) -> Result<CapabilitiesResponse, P4Error> {
     client
-        .set_forwarding_pipeline_config(&set_pipeline_request)
-        .unwrap_or_else(|err| panic!("{}: failed to set forwarding pipeline ({})", target, err));
+        .capabilities(&CapabilitiesRequest::new())
+        .map_err(|e| P4Error{message: format!("{}: failed to retrieve capabilities ({})", target, e)})
+}
+
+/// Retry and wait-for-ready behavior for the `*_retry` wrappers around unary P4Runtime calls,
+/// so a controller can survive a switch restart instead of failing the first call it loses the
+/// connection during.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Backoff before the first retry.
+    pub initial_backoff: Duration,
+    /// Ceiling the backoff is capped at, no matter how many attempts have elapsed.
+    pub max_backoff: Duration,
+    /// Factor the backoff is multiplied by after each failed attempt.
+    pub multiplier: f64,
+    /// Give up once this many attempts (including the first) have been made.
+    pub max_attempts: u32,
+    /// Sets the underlying `CallOption`'s wait-for-ready flag, so a call blocks for the channel
+    /// to become `READY` instead of failing fast while a target is still coming back up.
+    pub wait_for_ready: bool,
+}
+
+impl Default for RetryPolicy {
+    /// Start backing off at 100ms, doubling up to a 5s cap, giving up after 5 attempts.
+    fn default() -> Self {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            max_attempts: 5,
+            wait_for_ready: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn call_option(&self) -> CallOption {
+        CallOption::default().wait_for_ready(self.wait_for_ready)
+    }
+}
+
+/// Whether a gRPC status is safe to retry for most unary P4Runtime calls: the channel couldn't
+/// reach the target at all (`UNAVAILABLE`), or the deadline passed before the target did
+/// anything observable about the request (`DEADLINE_EXCEEDED`).
+fn is_retriable(code: RpcStatusCode) -> bool {
+    matches!(code, RpcStatusCode::UNAVAILABLE | RpcStatusCode::DEADLINE_EXCEEDED)
+}
+
+/// Whether a `Write` RPC failure is safe to retry. Unlike [`is_retriable`], this excludes
+/// `DEADLINE_EXCEEDED`: a write batch isn't idempotent, and a deadline can expire after the
+/// target already applied some of the updates, so retrying it could apply them twice.
+/// `UNAVAILABLE` stays safe to retry, since it means the channel never reached the target.
+fn write_is_retriable(code: RpcStatusCode) -> bool {
+    code == RpcStatusCode::UNAVAILABLE
+}
+
+/// Runs one attempt of `op` per iteration, retrying with capped exponential backoff and full
+/// jitter -- a uniformly random sleep in `[0, current_backoff]`, to avoid every client backing
+/// off in lockstep -- whenever `op` fails with a status `is_retriable` accepts, until `policy`'s
+/// `max_attempts` is reached.
+///
+/// # Arguments
+/// * `policy` - retry count, backoff, and wait-for-ready configuration.
+/// * `is_retriable` - decides whether a given attempt's failure status is safe to retry.
+/// * `op` - issues one attempt of the RPC, given the `CallOption` `policy` wants it called with.
+async fn retry_unary<T>(
+    policy: &RetryPolicy,
+    is_retriable: fn(RpcStatusCode) -> bool,
+    mut op: impl FnMut(CallOption) -> Result<T, grpcio::Error>,
+) -> Result<T, grpcio::Error> {
+    let mut backoff = policy.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        match op(policy.call_option()) {
+            Ok(result) => return Ok(result),
+            Err(grpcio::Error::RpcFailure(status))
+                if attempt < policy.max_attempts && is_retriable(status.code()) =>
+            {
+                let jittered = backoff.mul_f64(random::<f64>());
+                tokio::time::sleep(jittered).await;
+                backoff = min(backoff.mul_f64(policy.multiplier), policy.max_backoff);
+                attempt += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Like [`get_capabilities`], but retries a retriable failure per `policy` instead of failing on
+/// the first one.
+pub async fn get_capabilities_retry(
+    target: &str,
+    client: &P4RuntimeClient,
+    policy: &RetryPolicy,
+) -> Result<CapabilitiesResponse, P4Error> {
+    retry_unary(policy, is_retriable, |opt| client.capabilities_opt(&CapabilitiesRequest::new(), opt))
+        .await
+        .map_err(|e| P4Error{message: format!("{}: failed to retrieve capabilities ({})", target, e)})
+}
+
+/// A target's P4Runtime API version, as `(major, minor, patch)`, parsed from the
+/// `Capabilities` RPC's `p4runtime_api_version` string.
+pub type P4RuntimeVersion = (u32, u32, u32);
+
+/// The P4Runtime API version this crate is written against. [`negotiate`] compares a target's
+/// reported version against this one before handing out a [`NegotiatedSession`].
+pub const SUPPORTED_P4RUNTIME_VERSION: P4RuntimeVersion = (1, 0, 0);
+
+/// A target whose P4Runtime version [`negotiate`] has confirmed is compatible with
+/// [`SUPPORTED_P4RUNTIME_VERSION`].
+///
+/// `write`, `read`, and `set_pipeline_config` are mirrored here as methods so that driving a
+/// target always goes through a version check first, instead of a caller reaching for the bare
+/// [`P4RuntimeClient`] functions and skipping it.
+#[derive(Clone, Debug)]
+pub struct NegotiatedSession {
+    client: P4RuntimeClient,
+    /// The target's P4Runtime API version.
+    pub version: P4RuntimeVersion,
+    /// The target's full `Capabilities` response, for fields this crate doesn't otherwise parse.
+    pub capabilities: CapabilitiesResponse,
+}
+
+impl NegotiatedSession {
+    /// The underlying P4 Runtime client, for RPCs this crate doesn't wrap.
+    pub fn client(&self) -> &P4RuntimeClient {
+        &self.client
+    }
+
+    /// See [`set_pipeline_config`].
+    pub fn set_pipeline_config(
+        &self,
+        p4info_str: &str,
+        json_str: &str,
+        cookie_str: &str,
+        action_str: &str,
+        device_id: u64,
+        role_id: u64,
+        target: &str,
+    ) {
+        set_pipeline_config(p4info_str, json_str, cookie_str, action_str, device_id, role_id, target, &self.client)
+    }
+
+    /// See [`write`].
+    pub fn write(
+        &self,
+        updates: Vec<proto::p4runtime::Update>,
+        device_id: u64,
+        role_id: u64,
+        target: &str,
+    ) -> Result<(), P4Error> {
+        write(updates, device_id, role_id, target, &self.client)
+    }
+
+    /// See [`read`].
+    pub async fn read(
+        &self,
+        entities: Vec<proto::p4runtime::Entity>,
+        device_id: u64,
+    ) -> Result<Vec<proto::p4runtime::Entity>, P4Error> {
+        read(entities, device_id, &self.client).await
+    }
+}
+
+/// Failure to establish a [`NegotiatedSession`] with a target.
+#[derive(Error, Debug)]
+pub enum NegotiationError {
+    /// The `Capabilities` RPC itself failed.
+    #[error("{0}")]
+    Rpc(#[from] P4Error),
+
+    /// The target's `p4runtime_api_version` string didn't parse as `"major.minor.patch"`.
+    #[error("{target}: could not parse P4Runtime API version {reported:?}")]
+    UnparseableVersion {
+        /// The target that reported the unparseable version, used for error messages.
+        target: String,
+        /// The raw, unparseable version string the target reported.
+        reported: String,
+    },
+
+    /// The target's major P4Runtime version doesn't match [`SUPPORTED_P4RUNTIME_VERSION`], so it
+    /// isn't expected to be wire-compatible with the RPCs this crate sends.
+    #[error("{target}: P4Runtime API version {reported:?} is incompatible with the version {supported:?} this crate supports")]
+    IncompatibleVersion {
+        /// The incompatible target, used for error messages.
+        target: String,
+        /// The version the target reported.
+        reported: P4RuntimeVersion,
+        /// The version this crate was built against, i.e. [`SUPPORTED_P4RUNTIME_VERSION`].
+        supported: P4RuntimeVersion,
+    },
+}
+
+/// Performs a P4Runtime capability/version handshake with a target before it's driven with
+/// `write`/`read`/`set_pipeline_config`, so callers don't silently talk to an incompatible switch
+/// agent.
+///
+/// Calls the `Capabilities` RPC and parses its `p4runtime_api_version`. Refuses with
+/// [`NegotiationError::IncompatibleVersion`] if the target's major version differs from
+/// [`SUPPORTED_P4RUNTIME_VERSION`]; logs a warning, but still succeeds, on a minor or patch
+/// mismatch, since those are expected to stay backward-compatible.
+///
+/// # Arguments
+/// * `target` - hardware/software entity hosting P4 Runtime, used for error messages and logging.
+/// * `client` - P4 Runtime client.
+pub fn negotiate(target: &str, client: &P4RuntimeClient) -> Result<NegotiatedSession, NegotiationError> {
+    let capabilities = get_capabilities(target, client)?;
+
+    let reported = capabilities.get_p4runtime_api_version();
+    let version = parse_p4runtime_version(reported).ok_or_else(|| NegotiationError::UnparseableVersion {
+        target: target.to_string(),
+        reported: reported.to_string(),
+    })?;
+
+    if version.0 != SUPPORTED_P4RUNTIME_VERSION.0 {
+        return Err(NegotiationError::IncompatibleVersion {
+            target: target.to_string(),
+            reported: version,
+            supported: SUPPORTED_P4RUNTIME_VERSION,
+        });
+    }
+    if version != SUPPORTED_P4RUNTIME_VERSION {
+        warn!(
+            "{}: P4Runtime API version {:?} differs from the version {:?} this crate supports; proceeding",
+            target, version, SUPPORTED_P4RUNTIME_VERSION,
+        );
+    }
+
+    Ok(NegotiatedSession {
+        client: client.clone(),
+        version,
+        capabilities,
+    })
+}
+
+/// Parses a P4Runtime API version string of the form `"major.minor.patch"` into its numeric
+/// components, or `None` if the string doesn't have that shape.
+fn parse_p4runtime_version(version: &str) -> Option<P4RuntimeVersion> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let patch = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major, minor, patch))
 }
 
 /// Retrieve configuration for the forwarding pipeline.
@@ -1604,6 +2919,130 @@ pub fn get_pipeline_config(
     pipeline.clone()
 }
 
+/// Like [`get_pipeline_config`], but retries a retriable failure per `policy` instead of failing
+/// on the first one.
+pub async fn get_pipeline_config_retry(
+    device_id: u64,
+    target: &str,
+    client: &P4RuntimeClient,
+    policy: &RetryPolicy,
+) -> ForwardingPipelineConfig {
+    let mut get_pipeline_request = GetForwardingPipelineConfigRequest::new();
+    get_pipeline_request.set_device_id(device_id);
+    get_pipeline_request.set_response_type(
+        proto::p4runtime::GetForwardingPipelineConfigRequest_ResponseType::P4INFO_AND_COOKIE,
+    );
+
+    let pipeline_response = retry_unary(policy, is_retriable, |opt| {
+        client.get_forwarding_pipeline_config_opt(&get_pipeline_request, opt)
+    })
+        .await
+        .unwrap_or_else(|err| {
+            panic!(
+                "{}: failed to retrieve forwarding pipeline ({})",
+                target, err
+            )
+        });
+    let pipeline = pipeline_response.get_config();
+    if !pipeline.has_p4info() {
+        panic!("{}: device did not return P4Info", target);
+    }
+    pipeline.clone()
+}
+
+/// What [`reconcile_pipeline_config`] found on a target and did about it.
+#[derive(Clone, Debug)]
+pub struct PipelineReconcileOutcome {
+    /// The target's P4Runtime API version, from the `Capabilities` RPC.
+    pub version: P4RuntimeVersion,
+    /// The cookie installed on the target before this call, if any.
+    pub installed_cookie: Option<u64>,
+    /// Whether the push was skipped because `installed_cookie` already matched the cookie being
+    /// reconciled, leaving the target's installed table state untouched.
+    pub skipped: bool,
+}
+
+/// Reconciles the pipeline config at `p4info_str`/`json_str` with what's already installed on
+/// `target`, instead of blindly committing it.
+///
+/// First calls the `Capabilities` RPC and refuses with [`P4Error`] if the target's major
+/// P4Runtime version doesn't match [`SUPPORTED_P4RUNTIME_VERSION`] -- pushing a pipeline to an
+/// incompatible target would otherwise fail in stranger ways once table programming starts.
+///
+/// Then calls [`get_pipeline_config_retry`] (which already requests `P4INFO_AND_COOKIE`) to read
+/// the target's installed cookie and compares it against `cookie_str`. If they match, the target
+/// already has this exact program installed, so this returns without calling
+/// [`try_set_pipeline_config_retry`] at all, preserving whatever table state is already there
+/// rather than paying for a reload that would have no effect. Otherwise it pushes with the
+/// `RECONCILE_AND_COMMIT` action, which asks the target to keep table state where it can while
+/// adopting the new program.
+///
+/// # Arguments
+/// * `p4info_str` - filepath for the p4info binary file.
+/// * `json_str` - filepath for the compiled P4 program's JSON representation.
+/// * `cookie_str` - cookie identifying the pipeline config being reconciled.
+/// * `device_id` - ID of the P4-enabled device.
+/// * `role_id` - the controller's desired role.
+/// * `target` - entity hosting P4 Runtime.
+/// * `client` - P4 Runtime client.
+/// * `policy` - retry policy for the underlying RPCs.
+pub async fn reconcile_pipeline_config(
+    p4info_str: &str,
+    json_str: &str,
+    cookie_str: &str,
+    device_id: u64,
+    role_id: u64,
+    target: &str,
+    client: &P4RuntimeClient,
+    policy: &RetryPolicy,
+) -> Result<PipelineReconcileOutcome, P4Error> {
+    let capabilities = get_capabilities_retry(target, client, policy).await?;
+    let reported = capabilities.get_p4runtime_api_version();
+    let version = parse_p4runtime_version(reported)
+        .ok_or_else(|| P4Error{message: format!("{}: could not parse P4Runtime API version {:?}", target, reported)})?;
+    if version.0 != SUPPORTED_P4RUNTIME_VERSION.0 {
+        return Err(P4Error{message: format!(
+            "{}: P4Runtime API version {:?} is incompatible with the version {:?} this crate supports",
+            target, version, SUPPORTED_P4RUNTIME_VERSION,
+        )});
+    }
+
+    let new_cookie = if cookie_str != "" {
+        Some(str::parse::<u64>(cookie_str)
+            .map_err(|err| P4Error{message: format!("{}: invalid cookie ({})", cookie_str, err)})?)
+    } else {
+        None
+    };
+
+    let installed = get_pipeline_config_retry(device_id, target, client, policy).await;
+    let installed_cookie = installed.has_cookie().then(|| installed.get_cookie().get_cookie());
+
+    if new_cookie.is_some() && installed_cookie == new_cookie {
+        debug!("{}: installed cookie {:?} already matches; skipping pipeline reload", target, new_cookie);
+        return Ok(PipelineReconcileOutcome { version, installed_cookie, skipped: true });
+    }
+
+    try_set_pipeline_config_retry(
+        p4info_str, json_str, cookie_str, "reconcile-and-commit", device_id, role_id, target, client, policy,
+    ).await?;
+
+    Ok(PipelineReconcileOutcome { version, installed_cookie, skipped: false })
+}
+
+/// Reports whether `new`'s tables, actions, digests, or controller packet metadata differ from
+/// `current`'s -- i.e. whether a target running `current` actually needs to be reconfigured to
+/// behave like `new`, as opposed to e.g. just getting a new cookie for the same program.
+///
+/// # Arguments
+/// * `current` - P4Info for the pipeline currently installed on a device.
+/// * `new` - P4Info for the pipeline a caller is considering installing.
+pub fn pipeline_differs(current: &p4info::P4Info, new: &p4info::P4Info) -> bool {
+    current.get_tables() != new.get_tables()
+        || current.get_actions() != new.get_actions()
+        || current.get_digests() != new.get_digests()
+        || current.get_controller_packet_metadata() != new.get_controller_packet_metadata()
+}
+
 /// Build an update for a [table entry](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-table-entry).
 /// 
 /// # Arguments
@@ -1659,13 +3098,123 @@ pub fn write(
 
     match client.write(&write_request) {
         Ok(_w) => Ok(()),
-        Err(e) => Err(P4Error{message: format!("{}, {}, {}: failed to write request ({})", target, device_id, role_id, e)}), 
+        Err(e) => Err(P4Error{message: format!("{}, {}, {}: failed to write request ({})", target, device_id, role_id, e)}),
+    }
+}
+
+/// Like [`write`], but on failure decodes the batched response's per-update errors via
+/// [`error::decode_write_errors`] instead of collapsing them into one opaque failure, so a caller
+/// can tell which entity in the batch was rejected and with what code rather than retrying the
+/// whole batch blindly.
+///
+/// # Arguments
+/// * `updates` - updates to be written.
+/// * `device_id` - ID for the P4 device to write to.
+/// * `role_id` - role of the controller.
+/// * `target` - entity hosting P4 runtime, used for debugging.
+/// * `client` - P4 Runtime client.
+pub fn write_detailed(
+    updates: Vec<proto::p4runtime::Update>,
+    device_id: u64,
+    role_id: u64,
+    target: &str,
+    client: &P4RuntimeClient,
+) -> Result<(), Vec<error::WriteError>> {
+    let mut write_request = WriteRequest::new();
+    write_request.set_device_id(device_id);
+    write_request.set_role_id(role_id);
+    write_request.set_updates(RepeatedField::from_vec(updates));
+
+    match client.write(&write_request) {
+        Ok(_w) => Ok(()),
+        Err(grpcio::Error::RpcFailure(status)) => {
+            let decoded = status.details.as_deref()
+                .and_then(|details| error::decode_write_errors(details).ok())
+                .filter(|errors| !errors.is_empty());
+            match decoded {
+                Some(errors) => Err(errors),
+                None => Err(vec![error::WriteError{
+                    index: 0,
+                    canonical_code: status.status as i32,
+                    message: format!("{}, {}, {}: failed to write request ({})", target, device_id, role_id, status),
+                }]),
+            }
+        },
+        Err(e) => Err(vec![error::WriteError{
+            index: 0,
+            canonical_code: -1,
+            message: format!("{}, {}, {}: failed to write request ({})", target, device_id, role_id, e),
+        }]),
+    }
+}
+
+/// Like [`write`], but retries per `policy` on [`write_is_retriable`] failures -- i.e. only when
+/// the channel never reached the target at all, since a `Write` batch isn't safe to resend once
+/// the target might have started applying it.
+pub async fn write_retry(
+    updates: Vec<proto::p4runtime::Update>,
+    device_id: u64,
+    role_id: u64,
+    target: &str,
+    client: &P4RuntimeClient,
+    policy: &RetryPolicy,
+) -> Result<(), P4Error> {
+    let mut write_request = WriteRequest::new();
+    write_request.set_device_id(device_id);
+    write_request.set_role_id(role_id);
+    write_request.set_updates(RepeatedField::from_vec(updates));
+
+    retry_unary(policy, write_is_retriable, |opt| client.write_opt(&write_request, opt))
+        .await
+        .map(|_w| ())
+        .map_err(|e| P4Error{message: format!("{}, {}, {}: failed to write request ({})", target, device_id, role_id, e)})
+}
+
+/// Combines [`write_retry`]'s retrying on transient failures with [`write_detailed`]'s structured
+/// per-update error decoding, so a caller doesn't have to choose between resilience to a dropped
+/// channel and actionable diagnostics on a rejected batch.
+pub async fn write_detailed_retry(
+    updates: Vec<proto::p4runtime::Update>,
+    device_id: u64,
+    role_id: u64,
+    target: &str,
+    client: &P4RuntimeClient,
+    policy: &RetryPolicy,
+) -> Result<(), Vec<error::WriteError>> {
+    let mut write_request = WriteRequest::new();
+    write_request.set_device_id(device_id);
+    write_request.set_role_id(role_id);
+    write_request.set_updates(RepeatedField::from_vec(updates));
+
+    match retry_unary(policy, write_is_retriable, |opt| client.write_opt(&write_request, opt)).await {
+        Ok(_w) => Ok(()),
+        Err(grpcio::Error::RpcFailure(status)) => {
+            let decoded = status.details.as_deref()
+                .and_then(|details| error::decode_write_errors(details).ok())
+                .filter(|errors| !errors.is_empty());
+            match decoded {
+                Some(errors) => Err(errors),
+                None => Err(vec![error::WriteError{
+                    index: 0,
+                    canonical_code: status.status as i32,
+                    message: format!("{}, {}, {}: failed to write request ({})", target, device_id, role_id, status),
+                }]),
+            }
+        },
+        Err(e) => Err(vec![error::WriteError{
+            index: 0,
+            canonical_code: -1,
+            message: format!("{}, {}, {}: failed to write request ({})", target, device_id, role_id, e),
+        }]),
     }
 }
 
 /// Retrieve one or more P4 entities.
 ///
 /// Calls the [`Read RPC`](https://p4.org/p4-spec/p4runtime/main/P4Runtime-Spec.html#sec-read-rpc).
+/// The Read RPC is server-streaming: a wildcard query matching many entries may split its response
+/// across several `ReadResponse` messages, so this accumulates every chunk the stream yields
+/// before returning, rather than just the first.
 ///
 /// # Arguments
 /// * `entities` - a list of P4 entities, each acting as a query filter.
@@ -1676,19 +3225,106 @@ pub async fn read(
     device_id: u64,
     client: &P4RuntimeClient,
 ) -> Result<Vec<proto::p4runtime::Entity>, P4Error> {
+    let mut stream = read_stream(entities, device_id, client)?;
+
+    let mut result = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        result.extend(chunk?);
+    }
+    Ok(result)
+}
+
+/// Like [`read`], but returns the response stream itself instead of buffering every chunk into one
+/// `Vec`, so a caller reading a table too large to hold in memory at once can process each
+/// `ReadResponse`'s entities as it arrives.
+///
+/// # Arguments
+/// * `entities` - a list of P4 entities, each acting as a query filter.
+/// * `device_id` - uniquely identifies the target P4 device.
+/// * `client` - P4 Runtime client.
+pub fn read_stream(
+    entities: Vec<proto::p4runtime::Entity>,
+    device_id: u64,
+    client: &P4RuntimeClient,
+) -> Result<impl Stream<Item = Result<Vec<proto::p4runtime::Entity>, P4Error>>, P4Error> {
     let mut read_request = ReadRequest::new();
     read_request.set_device_id(device_id);
     read_request.set_entities(RepeatedField::from_vec(entities));
 
-    let mut stream = match client.read(&read_request) {
-        Ok(r) => r.enumerate(),
-        Err(e) => return Err(P4Error {message: format!("{}: failed to read request({})", device_id, e)}),
-    };
+    let stream = client.read(&read_request)
+        .map_err(|e| P4Error {message: format!("{}: failed to read request({})", device_id, e)})?;
 
-    let (_, response) = stream.next().await.unwrap();
-    match response {
+    Ok(stream.map(move |response| match response {
         Ok(r) => Ok(r.get_entities().to_vec()),
         Err(e) => Err(P4Error{ message: format!("{}: received invalid response({})", device_id, e)}),
+    }))
+}
+
+/// Owns a [`P4RuntimeClient`] together with the `device_id`, `role_id`, and `target` every RPC
+/// against it needs, and a [`RetryPolicy`] governing all of them, so a caller managing several
+/// switches doesn't have to thread those arguments through every call or pick a retry policy per
+/// call. `set_pipeline_config`, `get_pipeline_config`, `reconcile_pipeline_config`, and `write`
+/// retry transient failures (`UNAVAILABLE`, `DEADLINE_EXCEEDED` where safe) with exponential
+/// backoff per [`RetryPolicy`]; `read` makes a single attempt and does not retry. Failures come
+/// back as error values rather than panics, except that `get_pipeline_config` and
+/// `reconcile_pipeline_config` read the installed config through [`get_pipeline_config_retry`],
+/// which panics once its retries are exhausted or if the device returns no P4Info.
+pub struct Controller {
+    client: P4RuntimeClient,
+    device_id: u64,
+    role_id: u64,
+    target: String,
+    policy: RetryPolicy,
+}
+
+impl Controller {
+    /// Creates a `Controller` for `device_id` on `target`, issuing RPCs as `role_id` and retrying
+    /// transient failures per `policy`.
+    pub fn new(client: P4RuntimeClient, device_id: u64, role_id: u64, target: String, policy: RetryPolicy) -> Self {
+        Controller { client, device_id, role_id, target, policy }
+    }
+
+    /// Like [`set_pipeline_config`], but returns a [`P4Error`] instead of panicking on a
+    /// request-building or RPC failure.
+    pub async fn set_pipeline_config(
+        &self,
+        p4info_str: &str,
+        json_str: &str,
+        cookie_str: &str,
+        action_str: &str,
+    ) -> Result<(), P4Error> {
+        try_set_pipeline_config_retry(
+            p4info_str, json_str, cookie_str, action_str,
+            self.device_id, self.role_id, &self.target, &self.client, &self.policy,
+        ).await
+    }
+
+    /// Like [`get_pipeline_config_retry`], scoped to this controller's device and target.
+    pub async fn get_pipeline_config(&self) -> ForwardingPipelineConfig {
+        get_pipeline_config_retry(self.device_id, &self.target, &self.client, &self.policy).await
+    }
+
+    /// Like [`reconcile_pipeline_config`], scoped to this controller's device, role, and target.
+    pub async fn reconcile_pipeline_config(
+        &self,
+        p4info_str: &str,
+        json_str: &str,
+        cookie_str: &str,
+    ) -> Result<PipelineReconcileOutcome, P4Error> {
+        reconcile_pipeline_config(
+            p4info_str, json_str, cookie_str,
+            self.device_id, self.role_id, &self.target, &self.client, &self.policy,
+        ).await
+    }
+
+    /// Like [`write_detailed_retry`], scoped to this controller's device, role, and target.
+    pub async fn write(&self, updates: Vec<proto::p4runtime::Update>) -> Result<(), Vec<error::WriteError>> {
+        write_detailed_retry(updates, self.device_id, self.role_id, &self.target, &self.client, &self.policy).await
+    }
+
+    /// Like [`read`], scoped to this controller's device.
+    pub async fn read(&self, entities: Vec<proto::p4runtime::Entity>) -> Result<Vec<proto::p4runtime::Entity>, P4Error> {
+        read(entities, self.device_id, &self.client).await
     }
 }
 
@@ -0,0 +1,83 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Desired-state reconciliation for table entries.
+//!
+//! Without this, a caller must hand-build every [`Update`] via
+//! [`crate::build_table_entry_update`] and decide INSERT vs. MODIFY vs. DELETE itself.
+//! [`reconcile`] instead takes the set of entries a [`Table`] should contain and the entries
+//! [`crate::read`] currently reports, and computes the minimal batch of `Update`s that converges
+//! the switch to the desired state, ready to hand to [`crate::write`].
+
+use std::collections::HashMap;
+
+use proto::p4runtime::{Entity, Update, Update_Type};
+
+use crate::{Table, TableEntry, TableKey};
+
+/// Returns `key` with `priority` zeroed out when `table` doesn't use priority, so two entries that
+/// only ever differ by an ignored priority field are treated as the same entry.
+fn identity(table: &Table, key: &TableKey) -> TableKey {
+    let mut key = key.clone();
+    if !table.has_priority() {
+        key.priority = 0;
+    }
+    key
+}
+
+/// Computes the [`Update`]s that converge `table`'s entries on the switch, currently `current` (as
+/// obtained from [`crate::read`]), to `desired`.
+///
+/// Entries present only in `desired` become `INSERT`s, entries present in both whose action or
+/// parameters differ become `MODIFY`s, and entries present only in `current` become `DELETE`s.
+/// Identity is keyed on [`TableKey`], normalized via [`Table::has_priority`] so priority only
+/// participates in identity for the ternary/LPM/range tables that use it.
+pub fn reconcile(table: &Table, desired: &[TableEntry], current: &[TableEntry]) -> Vec<Update> {
+    let mut current_by_key: HashMap<TableKey, &TableEntry> = current.iter()
+        .map(|te| (identity(table, &te.key), te))
+        .collect();
+
+    let mut updates = Vec::new();
+    for desired_entry in desired {
+        match current_by_key.remove(&identity(table, &desired_entry.key)) {
+            None => updates.push(build_update(Update_Type::INSERT, desired_entry)),
+            Some(current_entry) if current_entry.value != desired_entry.value =>
+                updates.push(build_update(Update_Type::MODIFY, desired_entry)),
+            Some(_) => (),
+        }
+    }
+
+    // Whatever's left in `current_by_key` matched nothing in `desired` and should be removed.
+    for stale_entry in current_by_key.values() {
+        updates.push(build_update(Update_Type::DELETE, stale_entry));
+    }
+
+    updates
+}
+
+fn build_update(update_type: Update_Type, entry: &TableEntry) -> Update {
+    let mut entity = Entity::new();
+    entity.set_table_entry(entry.into());
+
+    let mut update = Update::new();
+    update.set_field_type(update_type);
+    update.set_entity(entity);
+    update
+}
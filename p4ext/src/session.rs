@@ -0,0 +1,331 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! A high-level session manager over the P4Runtime `StreamChannel` duplex.
+//!
+//! `P4RuntimeClient::stream_channel` only hands back the raw sink/receiver pair, leaving every
+//! caller to re-implement master arbitration, stream reconnection, and demultiplexing incoming
+//! `PacketIn`/`DigestList`/`IdleTimeoutNotification` messages -- the same problem `SwitchClient`
+//! solves internally in `nerpa_controller`. [`PacketIoSession`] generalizes that so other
+//! P4Runtime clients can reuse it without their own copy of the arbitration bookkeeping.
+
+use futures::{SinkExt, StreamExt};
+
+use grpcio::{ClientDuplexReceiver, StreamingCallSink, WriteFlags};
+
+use proto::p4runtime::{
+    DigestList,
+    IdleTimeoutNotification,
+    MasterArbitrationUpdate,
+    PacketIn,
+    PacketOut,
+    StreamMessageRequest,
+    StreamMessageResponse,
+    StreamMessageResponse_oneof_update,
+    Uint128,
+};
+
+use proto::p4runtime_grpc::P4RuntimeClient;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, watch, Mutex, Notify};
+
+use tracing::{debug, error, warn};
+
+use crate::P4Error;
+
+/// Whether a [`PacketIoSession`] currently holds primary for the device it's arbitrating on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// This session is bidding the highest election id the switch has seen, so it may issue
+    /// writes and packet-outs.
+    Primary,
+    /// Another controller -- or no controller yet -- holds primary; this session must not write
+    /// to the switch.
+    Backup,
+}
+
+/// Converts a P4Runtime `Uint128` election id into a plain `u128` for comparison.
+fn uint128_to_u128(id: &Uint128) -> u128 {
+    ((id.get_high() as u128) << 64) | (id.get_low() as u128)
+}
+
+/// Converts a plain `u128` election id back into the `Uint128` a `MasterArbitrationUpdate` expects.
+fn u128_to_uint128(id: u128) -> Uint128 {
+    let mut uint128 = Uint128::new();
+    uint128.set_high((id >> 64) as u64);
+    uint128.set_low(id as u64);
+    uint128
+}
+
+/// Sends `req` on `sink`, retrying with exponential backoff if the send fails, e.g. because the
+/// stream is still being established.
+async fn send_with_retry(sink: &mut StreamingCallSink<StreamMessageRequest>, req: StreamMessageRequest) {
+    let mut wait = Duration::from_secs(1);
+    loop {
+        match sink.send((req.clone(), WriteFlags::default())).await {
+            Ok(_) => break,
+            Err(e) => {
+                warn!("failed to send stream channel request: {:#?}", e);
+                tokio::time::sleep(wait).await;
+                wait *= 2;
+            },
+        }
+    }
+}
+
+/// Opens the `StreamChannel` duplex, retrying until it succeeds, then bids `election_id` for
+/// `device_id` on it.
+async fn open_and_arbitrate(
+    client: &P4RuntimeClient,
+    device_id: u64,
+    election_id: u128,
+) -> (StreamingCallSink<StreamMessageRequest>, ClientDuplexReceiver<StreamMessageResponse>) {
+    let (mut sink, receiver) = loop {
+        match client.stream_channel() {
+            Ok(result) => break result,
+            Err(e) => {
+                error!("failed to open stream channel: {:#?}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            },
+        }
+    };
+
+    let mut update = MasterArbitrationUpdate::new();
+    update.set_device_id(device_id);
+    update.set_election_id(u128_to_uint128(election_id));
+    let mut req = StreamMessageRequest::new();
+    req.set_arbitration(update);
+    send_with_retry(&mut sink, req).await;
+
+    (sink, receiver)
+}
+
+/// A high-level session over the P4Runtime `StreamChannel` duplex: arbitrates mastership for one
+/// device, keeps the stream alive across disconnects, demultiplexes incoming
+/// `StreamMessageResponse`s into typed channels a caller can `recv_*` from, and offers
+/// `send_packet_out` on the outbound side.
+pub struct PacketIoSession {
+    sink: Arc<Mutex<StreamingCallSink<StreamMessageRequest>>>,
+    device_id: u64,
+    election_id: Arc<Mutex<u128>>,
+    role_tx: watch::Sender<Role>,
+    packet_in_rx: Mutex<mpsc::UnboundedReceiver<PacketIn>>,
+    digest_rx: Mutex<mpsc::UnboundedReceiver<DigestList>>,
+    idle_timeout_rx: Mutex<mpsc::UnboundedReceiver<IdleTimeoutNotification>>,
+    shutdown: Arc<Notify>,
+}
+
+impl PacketIoSession {
+    /// Opens a `StreamChannel` session with `client`, bidding `initial_election_id` for
+    /// `device_id`, and spawns the background task that keeps the stream alive and demultiplexes
+    /// responses for the life of the returned `PacketIoSession`.
+    ///
+    /// # Arguments
+    /// * `client` - P4 Runtime client.
+    /// * `device_id` - ID of the P4 device to arbitrate mastership for.
+    /// * `initial_election_id` - election id to bid on open; `promote` bids higher later.
+    /// * `target` - hardware/software entity hosting P4 Runtime, used for logging.
+    pub async fn new(
+        client: P4RuntimeClient,
+        device_id: u64,
+        initial_election_id: u128,
+        target: String,
+    ) -> Self {
+        let (sink, receiver) = open_and_arbitrate(&client, device_id, initial_election_id).await;
+
+        let (role_tx, _) = watch::channel(Role::Backup);
+        let (packet_in_tx, packet_in_rx) = mpsc::unbounded_channel();
+        let (digest_tx, digest_rx) = mpsc::unbounded_channel();
+        let (idle_timeout_tx, idle_timeout_rx) = mpsc::unbounded_channel();
+
+        let sink = Arc::new(Mutex::new(sink));
+        let election_id = Arc::new(Mutex::new(initial_election_id));
+        let shutdown = Arc::new(Notify::new());
+
+        tokio::spawn(demux(
+            client,
+            receiver,
+            sink.clone(),
+            device_id,
+            election_id.clone(),
+            role_tx.clone(),
+            packet_in_tx,
+            digest_tx,
+            idle_timeout_tx,
+            shutdown.clone(),
+            target,
+        ));
+
+        PacketIoSession {
+            sink,
+            device_id,
+            election_id,
+            role_tx,
+            packet_in_rx: Mutex::new(packet_in_rx),
+            digest_rx: Mutex::new(digest_rx),
+            idle_timeout_rx: Mutex::new(idle_timeout_rx),
+            shutdown,
+        }
+    }
+
+    /// This session's current primary/backup role.
+    pub fn role(&self) -> Role {
+        *self.role_tx.borrow()
+    }
+
+    /// Returns a channel reporting this session's current role, and every later transition
+    /// between primary and backup, so a caller can gate `write()` calls on holding mastership
+    /// instead of polling `role()`.
+    pub fn watch_role(&self) -> watch::Receiver<Role> {
+        self.role_tx.subscribe()
+    }
+
+    /// Bids this session's own election id plus one, to request promotion to primary. It does not
+    /// look at the election id the switch last granted, so a single call stays below another
+    /// controller's id if that id is more than one higher. The switch's next arbitration response, observed by the background
+    /// demultiplexing task, is what actually grants it; `watch_role` reports that transition.
+    pub async fn promote(&self) {
+        let mut election_id = self.election_id.lock().await;
+        *election_id += 1;
+
+        let mut update = MasterArbitrationUpdate::new();
+        update.set_device_id(self.device_id);
+        update.set_election_id(u128_to_uint128(*election_id));
+        let mut req = StreamMessageRequest::new();
+        req.set_arbitration(update);
+
+        send_with_retry(&mut *self.sink.lock().await, req).await;
+    }
+
+    /// Sends a `PacketOut` on the stream channel.
+    pub async fn send_packet_out(&self, packet: PacketOut) -> Result<(), P4Error> {
+        let mut req = StreamMessageRequest::new();
+        req.set_packet(packet);
+        self.sink.lock().await.send((req, WriteFlags::default())).await
+            .map_err(|e| P4Error{message: format!("failed to send packet-out: {}", e)})
+    }
+
+    /// Waits for the next `PacketIn` the switch sends.
+    pub async fn recv_packet_in(&self) -> Option<PacketIn> {
+        self.packet_in_rx.lock().await.recv().await
+    }
+
+    /// Waits for the next `DigestList` the switch sends.
+    pub async fn recv_digest(&self) -> Option<DigestList> {
+        self.digest_rx.lock().await.recv().await
+    }
+
+    /// Waits for the next `IdleTimeoutNotification` the switch sends.
+    pub async fn recv_idle_timeout(&self) -> Option<IdleTimeoutNotification> {
+        self.idle_timeout_rx.lock().await.recv().await
+    }
+}
+
+impl Drop for PacketIoSession {
+    /// Signals the background `demux` task to stop, so it doesn't keep the `StreamChannel` and
+    /// `P4RuntimeClient` alive -- and keep reconnecting on failure -- after the last handle to this
+    /// session is gone.
+    fn drop(&mut self) {
+        self.shutdown.notify_one();
+    }
+}
+
+/// Reads `StreamMessageResponse`s from the stream channel, demultiplexing arbitration updates,
+/// `PacketIn`s, `DigestList`s, and `IdleTimeoutNotification`s into their respective channels.
+///
+/// If the stream ends or errors -- the switch restarted, dropped the connection, etc. -- tears
+/// down cleanly and re-establishes it, rebidding this session's current election id the same way
+/// `PacketIoSession::new` did initially. The freshly reconnected sink replaces the one
+/// `send_packet_out`/`promote` use. Returns once `shutdown` is notified, e.g. because the owning
+/// `PacketIoSession` was dropped.
+async fn demux(
+    client: P4RuntimeClient,
+    mut receiver: ClientDuplexReceiver<StreamMessageResponse>,
+    sink: Arc<Mutex<StreamingCallSink<StreamMessageRequest>>>,
+    device_id: u64,
+    election_id: Arc<Mutex<u128>>,
+    role_tx: watch::Sender<Role>,
+    packet_in_tx: mpsc::UnboundedSender<PacketIn>,
+    digest_tx: mpsc::UnboundedSender<DigestList>,
+    idle_timeout_tx: mpsc::UnboundedSender<IdleTimeoutNotification>,
+    shutdown: Arc<Notify>,
+    target: String,
+) {
+    loop {
+        loop {
+            let result = tokio::select! {
+                result = receiver.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = shutdown.notified() => {
+                    debug!("{}: stream channel session shut down", target);
+                    return;
+                },
+            };
+            match result {
+                Ok(response) => match response.update {
+                    None => debug!("{}: received empty response from stream channel", target),
+                    Some(update) => {
+                        use StreamMessageResponse_oneof_update::*;
+                        match update {
+                            arbitration(update) => {
+                                let elected = uint128_to_u128(update.get_election_id());
+                                let ours = *election_id.lock().await;
+                                let role = if elected == ours { Role::Primary } else { Role::Backup };
+                                role_tx.send_if_modified(|current| {
+                                    if *current == role {
+                                        false
+                                    } else {
+                                        *current = role;
+                                        true
+                                    }
+                                });
+                            },
+                            packet(p) => if packet_in_tx.send(p).is_err() {
+                                debug!("{}: dropped a PacketIn; no receiver listening", target);
+                            },
+                            digest(d) => if digest_tx.send(d).is_err() {
+                                debug!("{}: dropped a DigestList; no receiver listening", target);
+                            },
+                            idle_timeout_notification(n) => if idle_timeout_tx.send(n).is_err() {
+                                debug!("{}: dropped an IdleTimeoutNotification; no receiver listening", target);
+                            },
+                            other => debug!("{}: received unhandled stream channel message: {:#?}", target, other),
+                        }
+                    },
+                },
+                Err(e) => {
+                    error!("{}: error on stream channel: {:#?}", target, e);
+                    break;
+                },
+            }
+        }
+
+        warn!("{}: lost stream channel to switch; reconnecting", target);
+        let election = *election_id.lock().await;
+        let (new_sink, new_receiver) = open_and_arbitrate(&client, device_id, election).await;
+        receiver = new_receiver;
+        *sink.lock().await = new_sink;
+    }
+}
@@ -86,6 +86,36 @@ async fn write_read() {
     assert_eq!(read_result.unwrap().to_vec(), write_entities);
 }
 
+#[test]
+fn clear_low_bits_zeroes_in_place_across_a_byte_boundary() {
+    // 0x1234 has bits set in both bytes; clearing the low 12 bits should leave only the
+    // high-order nibble of the first byte (0x1000), not shift the surviving bits down into a
+    // shorter value the way truncating the low bytes instead of zeroing them would.
+    let value: p4ext::FieldValue = 0x1234u128.into();
+    let cleared = value.clear_low_bits(12);
+    let expected: p4ext::FieldValue = 0x1000u128.into();
+    assert_eq!(cleared, expected);
+}
+
+#[test]
+fn lpm_clears_the_value_in_place_rather_than_shortening_it() {
+    let match_field = p4ext::MatchField {
+        preamble: p4ext::Preamble { name: "ipv4_dst".into(), ..Default::default() },
+        bit_width: 32,
+        match_type: p4ext::MatchType::LPM,
+    };
+    let value: p4ext::FieldValue = 0xc0a80105u128.into(); // 192.168.1.5
+    let encoded = p4ext::codec::lpm(&match_field, value, 24).unwrap();
+    match encoded {
+        p4ext::FieldMatchType::LPM { value, plen } => {
+            assert_eq!(plen, 24);
+            let expected: p4ext::FieldValue = 0xc0a80100u128.into(); // 192.168.1.0/24
+            assert_eq!(value, expected);
+        },
+        other => panic!("expected an LPM match, got {:?}", other),
+    }
+}
+
 #[tokio::test]
 async fn stream_channel() {
     let setup = p4ext::TestSetup::new();
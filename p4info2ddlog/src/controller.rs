@@ -21,14 +21,14 @@ SOFTWARE.
 use anyhow::{Context, Result};
 use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::fmt::Write;
 use std::fs::{
     File,
-    metadata
+    metadata,
+    read_to_string,
 };
-use std::io::{BufRead, BufReader};
 use std::io::Write as IoWrite;
 use std::path::Path;
+use toml_edit::{Document, Item, InlineTable, Table, Value};
 
 const TOML_FN: &str = "../nerpa_controller/Cargo.toml";
 
@@ -53,78 +53,90 @@ pub fn write_toml(
         prog_name,
     ].iter().cloned().collect();
 
-    let mut toml_out = match Path::new(TOML_FN).exists() {
-        true => edit_toml(reserved_keys)?,
-        false => create_toml(),
+    let mut toml_doc = match Path::new(TOML_FN).exists() {
+        true => edit_toml(&reserved_keys)?,
+        false => create_toml()?,
     };
 
+    let deps = toml_doc["dependencies"]
+        .or_insert(Item::Table(Table::new()));
+
     // Write the dependencies that vary based on the user input.
-    writeln!(toml_out, "differential_datalog = {{path = \"{}/{}_ddlog/differential_datalog\"}}", io_dir, prog_name)?;
-    writeln!(toml_out, "{} = {{path = \"{}/{}_ddlog\"}}", prog_name, io_dir, prog_name)?;
-    writeln!(toml_out, "types = {{path = \"{}/{}_ddlog/types\"}}", io_dir, prog_name)?;
-    writeln!(toml_out, "types__{}_dp = {{path = \"{}/{}_ddlog/types/{}_dp\"}}", prog_name, io_dir, prog_name, prog_name)?;
+    deps["differential_datalog"] = path_dep(format!("{}/{}_ddlog/differential_datalog", io_dir, prog_name));
+    deps[prog_name] = path_dep(format!("{}/{}_ddlog", io_dir, prog_name));
+    deps["types"] = path_dep(format!("{}/{}_ddlog/types", io_dir, prog_name));
+    deps[types_dp_name.as_str()] = path_dep(format!("{}/{}_ddlog/types/{}_dp", io_dir, prog_name, prog_name));
 
-    if !dp_path_opt.is_none() {
-        writeln!(toml_out, "dp2ddlog = {{path = \"{}\"}}", dp_path_opt.unwrap())?;
+    if let Some(dp_path) = dp_path_opt {
+        deps["dp2ddlog"] = path_dep(dp_path.to_string());
     }
 
     // If the program directory contains an OVS schemafile, we add the ovsdb client dependency.
     let ovs_schema_fn = format!("{}/{}.ovsschema", io_dir, prog_name);
     if metadata(ovs_schema_fn.as_str()).is_ok() {
-        writeln!(toml_out, "ovsdb_client = {{path = \"../ovsdb_client\"}}")?;
+        deps["ovsdb_client"] = path_dep("../ovsdb_client".to_string());
     }
 
     let toml_fn_os = OsStr::new(&TOML_FN);
     File::create(toml_fn_os)
         .with_context(|| format!("{}: create failed", TOML_FN))?
-        .write_all(toml_out.as_bytes())
+        .write_all(toml_doc.to_string().as_bytes())
         .with_context(|| format!("{}: write failed", TOML_FN))?;
 
     Ok(())
 }
 
-fn edit_toml(
-    reserved_keys: HashSet<&str>,
-) -> Result<String> {
-    let toml_fn = "../nerpa_controller/Cargo.toml";
-    let file = File::open(toml_fn)?;
-    let reader = BufReader::new(file);
-
-    let mut toml_out = String::new();
-
-    for line_res in reader.lines() {
-        let line = line_res?;
-
-        // Check the first token.
-        let token_opt = line.split_whitespace().next();
-
-        // Preserve whitespace.
-        if token_opt.is_none() {
-            writeln!(toml_out)?;
-            continue;
-        }
+/// Build a `{path = "..."}` inline-table dependency entry.
+fn path_dep(path: String) -> Item {
+    let mut table = InlineTable::default();
+    table.insert("path", Value::from(path));
+    Item::Value(Value::InlineTable(table))
+}
 
-        // Skip the lines with reserved inputs.
-        if reserved_keys.contains(token_opt.unwrap()) {
-            continue;
-        }
+/// Returns the `path` a dependency entry points at, whether it's written as an
+/// inline table (`foo = {path = "..."}`) or a dotted sub-table (`[dependencies.foo]`).
+fn dep_path(item: &Item) -> Option<&str> {
+    match item {
+        Item::Value(Value::InlineTable(table)) => table.get("path").and_then(Value::as_str),
+        Item::Table(table) => table.get("path").and_then(Item::as_str),
+        _ => None,
+    }
+}
 
-        // Exclude any dependences that include `nerpa_controlplane`.
-        // Since Nerpa programs are written in this subdirectory, that should remove
-        // any additional dependencies associated with old programs.
-        if line.contains("nerpa_controlplane") {
-            continue;
+fn edit_toml(
+    reserved_keys: &HashSet<&str>,
+) -> Result<Document> {
+    let contents = read_to_string(TOML_FN)
+        .with_context(|| format!("{}: read failed", TOML_FN))?;
+    let mut toml_doc = contents.parse::<Document>()
+        .with_context(|| format!("{}: parse failed", TOML_FN))?;
+
+    if let Some(deps) = toml_doc["dependencies"].as_table_mut() {
+        // Drop the keys that we're about to regenerate below, plus any
+        // leftover dependency on a program under `nerpa_controlplane`,
+        // since Nerpa programs are written in that subdirectory and only
+        // one program's dependencies should be present at a time.
+        let stale_keys: Vec<String> = deps
+            .iter()
+            .filter(|(key, item)| {
+                reserved_keys.contains(key)
+                    || dep_path(item)
+                        .map(|path| path.contains("nerpa_controlplane"))
+                        .unwrap_or(false)
+            })
+            .map(|(key, _)| key.to_string())
+            .collect();
+
+        for key in stale_keys {
+            deps.remove(&key);
         }
-
-        // Print all other lines.
-        writeln!(toml_out, "{}", line)?;
     }
 
-    Ok(toml_out)
+    Ok(toml_doc)
 }
 
-fn create_toml() -> String {
-    format!(
+fn create_toml() -> Result<Document> {
+    let toml_str =
 "[package]
 name = \"nerpa_controller\"
 version = \"0.1.0\"
@@ -148,12 +160,14 @@ futures = \"0.3.12\"
 grpcio = \"0.9.0\"
 itertools = \"0.10.0\"
 num-traits = \"0.2.14\"
-p4ext = {{path = \"../p4ext\"}}
-proto = {{path = \"../proto\"}}
+p4ext = {path = \"../p4ext\"}
+proto = {path = \"../proto\"}
 protobuf = \"2.22.0\"
 protobuf-codegen = \"2.22.0\"
-tokio = {{ version = \"1.2.0\", features = [\"full\"]}}
+tokio = { version = \"1.2.0\", features = [\"full\"]}
 tracing = \"0.1\"
-"
-    )
+";
+
+    toml_str.parse::<Document>()
+        .with_context(|| "failed to parse built-in Cargo.toml template")
 }
@@ -25,6 +25,20 @@ use proto::p4types::P4TypeInfo;
 
 use std::fmt::Write;
 
+/// Whether any member of any digest in `digests` has a bitwidth over 128, i.e. whether the
+/// generated `digest_to_ddlog` needs the `ddlog_std::Vec` import to build one as a byte vector.
+fn has_wide_field(digests: &[Digest], type_info: &P4TypeInfo) -> bool {
+    digests.iter().any(|d| {
+        let digest_name = d.get_preamble().get_name();
+        type_info.get_structs().get(digest_name).map_or(false, |s| {
+            s.get_members().iter().any(|m| {
+                let bs = m.get_type_spec();
+                bs.has_bitstring() && bs.get_bitstring().has_bit() && bs.get_bitstring().get_bit().get_bitwidth() > 128
+            })
+        })
+    })
+}
+
 pub fn write_rs(
     digests: &[Digest],
     type_info: &P4TypeInfo,
@@ -37,6 +51,9 @@ pub fn write_rs(
     writeln!(d2d_out, "use differential_datalog::ddval::{{DDValConvert, DDValue}};")?;
 
     writeln!(d2d_out, "use {}_ddlog::Relations;", prog_name)?;
+    if has_wide_field(digests, type_info) {
+        writeln!(d2d_out, "use {}_ddlog::typedefs::ddlog_std;", prog_name)?;
+    }
     writeln!(d2d_out)?;
     writeln!(d2d_out, "pub fn digest_to_ddlog(digest_id: u32, digest_data: &P4Data) -> Update<DDValue> {{")?;
 
@@ -67,17 +84,24 @@ pub fn write_rs(
             let field_value = {
                 let bitwidth = member_type_spec.get_bitstring().get_bit().get_bitwidth();
 
-                let num_bits = match bitwidth {
-                    1..=8 => 8,
-                    9..=16 => 16,
-                    17..=32 => 32,
-                    33..=64 => 64,
-                    65..=128 => 128,
-                    _ => panic!("unsupported bitwidth: {}", bitwidth),
-                };
-
-                // Get the bitstring, pad it with zeros, and convert it to the correct uint.
-                format!("NetworkEndian::read_u{}(&pad_left_zeros(members[{}].get_bitstring(), {}))", num_bits, mi, num_bits / 8)
+                if bitwidth > 128 {
+                    // Wider than any fixed-width uint DDlog generates a Rust type for: carry it
+                    // as an opaque, left-zero-padded byte vector instead.
+                    let byte_len = (bitwidth as usize + 7) / 8;
+                    format!("ddlog_std::Vec::from(pad_left_zeros(members[{}].get_bitstring(), {}))", mi, byte_len)
+                } else {
+                    let num_bits = match bitwidth {
+                        1..=8 => 8,
+                        9..=16 => 16,
+                        17..=32 => 32,
+                        33..=64 => 64,
+                        65..=128 => 128,
+                        _ => unreachable!(),
+                    };
+
+                    // Get the bitstring, pad it with zeros, and convert it to the correct uint.
+                    format!("NetworkEndian::read_u{}(&pad_left_zeros(members[{}].get_bitstring(), {}))", num_bits, mi, num_bits / 8)
+                }
             };
 
             writeln!(d2d_out, "          {}: {},", field_name, field_value)?;
@@ -48,6 +48,8 @@ path = \"src/lib.rs\"
 
 [dependencies]
 byteorder = \"1.4.3\"
+lazy_static = \"1.4\"
+protobuf = \"2.22.0\"
 differential_datalog = {{path = \"{}/differential_datalog\"}}
 {} = {{path = \"{}\"}}
 proto = {{path = \"../proto\"}}
@@ -66,11 +68,19 @@ types__{}_dp = {{path = \"{}/types/{}_dp\"}}
 
 /// Writes the dp2ddlog Rust program.
 /// Using P4Info, generates code to convert digests and packet metadata to input relations.
+///
+/// # Arguments
+/// * `p4info_hash` - hash of the serialized P4Info this crate is generated from, embedded as
+///   `P4INFO_HASH` so [`check_p4info_hash`]-equivalent callers can detect a stale regeneration.
+/// * `schema_version` - the P4Info package version (`PkgInfo.version`), embedded alongside the
+///   hash for a more readable mismatch message; may be empty if the P4 program didn't set one.
 pub fn write_rs(
     digests: &[Digest],
     type_info: &P4TypeInfo,
     controller_metadata: &[ControllerPacketMetadata],
-    prog_name: &str
+    prog_name: &str,
+    p4info_hash: u64,
+    schema_version: &str,
 ) -> Result<String> {
     let mut d2d_out = String::new();
     writeln!(d2d_out, "use proto::p4data::P4Data;")?;
@@ -78,6 +88,7 @@ pub fn write_rs(
     writeln!(d2d_out, "use differential_datalog::program::{{RelId, Update}};")?;
     writeln!(d2d_out, "use differential_datalog::ddval::{{DDValConvert, DDValue}};")?;
     writeln!(d2d_out, "use proto::p4runtime::{{PacketIn, PacketMetadata, PacketOut}};")?;
+    writeln!(d2d_out, "use protobuf::RepeatedField;")?;
 
     writeln!(d2d_out, "use {}_ddlog::Relations;", prog_name)?;
     writeln!(d2d_out, "use {}_ddlog::typedefs::ddlog_std;", prog_name)?;
@@ -94,6 +105,10 @@ pub fn write_rs(
     let packetout_out = write_packet(controller_metadata, prog_name, false).unwrap();
     writeln!(d2d_out, "{}", packetout_out)?;
 
+    // unwrap is safe, because write_packet_out_builder cannot return an error result
+    let packetout_builder_out = write_packet_out_builder(controller_metadata, prog_name).unwrap();
+    writeln!(d2d_out, "{}", packetout_builder_out)?;
+
     let helpers = "
 fn pad_left_zeros(inp: &[u8], size: usize) -> Vec<u8> {
     if inp.len() > size {
@@ -110,6 +125,149 @@ fn pad_left_zeros(inp: &[u8], size: usize) -> Vec<u8> {
 }";
     writeln!(d2d_out, "{}", helpers)?;
 
+    let rate_limit = "
+/// Lock-free token-bucket rate limiting for the `*_to_ddlog` conversion functions, keyed by
+/// digest id or packet metadata header name, so a misbehaving or compromised switch flooding
+/// digests/packet-ins at line rate can have that load shed before the `Update` is constructed,
+/// instead of handing an unbounded stream to the DDlog engine. A key with no configured limiter
+/// is always allowed, so rate limiting is opt-in per key.
+mod rate_limit {
+    use lazy_static::lazy_static;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::RwLock;
+    use std::time::Instant;
+
+    /// Tokens are tracked scaled x1000, so a fractional per-nanosecond refill rate doesn't round
+    /// away to zero between events.
+    const TOKEN_SCALE: u64 = 1000;
+
+    struct Limiter {
+        rate_per_ns: f64,
+        burst_milli: u64,
+        epoch: Instant,
+        last_refill_ns: AtomicU64,
+        tokens_milli: AtomicU64,
+        drops: AtomicU64,
+    }
+
+    impl Limiter {
+        fn new(tokens_per_sec: f64, burst: f64) -> Self {
+            let burst_milli = (burst * TOKEN_SCALE as f64) as u64;
+            Limiter {
+                rate_per_ns: tokens_per_sec / 1_000_000_000.0,
+                burst_milli,
+                epoch: Instant::now(),
+                last_refill_ns: AtomicU64::new(0),
+                tokens_milli: AtomicU64::new(burst_milli),
+                drops: AtomicU64::new(0),
+            }
+        }
+
+        /// Takes one token if available, refilling first for the time elapsed since the last
+        /// call. Retries the token withdrawal on a lost compare-exchange race instead of taking a
+        /// lock; a losing racer's stale `last_refill_ns` only under-credits the next caller's
+        /// refill, never over-credits it, so it's safe to leave that CAS best-effort.
+        fn allow(&self) -> bool {
+            let now_ns = self.epoch.elapsed().as_nanos() as u64;
+            loop {
+                let last_refill_ns = self.last_refill_ns.load(Ordering::Acquire);
+                let tokens_milli = self.tokens_milli.load(Ordering::Acquire);
+
+                let elapsed_ns = now_ns.saturating_sub(last_refill_ns);
+                let added = (elapsed_ns as f64 * self.rate_per_ns * TOKEN_SCALE as f64) as u64;
+                let refilled = tokens_milli.saturating_add(added).min(self.burst_milli);
+
+                let (new_tokens, allowed) = if refilled >= TOKEN_SCALE {
+                    (refilled - TOKEN_SCALE, true)
+                } else {
+                    (refilled, false)
+                };
+
+                if self.tokens_milli.compare_exchange_weak(
+                    tokens_milli, new_tokens, Ordering::AcqRel, Ordering::Relaxed,
+                ).is_err() {
+                    continue;
+                }
+                let _ = self.last_refill_ns.compare_exchange_weak(
+                    last_refill_ns, now_ns, Ordering::AcqRel, Ordering::Relaxed,
+                );
+
+                if !allowed {
+                    self.drops.fetch_add(1, Ordering::Relaxed);
+                }
+                return allowed;
+            }
+        }
+
+        fn drop_count(&self) -> u64 {
+            self.drops.load(Ordering::Relaxed)
+        }
+    }
+
+    lazy_static! {
+        static ref LIMITERS: RwLock<HashMap<String, Limiter>> = RwLock::new(HashMap::new());
+    }
+
+    /// Configures (or replaces) the rate limit for `key` at `tokens_per_sec`, allowing bursts up
+    /// to `burst` tokens. `key` is a digest id (as a string) for `digest_to_ddlog`, or a packet
+    /// metadata header name (`\"packet_in\"`/`\"packet_out\"`) for `packet_in_to_ddlog`/
+    /// `packet_out_to_ddlog`.
+    pub fn configure(key: &str, tokens_per_sec: f64, burst: f64) {
+        LIMITERS.write().unwrap().insert(key.to_string(), Limiter::new(tokens_per_sec, burst));
+    }
+
+    /// Removes any rate limit configured for `key`, so it goes back to always being allowed.
+    pub fn clear(key: &str) {
+        LIMITERS.write().unwrap().remove(key);
+    }
+
+    /// Reports whether an event for `key` is allowed right now. A `key` with no limiter
+    /// configured via [`configure`] is always allowed.
+    pub fn check(key: &str) -> bool {
+        match LIMITERS.read().unwrap().get(key) {
+            Some(limiter) => limiter.allow(),
+            None => true,
+        }
+    }
+
+    /// Number of events denied for `key` so far, for observability; `0` if `key` was never
+    /// configured or has dropped nothing.
+    pub fn drop_count(key: &str) -> u64 {
+        LIMITERS.read().unwrap().get(key).map_or(0, |limiter| limiter.drop_count())
+    }
+}";
+    writeln!(d2d_out, "{}", rate_limit)?;
+
+    // Provenance: the P4Info this crate was generated from, so a controller can refuse to start
+    // against a switch whose P4Info has since moved on without these dependent crates catching up.
+    writeln!(d2d_out, "pub const P4INFO_HASH: u64 = {};", p4info_hash)?;
+    writeln!(d2d_out, "pub const P4INFO_SCHEMA_VERSION: &str = {:?};", schema_version)?;
+    let hash_check = "
+/// Hashes bytes the same way codegen hashed the P4Info this crate was generated from, so
+/// `check_p4info_hash` can tell whether a switch's current P4Info still matches.
+fn hash_p4info_bytes(bytes: &[u8]) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Compares a switch's current P4Info against the one this crate was generated from. Call this
+/// at controller startup, before trusting any digest or packet decoding this crate does, so a
+/// stale-codegen situation produces a clear error instead of a mysterious decode failure.
+pub fn check_p4info_hash(p4info_bytes: &[u8]) -> Result<(), String> {
+    let actual_hash = hash_p4info_bytes(p4info_bytes);
+    if actual_hash != P4INFO_HASH {
+        return Err(format!(
+            \"P4Info hash {:016x} doesn't match the one dp2ddlog was generated from ({:016x}, schema version {:?}); regenerate the control plane crates\",
+            actual_hash, P4INFO_HASH, P4INFO_SCHEMA_VERSION,
+        ));
+    }
+    Ok(())
+}";
+    writeln!(d2d_out, "{}", hash_check)?;
 
     Ok(d2d_out)
 }
@@ -121,13 +279,20 @@ fn write_digest(
 ) -> Result<String> {
     let mut d2d_out = String::new();
 
-    writeln!(d2d_out, "pub fn digest_to_ddlog(digest_id: u32, digest_data: &P4Data) -> Option<Update<DDValue>> {{")?;
+    writeln!(d2d_out, "pub fn digest_to_ddlog(digest_id: u32, digest_data: &P4Data, device_id: u64) -> Option<Update<DDValue>> {{")?;
+    // A controller driving a fleet of switches passes `device_id` so a digest struct with a
+    // "device"-named field can be tagged with the device it came from; a program with no such
+    // field never reads the parameter.
+    writeln!(d2d_out, "  let _ = device_id;")?;
     if digests.len() == 0 {
         writeln!(d2d_out, "  return None;")?;
         writeln!(d2d_out, "}}")?;
         return Ok(d2d_out);
     }
 
+    // Shed load from a flooding/misbehaving switch before building the `Update` at all.
+    writeln!(d2d_out, "  if !rate_limit::check(&digest_id.to_string()) {{ return None; }}")?;
+
     // P4 Runtime only allows a digest to be a struct with bitstring fields.
     writeln!(d2d_out, "  let members = digest_data.get_field_struct().get_members();")?;
     writeln!(d2d_out, "  match digest_id {{")?;
@@ -154,18 +319,32 @@ fn write_digest(
             let field_value = {
                 let bitwidth = member_type_spec.get_bitstring().get_bit().get_bitwidth();
 
-                let num_bits = match bitwidth {
-                    1..=16 => 16,
-                    17..=32 => 32,
-                    33..=64 => 64,
-                    65..=128 => 128,
-                    _ => panic!("unsupported bitwidth: {}", bitwidth),
-                };
-
-                let handle_u8 = if bitwidth <= 8 {" as u8" } else {""};
-
-                // Get the bitstring, pad it with zeros, and convert it to the correct uint.
-                format!("NetworkEndian::read_u{}(&pad_left_zeros(members[{}].get_bitstring(), {})){}", num_bits, mi, num_bits / 8, handle_u8)
+                if bitwidth > 128 {
+                    // Wider than any fixed-width uint DDlog generates a Rust type for: carry it
+                    // as an opaque, left-zero-padded byte vector instead.
+                    let byte_len = (bitwidth as usize + 7) / 8;
+                    format!("ddlog_std::Vec::from(pad_left_zeros(members[{}].get_bitstring(), {}))", mi, byte_len)
+                } else {
+                    let num_bits = match bitwidth {
+                        1..=16 => 16,
+                        17..=32 => 32,
+                        33..=64 => 64,
+                        65..=128 => 128,
+                        _ => unreachable!(),
+                    };
+
+                    let handle_u8 = if bitwidth <= 8 {" as u8" } else {""};
+
+                    if field_name.to_lowercase().contains("device") {
+                        // A field naming which device this digest is for is populated from the
+                        // controller's own bookkeeping, not parsed out of the digest's bitstring
+                        // -- the switch doesn't report its own device id in the digest payload.
+                        format!("device_id as u{}{}", num_bits, handle_u8)
+                    } else {
+                        // Get the bitstring, pad it with zeros, and convert it to the correct uint.
+                        format!("NetworkEndian::read_u{}(&pad_left_zeros(members[{}].get_bitstring(), {})){}", num_bits, mi, num_bits / 8, handle_u8)
+                    }
+                }
             };
 
             writeln!(d2d_out, "          {}: {},", field_name, field_value)?;
@@ -196,7 +375,14 @@ fn write_packet(
         false => ("packet_out", "PacketOut")
     };
 
-    writeln!(d2d_out, "pub fn {}_to_ddlog(p: {}) -> Option<Update<DDValue>> {{", filter, inp_type)?;
+    writeln!(d2d_out, "pub fn {}_to_ddlog(p: {}, device_id: u64) -> Option<Update<DDValue>> {{", filter, inp_type)?;
+    // A controller driving a fleet of switches passes `device_id` so a `packet_in`/`packet_out`
+    // struct with a "device"-named field can be tagged with the device it came from; a program
+    // with no such field never reads the parameter.
+    writeln!(d2d_out, "  let _ = device_id;")?;
+
+    // Shed load from a flooding/misbehaving switch before building the `Update` at all.
+    writeln!(d2d_out, "  if !rate_limit::check({:?}) {{ return None; }}", filter)?;
 
     // Filter the controller metadata array to the element with name `packet_in`.
     // p4c allows there to be only one header with this name/annotation.
@@ -224,21 +410,35 @@ fn write_packet(
         let id = pm.get_id();
         let field_value = {
             let bitwidth = pm.get_bitwidth();
-            let num_bits = match bitwidth {
-                1..=16 => 16,
-                17..=32 => 32,
-                33..=64 => 64,
-                65..=128 => 128,
-                _ => panic!("unsupported bitwidth: {}", bitwidth),
-            };
 
-            let handle_u8 = if bitwidth <= 8 {" as u8" } else {""};
+            if bitwidth > 128 {
+                // Wider than any fixed-width uint DDlog generates a Rust type for: carry it as
+                // an opaque, left-zero-padded byte vector instead.
+                let byte_len = (bitwidth as usize + 7) / 8;
+                let meta_value = format!("metadata.iter().filter(|m| m.get_metadata_id() == {}).cloned().collect::<Vec<PacketMetadata>>()[0].get_value()", id);
+                format!("ddlog_std::Vec::from(pad_left_zeros({}, {}))", meta_value, byte_len)
+            } else {
+                let num_bits = match bitwidth {
+                    1..=16 => 16,
+                    17..=32 => 32,
+                    33..=64 => 64,
+                    65..=128 => 128,
+                    _ => unreachable!(),
+                };
 
-            let meta_value = format!("metadata.iter().filter(|m| m.get_metadata_id() == {}).cloned().collect::<Vec<PacketMetadata>>()[0].get_value()", id);
+                let handle_u8 = if bitwidth <= 8 {" as u8" } else {""};
 
-            let field_value = format!("NetworkEndian::read_u{}(&pad_left_zeros({}, {})){}", num_bits, meta_value, num_bits / 8, handle_u8);
+                if field_name.to_lowercase().contains("device") {
+                    // A field naming which device this packet is for/from is populated from the
+                    // controller's own bookkeeping, not parsed out of the packet's metadata -- the
+                    // switch doesn't report its own device id as controller packet metadata.
+                    format!("device_id as u{}{}", num_bits, handle_u8)
+                } else {
+                    let meta_value = format!("metadata.iter().filter(|m| m.get_metadata_id() == {}).cloned().collect::<Vec<PacketMetadata>>()[0].get_value()", id);
 
-            field_value
+                    format!("NetworkEndian::read_u{}(&pad_left_zeros({}, {})){}", num_bits, meta_value, num_bits / 8, handle_u8)
+                }
+            }
         };
 
         writeln!(d2d_out, "      {}: {},", field_name, field_value)?;
@@ -248,5 +448,79 @@ fn write_packet(
     writeln!(d2d_out, "  }})")?; // close brace for the update
     writeln!(d2d_out, "}}")?; // close brace for `fn`
 
+    return Ok(d2d_out);
+}
+
+/// The Rust integer type DDlog stores a `bit<bitwidth>` member as, as its size in bytes --
+/// matches the `num_bits`/`handle_u8` choices [`write_packet`] makes when decoding the same
+/// field off the wire. Only meaningful for `bitwidth <= 128`; wider fields are a byte vector
+/// instead (see the `bitwidth > 128` branch in both [`write_digest`] and [`write_packet`]).
+fn rust_uint_bytes(bitwidth: u32) -> usize {
+    match bitwidth {
+        1..=8 => 1,
+        9..=16 => 2,
+        17..=32 => 4,
+        33..=64 => 8,
+        65..=128 => 16,
+        _ => unreachable!(),
+    }
+}
+
+/// Writes `ddlog_to_packet_out`, the inverse of `packet_out_to_ddlog`: assembles a wire-format
+/// `PacketOut` from a DDlog `types__{prog}_dp::PacketOut` row, instead of only decoding one
+/// received off the wire. For each `packet_out` controller metadata field, this reads the
+/// matching struct member, encodes it big-endian, and truncates it down to `ceil(bitwidth/8)`
+/// bytes -- the inverse of [`write_packet`]'s `pad_left_zeros` -- so the emitted
+/// `PacketMetadata.value` is minimally sized the way p4c expects. A wide (`bitwidth > 128`)
+/// field is already stored as exactly that many bytes, so it's copied through unchanged.
+fn write_packet_out_builder(
+    controller_metadata: &[ControllerPacketMetadata],
+    prog_name: &str,
+) -> Result<String> {
+    let mut d2d_out = String::new();
+
+    writeln!(d2d_out, "pub fn ddlog_to_packet_out(v: &DDValue) -> PacketOut {{")?;
+
+    // Same lookup `write_packet` uses to find the single `packet_out` header, if any.
+    let packet_metadata_vec: Vec<ControllerPacketMetadata> = controller_metadata
+        .to_vec()
+        .into_iter()
+        .filter(|m| m.get_preamble().get_name() == "packet_out")
+        .collect();
+    if packet_metadata_vec.len() != 1 {
+        writeln!(d2d_out, "  panic!(\"no packet_out controller metadata declared; cannot assemble a PacketOut\")")?;
+        writeln!(d2d_out, "}}")?;
+        return Ok(d2d_out);
+    }
+    let packet_metadata = &packet_metadata_vec[0];
+
+    writeln!(d2d_out, "  let p = types__{}_dp::PacketOut::from_ddvalue_ref(v);", prog_name)?;
+    writeln!(d2d_out, "  let mut metadata = Vec::new();")?;
+    for pm in packet_metadata.get_metadata().iter() {
+        let field_name = pm.get_name();
+        let id = pm.get_id();
+        let bitwidth = pm.get_bitwidth();
+        let byte_len = (bitwidth as usize + 7) / 8;
+
+        let value_expr = if bitwidth > 128 {
+            format!("p.{}.to_vec()", field_name)
+        } else {
+            let num_bytes = rust_uint_bytes(bitwidth);
+            format!("p.{}.to_be_bytes()[{}..].to_vec()", field_name, num_bytes - byte_len)
+        };
+
+        writeln!(d2d_out, "  {{")?;
+        writeln!(d2d_out, "    let mut m = PacketMetadata::new();")?;
+        writeln!(d2d_out, "    m.set_metadata_id({});", id)?;
+        writeln!(d2d_out, "    m.set_value({});", value_expr)?;
+        writeln!(d2d_out, "    metadata.push(m);")?;
+        writeln!(d2d_out, "  }}")?;
+    }
+    writeln!(d2d_out, "  let mut packet_out = PacketOut::new();")?;
+    writeln!(d2d_out, "  packet_out.set_metadata(RepeatedField::from_vec(metadata));")?;
+    writeln!(d2d_out, "  packet_out.set_payload(p.packet.to_vec());")?;
+    writeln!(d2d_out, "  packet_out")?;
+    writeln!(d2d_out, "}}")?; // close brace for `fn`
+
     return Ok(d2d_out);
 }
\ No newline at end of file
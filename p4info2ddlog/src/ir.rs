@@ -0,0 +1,536 @@
+/*
+Copyright (c) 2021 VMware, Inc.
+SPDX-License-Identifier: MIT
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Typed intermediate representation for the table/field/action analysis that
+//! [`crate::p4info_to_ddlog`] derives from each [`p4ext::Table`], plus a pluggable [`Emit`] trait
+//! for turning that analysis into a concrete output format. Building the IR once and handing it
+//! to an `Emit` implementation lets the DDlog `.dl` text and the JSON metadata schema share the
+//! same analysis instead of duplicating it.
+
+use anyhow::Result;
+
+use p4ext::{Action, ActionProfile, MatchType, Table};
+
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// Shape of the DDlog type generated for a match field, mirroring [`p4ext::MatchType`] but
+/// without its `Other` string payload, which is carried separately so `JsonEmit` doesn't have to
+/// parse an opaque string to tell "ternary" from "some architecture-specific thing".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MatchKindIr {
+    /// Exact match: matches the field's basic type.
+    Exact,
+    /// Longest-prefix match: a value plus a 32-bit prefix length.
+    Lpm,
+    /// Ternary match: a value plus a mask of the field's width.
+    Ternary,
+    /// Range match: inclusive lower and upper bounds of the field's width.
+    Range,
+    /// Optional match: the field's basic type, wrapped so it may be omitted.
+    Optional,
+    /// Unspecified match type, or an architecture-specific one named by the `String`.
+    Other(Option<String>),
+}
+
+impl MatchKindIr {
+    /// Returns the name this match kind is reported under in [`JsonEmit`]'s output.
+    fn label(&self) -> &str {
+        match self {
+            MatchKindIr::Exact => "exact",
+            MatchKindIr::Lpm => "lpm",
+            MatchKindIr::Ternary => "ternary",
+            MatchKindIr::Range => "range",
+            MatchKindIr::Optional => "optional",
+            MatchKindIr::Other(_) => "other",
+        }
+    }
+}
+
+impl From<&MatchType> for MatchKindIr {
+    fn from(mt: &MatchType) -> Self {
+        match mt {
+            MatchType::Unspecified => MatchKindIr::Other(None),
+            MatchType::Exact => MatchKindIr::Exact,
+            MatchType::LPM => MatchKindIr::Lpm,
+            MatchType::Ternary => MatchKindIr::Ternary,
+            MatchType::Range => MatchKindIr::Range,
+            MatchType::Optional => MatchKindIr::Optional,
+            MatchType::Other(name) => MatchKindIr::Other(Some(name.clone())),
+        }
+    }
+}
+
+/// One field of a [`RelationIr`]: either a P4 match field or the synthetic `priority` field added
+/// when [`p4ext::Table::has_priority`] is true, for which `match_kind` is `None` since it isn't
+/// matched against through P4Runtime's own match-field machinery.
+#[derive(Clone, Debug)]
+pub struct FieldIr {
+    /// Field name, as it appears in the generated relation.
+    pub name: String,
+    /// Full DDlog type, e.g. `"(bit<5>, bit<5>)"` for a ternary-matched 5-bit field.
+    pub full_type: String,
+    /// Width in bits of the underlying P4 value, before any match-type wrapping.
+    pub bit_width: i32,
+    /// Match behavior, or `None` for the synthetic `priority` field.
+    pub match_kind: Option<MatchKindIr>,
+}
+
+/// One parameter of an [`ActionVariantIr`].
+#[derive(Clone, Debug)]
+pub struct ActionParamIr {
+    /// Parameter name.
+    pub name: String,
+    /// DDlog type of the parameter: a named type, for params with a `type_name` (also pushed onto
+    /// the typedef worklist when the IR is built), or a plain `bit<N>`/`bool`.
+    pub ddlog_type: String,
+}
+
+/// One variant (i.e. one P4 action usable in table entries) of an [`ActionEnumIr`].
+#[derive(Clone, Debug)]
+pub struct ActionVariantIr {
+    /// The action's alias, used as the variant's name suffix (`{action_enum}{alias}`).
+    pub alias: String,
+    /// The action's parameters, empty for a parameterless action.
+    pub params: Vec<ActionParamIr>,
+}
+
+/// The `typedef {name}Action = {name}Action<Alias1>{..} | ...` enum shared by a [`RelationIr`]
+/// (for a direct table) or a [`ProfileIr`] (for every table indirecting through it).
+#[derive(Clone, Debug)]
+pub struct ActionEnumIr {
+    /// Name of the generated DDlog typedef, `"{name}Action"`.
+    pub type_name: String,
+    /// One variant per action usable in a table entry.
+    pub variants: Vec<ActionVariantIr>,
+}
+
+/// Builds the full `{prefix}Action` enum for `table`'s entry actions -- one variant per action,
+/// regardless of how many there are -- pushing any named action-param type onto `worklist`.
+fn build_action_enum(prefix: &str, table: &Table, worklist: &mut Vec<String>) -> ActionEnumIr {
+    let type_name = format!("{}Action", prefix);
+    let variants = table
+        .entry_actions()
+        .map(|ar| &ar.action)
+        .map(|a: &Action| ActionVariantIr {
+            alias: a.preamble.alias.clone(),
+            params: a
+                .params
+                .iter()
+                .map(|p| {
+                    // A named type (e.g. an enum or a `new_type`) takes priority over the raw
+                    // bitwidth; feed it into the typedef worklist so its definition gets emitted
+                    // too.
+                    let ddlog_type = match &p.type_name {
+                        Some(type_name) => {
+                            worklist.push(type_name.clone());
+                            type_name.clone()
+                        },
+                        None => p.p4_basic_type(),
+                    };
+                    ActionParamIr { name: p.preamble.name.clone(), ddlog_type }
+                })
+                .collect(),
+        })
+        .collect();
+    ActionEnumIr { type_name, variants }
+}
+
+/// Builds the `{prefix}Action` enum for a *direct* table's entry actions, the way
+/// [`crate::p4info_to_ddlog`] did before this was factored out into an IR: if there's just one
+/// action and it has no parameters, no enum (or action column) is needed at all.
+fn build_inline_action_enum(
+    prefix: &str,
+    table: &Table,
+    worklist: &mut Vec<String>,
+) -> Option<ActionEnumIr> {
+    let actions: Vec<&Action> = table.entry_actions().map(|ar| &ar.action).collect();
+    let needs_actions = actions.len() > 1 || (actions.len() == 1 && !actions[0].params.is_empty());
+    if !needs_actions {
+        return None;
+    }
+    Some(build_action_enum(prefix, table, worklist))
+}
+
+/// One [`p4ext::ActionProfile`]'s analysis: the member (and, for an action selector, group)
+/// relations shared by every [`RelationIr`] whose table indirects its actions through it, instead
+/// of inlining them.
+#[derive(Clone, Debug)]
+pub struct ProfileIr {
+    /// The profile's base name (see [`p4ext::Table::base_name`]), used to name the generated
+    /// relations and action enum.
+    pub name: String,
+    /// The `{name}Action` enum shared by every member of the profile, built from the entry
+    /// actions of the first table found that references this profile -- tables sharing a profile
+    /// are expected to agree on its action set.
+    pub action_enum: ActionEnumIr,
+    /// Name of the `{name}Member(member_id: bit<32>, action: {name}Action)` relation.
+    pub member_relation_name: String,
+    /// Name of the `{name}Group(group_id: bit<32>, members: Vec<(bit<32>, bit<32>)>)` relation
+    /// mapping a group to its weighted `(member_id, weight)` pairs. `None` unless the profile is
+    /// an action selector.
+    pub group_relation_name: Option<String>,
+    /// Maximum number of members in a single group, reported as a comment annotation on the
+    /// generated group relation so the assembler side can later build `ActionProfileGroup`
+    /// updates with the right `max_group_size`. Only meaningful when `group_relation_name` is
+    /// `Some`.
+    pub max_group_size: Option<i32>,
+}
+
+impl ProfileIr {
+    /// Builds the `ProfileIr` for `profile`, using `table` (one of the tables whose
+    /// `implementation` is `profile`) for its action set.
+    pub fn new(profile: &ActionProfile, table: &Table, worklist: &mut Vec<String>) -> ProfileIr {
+        let name = strip_pipeline_prefix(&profile.preamble.name).to_string();
+        ProfileIr {
+            action_enum: build_action_enum(&name, table, worklist),
+            member_relation_name: format!("{}Member", name),
+            group_relation_name: profile.with_selector.then(|| format!("{}Group", name)),
+            max_group_size: profile.max_group_size,
+            name,
+        }
+    }
+}
+
+/// How a [`RelationIr`]'s entries choose their action.
+#[derive(Clone, Debug)]
+pub enum ActionRefIr {
+    /// The table's own actions are inlined into the relation. `action_enum` is `None` when the
+    /// table has a single parameterless action, needing no explicit action column at all.
+    Inline {
+        /// The `{table}Action` enum, if the table's actions need representing explicitly.
+        action_enum: Option<ActionEnumIr>,
+        /// True if a `{table}DefaultAction` relation is also needed, because the table has no
+        /// constant default action.
+        emit_default_action_relation: bool,
+    },
+    /// The table is indirect: entries carry a member or group ID (depending on whether `profile`
+    /// is an action selector) that's looked up in a relation shared by every table using the same
+    /// [`p4ext::ActionProfile`], instead of inlining an action.
+    Indirect(ProfileIr),
+}
+
+/// The table/field/action analysis for one P4 table, independent of how it's rendered.
+#[derive(Clone, Debug)]
+pub struct RelationIr {
+    /// The table's base name (see [`p4ext::Table::base_name`]), used as the relation name.
+    pub name: String,
+    /// The relation's fields: one per match field, plus a synthetic `priority` field if the table
+    /// needs one.
+    pub fields: Vec<FieldIr>,
+    /// How entries of this relation choose their action.
+    pub action_ref: ActionRefIr,
+    /// True if the user annotated the table with `@nerpa_singleton` (see
+    /// [`p4ext::Table::is_nerpa_singleton`]). Only takes effect if the relation ends up with
+    /// exactly one column once the action/member/group column (if any) is folded in.
+    pub singleton: bool,
+}
+
+impl RelationIr {
+    /// Builds the `RelationIr` for `table`. `profiles` must already hold the [`ProfileIr`] for
+    /// `table`'s implementation, if it has one (built once per distinct action profile, since
+    /// several tables can share one). Any action parameter with a named type is pushed onto
+    /// `worklist`, mirroring what the inline codegen this replaces did, so a caller draining
+    /// `worklist` still emits a `typedef` for every such type.
+    pub fn from_table(
+        table: &Table,
+        profiles: &HashMap<u32, ProfileIr>,
+        worklist: &mut Vec<String>,
+    ) -> RelationIr {
+        let name = table.base_name().to_string();
+
+        let mut fields: Vec<FieldIr> = table
+            .match_fields
+            .iter()
+            .map(|mf| FieldIr {
+                name: mf.preamble.name.clone(),
+                full_type: mf.p4_full_type(),
+                bit_width: mf.bit_width,
+                match_kind: Some(MatchKindIr::from(&mf.match_type)),
+            })
+            .collect();
+
+        if table.has_priority() {
+            fields.push(FieldIr {
+                name: "priority".to_string(),
+                full_type: "bit<32>".to_string(),
+                bit_width: 32,
+                match_kind: None,
+            });
+        }
+
+        let action_ref = match &table.implementation {
+            Some(ap) => {
+                let profile = profiles.get(&ap.preamble.id).unwrap_or_else(|| {
+                    panic!("no ProfileIr built for action profile {}", ap.preamble.name)
+                });
+                ActionRefIr::Indirect(profile.clone())
+            },
+            None => {
+                let action_enum = build_inline_action_enum(&name, table, worklist);
+                let emit_default_action_relation =
+                    action_enum.is_some() && table.const_default_action.is_none();
+                ActionRefIr::Inline { action_enum, emit_default_action_relation }
+            },
+        };
+
+        RelationIr { name, fields, action_ref, singleton: table.is_nerpa_singleton() }
+    }
+}
+
+/// Extracts the unqualified name from a `<pipeline>.<name>`-style P4 full name, as the P4 compiler
+/// names tables (see [`p4ext::Table::base_name`]). Returns the full name unchanged if it isn't in
+/// that format.
+fn strip_pipeline_prefix(full_name: &str) -> &str {
+    match full_name.split('.').collect::<Vec<_>>().as_slice() {
+        [_pipeline_name, name] => name,
+        _ => full_name,
+    }
+}
+
+/// Renders a set of [`RelationIr`]s into a concrete output format.
+pub trait Emit {
+    /// Renders `ir` as this backend's output format.
+    fn emit(&self, ir: &[RelationIr]) -> Result<String>;
+}
+
+/// Emits relations as the `typedef`/`output relation` DDlog declarations
+/// [`crate::p4info_to_ddlog`] wrote inline before this analysis was factored out into
+/// [`RelationIr`]. Produces byte-for-byte the same text as before for tables with no
+/// `implementation`.
+pub struct DdlogEmit;
+
+impl DdlogEmit {
+    /// Writes the `{name}Action` typedef shared by every variant of `action_enum`.
+    fn emit_action_enum(output: &mut String, action_enum: &ActionEnumIr) -> Result<()> {
+        write!(output, "typedef {}", action_enum.type_name)?;
+        for (i, v) in action_enum.variants.iter().enumerate() {
+            write!(
+                output,
+                " {} {}{}",
+                if i == 0 { "=" } else { "|" },
+                action_enum.type_name,
+                v.alias,
+            )?;
+            if !v.params.is_empty() {
+                let params: String = v
+                    .params
+                    .iter()
+                    .map(|p| format!("{}: {}", p.name, p.ddlog_type))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(output, "{{{}}}", params)?;
+            }
+        }
+        writeln!(output)?;
+        Ok(())
+    }
+
+    /// Writes the action enum and member/group relations shared by every table that indirects
+    /// through `profile`.
+    fn emit_profile(output: &mut String, profile: &ProfileIr) -> Result<()> {
+        Self::emit_action_enum(output, &profile.action_enum)?;
+
+        writeln!(output, "output relation {}(", profile.member_relation_name)?;
+        writeln!(output, "    member_id: bit<32>,")?;
+        writeln!(output, "    action: {}", profile.action_enum.type_name)?;
+        writeln!(output, ")")?;
+
+        if let Some(group_relation_name) = &profile.group_relation_name {
+            if let Some(max_group_size) = profile.max_group_size {
+                writeln!(output, "// @max_group_size {}", max_group_size)?;
+            }
+            writeln!(output, "output relation {}(", group_relation_name)?;
+            writeln!(output, "    group_id: bit<32>,")?;
+            writeln!(output, "    members: Vec<(bit<32>, bit<32>)>")?;
+            writeln!(output, ")")?;
+        }
+        Ok(())
+    }
+
+    /// Writes `relation`'s own declaration: its action typedef (for a direct table), the relation
+    /// itself, and its `DefaultAction` relation if it needs one.
+    fn emit_relation(output: &mut String, relation: &RelationIr) -> Result<()> {
+        // Declarations for 'relation', as (field_name, type) tuples; the action/member/group
+        // column, if any, is appended last, matching the column order of the original codegen.
+        let mut decls: Vec<(String, String)> = relation
+            .fields
+            .iter()
+            .map(|f| (f.name.clone(), f.full_type.clone()))
+            .collect();
+
+        match &relation.action_ref {
+            ActionRefIr::Inline { action_enum: Some(ae), .. } => {
+                Self::emit_action_enum(output, ae)?;
+                decls.push(("action".to_string(), ae.type_name.clone()));
+            },
+            ActionRefIr::Inline { action_enum: None, .. } => {},
+            ActionRefIr::Indirect(profile) => {
+                let column = match &profile.group_relation_name {
+                    Some(_) => "group_id",
+                    None => "member_id",
+                };
+                decls.push((column.to_string(), "bit<32>".to_string()));
+            },
+        }
+
+        if decls.len() == 1 && relation.singleton {
+            let (_, full_type) = &decls[0];
+            writeln!(output, "output relation {}[{}]", relation.name, full_type)?;
+        } else {
+            writeln!(output, "output relation {}(", relation.name)?;
+            for (i, (name, full_type)) in decls.iter().enumerate() {
+                let delimiter = if i == decls.len() - 1 { "" } else { "," };
+                writeln!(output, "    {}: {}{}", name, full_type, delimiter)?;
+            }
+            writeln!(output, ")")?;
+        }
+
+        if let ActionRefIr::Inline { action_enum: Some(ae), emit_default_action_relation: true } =
+            &relation.action_ref
+        {
+            writeln!(output, "output relation {}DefaultAction(", relation.name)?;
+            writeln!(output, "    action: {}", ae.type_name)?;
+            writeln!(output, ")")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Emit for DdlogEmit {
+    fn emit(&self, ir: &[RelationIr]) -> Result<String> {
+        let mut output = String::new();
+
+        // Every profile shared by one or more indirect tables is declared once, before any table
+        // that references it, the first time it's encountered.
+        let mut emitted_profiles: HashSet<String> = HashSet::new();
+        for relation in ir {
+            if let ActionRefIr::Indirect(profile) = &relation.action_ref {
+                if emitted_profiles.insert(profile.member_relation_name.clone()) {
+                    Self::emit_profile(&mut output, profile)?;
+                }
+            }
+        }
+
+        for relation in ir {
+            Self::emit_relation(&mut output, relation)?;
+        }
+
+        Ok(output)
+    }
+}
+
+/// Schema version for [`JsonEmit`]'s output. Bump this whenever the document's shape changes
+/// incompatibly, so downstream tooling can detect and reject a schema it doesn't understand.
+const JSON_SCHEMA_VERSION: u32 = 2;
+
+/// Emits relations as a versioned JSON document describing each relation's match-field layout
+/// (name, bitwidth, match type, LPM/range/ternary shape) and action signatures -- inline, or via a
+/// shared action-profile member/group relation -- so downstream tooling can consume a stable
+/// schema without parsing `.dl` text.
+pub struct JsonEmit;
+
+impl JsonEmit {
+    fn action_enum_to_json(action_enum: &ActionEnumIr) -> Value {
+        let variants: Vec<Value> = action_enum
+            .variants
+            .iter()
+            .map(|v| {
+                let params: Vec<Value> = v
+                    .params
+                    .iter()
+                    .map(|p| json!({ "name": p.name, "ddlog_type": p.ddlog_type }))
+                    .collect();
+                json!({ "alias": v.alias, "params": params })
+            })
+            .collect();
+        json!({ "type_name": action_enum.type_name, "variants": variants })
+    }
+
+    fn profile_to_json(profile: &ProfileIr) -> Value {
+        json!({
+            "name": profile.name,
+            "action_enum": Self::action_enum_to_json(&profile.action_enum),
+            "member_relation_name": profile.member_relation_name,
+            "group_relation_name": profile.group_relation_name,
+            "max_group_size": profile.max_group_size,
+        })
+    }
+}
+
+impl Emit for JsonEmit {
+    fn emit(&self, ir: &[RelationIr]) -> Result<String> {
+        let mut profiles: Vec<Value> = Vec::new();
+        let mut emitted_profiles: HashSet<String> = HashSet::new();
+
+        let relations: Vec<Value> = ir
+            .iter()
+            .map(|relation| {
+                let fields: Vec<Value> = relation
+                    .fields
+                    .iter()
+                    .map(|f| {
+                        json!({
+                            "name": f.name,
+                            "bit_width": f.bit_width,
+                            "ddlog_type": f.full_type,
+                            "match_kind": f.match_kind.as_ref().map(MatchKindIr::label),
+                        })
+                    })
+                    .collect();
+
+                let action_ref = match &relation.action_ref {
+                    ActionRefIr::Inline { action_enum, emit_default_action_relation } => json!({
+                        "kind": "inline",
+                        "action_enum": action_enum.as_ref().map(Self::action_enum_to_json),
+                        "emit_default_action_relation": emit_default_action_relation,
+                    }),
+                    ActionRefIr::Indirect(profile) => {
+                        if emitted_profiles.insert(profile.member_relation_name.clone()) {
+                            profiles.push(Self::profile_to_json(profile));
+                        }
+                        json!({
+                            "kind": if profile.group_relation_name.is_some() { "group" } else { "member" },
+                            "profile": profile.name,
+                        })
+                    },
+                };
+
+                json!({
+                    "name": relation.name,
+                    "singleton": relation.singleton,
+                    "fields": fields,
+                    "action_ref": action_ref,
+                })
+            })
+            .collect();
+
+        let doc = json!({
+            "schema_version": JSON_SCHEMA_VERSION,
+            "action_profiles": profiles,
+            "relations": relations,
+        });
+        Ok(serde_json::to_string_pretty(&doc)?)
+    }
+}
@@ -29,6 +29,9 @@ SOFTWARE.
 
 mod dp2ddlog;
 mod controller;
+mod ir;
+
+use ir::{DdlogEmit, Emit, JsonEmit, ProfileIr, RelationIr};
 
 use anyhow::{anyhow, Context, Result};
 
@@ -39,15 +42,52 @@ use proto::p4types::P4BitstringLikeTypeSpec_oneof_type_spec as P4BitstringTypeSp
 
 use protobuf::Message;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt::Write;
 use std::fs;
 use std::fs::File;
 use std::io::Write as IoWrite;
+use std::path::PathBuf;
+
+/// Extensions [`find_p4info`] looks for, in the order they're tried.
+const P4INFO_EXTENSIONS: &[&str] = &["p4info.bin", "p4info.txt", "p4info.json"];
+
+/// Locates `{file_dir}/{file_name}.p4info.*`, preferring the binary protobuf p4c normally emits
+/// but falling back to the protobuf text format (or JSON, once supported -- see [`read_p4info`])
+/// for hand-written or hand-edited P4Info.
+fn find_p4info(file_dir: &str, file_name: &str) -> Result<PathBuf> {
+    for ext in P4INFO_EXTENSIONS {
+        let candidate = PathBuf::from(format!("{}/{}.{}", file_dir, file_name, ext));
+        if candidate.exists() {
+            return Ok(candidate);
+        }
+    }
+    Err(anyhow!(
+        "{}/{}: no P4Info file found (tried .{})",
+        file_dir,
+        file_name,
+        P4INFO_EXTENSIONS.join(", ."),
+    ))
+}
 
 fn read_p4info(filename_os: &OsStr) -> Result<P4Info> {
     let filename = filename_os.to_string_lossy();
+    if filename.ends_with(".txt") {
+        let contents = fs::read_to_string(filename_os)
+            .with_context(|| format!("{}: read failed", filename))?;
+        return protobuf::text_format::parse_from_str(&contents)
+            .with_context(|| format!("{}: invalid P4Info text format", filename));
+    }
+    if filename.ends_with(".json") {
+        // The `protobuf` crate this workspace is pinned to predates its JSON mapping support
+        // (that landed alongside the v3 proto3-API rewrite). Until we pick that up, fail with a
+        // message that points at the gap instead of silently mis-parsing or panicking.
+        return Err(anyhow!(
+            "{}: JSON P4Info isn't supported yet (use .p4info.bin or .p4info.txt)",
+            filename
+        ));
+    }
     let mut file = File::open(filename_os).with_context(|| format!("{}: open failed", filename))?;
     Message::parse_from_reader(&mut file).with_context(|| format!("{}: read failed", filename))
 }
@@ -63,11 +103,18 @@ fn get_pipelines(
         .map(|a| (a.get_preamble().id, a.into()))
         .collect();
 
+    // Action profiles (and selectors) are referenced by id too.
+    let action_profile_by_id: HashMap<u32, p4ext::ActionProfile> = p4info
+        .get_action_profiles()
+        .iter()
+        .map(|ap| (ap.get_preamble().id, ap.into()))
+        .collect();
+
     // Break up table names into "<pipeline>.<table>" and group by pipeline.
     let mut pipelines: MultiMap<String, p4ext::Table> = p4info
         .get_tables()
         .iter()
-        .map(|table| p4ext::Table::new_from_proto(table, &action_by_id))
+        .map(|table| p4ext::Table::new_from_proto(table, &action_by_id, &action_profile_by_id))
         .filter_map(|table| {
             match table.pipeline_name() {
                 Some(pipeline) => Some((pipeline.to_string(), table)),
@@ -94,6 +141,45 @@ fn get_pipelines(
 
 use proto::p4types::P4DataTypeSpec_oneof_type_spec as P4DataTypeSpec;
 
+/// How serious a [`Diagnostic`] is. A `Warning` means codegen skipped or degraded something but
+/// kept going; an `Error` means the generated `.dl` would be wrong or incomplete, so
+/// [`p4info_to_ddlog`] refuses to write it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Warning,
+    Error,
+}
+
+/// One codegen problem, located by where in the P4Info it came from (e.g. `["foo", "bar"]` for
+/// field `bar` of digest or struct `foo`), collected instead of panicking so one unsupported
+/// construct doesn't stop the rest of the program from generating.
+#[derive(Debug, Clone)]
+struct Diagnostic {
+    severity: Severity,
+    path: Vec<String>,
+    message: String,
+}
+
+impl Diagnostic {
+    fn warning(path: Vec<String>, message: impl Into<String>) -> Diagnostic {
+        Diagnostic { severity: Severity::Warning, path, message: message.into() }
+    }
+
+    fn error(path: Vec<String>, message: impl Into<String>) -> Diagnostic {
+        Diagnostic { severity: Severity::Error, path, message: message.into() }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", level, self.path.join("."), self.message)
+    }
+}
+
 fn extract_p4data_types(
     type_spec: &Option<proto::p4types::P4DataTypeSpec_oneof_type_spec>
 ) -> Vec<String> {
@@ -112,8 +198,9 @@ fn extract_p4data_types(
         Some(P4DataTypeSpec::header_stack(ref hs)) => types.push(hs.get_header().get_name().to_owned()),
         Some(P4DataTypeSpec::header_union_stack(ref hus)) => types.push(hus.get_header_union().get_name().to_owned()),
         Some(P4DataTypeSpec::field_enum(ref fe)) => types.push(fe.get_name().to_owned()),
-        // Since the Debug trait is implemented for `P4ErrorType`, this should print the name as a String.
-        Some(P4DataTypeSpec::error(ref e)) => types.push(format!("{:#?}", e)),
+        // There's only one P4 error type per program, so there's no name to read off the
+        // `P4ErrorTypeSpec` itself; `emit_typedef` special-cases this fixed name.
+        Some(P4DataTypeSpec::error(ref _e)) => types.push("error".to_owned()),
         Some(P4DataTypeSpec::serializable_enum(ref se)) => types.push(se.get_name().to_owned()),
         Some(P4DataTypeSpec::new_type(ref nt)) => types.push(nt.get_name().to_owned()),
         None => {},
@@ -122,24 +209,53 @@ fn extract_p4data_types(
     types
 }
 
+/// Interprets a P4Info `bytes` field (big-endian, as used for serializable-enum member values)
+/// as an unsigned integer.
+fn bytes_to_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b))
+}
+
+/// Hashes `bytes` for the provenance header stamped into generated files. Not cryptographic --
+/// just enough to tell "this P4Info changed" from "this P4Info didn't" across codegen runs.
+fn hash_p4info_bytes(bytes: &[u8]) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn bitstring_to_ddlog_type(
+    bs: &proto::p4types::P4BitstringLikeTypeSpec,
+    path: &[String],
+    diags: &mut Vec<Diagnostic>,
+) -> String {
+    match &bs.type_spec {
+        Some(P4BitstringTypeSpec::bit(b)) => format!("bit<{}>", b.get_bitwidth()),
+        Some(P4BitstringTypeSpec::varbit(v)) => format!("bit<{}>", v.get_max_bitwidth()),
+        Some(P4BitstringTypeSpec::int(i)) => format!("signed<{}>", i.get_bitwidth()),
+        None => {
+            diags.push(Diagnostic::error(path.to_vec(), "bitstring-like type has no width spec"));
+            String::new()
+        },
+    }
+}
+
 fn p4data_to_ddlog_type(
-    type_spec: &Option<proto::p4types::P4DataTypeSpec_oneof_type_spec>
+    type_spec: &Option<proto::p4types::P4DataTypeSpec_oneof_type_spec>,
+    path: &[String],
+    diags: &mut Vec<Diagnostic>,
 ) -> String {
     match type_spec {
-        Some(P4DataTypeSpec::bitstring(ref bs)) => {
-            match &bs.type_spec {
-                Some(P4BitstringTypeSpec::bit(b)) => format!("bit<{}>", b.get_bitwidth()),
-                Some(P4BitstringTypeSpec::varbit(v)) => format!("bit<{}>", v.get_max_bitwidth()),
-                Some(P4BitstringTypeSpec::int(i)) => format!("signed<{}>", i.get_bitwidth()),
-                None => String::new(), // should never happen
-            }
-        },
+        Some(P4DataTypeSpec::bitstring(ref bs)) => bitstring_to_ddlog_type(bs, path, diags),
         Some(P4DataTypeSpec::bool(_)) => format!("bool"),
         Some(P4DataTypeSpec::tuple(t)) => {
             let members = t.get_members();
             let mut tuple_types = Vec::new();
-            for tm in members.iter() {
-                tuple_types.push(p4data_to_ddlog_type(&tm.type_spec));
+            for (i, tm) in members.iter().enumerate() {
+                let mut elem_path = path.to_vec();
+                elem_path.push(format!("#{}", i));
+                tuple_types.push(p4data_to_ddlog_type(&tm.type_spec, &elem_path, diags));
             }
 
             // P4 has 1-element tuples, while DDlog does not.
@@ -167,13 +283,138 @@ fn p4data_to_ddlog_type(
         // The header union stack is an array of type `header union` and length `size`.
         Some(P4DataTypeSpec::header_union_stack(ref hus)) => format!("Vec<{}>", hus.get_header_union().get_name()),
         Some(P4DataTypeSpec::field_enum(ref fe)) => fe.get_name().to_owned(),
-
-        // TODO: Potentially create P4 error type in DDlog.
         Some(P4DataTypeSpec::error(ref _e)) => format!("error"),
         Some(P4DataTypeSpec::serializable_enum(ref se)) => se.get_name().to_owned(),
         Some(P4DataTypeSpec::new_type(ref nt)) => nt.get_name().to_owned(),
-        None => format!(""), // should never happen
+        None => {
+            diags.push(Diagnostic::error(path.to_vec(), "field has no type spec"));
+            String::new()
+        },
+    }
+}
+
+/// Emits a `typedef` for the named type `name`, looked up across every `type_info` map that can
+/// hold it, and pushes any named types it in turn references onto `worklist` so a caller popping
+/// the worklist to exhaustion emits the transitive closure. Structs and headers can reference
+/// each other (including cyclically, e.g. a header union listing headers that share a struct),
+/// so the caller -- not this function -- is responsible for tracking which names are already
+/// emitted and skipping them.
+fn emit_typedef(
+    type_info: &proto::p4types::P4TypeInfo,
+    name: &str,
+    output: &mut String,
+    worklist: &mut Vec<String>,
+    diags: &mut Vec<Diagnostic>,
+) -> Result<()> {
+    if let Some(s) = type_info.get_structs().get(name) {
+        write!(output, "typedef {} = {}{{", name, name)?;
+        let members = s.get_members();
+        for (i, m) in members.iter().enumerate() {
+            let delimiter = if i == members.len() - 1 { "" } else { "," };
+            let type_spec = &m.get_type_spec().type_spec;
+            worklist.append(&mut extract_p4data_types(type_spec));
+            let path = vec![name.to_string(), m.get_name().to_string()];
+            write!(output, "{}: {}{}", m.get_name(), p4data_to_ddlog_type(type_spec, &path, diags), delimiter)?;
+        }
+        writeln!(output, "}}")?;
+        return Ok(());
+    }
+
+    if let Some(h) = type_info.get_headers().get(name) {
+        // `valid` tracks whether the header was extracted from the packet (`isValid()` in P4),
+        // since DDlog has no notion of header validity of its own.
+        write!(output, "typedef {} = {}{{valid: bool", name, name)?;
+        for m in h.get_members().iter() {
+            let path = vec![name.to_string(), m.get_name().to_string()];
+            write!(output, ", {}: {}", m.get_name(), bitstring_to_ddlog_type(m.get_type_spec(), &path, diags))?;
+        }
+        writeln!(output, "}}")?;
+        return Ok(());
+    }
+
+    if let Some(hu) = type_info.get_header_unions().get(name) {
+        write!(output, "typedef {} = {}{{", name, name)?;
+        let members = hu.get_members();
+        for (i, m) in members.iter().enumerate() {
+            let delimiter = if i == members.len() - 1 { "" } else { "," };
+            let header_name = m.get_header().get_name().to_owned();
+            write!(output, "{}: {}{}", m.get_name(), header_name, delimiter)?;
+            worklist.push(header_name);
+        }
+        writeln!(output, "}}")?;
+        return Ok(());
+    }
+
+    if name == "error" {
+        write!(output, "typedef error")?;
+        for (i, m) in type_info.get_error().get_members().iter().enumerate() {
+            write!(output, " {} {}", if i == 0 { "=" } else { "|" }, m)?;
+        }
+        writeln!(output)?;
+        return Ok(());
+    }
+
+    if let Some(fe) = type_info.get_enums().get(name) {
+        write!(output, "typedef {}", name)?;
+        for (i, m) in fe.get_members().iter().enumerate() {
+            write!(output, " {} {}{}", if i == 0 { "=" } else { "|" }, name, m)?;
+        }
+        writeln!(output)?;
+        return Ok(());
+    }
+
+    if let Some(se) = type_info.get_serializable_enums().get(name) {
+        let value_type = bitstring_to_ddlog_type(se.get_underlying_type(), &[name.to_string()], diags);
+        let members = se.get_members();
+
+        write!(output, "typedef {}", name)?;
+        for (i, m) in members.iter().enumerate() {
+            write!(output, " {} {}{}", if i == 0 { "=" } else { "|" }, name, m.get_name())?;
+        }
+        writeln!(output)?;
+
+        // A bare constructor per named value, plus a function back to the numeric value P4Info
+        // assigned it, so the control plane can round-trip a digest/match-field value to and
+        // from the wire representation.
+        writeln!(output, "function {}2value(x: {}): {} = match (x) {{", name, name, value_type)?;
+        for (i, m) in members.iter().enumerate() {
+            let delimiter = if i == members.len() - 1 { "" } else { "," };
+            writeln!(output, "    {}{} -> {}{}", name, m.get_name(), bytes_to_u64(m.get_value()), delimiter)?;
+        }
+        writeln!(output, "}}")?;
+        return Ok(());
     }
+
+    // `new_type` still resolves to a bare name via `p4data_to_ddlog_type`; it's a transparent
+    // alias for another type (itself possibly a struct/header/enum), not a distinct definition,
+    // so there's nothing for this function to emit.
+    Ok(())
+}
+
+/// Emits `name`'s typedef into `output` only after recursively emitting the typedefs of every
+/// name it references, so a referenced type's `typedef` always precedes its user's -- DDlog, like
+/// most typed languages, expects declaration before use. `emitted` tracks every name already
+/// written (or in the process of being written, which breaks the cycles `emit_typedef`'s doc
+/// comment warns about) across the whole call tree, so a name referenced from more than one place
+/// is still emitted exactly once.
+fn emit_typedef_closure(
+    type_info: &proto::p4types::P4TypeInfo,
+    name: &str,
+    output: &mut String,
+    emitted: &mut HashSet<String>,
+    diags: &mut Vec<Diagnostic>,
+) -> Result<()> {
+    if !emitted.insert(name.to_string()) {
+        return Ok(());
+    }
+    let mut deps = Vec::new();
+    let mut own = String::new();
+    emit_typedef(type_info, name, &mut own, &mut deps, diags)?;
+    for dep in deps {
+        emit_typedef_closure(type_info, &dep, output, emitted, diags)?;
+    }
+    output.push_str(&own);
+    Ok(())
 }
 
 /// Convert P4 program information to DDlog relations. Generate external crates.
@@ -189,105 +430,58 @@ pub fn p4info_to_ddlog(
     crate_arg: Option<&str>,
     pipeline_arg: Option<&str>,
 ) -> Result<()> {
-    let p4info_fn = format!("{}/{}.p4info.bin", file_dir, file_name);
-    let p4info = read_p4info(OsStr::new(&p4info_fn))?;
+    let p4info_path = find_p4info(file_dir, file_name)?;
+    let p4info = read_p4info(p4info_path.as_os_str())?;
+
+    // A hash of the P4Info this file was generated from, stamped into both the `.dl` and the
+    // `dp2ddlog` crate, so a later mismatch (P4Info regenerated from the .p4 program, but these
+    // dependent crates weren't) is a clear error instead of a mysterious digest-decode failure.
+    // Hashed as raw file bytes, not a protobuf re-serialization, since map field ordering in a
+    // re-serialized message isn't guaranteed stable across processes -- the raw bytes are.
+    let p4info_raw_bytes = fs::read(&p4info_path)
+        .with_context(|| format!("{}: read failed", p4info_path.display()))?;
+    let p4info_hash = hash_p4info_bytes(&p4info_raw_bytes);
+    let schema_version = p4info.get_pkg_info().get_version().to_string();
 
     let pipelines = get_pipelines(p4info.clone(), pipeline_arg)?;
 
     let mut output = String::new();
-
-    // TODO: Create types corresponding to headers and header unions.
-    // It's possible that we need to do this for fields in output relations.
-    // Input relations are only generated from digests, and digests can only have bitstrings.
-
-    for (_, tables) in pipelines {
+    writeln!(output, "// Generated by p4info2ddlog {}.", env!("CARGO_PKG_VERSION"))?;
+    writeln!(
+        output,
+        "// P4Info schema version {:?}, hash {:016x}.",
+        schema_version, p4info_hash,
+    )?;
+    writeln!(output)?;
+
+    // Named types referenced anywhere in the generated relations, resolved to `typedef`s in one
+    // pass below. Table match fields are always plain bitstrings in P4Runtime (there's no
+    // struct/header-typed match), but action params can carry a `type_name`, and digest struct
+    // members can be arbitrarily nested -- both get fed in here as they're discovered.
+    let mut worklist: Vec<String> = Vec::new();
+
+    // Build the table/field/action analysis for every table up front, independent of how it's
+    // rendered, then hand it to `DdlogEmit` below for the `.dl` text (and, at the end of this
+    // function, to `JsonEmit` for the JSON metadata schema). Tables with an `implementation` share
+    // one `ProfileIr` per action profile (built once, from whichever such table is seen first),
+    // since several tables can indirect through the same profile.
+    let mut profiles: HashMap<u32, ProfileIr> = HashMap::new();
+    let mut relations: Vec<RelationIr> = Vec::new();
+    for (_, tables) in &pipelines {
         for table in tables {
-            let table_name = table.base_name();
-
-            // Declarations for 'table', as (field_name, type) tuples.
-            let mut decls = Vec::new();
-
-            // Basic declaration for each match field.
-            for mf in table.match_fields.iter() {
-                decls.push((mf.preamble.name.clone(), mf.p4_full_type()));
-            }
-
-            // If the match fields are all exact-match, we don't need
-            // a priority, otherwise include one.
-            if table.has_priority() {
-                decls.push(("priority".to_string(), "bit<32>".to_string()));
-            }
-
-            // Grab the actions for 'table'.  We only care about
-            // actions that we can set through the control plane, so
-            // filter those.
-            let actions: Vec<_> = table.entry_actions().map(|ar| &ar.action).collect();
-
-            // If there is just one action and it doesn't have any
-            // parameters, then we don't need to include the actions
-            // in the relation.
-            let needs_actions =
-                actions.len() > 1 || (actions.len() == 1 && !actions[0].params.is_empty());
-            if needs_actions {
-                let action_type_name = format!("{}Action", table_name);
-
-                write!(output, "typedef {}", action_type_name)?;
-                for (i, a) in actions.iter().enumerate() {
-                    write!(
-                        output,
-                        " {} {}{}",
-                        if i == 0 { "=" } else { "|" },
-                        action_type_name,
-                        a.preamble.alias
-                    )?;
-                    if !a.params.is_empty() {
-                        let params: String = a
-                            .params
-                            .iter()
-                            .map(|p| {
-                                format!("{}: {}", p.preamble.name, p.p4_basic_type())
-                            })
-                            .collect::<Vec<_>>()
-                            .join(", ");
-                        write!(output, "{{{}}}", params)?;
-                    }
-                }
-                writeln!(output)?;
-
-                decls.push(("action".to_string(), action_type_name));
-            }
-
-            // Ordinarily, we declare the relation to contain structs,
-            // but if the relation only has a single member and it's
-            // annotated with @nerpa_singleton, declare it as the type
-            // of that single member.
-            if decls.len() == 1 && table.is_nerpa_singleton() {
-                let (_, full_type) = &decls[0];
-                writeln!(output, "output relation {}[{}]", table_name, full_type)?;
-            } else {
-                writeln!(output, "output relation {}(", table_name)?;
-                for (i, (name, full_type)) in decls.iter().enumerate() {
-                    let delimiter = if i == decls.len() - 1 { "" } else { "," };
-                    writeln!(output, "    {}: {}{}", name, full_type, delimiter)?;
-                }
-                writeln!(output, ")")?;
-            }
-
-            // If the table does not have a constant `default_action`, then we
-            // create a relation to configure the default action.
-            // TODO: Check the form of this relation.
-            if needs_actions && table.const_default_action.is_none() {
-                writeln!(output, "output relation {}DefaultAction(", table_name)?;
-                writeln!(output, "    action: {}Action", table_name)?;
-                writeln!(output, ")")?;
+            if let Some(ap) = &table.implementation {
+                profiles
+                    .entry(ap.preamble.id)
+                    .or_insert_with(|| ProfileIr::new(ap, table, &mut worklist));
             }
+            relations.push(RelationIr::from_table(table, &profiles, &mut worklist));
         }
     }
+    write!(output, "{}", DdlogEmit.emit(&relations)?)?;
+
+    // Create input relations for the digest messages.
 
-    // Create input relations for the digest messages. 
-    
     // Map the digest name to its type information.
-    use std::collections::HashSet;
     let digest_names: HashSet<&str> = p4info
         .get_digests()
         .iter()
@@ -302,35 +496,20 @@ pub fn p4info_to_ddlog(
     let mut digest_structs = all_structs.clone();
     digest_structs.retain(|k, _| digest_names.contains(k.as_str()));
 
-    // Define all custom types needed for the input relations.
-    let mut typedefs_vec = Vec::new();
+    // Add the types the digest structs reference directly to the worklist seeded above, then
+    // keep emitting and following references until it's empty, so nested structs/headers/
+    // header-unions (and the named action-param types collected above) all get a `typedef`.
     for (_, ds) in digest_structs.iter() {
-        let members = ds.get_members();
-
-        for m in members.iter() {
-            typedefs_vec.append(&mut extract_p4data_types(&m.get_type_spec().type_spec));
+        for m in ds.get_members().iter() {
+            worklist.append(&mut extract_p4data_types(&m.get_type_spec().type_spec));
         }
     }
 
-    use std::iter::FromIterator;
-    let typedefs_set = HashSet::<String>::from_iter(typedefs_vec);
-    for (k, s) in all_structs.iter() {
-        if !typedefs_set.contains(k) {
-            continue;
-        }
-
-        write!(output, "typedef {} = {}{{", k, k)?;
-        let members = s.get_members();
-        for (i, m) in members.iter().enumerate() {
-            let delimiter = if i == members.len() - 1 { "" } else { "," };
-
-            let name = m.get_name();
-            let type_spec = &m.get_type_spec().type_spec;
-            let full_type = p4data_to_ddlog_type(type_spec);
-
-            write!(output, "{}: {}{}", name, full_type, delimiter)?;
-        }
-        writeln!(output, "}}")?;
+    let type_info = p4info.get_type_info();
+    let mut diags: Vec<Diagnostic> = Vec::new();
+    let mut emitted = HashSet::<String>::new();
+    while let Some(name) = worklist.pop() {
+        emit_typedef_closure(type_info, &name, &mut output, &mut emitted, &mut diags)?;
     }
 
     // Format the digests as input relations.
@@ -342,13 +521,29 @@ pub fn p4info_to_ddlog(
         let mut fields = Vec::new();
         for m in members.iter() {
             let type_spec = m.get_type_spec();
-            // P4Runtime only allows digest structs to have bitstring members.
+            let name = m.get_name();
+            let path = vec![k.clone(), name.to_string()];
+
+            // P4Runtime only allows digest structs to have bitstring members; skip any that
+            // don't, so one unsupported field doesn't stop the rest of the digest (or the rest
+            // of the program) from generating.
             if !type_spec.has_bitstring() || !type_spec.get_bitstring().has_bit() {
-                panic!("digest struct fields can only have bitstrings of type bit");
+                diags.push(Diagnostic::warning(
+                    path,
+                    "digest struct fields can only have bitstrings of type bit; skipping field",
+                ));
+                continue;
             }
 
-            let name = m.get_name();
-            let full_type = p4data_to_ddlog_type(&type_spec.type_spec);
+            // P4 programs can carry bitstrings wider than DDlog's `bit<N>` gets a fixed-width
+            // Rust integer for (128 bits); those are emitted as an opaque, left-zero-padded byte
+            // vector instead of forcing them through a type `digest_to_ddlog` can't construct.
+            let bitwidth = type_spec.get_bitstring().get_bit().get_bitwidth();
+            let full_type = if bitwidth > 128 {
+                "Vec<bit<8>>".to_string()
+            } else {
+                p4data_to_ddlog_type(&type_spec.type_spec, &path, &mut diags)
+            };
 
             fields.push((name, full_type));
         }
@@ -376,7 +571,15 @@ pub fn p4info_to_ddlog(
         let cm_meta = cm.get_metadata();
         writeln!(output, "{} relation {}(", relation_type, relation_name)?;
         for cmm in cm_meta.iter() {
-            writeln!(output, "    {}: bit<{}>,", cmm.get_name(), cmm.get_bitwidth())?;
+            // See the digest input relations above: bitstrings wider than 128 bits are emitted
+            // as a byte vector rather than a fixed-width `bit<N>`.
+            let bitwidth = cmm.get_bitwidth();
+            let field_type = if bitwidth > 128 {
+                "Vec<bit<8>>".to_string()
+            } else {
+                format!("bit<{}>", bitwidth)
+            };
+            writeln!(output, "    {}: {},", cmm.get_name(), field_type)?;
         }
 
         if is_packet_in {
@@ -389,6 +592,20 @@ pub fn p4info_to_ddlog(
         writeln!(output, ")")?;
     }
 
+    // Report every diagnostic collected along the way, but only abort -- without writing a `.dl`
+    // that the rest of the toolchain would choke on -- if at least one was an error.
+    let error_count = diags.iter().filter(|d| d.severity == Severity::Error).count();
+    for diag in &diags {
+        eprintln!("{}: {}", file_name, diag);
+    }
+    if error_count > 0 {
+        return Err(anyhow!(
+            "{}: {} error(s) generating DDlog from P4Info",
+            file_name,
+            error_count,
+        ));
+    }
+
     let output_fn = format!("{}/{}_dp.dl", file_dir, file_name);
     let output_filename_os = OsStr::new(&output_fn);
     let output_filename = output_filename_os.to_string_lossy();
@@ -397,6 +614,16 @@ pub fn p4info_to_ddlog(
         .write_all(output.as_bytes())
         .with_context(|| format!("{}: write failed", output_filename))?;
 
+    // Alongside the `.dl`, write the same table/field/action analysis as a versioned JSON
+    // document, so downstream tooling can consume a stable schema without parsing DDlog text.
+    let schema_fn = format!("{}/{}_dp.schema.json", file_dir, file_name);
+    let schema_filename_os = OsStr::new(&schema_fn);
+    let schema_filename = schema_filename_os.to_string_lossy();
+    File::create(schema_filename_os)
+        .with_context(|| format!("{}: create failed", schema_filename))?
+        .write_all(JsonEmit.emit(&relations)?.as_bytes())
+        .with_context(|| format!("{}: write failed", schema_filename))?;
+
     // Update dependencies in the `nerpa_controller` crate.
     controller::write_toml(
         file_dir,
@@ -424,7 +651,9 @@ pub fn p4info_to_ddlog(
         p4info.get_digests(),
         p4info.get_type_info(),
         p4info.get_controller_packet_metadata(),
-        file_name
+        file_name,
+        p4info_hash,
+        &schema_version,
     )?;
 
     File::create(crate_rs_os)
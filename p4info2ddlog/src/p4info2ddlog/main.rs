@@ -37,13 +37,13 @@ fn main() -> Result<()> {
         .about("Outputs DDlog relations corresponding to P4 tables")
         .arg(
             Arg::with_name(FILE_DIR_ARG)
-                .help("path to directory with input file (*.p4info.bin) and where output (*.dl) will be written")
+                .help("path to directory with input file (*.p4info.bin or *.p4info.txt) and where output (*.dl) will be written")
                 .required(true)
                 .index(1),
         )
         .arg(
             Arg::with_name(FILE_NAME_ARG)
-                .help("program name before the extension: {program}.p4info.bin, {program}.dl")
+                .help("program name before the extension: {program}.p4info.bin or {program}.p4info.txt, {program}.dl")
                 .required(true)
                 .index(2),
         )
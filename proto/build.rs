@@ -28,6 +28,7 @@ fn main() {
         ("p4runtime/proto", "p4/config/v1/p4types.proto"),
         ("googleapis", "google/rpc/status.proto"),
         ("googleapis", "google/rpc/code.proto"),
+        ("bmv2", "packet_io.proto"),
     ];
     for proto in &protos {
         println!("cargo:rerun-if-changed={}/{}", proto.0, proto.1);
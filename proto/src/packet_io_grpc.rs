@@ -0,0 +1,62 @@
+// This file is generated. Do not edit
+// @generated
+
+// https://github.com/Manishearth/rust-clippy/issues/702
+#![allow(unknown_lints)]
+#![allow(clippy::all)]
+
+#![allow(box_pointers)]
+#![allow(dead_code)]
+#![allow(missing_docs)]
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+#![allow(trivial_casts)]
+#![allow(unsafe_code)]
+#![allow(unused_imports)]
+#![allow(unused_results)]
+
+const METHOD_PACKET_IO_STREAM_PACKETS: ::grpcio::Method<super::packet_io::PacketIoMessage, super::packet_io::PacketIoMessage> = ::grpcio::Method {
+    ty: ::grpcio::MethodType::Duplex,
+    name: "/bmv2.PacketIo/StreamPackets",
+    req_mar: ::grpcio::Marshaller { ser: ::grpcio::pb_ser, de: ::grpcio::pb_de },
+    resp_mar: ::grpcio::Marshaller { ser: ::grpcio::pb_ser, de: ::grpcio::pb_de },
+};
+
+#[derive(Clone)]
+pub struct PacketIoClient {
+    client: ::grpcio::Client,
+}
+
+impl PacketIoClient {
+    pub fn new(channel: ::grpcio::Channel) -> Self {
+        PacketIoClient {
+            client: ::grpcio::Client::new(channel),
+        }
+    }
+
+    pub fn stream_packets_opt(&self, opt: ::grpcio::CallOption) -> ::grpcio::Result<(::grpcio::ClientDuplexSender<super::packet_io::PacketIoMessage>, ::grpcio::ClientDuplexReceiver<super::packet_io::PacketIoMessage>)> {
+        self.client.duplex_streaming(&METHOD_PACKET_IO_STREAM_PACKETS, opt)
+    }
+
+    pub fn stream_packets(&self) -> ::grpcio::Result<(::grpcio::ClientDuplexSender<super::packet_io::PacketIoMessage>, ::grpcio::ClientDuplexReceiver<super::packet_io::PacketIoMessage>)> {
+        self.stream_packets_opt(::grpcio::CallOption::default())
+    }
+
+    pub fn spawn<F>(&self, f: F) where F: ::futures::Future<Output = ()> + Send + 'static {
+        self.client.spawn(f)
+    }
+}
+
+pub trait PacketIo {
+    fn stream_packets(&mut self, ctx: ::grpcio::RpcContext, stream: ::grpcio::RequestStream<super::packet_io::PacketIoMessage>, sink: ::grpcio::DuplexSink<super::packet_io::PacketIoMessage>);
+}
+
+pub fn create_packet_io<S: PacketIo + Send + Clone + 'static>(s: S) -> ::grpcio::Service {
+    let mut builder = ::grpcio::ServiceBuilder::new();
+    let mut instance = s;
+    builder = builder.add_duplex_streaming_handler(&METHOD_PACKET_IO_STREAM_PACKETS, move |ctx, req, resp| {
+        instance.stream_packets(ctx, req, resp)
+    });
+    builder.build()
+}